@@ -1,3 +1,8 @@
+use crate::logging::Verbosity;
+use crate::sqlite::core::value::Value;
+use crate::sqlite::storage::db::MEMORY_PATH;
+use crate::sqlite::storage::generate::GenerateOptions;
+use crate::sqlite::storage::pager::PageVerification;
 use std::{env, fmt::Display, path::PathBuf};
 
 /// Available commands for the SQLite CLI
@@ -14,6 +19,21 @@ pub enum Command {
 pub enum MetaCommand {
     DbInfo,
     Tables,
+    /// Writes a zstd-compressed page container next to the database
+    Compress,
+    /// Prints what every page of the file is used for
+    PageMap,
+    /// Prints the depth, page counts and fill factor of every B-tree
+    BTreeStats,
+    /// Prints the free space inside the B-tree pages of every table and index,
+    /// or of each page of the named one
+    FreeSpace(Option<String>),
+    /// Creates the database file filled with generated rows
+    GenDb(GenerateOptions),
+    /// Prints a digest of the schema and rows, independent of page layout
+    Hash,
+    /// Runs the SQL statements in a file
+    Read(PathBuf),
 }
 
 impl std::str::FromStr for Command {
@@ -25,6 +45,24 @@ impl std::str::FromStr for Command {
             match s {
                 ".dbinfo" => Ok(Command::Meta(MetaCommand::DbInfo)),
                 ".tables" => Ok(Command::Meta(MetaCommand::Tables)),
+                ".compress" => Ok(Command::Meta(MetaCommand::Compress)),
+                ".pagemap" => Ok(Command::Meta(MetaCommand::PageMap)),
+                ".btree-stats" => Ok(Command::Meta(MetaCommand::BTreeStats)),
+                _ if s == ".freespace" || s.starts_with(".freespace ") => {
+                    let object = s[".freespace".len()..].trim();
+                    Ok(Command::Meta(MetaCommand::FreeSpace(
+                        (!object.is_empty()).then(|| object.to_string()),
+                    )))
+                }
+                ".hash" => Ok(Command::Meta(MetaCommand::Hash)),
+                _ if s.starts_with(".read ") => match s[".read".len()..].trim() {
+                    "" => Err("Usage: .read FILE".to_string()),
+                    path => Ok(Command::Meta(MetaCommand::Read(PathBuf::from(path)))),
+                },
+                ".read" => Err("Usage: .read FILE".to_string()),
+                _ if s == ".gendb" || s.starts_with(".gendb ") => Ok(Command::Meta(
+                    MetaCommand::GenDb(s[".gendb".len()..].parse()?),
+                )),
                 _ => Err(format!("Unknown meta command: {}", s)),
             }
         } else {
@@ -39,6 +77,16 @@ impl Display for Command {
         match self {
             Command::Meta(MetaCommand::DbInfo) => write!(f, ".dbinfo"),
             Command::Meta(MetaCommand::Tables) => write!(f, ".tables"),
+            Command::Meta(MetaCommand::Compress) => write!(f, ".compress"),
+            Command::Meta(MetaCommand::PageMap) => write!(f, ".pagemap"),
+            Command::Meta(MetaCommand::BTreeStats) => write!(f, ".btree-stats"),
+            Command::Meta(MetaCommand::FreeSpace(None)) => write!(f, ".freespace"),
+            Command::Meta(MetaCommand::FreeSpace(Some(object))) => {
+                write!(f, ".freespace {}", object)
+            }
+            Command::Meta(MetaCommand::GenDb(options)) => write!(f, ".gendb {}", options),
+            Command::Meta(MetaCommand::Hash) => write!(f, ".hash"),
+            Command::Meta(MetaCommand::Read(path)) => write!(f, ".read {}", path.display()),
             Command::Sql(sql) => write!(f, "{}", sql),
         }
     }
@@ -46,24 +94,114 @@ impl Display for Command {
 
 /// Command line arguments for the SQLite CLI
 pub struct Args {
-    /// Path to the SQLite database file to process
+    /// Path to the SQLite database file to process; `:memory:` when none is
+    /// given, for a database held in memory
     pub file: PathBuf,
 
     /// The command to execute (dbinfo)
     pub command: Command,
+
+    /// Passphrase for SQLCipher-encrypted databases (--key)
+    pub key: Option<String>,
+
+    /// Print a line of column names before query results (--header)
+    pub header: bool,
+
+    /// Threads full scans of aggregate queries may use (--threads)
+    pub threads: usize,
+
+    /// Read the database file through a memory map (--mmap)
+    pub mmap: bool,
+
+    /// Print the pages, cells and bytes each query read after its result
+    /// (--stats)
+    pub stats: bool,
+
+    /// Check every B-tree page read before using it (--verify), and checksum
+    /// every page read from the file (--verify-checksums)
+    pub verify: PageVerification,
+
+    /// Values bound to the statement's parameters in order (--param); text
+    /// that looks like a number binds as that number
+    pub params: Vec<Value>,
+
+    /// Diagnostic output level (-q, -v, -vv, -vvv)
+    pub verbosity: Verbosity,
 }
 
 impl Args {
     pub fn parse() -> Result<Self, String> {
-        let args: Vec<String> = env::args().collect();
+        let usage = "Usage: <program> [-q | -v...] [--key <passphrase>] [--header] [--threads <n>] [--mmap] [--stats] [--verify | --verify-checksums] [--param <value>]... [<database_file>] <command-or-sql-statement>";
+        let mut args = env::args().skip(1);
+        let mut key = None;
+        let mut header = false;
+        let mut threads = 1;
+        let mut mmap = false;
+        let mut stats = false;
+        let mut verify = PageVerification::Off;
+        let mut params = Vec::new();
+        let mut quiet = false;
+        let mut verbose = 0u8;
+        let mut positional = Vec::new();
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--key" => key = Some(args.next().ok_or(usage)?),
+                "--header" => header = true,
+                "--threads" => {
+                    threads = args
+                        .next()
+                        .and_then(|n| n.parse().ok())
+                        .filter(|&n| n > 0)
+                        .ok_or("--threads needs a positive number")?
+                }
+                "--mmap" => mmap = true,
+                "--stats" => stats = true,
+                "--verify" => verify = PageVerification::Structure,
+                "--verify-checksums" => verify = PageVerification::Checksums,
+                "--param" => {
+                    params.push(Value::Text(args.next().ok_or(usage)?).with_numeric_affinity())
+                }
+                "-q" | "--quiet" => quiet = true,
+                "--verbose" => verbose = verbose.saturating_add(1),
+                // -v, -vv, -vvv
+                flag if flag.len() > 1
+                    && flag.starts_with('-')
+                    && flag[1..].chars().all(|c| c == 'v') =>
+                {
+                    verbose = verbose.saturating_add((flag.len() - 1) as u8)
+                }
+                _ => positional.push(arg),
+            }
+        }
 
-        if args.len() != 3 {
-            return Err("Usage: <program> <database_file> <command-or-sql-statement>".to_string());
+        if quiet && verbose > 0 {
+            return Err("--quiet and --verbose cannot be combined".to_string());
         }
+        let verbosity = if quiet {
+            Verbosity::Quiet
+        } else {
+            Verbosity::from_count(verbose)
+        };
 
-        let file = PathBuf::from(&args[1]);
-        let command = args[2].parse()?;
+        let (file, command) = match positional.as_slice() {
+            [command] => (PathBuf::from(MEMORY_PATH), command),
+            [file, command] => (PathBuf::from(file), command),
+            _ => return Err(usage.to_string()),
+        };
+        let command = command.parse()?;
 
-        Ok(Args { file, command })
+        Ok(Args {
+            file,
+            command,
+            key,
+            header,
+            threads,
+            mmap,
+            stats,
+            verify,
+            params,
+            verbosity,
+        })
     }
 }
@@ -0,0 +1,61 @@
+//! Logging Setup
+//!
+//! Diagnostics are emitted through `tracing`. The CLI is quiet by default and only
+//! reports warnings; each `-v` raises the verbosity by one level:
+//!
+//! - `-q`: errors only
+//! - default: warnings
+//! - `-v`: one structured event per query (SQL, rows, pages read, duration)
+//! - `-vv`: parser, planner and storage debugging output
+//! - `-vvv`: everything
+//!
+//! Setting `RUST_LOG` overrides the command line verbosity.
+
+use tracing_subscriber::{fmt, EnvFilter};
+
+/// How much diagnostic output the CLI prints
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum Verbosity {
+    Quiet,
+    #[default]
+    Normal,
+    Verbose,
+    Debug,
+    Trace,
+}
+
+impl Verbosity {
+    /// Builds a verbosity from the number of `-v` flags
+    pub fn from_count(count: u8) -> Self {
+        match count {
+            0 => Verbosity::Normal,
+            1 => Verbosity::Verbose,
+            2 => Verbosity::Debug,
+            _ => Verbosity::Trace,
+        }
+    }
+
+    /// Returns the tracing filter directive for this verbosity
+    pub fn directive(&self) -> &'static str {
+        match self {
+            Verbosity::Quiet => "error",
+            Verbosity::Normal => "warn",
+            Verbosity::Verbose => "info",
+            Verbosity::Debug => "debug",
+            Verbosity::Trace => "trace",
+        }
+    }
+}
+
+/// Installs the global tracing subscriber, writing to stderr
+pub fn init(verbosity: Verbosity) {
+    let filter = match std::env::var("RUST_LOG") {
+        Ok(directives) if !directives.is_empty() => EnvFilter::new(directives),
+        _ => EnvFilter::new(verbosity.directive()),
+    };
+
+    fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr)
+        .init();
+}
@@ -1,46 +1,201 @@
 use anyhow::Result;
-use tracing::info;
-use tracing_subscriber::fmt;
+use sqlite::core::value::Value;
+use sqlite::query::execute::{ExecuteResult, ResultRows};
+use sqlite::storage::db::{OpenConfig, SQLiteDatabase};
 
 pub mod cli;
+pub mod logging;
 pub mod sqlite;
 
 fn main() -> Result<()> {
-    // std::env::set_var("RUST_LOG", "info");
-
-    fmt()
-        .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
-        .init();
-
     let args = cli::Args::parse().expect("Failed to parse arguments");
+    logging::init(args.verbosity);
     run(args)?;
 
     Ok(())
 }
 
 pub fn run(args: cli::Args) -> Result<()> {
-    match args.command {
+    match &args.command {
         cli::Command::Meta(meta) => match meta {
             cli::MetaCommand::DbInfo => {
-                let mut db = sqlite::storage::db::SQLiteDatabase::open(&args.file)?;
+                let mut db = open_database(&args, false)?;
                 let info = db.get_info()?;
                 println!("database page size: {}", info.page_size());
                 println!("number of tables: {}", info.num_tables());
             }
             cli::MetaCommand::Tables => {
-                let mut db = sqlite::storage::db::SQLiteDatabase::open(&args.file)?;
+                let mut db = open_database(&args, false)?;
                 let tables = db.list_tables()?;
                 println!("{}", tables.join(" "));
             }
+            cli::MetaCommand::Compress => compress_database(&args)?,
+            cli::MetaCommand::PageMap => {
+                let mut db = open_database(&args, false)?;
+                for (page_num, kind) in db.page_map()?.iter() {
+                    println!("{} {}", page_num, kind);
+                }
+            }
+            cli::MetaCommand::BTreeStats => {
+                let mut db = open_database(&args, false)?;
+                for stats in db.btree_stats()? {
+                    println!("{}", stats);
+                }
+            }
+            cli::MetaCommand::FreeSpace(object) => {
+                let mut db = open_database(&args, false)?;
+                let report = db.free_space_report()?;
+                match object {
+                    None => {
+                        for entry in &report {
+                            println!("{}", entry);
+                        }
+                    }
+                    Some(object) => {
+                        let entry = report
+                            .iter()
+                            .find(|entry| entry.name.eq_ignore_ascii_case(object))
+                            .ok_or_else(|| anyhow::anyhow!("No table or index named {}", object))?;
+                        for (page_num, space) in &entry.pages {
+                            println!("{}: {}", page_num, space);
+                        }
+                    }
+                }
+            }
+            cli::MetaCommand::Hash => {
+                let mut db = open_database(&args, false)?;
+                println!("{}", db.content_hash()?);
+            }
+            cli::MetaCommand::Read(path) => {
+                let script = std::fs::read_to_string(path)?;
+                let commands = sqlite::parser::statement::Command::parse_all(&script)?;
+                let mut db = open_database(&args, true)?;
+                // Results are printed as they come, so earlier output survives an error
+                for command in &commands {
+                    print_result(&db.execute_command(command)?, args.header);
+                }
+            }
+            cli::MetaCommand::GenDb(options) => {
+                let summary = sqlite::storage::generate::generate(&args.file, options)?;
+                println!(
+                    "generated {} rows in {} pages ({})",
+                    summary.rows,
+                    summary.page_count,
+                    args.file.display()
+                );
+            }
         },
         // Try parsing as SQL statement
         cli::Command::Sql(sql) => {
-            let statement = sqlite::parser::statement::Statement::parse(&sql)?;
-            info!("Statement: {:?}", statement);
-            let mut db = sqlite::storage::db::SQLiteDatabase::open(&args.file)?;
-            let result = db.execute(&statement)?;
-            println!("{}", result);
+            let mut db = open_database(&args, true)?;
+            let result = if args.params.is_empty() {
+                db.query(sql)?
+            } else {
+                let mut statement = db.prepare(sql)?;
+                for (i, value) in args.params.iter().enumerate() {
+                    statement.bind(i + 1, value.clone())?;
+                }
+                db.execute_prepared(&statement)?
+            };
+            print_result(&result, args.header);
+            if args.stats {
+                // On stderr, so the result on stdout stays machine-readable
+                eprintln!("{}", db.last_query_stats());
+            }
         }
     }
     Ok(())
 }
+
+/// Prints a query result, one row per line with columns separated by `|`,
+/// after a line of column names if `header` is set and the statement has any
+fn print_result(result: &ExecuteResult, header: bool) {
+    if header && !result.columns.is_empty() {
+        let names: Vec<&str> = result
+            .columns
+            .iter()
+            .map(|column| column.name.as_str())
+            .collect();
+        println!("{}", names.join("|"));
+    }
+    match &result.rows {
+        ResultRows::Count(count) => println!("{}", count),
+        ResultRows::Values(rows) => {
+            for row in rows {
+                let columns: Vec<String> = row.iter().map(format_value).collect();
+                println!("{}", columns.join("|"));
+            }
+        }
+    }
+}
+
+/// Renders a value for output as the sqlite3 shell does, with NULL as an
+/// empty field, except that BLOBs are hex literals such as `X'CAFE'` since
+/// their bytes need not be text
+fn format_value(value: &Value) -> String {
+    match value {
+        Value::Null => String::new(),
+        Value::Blob(bytes) => {
+            let hex: String = bytes.iter().map(|byte| format!("{:02X}", byte)).collect();
+            format!("X'{}'", hex)
+        }
+        value => value.to_string(),
+    }
+}
+
+/// Opens the database named on the command line, decrypting it if a key was
+/// given, with the scan threads, memory map and page verification it asks for;
+/// with `create`, a file that does not exist yet becomes a new database
+fn open_database(args: &cli::Args, create: bool) -> Result<SQLiteDatabase> {
+    let mut db = match &args.key {
+        None => SQLiteDatabase::open_with(
+            &args.file,
+            OpenConfig {
+                create,
+                mmap: args.mmap,
+                ..OpenConfig::default()
+            },
+        ),
+        #[cfg(feature = "sqlcipher")]
+        Some(key) => {
+            use sqlite::storage::cipher::{CipherConfig, CipherKey};
+            SQLiteDatabase::open_encrypted(
+                &args.file,
+                &CipherKey::Passphrase(key.clone()),
+                CipherConfig::default(),
+            )
+        }
+        #[cfg(not(feature = "sqlcipher"))]
+        Some(_) => Err(anyhow::anyhow!(
+            "Encrypted databases require building with the `sqlcipher` feature"
+        )),
+    }?;
+    db.set_scan_threads(args.threads);
+    db.set_page_verification(args.verify);
+    Ok(db)
+}
+
+/// Writes a compressed page container next to the database file
+fn compress_database(args: &cli::Args) -> Result<()> {
+    #[cfg(feature = "zstd")]
+    {
+        use sqlite::storage::compressed::CompressedFile;
+        let dest = CompressedFile::sidecar_path(&args.file);
+        let summary = CompressedFile::create(&args.file, &dest, 19)?;
+        println!(
+            "compressed {} pages: {} -> {} bytes ({})",
+            summary.page_count,
+            summary.original_size,
+            summary.compressed_size,
+            dest.display()
+        );
+        Ok(())
+    }
+    #[cfg(not(feature = "zstd"))]
+    {
+        let _ = args;
+        Err(anyhow::anyhow!(
+            "Compressed containers require building with the `zstd` feature"
+        ))
+    }
+}
@@ -1,6 +1,22 @@
+use super::checked::{self, Reader};
+use super::error::{corrupt, CorruptPage};
+use super::freespace::PageFreeSpace;
+use super::header::DatabaseHeader;
+use super::shared_page::SharedPage;
+use super::varint::decode_varint;
 use anyhow::{anyhow, Result};
-use std::io::{Read, Seek, SeekFrom};
-use tracing::info;
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::ops::{Deref, Range, RangeInclusive};
+use tracing::debug;
+
+/// Most fragmented free bytes a B-tree page has, as SQLite defragments a page
+/// before letting more accumulate
+const MAX_FRAGMENTED_BYTES: u8 = 60;
+
+/// Deepest B-tree any walk of one descends before treating the tree as
+/// corrupt, which stops a cycle of child pointers from recursing forever
+pub const MAX_DEPTH: usize = 64;
 
 /// Represents a B-tree page in SQLite
 ///
@@ -13,17 +29,93 @@ use tracing::info;
 /// - Unallocated space
 /// - Cell content area
 /// - Reserved region
+///
+/// On page 1 all of this follows the 100-byte database header, so the page
+/// header starts at offset 100; cell pointers are still offsets from the start
+/// of the page. Every access to the page contents is bounds-checked; malformed
+/// pages produce `Corrupt` errors naming this page.
+///
+/// The page data is shared with the pager's page cache rather than copied out
+/// of it, and cells whose payload fits on the page borrow it from there.
 pub struct BTreePage {
+    /// Page number within the database file
+    page_num: u32,
     /// Raw page data
-    data: Vec<u8>,
-    /// Page type (leaf=13, interior=5)
-    page_type: u8,
-    /// Number of cells in page
-    num_cells: u16,
-    /// Position in the page data
-    position: usize,
+    data: SharedPage,
+    /// Where the B-tree page header starts: 100 on page 1, 0 elsewhere
+    header_offset: usize,
+    /// The B-tree page header
+    header: BTreePageHeader,
+}
+
+/// One cell of a B-tree page, laid out according to the page type
+///
+/// ## Cell Layouts
+///
+/// - Table leaf (13): payload size varint, rowid varint, payload
+/// - Table interior (5): 4-byte left child page, rowid varint
+/// - Index leaf (10): payload size varint, payload
+/// - Index interior (2): 4-byte left child page, payload size varint, payload
+///
+/// A payload too large for the page keeps only a prefix on it, followed by the
+/// 4-byte number of its first overflow page. Parsed cells hold the whole
+/// payload, with the part on overflow pages read back.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Cell {
+    /// A table row: its rowid and record
+    TableLeaf { rowid: i64, payload: Payload },
+    /// A table B-tree key; the left child's subtree holds the rowids up to and
+    /// including `rowid` that are above the previous cell's key
+    TableInterior { left_child: u32, rowid: i64 },
+    /// An index entry: a record of the key columns followed by the rowid
+    IndexLeaf { payload: Payload },
+    /// An index entry that comes after every entry in its left child's subtree
+    IndexInterior { left_child: u32, payload: Payload },
 }
 
+/// The whole payload of a cell: where it fits on its page, a range of the
+/// shared page data, so that reading it copies nothing; where it spills, the
+/// bytes gathered from the page and its overflow chain
+#[derive(Debug, Clone)]
+pub enum Payload {
+    /// A payload stored whole on its page
+    Local {
+        page: SharedPage,
+        range: Range<usize>,
+    },
+    /// A payload read back from overflow pages
+    Spilled(Vec<u8>),
+}
+
+impl Payload {
+    /// Returns the payload bytes as a vector of their own
+    pub fn into_vec(self) -> Vec<u8> {
+        match self {
+            Payload::Local { page, range } => page[range].to_vec(),
+            Payload::Spilled(bytes) => bytes,
+        }
+    }
+}
+
+impl Deref for Payload {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            Payload::Local { page, range } => &page[range.clone()],
+            Payload::Spilled(bytes) => bytes,
+        }
+    }
+}
+
+impl PartialEq for Payload {
+    fn eq(&self, other: &Self) -> bool {
+        **self == **other
+    }
+}
+
+impl Eq for Payload {}
+
 /// Represents a B-tree page header
 ///
 /// ## B-tree Page Header Format
@@ -33,9 +125,11 @@ pub struct BTreePage {
 /// - Bytes 3-4: Number of cells
 /// - Bytes 5-6: Cell content offset
 /// - Byte 7: Number of fragmented free bytes
+/// - Bytes 8-11: Right-most child page (interior pages only)
 #[derive(Debug)]
 pub struct BTreePageHeader {
-    /// Page type (leaf=13, interior=5)
+    /// Page type (index interior=2, table interior=5, index leaf=10, table
+    /// leaf=13)
     pub page_type: u8,
     /// Offset to first freeblock
     pub first_freeblock: u16,
@@ -48,100 +142,129 @@ pub struct BTreePageHeader {
 }
 
 impl BTreePage {
-    /// Reads a B-tree page from the given file at the specified page number
-    pub fn read(file: &mut std::fs::File, page_num: u32, page_size: u16) -> Result<Self> {
-        let mut page = vec![0; page_size as usize];
-
-        // Calculate page offset
-        let offset = ((page_num - 1) as u64) * (page_size as u64);
-        info!("Seeking to offset: {} for page {}", offset, page_num);
-
-        // Verify file length
-        let file_len = file.seek(SeekFrom::End(0))?;
-        if offset >= file_len {
-            return Err(anyhow!(
-                "Page offset {} exceeds file length {}",
-                offset,
-                file_len
-            ));
-        }
-
-        // Read the page
-        file.seek(SeekFrom::Start(offset))?;
-        let bytes_read = file.read(&mut page)?;
-        if bytes_read != page_size as usize {
-            return Err(anyhow!(
-                "Partial read: got {} bytes, expected {}",
-                bytes_read,
-                page_size
-            ));
+    /// Parses a B-tree page from the raw data of the given page, checking that
+    /// its page type is one of the four B-tree page types
+    pub fn parse(page_num: u32, page: SharedPage) -> Result<Self> {
+        let header_offset = Self::header_offset_for(page_num);
+        let header = checked::tail(&page, header_offset)
+            .and_then(BTreePageHeader::parse)
+            .on_page(page_num)?;
+        if !matches!(header.page_type, 2 | 5 | 10 | 13) {
+            return Err(corrupt(
+                header_offset,
+                format!("invalid page type: {}", header.page_type),
+            ))
+            .on_page(page_num);
         }
 
-        let page_type = page[0];
-        let num_cells = u16::from_be_bytes([page[3], page[4]]);
-
         Ok(Self {
+            page_num,
             data: page,
-            page_type,
-            num_cells,
-            position: 0,
+            header_offset,
+            header,
         })
     }
 
+    /// Returns where the B-tree page header of the given page starts, which is
+    /// after the database header on page 1
+    pub fn header_offset_for(page_num: u32) -> usize {
+        if page_num == 1 {
+            DatabaseHeader::HEADER_SIZE
+        } else {
+            0
+        }
+    }
+
+    /// Returns the page number
+    pub fn page_num(&self) -> u32 {
+        self.page_num
+    }
+
+    /// Returns where the B-tree page header starts
+    pub fn header_offset(&self) -> usize {
+        self.header_offset
+    }
+
+    /// Returns the B-tree page header
+    pub fn header(&self) -> &BTreePageHeader {
+        &self.header
+    }
+
     /// Returns the page type
     pub fn page_type(&self) -> u8 {
-        self.page_type
+        self.header.page_type
+    }
+
+    /// Returns true for interior pages, which have child pages
+    pub fn is_interior(&self) -> bool {
+        self.header.is_interior()
+    }
+
+    /// Returns true for pages of index B-trees
+    pub fn is_index(&self) -> bool {
+        matches!(self.header.page_type, 2 | 10)
     }
 
     /// Returns number of cells in the page
     pub fn num_cells(&self) -> u16 {
-        self.num_cells
+        self.header.num_cells
     }
 
-    /// Reads and returns the cell pointer array
-    pub fn read_cell_pointers(&self, header_offset: usize) -> Vec<usize> {
-        let mut cell_pointers = Vec::with_capacity(self.num_cells as usize);
-        let array_start = header_offset + 8;
-
-        for i in 0..self.num_cells {
-            let offset = array_start + (i as usize * 2);
-            let ptr = u16::from_be_bytes([self.data[offset], self.data[offset + 1]]) as usize;
-            cell_pointers.push(ptr);
+    /// Returns the offset of the `i`-th cell from the start of the page
+    pub fn cell_pointer(&self, i: usize) -> Result<usize> {
+        let offset = self.header.cell_pointer_array_offset(self.header_offset) + i * 2;
+        let ptr = checked::be_u16(&self.data, offset).on_page(self.page_num)? as usize;
+        if ptr >= self.data.len() {
+            return Err(corrupt(
+                offset,
+                format!("cell pointer {} outside page", ptr),
+            ))
+            .on_page(self.page_num);
         }
+        Ok(ptr)
+    }
 
-        cell_pointers
+    /// Reads and returns the cell pointer array, which follows the 8-byte
+    /// header of leaf pages and the 12-byte header of interior pages
+    pub fn read_cell_pointers(&self) -> Result<Vec<usize>> {
+        (0..self.num_cells() as usize)
+            .map(|i| self.cell_pointer(i))
+            .collect()
     }
 
-    /// Gets child page numbers from an interior page
+    /// Gets child page numbers from an interior page, in order
     pub fn get_child_pages(&self) -> Result<Vec<u32>> {
-        if self.page_type != 5 {
-            return Err(anyhow!("Not an interior page"));
-        }
+        (0..=self.num_cells() as usize)
+            .map(|i| self.child(i))
+            .collect()
+    }
 
-        let mut children = Vec::new();
-        let array_start = 12;
-
-        // Get child pages from cell pointers
-        for i in 0..self.num_cells {
-            let ptr_offset = array_start + (i as usize * 2);
-            let cell_ptr =
-                u16::from_be_bytes([self.data[ptr_offset], self.data[ptr_offset + 1]]) as usize;
-
-            let child_page = u32::from_be_bytes([
-                self.data[cell_ptr],
-                self.data[cell_ptr + 1],
-                self.data[cell_ptr + 2],
-                self.data[cell_ptr + 3],
-            ]);
-            children.push(child_page);
+    /// Returns the `i`-th child of an interior page: the left child of the
+    /// `i`-th cell, or the right-most child when `i` is the number of cells
+    pub fn child(&self, i: usize) -> Result<u32> {
+        if i < self.num_cells() as usize {
+            checked::be_u32(&self.data, self.cell_pointer(i)?).on_page(self.page_num)
+        } else {
+            self.right_child()
         }
+    }
 
-        // Add rightmost pointer
-        let rightmost =
-            u32::from_be_bytes([self.data[8], self.data[9], self.data[10], self.data[11]]);
-        children.push(rightmost);
+    /// Returns the right-most child of an interior page, which holds the keys
+    /// above every cell's key
+    pub fn right_child(&self) -> Result<u32> {
+        if !self.is_interior() {
+            return Err(anyhow!("Not an interior page"));
+        }
+        checked::be_u32(&self.data, self.header_offset + 8).on_page(self.page_num)
+    }
 
-        Ok(children)
+    /// Gets the children of an interior table page whose subtrees may hold
+    /// rowids in `rowids`, in order
+    pub fn children_in_range(&self, rowids: &RangeInclusive<i64>) -> Result<Vec<u32>> {
+        if self.page_type() != 5 {
+            return Err(anyhow!("Not an interior page"));
+        }
+        children_in_range(&self.data, self.header_offset, rowids).on_page(self.page_num)
     }
 
     /// Gets raw page data
@@ -149,175 +272,434 @@ impl BTreePage {
         &self.data
     }
 
-    /// Gets the raw data for a cell at the given index
-    pub fn get_cell_data(&self, cell_index: u16) -> Result<Vec<u8>> {
-        if cell_index >= self.num_cells {
+    /// Parses the cell at the given index, reading any part of its payload
+    /// stored on overflow pages with `read_page`
+    pub fn cell(
+        &self,
+        cell_index: u16,
+        usable_size: usize,
+        read_page: &mut dyn FnMut(u32) -> Result<Vec<u8>>,
+    ) -> Result<Cell> {
+        if cell_index >= self.num_cells() {
             return Err(anyhow!("Cell index out of bounds"));
         }
+        let cell_start = self.cell_pointer(cell_index as usize)?;
+        debug!("Cell {} at offset {}", cell_index, cell_start);
+        Cell::parse(
+            &self.data,
+            self.page_type(),
+            cell_start,
+            usable_size,
+            read_page,
+        )
+        .on_page(self.page_num)
+    }
 
-        // Parse the page header
-        let header = BTreePageHeader::parse(&self.data)?;
-        info!("Page header: {:?}", header);
-        
-        // Get cell pointer array
-        let mut cell_pointers = self.read_cell_pointers(0);
-        // Sort cell pointers in ascending order
-        cell_pointers.sort_unstable();
-        info!("Sorted cell pointers: {:?}", cell_pointers);
-        info!("Accessing cell index: {}", cell_index);
-        
-        let cell_start = cell_pointers[cell_index as usize];
-        info!("Cell start offset: {}", cell_start);
-        
-        // For leaf pages, get data after the payload length and rowid
-        if self.page_type == 13 {
-            info!("Processing leaf page (type 13)");
-            
-            // Calculate cell end
-            let cell_end = if cell_index as usize + 1 < cell_pointers.len() {
-                let end = cell_pointers[cell_index as usize + 1];
-                info!("Using next cell pointer as end: {}", end);
-                end
-            } else {
-                // For the last cell, use the page size as the end
-                let end = self.data.len();
-                info!("Using page size as end (last cell): {}", end);
-                end
-            };
-
-            info!("Page data length: {}", self.data.len());
-            info!("Cell boundaries - start: {}, end: {}", cell_start, cell_end);
-
-            // Validate boundaries
-            if cell_start >= self.data.len() {
-                return Err(anyhow!("Cell start {} exceeds page size {}", cell_start, self.data.len()));
-            }
-            if cell_end > self.data.len() {
-                return Err(anyhow!("Cell end {} exceeds page size {}", cell_end, self.data.len()));
-            }
-            if cell_start >= cell_end {
-                return Err(anyhow!(
-                    "Invalid cell boundaries: start={} >= end={}. Header: {:?}, Cell pointers: {:?}", 
-                    cell_start, 
-                    cell_end,
-                    header,
-                    cell_pointers
-                ));
-            }
+    /// Checks the layout of the page as a whole, which reading it only checks
+    /// as far as it reads: the cell content area must lie between the cell
+    /// pointer array and the end of the usable area, every cell must start
+    /// inside it, the freeblock chain must stay inside it, and no more bytes
+    /// may be fragmented than SQLite ever leaves
+    pub fn verify(&self, usable_size: usize) -> Result<()> {
+        let usable = self
+            .data
+            .get(..usable_size)
+            .ok_or_else(|| {
+                corrupt(
+                    0,
+                    format!(
+                        "usable size {} exceeds page size {}",
+                        usable_size,
+                        self.data.len()
+                    ),
+                )
+            })
+            .on_page(self.page_num)?;
+        PageFreeSpace::analyze(usable, self.header_offset).on_page(self.page_num)?;
+
+        if self.header.fragmented_free_bytes > MAX_FRAGMENTED_BYTES {
+            return Err(corrupt(
+                self.header_offset + 7,
+                format!(
+                    "{} fragmented free bytes, more than {}",
+                    self.header.fragmented_free_bytes, MAX_FRAGMENTED_BYTES
+                ),
+            ))
+            .on_page(self.page_num);
+        }
 
-            Ok(self.data[cell_start..cell_end].to_vec())
-        } else {
-            info!("Not a leaf page, type: {}", self.page_type);
-            Err(anyhow!("Not a leaf page"))
+        // A content offset of zero is interpreted as 65536
+        let content_start = match self.header.content_offset {
+            0 => 65536,
+            offset => offset as usize,
+        };
+        let pointers = self.header.cell_pointer_array_offset(self.header_offset);
+        for i in 0..self.num_cells() as usize {
+            let ptr = self.cell_pointer(i)?;
+            if ptr < content_start || ptr >= usable_size {
+                return Err(corrupt(
+                    pointers + i * 2,
+                    format!(
+                        "cell {} at offset {} outside cell content area {}..{}",
+                        i, ptr, content_start, usable_size
+                    ),
+                ))
+                .on_page(self.page_num);
+            }
         }
+        Ok(())
     }
+}
 
-    pub fn read_column_value(&mut self, column_index: usize) -> Result<Option<String>> {
-        // Skip the rowid varint at the start of the record
-        self.read_varint()?;
-        
-        // Read header length
-        let header_size = self.read_varint()? as usize;
-        let header_end = self.position + header_size;
-        
-        // Read serial types
-        let mut serial_types = Vec::new();
-        while self.position < header_end {
-            serial_types.push(self.read_varint()?);
-        }
-        
-        // Skip to the target column
-        for i in 0..column_index {
-            self.skip_value(serial_types[i])?;
+impl BTreePageHeader {
+    /// Parse a B-tree page header from a byte slice
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        if data.len() < 8 {
+            return Err(corrupt(0, "page header too short"));
         }
-        
-        // Read the target column value
-        if column_index < serial_types.len() {
-            self.read_string_field(serial_types[column_index])
+
+        let mut header = Reader::new(data, 0);
+        Ok(Self {
+            page_type: header.u8()?,
+            first_freeblock: header.be_u16()?,
+            num_cells: header.be_u16()?,
+            content_offset: header.be_u16()?,
+            fragmented_free_bytes: header.u8()?,
+        })
+    }
+
+    /// Returns true for interior pages, whose header is 12 bytes long
+    pub fn is_interior(&self) -> bool {
+        matches!(self.page_type, 2 | 5)
+    }
+
+    /// Returns the size of the header: 12 bytes on interior pages, which end
+    /// it with the right-most child, and 8 on leaf pages
+    pub fn header_size(&self) -> usize {
+        if self.is_interior() {
+            12
         } else {
-            Ok(None)
+            8
         }
     }
 
-    fn skip_value(&mut self, type_code: u64) -> Result<()> {
-        let size = if type_code >= 13 {
-            ((type_code - 13) / 2) as usize
+    /// Returns the offset where cell pointer array starts
+    pub fn cell_pointer_array_offset(&self, header_offset: usize) -> usize {
+        header_offset + self.header_size()
+    }
+}
+
+impl Cell {
+    /// Parses the cell starting at `pos` on a page of the given type, reading
+    /// any part of its payload stored on overflow pages with `read_page`
+    pub fn parse(
+        page: &SharedPage,
+        page_type: u8,
+        pos: usize,
+        usable_size: usize,
+        read_page: &mut dyn FnMut(u32) -> Result<Vec<u8>>,
+    ) -> Result<Self> {
+        let mut cell = Reader::new(page, pos);
+        let left_child = match page_type {
+            2 | 5 => cell.be_u32()?,
+            10 | 13 => 0,
+            pt => return Err(corrupt(0, format!("invalid page type: {}", pt))),
+        };
+        if page_type == 5 {
+            let rowid = cell.varint()? as i64;
+            return Ok(Cell::TableInterior { left_child, rowid });
+        }
+
+        let payload_size = cell.varint()?;
+        let rowid = if page_type == 13 {
+            cell.varint()? as i64
         } else {
-            match type_code {
-                0 => 0,  // NULL
-                1 => 1,  // 8-bit signed int
-                2 => 2,  // 16-bit signed int
-                3 => 3,  // 24-bit signed int
-                4 => 4,  // 32-bit signed int
-                5 => 6,  // 48-bit signed int
-                6 => 8,  // 64-bit signed int
-                7 => 8,  // IEEE 754-2008 64
-                _ => return Err(anyhow!("Invalid serial type: {}", type_code)),
-            }
+            0
         };
-        self.position += size;
-        Ok(())
+        let payload = PayloadLayout::new(usable_size, page_type).read_shared_payload(
+            page,
+            cell.position(),
+            payload_size as usize,
+            read_page,
+        )?;
+
+        Ok(match page_type {
+            2 => Cell::IndexInterior {
+                left_child,
+                payload,
+            },
+            10 => Cell::IndexLeaf { payload },
+            _ => Cell::TableLeaf { rowid, payload },
+        })
     }
 
-    fn read_varint(&mut self) -> Result<u64> {
-        let mut result: u64 = 0;
-        let mut shift = 0;
+    /// Returns the rowid key of a table cell
+    pub fn rowid(&self) -> Option<i64> {
+        match self {
+            Cell::TableLeaf { rowid, .. } | Cell::TableInterior { rowid, .. } => Some(*rowid),
+            Cell::IndexLeaf { .. } | Cell::IndexInterior { .. } => None,
+        }
+    }
 
-        for _ in 0..8 {
-            let byte = self.data[self.position];
-            self.position += 1;
-            result |= ((byte & 0x7f) as u64) << shift;
-            if byte & 0x80 == 0 {
-                return Ok(result);
+    /// Returns the record payload, which table interior cells do not have
+    pub fn payload(&self) -> Option<&[u8]> {
+        match self {
+            Cell::TableLeaf { payload, .. }
+            | Cell::IndexLeaf { payload }
+            | Cell::IndexInterior { payload, .. } => Some(payload),
+            Cell::TableInterior { .. } => None,
+        }
+    }
+
+    /// Returns the left child page of an interior cell
+    pub fn left_child(&self) -> Option<u32> {
+        match self {
+            Cell::TableInterior { left_child, .. } | Cell::IndexInterior { left_child, .. } => {
+                Some(*left_child)
             }
-            shift += 7;
+            Cell::TableLeaf { .. } | Cell::IndexLeaf { .. } => None,
         }
+    }
+
+    /// Takes the record payload out of the cell
+    pub fn into_payload(self) -> Option<Payload> {
+        match self {
+            Cell::TableLeaf { payload, .. }
+            | Cell::IndexLeaf { payload }
+            | Cell::IndexInterior { payload, .. } => Some(payload),
+            Cell::TableInterior { .. } => None,
+        }
+    }
+}
 
-        // Handle last byte without continuation bit
-        let byte = self.data[self.position];
-        self.position += 1;
-        result |= (byte as u64) << shift;
-        Ok(result)
+/// Returns the number of bytes the cell starting at `pos` takes up on a page of
+/// the given type: its header fields, the part of its payload stored on the
+/// page and, for a payload that spills, the number of its first overflow page
+///
+/// Cells are never smaller than 4 bytes, the size of a freeblock header, so a
+/// shorter cell also takes up 4 bytes once it is freed.
+pub fn cell_size(page: &[u8], page_type: u8, pos: usize, usable_size: usize) -> Result<usize> {
+    let mut cell = Reader::new(page, pos);
+    match page_type {
+        2 | 5 => cell.skip(4)?,
+        10 | 13 => {}
+        pt => return Err(corrupt(0, format!("invalid page type: {}", pt))),
+    }
+    if page_type == 5 {
+        cell.varint()?;
+        return Ok(cell.position() - pos);
     }
 
-    fn read_string_field(&mut self, type_code: u64) -> Result<Option<String>> {
-        if type_code == 0 {
-            return Ok(None);
+    let payload_size = cell.varint()? as usize;
+    if page_type == 13 {
+        cell.varint()?;
+    }
+    let local = PayloadLayout::new(usable_size, page_type).local_size(payload_size);
+    let overflow = if local < payload_size { 4 } else { 0 };
+    let size = cell.position() - pos + local + overflow;
+    checked::slice(page, pos, size)?;
+    Ok(size.max(4))
+}
+
+/// Picks the children of the interior table page whose B-tree header starts
+/// at `header_offset` whose subtrees may hold rowids in `rowids`, in order
+///
+/// Each left child holds the keys above the previous cell's key, so the
+/// children wanted run from the first whose key reaches the start of the range
+/// to the first whose key reaches its end, or the right-most child if none
+/// does. Both ends are found by binary search, reading only the keys probed.
+pub fn children_in_range(
+    page: &[u8],
+    header_offset: usize,
+    rowids: &RangeInclusive<i64>,
+) -> Result<Vec<u32>> {
+    let num_cells = checked::be_u16(page, header_offset + 3)? as usize;
+    let cell_start = |i: usize| -> Result<usize> {
+        Ok(checked::be_u16(page, header_offset + 12 + i * 2)? as usize)
+    };
+    let key = |i: usize| -> Result<i64> { Ok(decode_varint(page, cell_start(i)? + 4)?.0 as i64) };
+    let first = search_cells(num_cells, |i| Ok(key(i)? < *rowids.start()))?;
+    let last = search_cells(num_cells, |i| Ok(key(i)? < *rowids.end()))?;
+    (first..=last)
+        .map(|i| {
+            if i < num_cells {
+                checked::be_u32(page, cell_start(i)?)
+            } else {
+                checked::be_u32(page, header_offset + 8)
+            }
+        })
+        .collect()
+}
+
+/// Binary search over the cells of a page, which are in key order: returns
+/// the index of the first cell for which `before` is false, or `num_cells` if
+/// there is none
+///
+/// `before` tells whether the `i`-th cell's key comes before the key sought,
+/// so it must be true for a run of cells at the start of the page and false
+/// for the rest.
+pub fn search_cells(
+    num_cells: usize,
+    mut before: impl FnMut(usize) -> Result<bool>,
+) -> Result<usize> {
+    let (mut low, mut high) = (0, num_cells);
+    while low < high {
+        let mid = low + (high - low) / 2;
+        if before(mid)? {
+            low = mid + 1;
+        } else {
+            high = mid;
         }
-        
-        let len = if type_code >= 13 {
-            ((type_code - 13) / 2) as usize
+    }
+    Ok(low)
+}
+
+/// Largest payload a cell may have: SQLite limits strings, BLOBs and records to
+/// 2^31 - 1 bytes
+const MAX_PAYLOAD_SIZE: usize = i32::MAX as usize;
+
+/// How the payloads of cells on one kind of B-tree page are split between the
+/// page and a chain of overflow pages
+///
+/// Payloads larger than the page's maximum local size keep only a prefix locally
+/// and spill the rest onto overflow pages. Index pages, leaf and interior alike,
+/// use a smaller maximum so that each page holds at least four cells. The
+/// local prefix is chosen so that the last overflow page is as full as
+/// possible, without going below the minimum local size.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PayloadLayout {
+    /// Usable bytes per page (page size minus reserved space)
+    usable_size: usize,
+    /// Largest payload kept entirely on the page
+    max_local: usize,
+    /// Smallest prefix kept on the page by a payload that spills
+    min_local: usize,
+}
+
+impl PayloadLayout {
+    /// Returns the layout of cells on pages of the given type
+    pub fn new(usable_size: usize, page_type: u8) -> Self {
+        let max_local = if matches!(page_type, 2 | 10) {
+            (usable_size - 12) * 64 / 255 - 23
         } else {
-            return Ok(None); // Non-text fields return None
+            usable_size - 35
         };
-        
-        let str_bytes = &self.data[self.position..self.position + len];
-        self.position += len;
-        
-        String::from_utf8(str_bytes.to_vec())
-            .map(Some)
-            .map_err(|e| anyhow!(e))
+        Self {
+            usable_size,
+            max_local,
+            min_local: (usable_size - 12) * 32 / 255 - 23,
+        }
     }
-}
 
-impl BTreePageHeader {
-    /// Parse a B-tree page header from a byte slice
-    pub fn parse(data: &[u8]) -> Result<Self> {
-        if data.len() < 8 {
-            return Err(anyhow!("Page header too short"));
+    /// Number of payload bytes a cell stores on its B-tree page
+    pub fn local_size(&self, payload_size: usize) -> usize {
+        if payload_size <= self.max_local {
+            return payload_size;
+        }
+        let local = self.min_local + (payload_size - self.min_local) % self.overflow_capacity();
+        if local <= self.max_local {
+            local
+        } else {
+            self.min_local
         }
+    }
 
-        Ok(Self {
-            page_type: data[0],
-            first_freeblock: u16::from_be_bytes([data[1], data[2]]),
-            num_cells: u16::from_be_bytes([data[3], data[4]]),
-            content_offset: u16::from_be_bytes([data[5], data[6]]),
-            fragmented_free_bytes: data[7],
+    /// Number of payload bytes a cell stores on overflow pages
+    pub fn overflow_size(&self, payload_size: usize) -> usize {
+        payload_size - self.local_size(payload_size)
+    }
+
+    /// Payload bytes each overflow page holds after its next-page pointer
+    pub fn overflow_capacity(&self) -> usize {
+        self.usable_size - 4
+    }
+
+    /// Reads a payload of `payload_size` bytes whose local part starts at
+    /// `start` on `page`, followed when it spills by the 4-byte number of its
+    /// first overflow page, whose chain is read with `read_page`
+    ///
+    /// A payload stored whole on the page is borrowed from it; only one that
+    /// spills is copied, along with the rest of it from the overflow pages.
+    pub fn read_payload<'a>(
+        &self,
+        page: &'a [u8],
+        start: usize,
+        payload_size: usize,
+        read_page: &mut dyn FnMut(u32) -> Result<Vec<u8>>,
+    ) -> Result<Cow<'a, [u8]>> {
+        if payload_size > MAX_PAYLOAD_SIZE {
+            return Err(corrupt(
+                start,
+                format!("payload of {} bytes is too large", payload_size),
+            ));
+        }
+        let local = self.local_size(payload_size);
+        let local_part = checked::slice(page, start, local)?;
+        if local == payload_size {
+            return Ok(Cow::Borrowed(local_part));
+        }
+        let mut payload = local_part.to_vec();
+        let first_overflow = checked::be_u32(page, start + local)?;
+        read_overflow_chain(
+            read_page,
+            self.usable_size,
+            first_overflow,
+            payload_size - local,
+            &mut payload,
+        )?;
+        Ok(Cow::Owned(payload))
+    }
+
+    /// Reads a payload as `read_payload` does from a shared page, returning
+    /// one stored whole on the page as a range of it
+    pub fn read_shared_payload(
+        &self,
+        page: &SharedPage,
+        start: usize,
+        payload_size: usize,
+        read_page: &mut dyn FnMut(u32) -> Result<Vec<u8>>,
+    ) -> Result<Payload> {
+        let payload = self.read_payload(page, start, payload_size, read_page)?;
+        Ok(match payload {
+            Cow::Borrowed(bytes) => Payload::Local {
+                page: page.clone(),
+                range: start..start + bytes.len(),
+            },
+            Cow::Owned(bytes) => Payload::Spilled(bytes),
         })
     }
+}
 
-    /// Returns the offset where cell pointer array starts
-    pub fn cell_pointer_array_offset(&self, header_offset: usize) -> usize {
-        header_offset + 8 // 8 bytes for the header
+/// Appends `remaining` bytes from the overflow chain starting at `first`,
+/// reading its pages with `read_page`
+///
+/// Each overflow page starts with the 4-byte number of the next one, 0 on the
+/// last, followed by up to `usable_size - 4` bytes of payload. A chain that
+/// comes back to a page it has already visited is corrupt.
+pub fn read_overflow_chain(
+    read_page: &mut dyn FnMut(u32) -> Result<Vec<u8>>,
+    usable_size: usize,
+    first: u32,
+    mut remaining: usize,
+    payload: &mut Vec<u8>,
+) -> Result<()> {
+    let mut page_num = first;
+    let mut visited = HashSet::new();
+    while remaining > 0 {
+        if page_num == 0 {
+            return Err(corrupt(
+                0,
+                format!("overflow chain ends {} bytes early", remaining),
+            ));
+        }
+        if !visited.insert(page_num) {
+            return Err(corrupt(0, "overflow chain loops")).on_page(page_num);
+        }
+        let page = read_page(page_num)?;
+        let take = remaining.min(usable_size - 4);
+        payload.extend_from_slice(checked::slice(&page, 4, take).on_page(page_num)?);
+        remaining -= take;
+        page_num = checked::be_u32(&page, 0).on_page(page_num)?;
     }
+    Ok(())
 }
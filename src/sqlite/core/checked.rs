@@ -0,0 +1,111 @@
+//! Bounds-Checked Byte Access
+//!
+//! Every read of on-disk structures goes through these helpers instead of direct
+//! slice indexing, so a malformed or truncated file produces a `Corrupt` error
+//! rather than a panic. Offsets are relative to the buffer passed in.
+//!
+//! Structures read field after field, such as records and cells, use a
+//! [`Reader`], which keeps track of the position and reports it in errors.
+
+use super::error::corrupt;
+use super::varint::decode_varint;
+use anyhow::Result;
+
+/// Returns `data[offset..offset + len]`
+pub fn slice(data: &[u8], offset: usize, len: usize) -> Result<&[u8]> {
+    offset
+        .checked_add(len)
+        .and_then(|end| data.get(offset..end))
+        .ok_or_else(|| {
+            corrupt(
+                offset,
+                format!("{} bytes overrun {}-byte buffer", len, data.len()),
+            )
+        })
+}
+
+/// Returns `data[offset..]`
+pub fn tail(data: &[u8], offset: usize) -> Result<&[u8]> {
+    data.get(offset..)
+        .ok_or_else(|| corrupt(offset, format!("offset outside {}-byte buffer", data.len())))
+}
+
+/// Reads one byte
+pub fn byte(data: &[u8], offset: usize) -> Result<u8> {
+    Ok(slice(data, offset, 1)?[0])
+}
+
+/// Reads a big-endian u16
+pub fn be_u16(data: &[u8], offset: usize) -> Result<u16> {
+    let bytes = slice(data, offset, 2)?;
+    Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+}
+
+/// Reads a big-endian u32
+pub fn be_u32(data: &[u8], offset: usize) -> Result<u32> {
+    let bytes = slice(data, offset, 4)?;
+    Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+/// Reads consecutive fields of a buffer, failing instead of running past its end
+#[derive(Debug, Clone)]
+pub struct Reader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    /// Starts reading `data` at `position`
+    pub fn new(data: &'a [u8], position: usize) -> Self {
+        Self { data, position }
+    }
+
+    /// Returns the offset of the next field
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    /// Returns the number of bytes left to read
+    pub fn remaining(&self) -> usize {
+        self.data.len().saturating_sub(self.position)
+    }
+
+    /// Reads the next `len` bytes
+    pub fn bytes(&mut self, len: usize) -> Result<&'a [u8]> {
+        let bytes = slice(self.data, self.position, len)?;
+        self.position += len;
+        Ok(bytes)
+    }
+
+    /// Moves past the next `len` bytes
+    pub fn skip(&mut self, len: usize) -> Result<()> {
+        self.bytes(len).map(|_| ())
+    }
+
+    /// Reads one byte
+    pub fn u8(&mut self) -> Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    /// Reads a big-endian u16
+    pub fn be_u16(&mut self) -> Result<u16> {
+        let value = be_u16(self.data, self.position)?;
+        self.position += 2;
+        Ok(value)
+    }
+
+    /// Reads a big-endian u32
+    pub fn be_u32(&mut self) -> Result<u32> {
+        let value = be_u32(self.data, self.position)?;
+        self.position += 4;
+        Ok(value)
+    }
+
+    /// Reads a varint
+    pub fn varint(&mut self) -> Result<u64> {
+        let (value, len) = decode_varint(self.data, self.position)
+            .map_err(|_| corrupt(self.position, "truncated varint"))?;
+        self.position += len;
+        Ok(value)
+    }
+}
@@ -0,0 +1,130 @@
+//! Collations and Index Key Order
+//!
+//! A collation decides how two TEXT values compare. SQLite has three built in:
+//!
+//! - `BINARY`: bytewise, the default
+//! - `NOCASE`: bytewise after folding ASCII letters to lower case
+//! - `RTRIM`: bytewise after removing trailing spaces
+//!
+//! BINARY compares the bytes the text is stored as, so its order depends on
+//! the database's [`TextEncoding`]. SQLite defines NOCASE and RTRIM for UTF-8
+//! only and converts other text to UTF-8 for them, so they ignore it.
+//!
+//! Values of other storage classes compare as [`Value::sort_cmp`] does whatever
+//! the collation.
+//!
+//! ## Index Key Order
+//!
+//! An index entry is a record of the key columns followed by the rowid. Two
+//! entries compare field by field: each key column with its own collation,
+//! reversed if the column is DESC, and the rowid as an ascending integer. The
+//! first field that differs decides; if one entry runs out of fields first, the
+//! two are equal, so an entry matches any key that is a prefix of it.
+
+use crate::sqlite::core::header::TextEncoding;
+use crate::sqlite::core::value::Value;
+use std::cmp::Ordering;
+
+/// A built-in collating sequence
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Collation {
+    #[default]
+    Binary,
+    NoCase,
+    RTrim,
+}
+
+impl Collation {
+    /// Looks up a collation by name, ignoring case and quotes; None if it is not
+    /// built in
+    pub fn from_name(name: &str) -> Option<Self> {
+        let name = name.trim_matches(|c| matches!(c, '"' | '\'' | '`' | '[' | ']'));
+        if name.eq_ignore_ascii_case("BINARY") {
+            Some(Collation::Binary)
+        } else if name.eq_ignore_ascii_case("NOCASE") {
+            Some(Collation::NoCase)
+        } else if name.eq_ignore_ascii_case("RTRIM") {
+            Some(Collation::RTrim)
+        } else {
+            None
+        }
+    }
+
+    /// Compares two values, applying the collation if both are TEXT, for a
+    /// database storing text in `encoding`
+    pub fn compare(&self, left: &Value, right: &Value, encoding: TextEncoding) -> Ordering {
+        match (self, left, right) {
+            (Collation::Binary, Value::Text(l), Value::Text(r)) => encoding.compare(l, r),
+            (Collation::NoCase, Value::Text(l), Value::Text(r)) => l
+                .bytes()
+                .map(|b| b.to_ascii_lowercase())
+                .cmp(r.bytes().map(|b| b.to_ascii_lowercase())),
+            (Collation::RTrim, Value::Text(l), Value::Text(r)) => l
+                .trim_end_matches(' ')
+                .as_bytes()
+                .cmp(r.trim_end_matches(' ').as_bytes()),
+            _ => left.sort_cmp(right),
+        }
+    }
+
+    /// Returns the value standing for every value equal to `value` under the
+    /// collation, so that equal values can be found by hashing: NOCASE folds
+    /// ASCII letters to lower case and RTRIM drops trailing spaces
+    pub fn canonical(&self, value: &Value) -> Value {
+        match (self, value) {
+            (Collation::NoCase, Value::Text(text)) => Value::Text(text.to_ascii_lowercase()),
+            (Collation::RTrim, Value::Text(text)) => {
+                Value::Text(text.trim_end_matches(' ').to_string())
+            }
+            (_, value) => value.clone(),
+        }
+    }
+
+    /// Returns true if `text` starts with `prefix` under the collation, so that
+    /// the texts with a given prefix sort next to each other
+    ///
+    /// RTRIM has no such property for prefixes ending in spaces and is
+    /// compared bytewise.
+    pub fn starts_with(&self, text: &str, prefix: &str) -> bool {
+        match self {
+            Collation::NoCase => {
+                text.len() >= prefix.len()
+                    && text.as_bytes()[..prefix.len()].eq_ignore_ascii_case(prefix.as_bytes())
+            }
+            Collation::Binary | Collation::RTrim => text.starts_with(prefix),
+        }
+    }
+}
+
+/// How one column of an index key is ordered
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct KeyColumn {
+    pub collation: Collation,
+    pub desc: bool,
+    /// Encoding of the database the key's text is stored in
+    pub encoding: TextEncoding,
+}
+
+impl KeyColumn {
+    /// Compares two values of the column in index order
+    pub fn compare(&self, left: &Value, right: &Value) -> Ordering {
+        let ordering = self.collation.compare(left, right, self.encoding);
+        if self.desc {
+            ordering.reverse()
+        } else {
+            ordering
+        }
+    }
+}
+
+/// Compares two index entries in index order, given how each key column is
+/// ordered; fields past the key columns, such as the rowid, compare ascending
+/// with BINARY
+pub fn compare_index_keys(left: &[Value], right: &[Value], key: &[KeyColumn]) -> Ordering {
+    left.iter()
+        .zip(right)
+        .enumerate()
+        .map(|(i, (l, r))| key.get(i).copied().unwrap_or_default().compare(l, r))
+        .find(|ordering| ordering.is_ne())
+        .unwrap_or(Ordering::Equal)
+}
@@ -0,0 +1,80 @@
+//! Structured Database Errors
+//!
+//! Errors raised while decoding the file format carry the page and byte offset of
+//! the malformed structure. They travel inside `anyhow::Error` like every other
+//! error and can be recovered with `error.downcast_ref::<DatabaseError>()`.
+//!
+//! A write that would break a constraint of the table fails with a
+//! [`ConstraintViolation`] naming the constraint, recovered the same way.
+//!
+//! Low-level decoders (records, varints) only see a byte slice and report the
+//! offset within that slice; the code that read the page attaches the page number
+//! with `CorruptPage::on_page`.
+
+use anyhow::Result;
+use thiserror::Error;
+
+/// Errors describing a problem with the database file itself
+#[derive(Debug, Error)]
+pub enum DatabaseError {
+    /// The file does not follow the SQLite file format
+    #[error("database disk image is malformed: {detail} ({}offset {offset})", page_prefix(.page))]
+    Corrupt {
+        /// Page holding the malformed structure, if known
+        page: Option<u32>,
+        /// Byte offset of the malformed structure within the page or buffer
+        offset: usize,
+        /// What was wrong
+        detail: String,
+    },
+}
+
+/// A row that a write would have stored breaks a constraint of its table
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum ConstraintViolation {
+    /// A NOT NULL column would have held NULL
+    #[error("NOT NULL constraint failed: {table}.{column}")]
+    NotNull { table: String, column: String },
+    /// Another row already has the same rowid, PRIMARY KEY or UNIQUE key; the
+    /// columns are those of the key, or `rowid` for the rowid itself
+    #[error("UNIQUE constraint failed: {}", qualified(.table, .columns))]
+    Unique { table: String, columns: Vec<String> },
+}
+
+fn qualified(table: &str, columns: &[String]) -> String {
+    columns
+        .iter()
+        .map(|column| format!("{}.{}", table, column))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn page_prefix(page: &Option<u32>) -> String {
+    page.map_or_else(String::new, |page| format!("page {}, ", page))
+}
+
+/// Builds a `Corrupt` error that is not yet attributed to a page
+pub fn corrupt(offset: usize, detail: impl Into<String>) -> anyhow::Error {
+    DatabaseError::Corrupt {
+        page: None,
+        offset,
+        detail: detail.into(),
+    }
+    .into()
+}
+
+/// Attaches a page number to `Corrupt` errors that do not have one yet
+pub trait CorruptPage<T> {
+    fn on_page(self, page_num: u32) -> Result<T>;
+}
+
+impl<T> CorruptPage<T> for Result<T> {
+    fn on_page(self, page_num: u32) -> Result<T> {
+        self.map_err(|mut error| {
+            if let Some(DatabaseError::Corrupt { page, .. }) = error.downcast_mut() {
+                page.get_or_insert(page_num);
+            }
+            error
+        })
+    }
+}
@@ -6,7 +6,7 @@
 //! ## Database Header Format (First 100 bytes)
 //!
 //! - Bytes 0-15: Header string "SQLite format 3\0"
-//! - Bytes 16-17: Page size in bytes (big-endian)
+//! - Bytes 16-17: Page size in bytes (big-endian); 1 means 65536
 //! - Byte 18: File format write version
 //! - Byte 19: File format read version  
 //! - Byte 20: Reserved space at end of each page
@@ -27,13 +27,47 @@
 //! - Bytes 96-99: Version-valid-for number
 
 use anyhow::Result;
-use tracing::info;
+use std::cmp::Ordering;
+use tracing::debug;
+
+/// SQLite version recorded in the header of new databases (3.40.1)
+pub const SQLITE_VERSION: u32 = 3_040_001;
+
+/// The encoding a database stores its text in
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TextEncoding {
+    #[default]
+    Utf8,
+    Utf16Le,
+    Utf16Be,
+}
+
+impl TextEncoding {
+    /// Compares two texts by the bytes they are stored as, the order the
+    /// BINARY collation gives them
+    ///
+    /// UTF-8 and UTF-16be order texts by code point, apart from UTF-16be
+    /// putting U+E000 to U+FFFF after the characters beyond U+FFFF. UTF-16le
+    /// compares the low byte of each code unit first, so `'ñ'` (F1 00) sorts
+    /// after `'ā'` (01 01).
+    pub fn compare(&self, left: &str, right: &str) -> Ordering {
+        match self {
+            TextEncoding::Utf8 => left.as_bytes().cmp(right.as_bytes()),
+            TextEncoding::Utf16Le => left
+                .encode_utf16()
+                .flat_map(u16::to_le_bytes)
+                .cmp(right.encode_utf16().flat_map(u16::to_le_bytes)),
+            TextEncoding::Utf16Be => left.encode_utf16().cmp(right.encode_utf16()),
+        }
+    }
+}
 
 /// Represents the SQLite database header (first 100 bytes)
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct DatabaseHeader {
-    /// Page size in bytes (bytes 16-17)
-    pub page_size: u16,
+    /// Page size in bytes (bytes 16-17), with the stored value 1 decoded as
+    /// 65536
+    pub page_size: u32,
     /// File format write version (byte 18)
     pub write_version: u8,
     /// File format read version (byte 19)
@@ -83,6 +117,32 @@ impl DatabaseHeader {
     /// Magic string that should appear at the start of every SQLite file
     const MAGIC_STRING: &'static [u8] = b"SQLite format 3\0";
 
+    /// Builds the header of a new database one page long: UTF-8, in rollback
+    /// journal mode, without reserved space and with schema format 4
+    pub fn new_database(page_size: u32) -> [u8; Self::HEADER_SIZE] {
+        let mut header = [0u8; Self::HEADER_SIZE];
+        // 65536 is stored as 1
+        let stored_page_size = if page_size == 65536 {
+            1
+        } else {
+            page_size as u16
+        };
+        header[..16].copy_from_slice(Self::MAGIC_STRING);
+        header[16..18].copy_from_slice(&stored_page_size.to_be_bytes());
+        header[18] = 1; // write version: rollback journal
+        header[19] = 1; // read version
+        header[21] = 64; // maximum embedded payload fraction
+        header[22] = 32; // minimum embedded payload fraction
+        header[23] = 32; // leaf payload fraction
+        header[24..28].copy_from_slice(&1u32.to_be_bytes()); // file change counter
+        header[28..32].copy_from_slice(&1u32.to_be_bytes()); // database size
+        header[44..48].copy_from_slice(&4u32.to_be_bytes()); // schema format
+        header[56..60].copy_from_slice(&1u32.to_be_bytes()); // UTF-8
+        header[92..96].copy_from_slice(&1u32.to_be_bytes()); // version-valid-for
+        header[96..100].copy_from_slice(&SQLITE_VERSION.to_be_bytes());
+        header
+    }
+
     /// Parses a database header from raw bytes
     pub fn parse(header_bytes: &[u8]) -> Result<Self> {
         if header_bytes.len() < Self::HEADER_SIZE {
@@ -95,7 +155,10 @@ impl DatabaseHeader {
         }
 
         let header = DatabaseHeader {
-            page_size: u16::from_be_bytes([header_bytes[16], header_bytes[17]]),
+            page_size: match u16::from_be_bytes([header_bytes[16], header_bytes[17]]) {
+                1 => 65536,
+                page_size => page_size as u32,
+            },
             write_version: header_bytes[18],
             read_version: header_bytes[19],
             reserved_space: header_bytes[20],
@@ -188,10 +251,32 @@ impl DatabaseHeader {
             ]),
         };
 
-        info!("Parsed database header: {:?}", header);
+        debug!("Parsed database header: {:?}", header);
         Ok(header)
     }
 
+    /// Returns the bytes of each page left after the reserved space at its end
+    pub fn usable_size(&self) -> usize {
+        (self.page_size as usize).saturating_sub(self.reserved_space as usize)
+    }
+
+    /// Returns true if the database keeps pointer-map pages, as auto-vacuum
+    /// and incremental-vacuum databases do
+    pub fn is_auto_vacuum(&self) -> bool {
+        self.largest_root_page != 0
+    }
+
+    /// Returns the encoding the database stores its text in
+    pub fn encoding(&self) -> TextEncoding {
+        if self.is_utf16le() {
+            TextEncoding::Utf16Le
+        } else if self.is_utf16be() {
+            TextEncoding::Utf16Be
+        } else {
+            TextEncoding::Utf8
+        }
+    }
+
     /// Returns true if the database uses UTF-8 encoding
     pub fn is_utf8(&self) -> bool {
         self.text_encoding == 1
@@ -206,4 +291,35 @@ impl DatabaseHeader {
     pub fn is_utf16be(&self) -> bool {
         self.text_encoding == 3
     }
+
+    /// Decodes text stored in the database encoding
+    pub fn decode_text(&self, bytes: &[u8]) -> String {
+        let units = bytes.chunks_exact(2);
+        if self.is_utf16le() {
+            String::from_utf16_lossy(
+                &units
+                    .map(|u| u16::from_le_bytes([u[0], u[1]]))
+                    .collect::<Vec<_>>(),
+            )
+        } else if self.is_utf16be() {
+            String::from_utf16_lossy(
+                &units
+                    .map(|u| u16::from_be_bytes([u[0], u[1]]))
+                    .collect::<Vec<_>>(),
+            )
+        } else {
+            String::from_utf8_lossy(bytes).into_owned()
+        }
+    }
+
+    /// Encodes text in the database encoding, the inverse of `decode_text`
+    pub fn encode_text(&self, text: &str) -> Vec<u8> {
+        if self.is_utf16le() {
+            text.encode_utf16().flat_map(u16::to_le_bytes).collect()
+        } else if self.is_utf16be() {
+            text.encode_utf16().flat_map(u16::to_be_bytes).collect()
+        } else {
+            text.as_bytes().to_vec()
+        }
+    }
 }
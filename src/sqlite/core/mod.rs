@@ -1,5 +1,12 @@
 pub mod btree;
+pub mod checked;
+pub mod collation;
+pub mod error;
+pub mod freespace;
 pub mod header;
+pub mod page_writer;
 pub mod record;
 pub mod schema;
+pub mod shared_page;
+pub mod value;
 pub mod varint;
@@ -0,0 +1,364 @@
+//! B-tree Page Editing
+//!
+//! Adds, removes and replaces the cells of a single B-tree page in place,
+//! managing its cell content area the way SQLite does.
+//!
+//! ## Allocating Space
+//!
+//! A new cell goes into the first freeblock large enough to hold it, taking
+//! the end of the freeblock; if fewer than 4 bytes of the freeblock would be
+//! left, the whole freeblock is used and the rest counted as fragmented bytes.
+//! Failing that, the cell goes at the start of the cell content area, growing
+//! it into the unallocated gap. If the gap is too small too, but the page has
+//! enough free space in total, the page is defragmented first: its cells are
+//! packed against the end of the usable area, which turns every freeblock and
+//! fragment into one gap.
+//!
+//! ## Freeing Space
+//!
+//! The bytes of a removed cell become a freeblock, kept in the chain in offset
+//! order. Adjacent freeblocks, and freeblocks separated only by fragmented
+//! bytes, are merged, and a freeblock at the start of the cell content area is
+//! returned to the gap.
+
+use super::btree::cell_size;
+use super::error::corrupt;
+use super::freespace::{Freeblock, PageFreeSpace};
+use anyhow::Result;
+use std::cmp::Ordering;
+
+/// Most fragmented bytes a page may have; a freeblock that would leave more is
+/// not used and the page is defragmented instead
+const MAX_FRAGMENTED_BYTES: usize = 60;
+
+/// Edits the cells of one B-tree page
+pub struct PageWriter<'a> {
+    /// Raw page data
+    page: &'a mut [u8],
+    /// Where the B-tree page header starts: 100 on page 1, 0 elsewhere
+    header_offset: usize,
+    /// End of the cell content area (page size minus reserved space)
+    usable_size: usize,
+}
+
+impl<'a> PageWriter<'a> {
+    /// Starts editing a B-tree page whose header starts at `header_offset`,
+    /// checking its header and freeblock chain
+    pub fn new(page: &'a mut [u8], header_offset: usize, usable_size: usize) -> Result<Self> {
+        PageFreeSpace::analyze(page, header_offset)?;
+        Ok(Self {
+            page,
+            header_offset,
+            usable_size,
+        })
+    }
+
+    /// Starts editing a page as an empty B-tree page of `page_type`, whatever
+    /// it held before
+    pub fn init(
+        page: &'a mut [u8],
+        header_offset: usize,
+        usable_size: usize,
+        page_type: u8,
+    ) -> Self {
+        let header_size = if matches!(page_type, 2 | 5) { 12 } else { 8 };
+        page[header_offset..header_offset + header_size].fill(0);
+        page[header_offset] = page_type;
+        let mut writer = Self {
+            page,
+            header_offset,
+            usable_size,
+        };
+        writer.set_content_start(usable_size);
+        writer
+    }
+
+    /// Returns the page type
+    pub fn page_type(&self) -> u8 {
+        self.page[self.header_offset]
+    }
+
+    /// Returns the number of cells on the page
+    pub fn num_cells(&self) -> usize {
+        self.header_u16(3)
+    }
+
+    /// Returns the offset of the `i`-th cell
+    pub fn cell_offset(&self, i: usize) -> usize {
+        let pointer = self.pointer_offset(i);
+        u16::from_be_bytes([self.page[pointer], self.page[pointer + 1]]) as usize
+    }
+
+    /// Returns the number of bytes the `i`-th cell takes up
+    pub fn cell_size(&self, i: usize) -> Result<usize> {
+        cell_size(
+            self.page,
+            self.page_type(),
+            self.cell_offset(i),
+            self.usable_size,
+        )
+    }
+
+    /// Returns the bytes of the `i`-th cell
+    pub fn cell(&self, i: usize) -> Result<&[u8]> {
+        let offset = self.cell_offset(i);
+        Ok(&self.page[offset..offset + self.cell_size(i)?])
+    }
+
+    /// Returns the `i`-th child of an interior page, where `num_cells` is the
+    /// right-most child
+    pub fn child(&self, i: usize) -> Result<u32> {
+        let offset = self.child_offset(i)?;
+        let bytes = &self.page[offset..offset + 4];
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    /// Points the `i`-th child of an interior page at `child`, where
+    /// `num_cells` is the right-most child
+    pub fn set_child(&mut self, i: usize, child: u32) -> Result<()> {
+        let offset = self.child_offset(i)?;
+        self.page[offset..offset + 4].copy_from_slice(&child.to_be_bytes());
+        Ok(())
+    }
+
+    /// Returns the total free space on the page: the gap, the freeblocks and
+    /// the fragmented bytes
+    pub fn free_space(&self) -> Result<usize> {
+        Ok(PageFreeSpace::analyze(self.page, self.header_offset)?.total())
+    }
+
+    /// Inserts `cell` as the `i`-th cell, returning false without changing
+    /// the page if it does not fit
+    pub fn insert_cell(&mut self, i: usize, cell: &[u8]) -> Result<bool> {
+        if i > self.num_cells() {
+            return Err(corrupt(
+                self.header_offset + 3,
+                format!("cell {} inserted after the last cell", i),
+            ));
+        }
+        let size = cell.len().max(4);
+        if self.free_space()? < size + 2 {
+            return Ok(false);
+        }
+
+        let offset = self.allocate(size, 2)?;
+        self.page[offset..offset + cell.len()].copy_from_slice(cell);
+        let num_cells = self.num_cells();
+        let start = self.pointer_offset(i);
+        let end = self.pointer_offset(num_cells);
+        self.page.copy_within(start..end, start + 2);
+        self.page[start..start + 2].copy_from_slice(&(offset as u16).to_be_bytes());
+        self.set_header_u16(3, num_cells + 1);
+        Ok(true)
+    }
+
+    /// Removes the `i`-th cell, turning its bytes into free space
+    pub fn drop_cell(&mut self, i: usize) -> Result<()> {
+        let num_cells = self.num_cells();
+        if i >= num_cells {
+            return Err(corrupt(
+                self.header_offset + 3,
+                format!("cell {} dropped from a page of {} cells", i, num_cells),
+            ));
+        }
+        let offset = self.cell_offset(i);
+        let size = self.cell_size(i)?;
+        self.free(offset, size)?;
+
+        let start = self.pointer_offset(i);
+        let end = self.pointer_offset(num_cells);
+        self.page.copy_within(start + 2..end, start);
+        self.set_header_u16(3, num_cells - 1);
+        Ok(())
+    }
+
+    /// Replaces the `i`-th cell with `cell`, returning false without changing
+    /// the page if the new cell does not fit
+    ///
+    /// A cell of the same size is overwritten where it is.
+    pub fn replace_cell(&mut self, i: usize, cell: &[u8]) -> Result<bool> {
+        let old_size = self.cell_size(i)?;
+        let size = cell.len().max(4);
+        if size == old_size {
+            let offset = self.cell_offset(i);
+            self.page[offset..offset + cell.len()].copy_from_slice(cell);
+            return Ok(true);
+        }
+        if self.free_space()? + old_size < size {
+            return Ok(false);
+        }
+        self.drop_cell(i)?;
+        self.insert_cell(i, cell)
+    }
+
+    /// Packs every cell against the end of the usable area in cell pointer
+    /// order, leaving all free space in the gap
+    pub fn defragment(&mut self) -> Result<()> {
+        let cells = (0..self.num_cells())
+            .map(|i| Ok((self.cell_offset(i), self.cell_size(i)?)))
+            .collect::<Result<Vec<(usize, usize)>>>()?;
+        let original = self.page.to_vec();
+
+        let mut content_start = self.usable_size;
+        for (i, (offset, size)) in cells.into_iter().enumerate() {
+            content_start -= size;
+            self.page[content_start..content_start + size]
+                .copy_from_slice(&original[offset..offset + size]);
+            let pointer = self.pointer_offset(i);
+            self.page[pointer..pointer + 2].copy_from_slice(&(content_start as u16).to_be_bytes());
+        }
+        self.set_header_u16(1, 0);
+        self.set_content_start(content_start);
+        self.page[self.header_offset + 7] = 0;
+        Ok(())
+    }
+
+    /// Finds room for `size` bytes of cell content, keeping `pointer_bytes`
+    /// of the gap free for new cell pointers, and returns its offset
+    ///
+    /// The caller has checked that the page has enough free space in total.
+    fn allocate(&mut self, size: usize, pointer_bytes: usize) -> Result<usize> {
+        let space = PageFreeSpace::analyze(self.page, self.header_offset)?;
+        if space.unallocated >= pointer_bytes {
+            let fragmented = self.page[self.header_offset + 7] as usize;
+            let found = space.freeblocks.iter().position(|block| {
+                block.size >= size
+                    && (block.size - size >= 4
+                        || fragmented + block.size - size <= MAX_FRAGMENTED_BYTES)
+            });
+            if let Some(position) = found {
+                let mut blocks = space.freeblocks;
+                let block = blocks[position];
+                let left = block.size - size;
+                if left >= 4 {
+                    blocks[position].size = left;
+                    self.write_freeblocks(&blocks);
+                    return Ok(block.offset + left);
+                }
+                blocks.remove(position);
+                self.write_freeblocks(&blocks);
+                self.page[self.header_offset + 7] = (fragmented + left) as u8;
+                return Ok(block.offset);
+            }
+        }
+
+        if space.unallocated < size + pointer_bytes {
+            self.defragment()?;
+        }
+        let content_start = self.content_start() - size;
+        self.set_content_start(content_start);
+        Ok(content_start)
+    }
+
+    /// Turns `size` bytes at `offset` into a freeblock, merging it with its
+    /// neighbours
+    fn free(&mut self, offset: usize, size: usize) -> Result<()> {
+        let mut blocks = PageFreeSpace::analyze(self.page, self.header_offset)?.freeblocks;
+        let position = blocks.partition_point(|block| block.offset < offset);
+        blocks.insert(position, Freeblock { offset, size });
+
+        // Merge with the next freeblock, then the previous one with the result
+        let mut fragmented = self.page[self.header_offset + 7] as usize;
+        for first in [Some(position), position.checked_sub(1)]
+            .into_iter()
+            .flatten()
+        {
+            if first + 1 >= blocks.len() {
+                continue;
+            }
+            let (block, next) = (blocks[first], blocks[first + 1]);
+            let end = block.offset + block.size;
+            if next.offset < end {
+                return Err(corrupt(offset, "freed cell overlaps a freeblock"));
+            }
+            let gap = next.offset - end;
+            if gap > 3 {
+                continue;
+            }
+            if gap > fragmented {
+                return Err(corrupt(
+                    self.header_offset + 7,
+                    "fragmented bytes miscounted",
+                ));
+            }
+            fragmented -= gap;
+            blocks[first].size = next.offset + next.size - block.offset;
+            blocks.remove(first + 1);
+        }
+        self.page[self.header_offset + 7] = fragmented as u8;
+
+        if blocks
+            .first()
+            .map_or(false, |block| block.offset == self.content_start())
+        {
+            let block = blocks.remove(0);
+            self.set_content_start(block.offset + block.size);
+        }
+        self.write_freeblocks(&blocks);
+        Ok(())
+    }
+
+    /// Rewrites the freeblock chain
+    fn write_freeblocks(&mut self, blocks: &[Freeblock]) {
+        let first = blocks.first().map_or(0, |block| block.offset);
+        self.set_header_u16(1, first);
+        for (i, block) in blocks.iter().enumerate() {
+            let next = blocks.get(i + 1).map_or(0, |next| next.offset);
+            self.page[block.offset..block.offset + 2].copy_from_slice(&(next as u16).to_be_bytes());
+            self.page[block.offset + 2..block.offset + 4]
+                .copy_from_slice(&(block.size as u16).to_be_bytes());
+        }
+    }
+
+    /// Returns where the page number of the `i`-th child is stored
+    fn child_offset(&self, i: usize) -> Result<usize> {
+        if !matches!(self.page_type(), 2 | 5) {
+            return Err(corrupt(self.header_offset, "leaf pages have no children"));
+        }
+        match i.cmp(&self.num_cells()) {
+            Ordering::Less => Ok(self.cell_offset(i)),
+            Ordering::Equal => Ok(self.header_offset + 8),
+            Ordering::Greater => Err(corrupt(
+                self.header_offset + 3,
+                format!("child {} of a page of {} cells", i, self.num_cells()),
+            )),
+        }
+    }
+
+    /// Returns the offset of the `i`-th cell pointer
+    fn pointer_offset(&self, i: usize) -> usize {
+        // Interior pages end their header with the right-most child
+        let header_size = if matches!(self.page_type(), 2 | 5) {
+            12
+        } else {
+            8
+        };
+        self.header_offset + header_size + i * 2
+    }
+
+    /// Returns the start of the cell content area
+    fn content_start(&self) -> usize {
+        // A content offset of zero is interpreted as 65536
+        match self.header_u16(5) {
+            0 => 65536,
+            offset => offset,
+        }
+    }
+
+    /// Moves the start of the cell content area
+    fn set_content_start(&mut self, offset: usize) {
+        self.set_header_u16(5, offset & 0xffff);
+    }
+
+    /// Reads a 2-byte field of the page header
+    fn header_u16(&self, field: usize) -> usize {
+        let offset = self.header_offset + field;
+        u16::from_be_bytes([self.page[offset], self.page[offset + 1]]) as usize
+    }
+
+    /// Writes a 2-byte field of the page header
+    fn set_header_u16(&mut self, field: usize, value: usize) {
+        let offset = self.header_offset + field;
+        self.page[offset..offset + 2].copy_from_slice(&(value as u16).to_be_bytes());
+    }
+}
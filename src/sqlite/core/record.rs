@@ -4,11 +4,11 @@
 //!
 //! ## Record Format
 //!
-//! A record in SQLite represents a single row of data and consists of:
+//! A record in SQLite represents a single row of data and is the payload of a
+//! B-tree cell, which stores the payload length and, in table B-trees, the
+//! rowid in front of it (see [`Cell`](super::btree::Cell)). It consists of:
 //!
 //! - A header containing:
-//!   - Total payload length (varint)
-//!   - Row ID (varint) - only for table b-trees
 //!   - Header size (varint)
 //!   - Serial type codes (sequence of varints)
 //! - The actual field data
@@ -26,91 +26,110 @@
 //! - 8: 0 (legacy)
 //! - 9: 1 (legacy)
 //! - 10,11: Internal use
-//! - N >= 13: Text/BLOB of (N-13)/2 bytes
+//! - N >= 12 and even: BLOB of (N-12)/2 bytes
+//! - N >= 13 and odd: Text of (N-13)/2 bytes
+//!
+//! All reads are bounds-checked; a record that runs past its buffer yields a
+//! `Corrupt` error whose offset is relative to the start of the record.
 
-use super::varint::Varint;
+use super::checked::Reader;
+use super::error::corrupt;
+use super::header::DatabaseHeader;
+use super::value::Value;
+use super::varint::{encode_varint, varint_len};
 use anyhow::{anyhow, Result};
-use tracing::info;
 
 /// Parser for SQLite records (table/index rows)
 pub struct Record<'a> {
-    data: &'a [u8],
-    position: usize,
+    reader: Reader<'a>,
+    /// Header of the database the record comes from, which names the
+    /// encoding of its text fields
+    header: &'a DatabaseHeader,
 }
 
 impl<'a> Record<'a> {
-    pub fn new(data: &'a [u8]) -> Self {
-        Self { data, position: 0 }
-    }
-
-    pub fn skip_payload_length(&mut self) -> Result<()> {
-        self.position += self.data.varint_size(&self.data[self.position..]);
-        Ok(())
-    }
-
-    pub fn skip_rowid(&mut self) -> Result<()> {
-        self.position += self.data.varint_size(&self.data[self.position..]);
-        Ok(())
+    pub fn new(data: &'a [u8], header: &'a DatabaseHeader) -> Self {
+        Self {
+            reader: Reader::new(data, 0),
+            header,
+        }
     }
 
     pub fn read_header(&mut self) -> Result<Vec<u64>> {
-        let header_size = self.data.read_varint(&self.data[self.position..])? as usize;
-        self.position += self.data.varint_size(&self.data[self.position..]);
-        let header_end =
-            self.position + header_size - self.data.varint_size(&self.data[self.position - 1..]);
+        // The header size counts its own varint
+        let header_start = self.reader.position();
+        let header_size = self.reader.varint()? as usize;
+        let header_end = header_start
+            .checked_add(header_size)
+            .filter(|&end| {
+                let position = self.reader.position();
+                end >= position && end - position <= self.reader.remaining()
+            })
+            .ok_or_else(|| {
+                corrupt(
+                    header_start,
+                    format!("invalid record header size {}", header_size),
+                )
+            })?;
 
         let mut serial_types = Vec::new();
-        while self.position < header_end {
-            let serial_type = self.data.read_varint(&self.data[self.position..])?;
-            self.position += self.data.varint_size(&self.data[self.position..]);
-            serial_types.push(serial_type);
+        while self.reader.position() < header_end {
+            serial_types.push(self.reader.varint()?);
         }
 
         Ok(serial_types)
     }
 
-    pub fn skip_fields(&mut self, count: usize, serial_types: &[u64]) {
+    /// Skips the first `count` fields, whose serial types are `serial_types`
+    pub fn skip_fields(&mut self, count: usize, serial_types: &[u64]) -> Result<()> {
         for &type_code in serial_types.iter().take(count) {
-            if type_code >= 13 {
-                self.position += ((type_code - 13) / 2) as usize;
-            }
+            let size = serial_type_size(type_code).ok_or_else(|| {
+                corrupt(
+                    self.reader.position(),
+                    format!("invalid serial type {}", type_code),
+                )
+            })?;
+            self.reader.skip(size)?;
         }
+        Ok(())
     }
 
+    /// Reads a field of the given serial type as text in the database
+    /// encoding, or returns None without moving past it if the type is not TEXT
+    ///
+    /// The record must hold the whole payload, including any part stored on
+    /// overflow pages; a field running past its end is corrupt.
     pub fn read_string_field(&mut self, type_code: u64) -> Result<Option<String>> {
-        if type_code >= 13 {
-            let size = ((type_code - 13) / 2) as usize;
-            info!(
-                "Attempting to read string field of size {} at position {} (data length: {})",
-                size,
-                self.position,
-                self.data.len()
-            );
-
-            // For now, just read what we have available
-            let available_size = std::cmp::min(size, self.data.len() - self.position);
-
-            if let Ok(string) =
-                String::from_utf8(self.data[self.position..self.position + available_size].to_vec())
-            {
-                info!("Successfully read string (truncated): {}", string);
-                self.position += available_size;
-                return Ok(Some(string));
-            }
+        if type_code < 13 || type_code % 2 == 0 {
+            return Ok(None);
         }
-        Ok(None)
+        let bytes = self.reader.bytes(((type_code - 13) / 2) as usize)?;
+        Ok(Some(self.header.decode_text(bytes)))
+    }
+
+    /// Reads a field of the given serial type as the raw bytes of a BLOB
+    ///
+    /// Fails without moving past the field if the type is not BLOB.
+    pub fn read_blob_field(&mut self, type_code: u64) -> Result<Vec<u8>> {
+        if type_code < 12 || type_code % 2 != 0 {
+            return Err(corrupt(
+                self.reader.position(),
+                format!("serial type {} is not a BLOB", type_code),
+            ));
+        }
+        Ok(self.reader.bytes(((type_code - 12) / 2) as usize)?.to_vec())
     }
 
     pub fn position(&self) -> usize {
-        self.position
+        self.reader.position()
     }
 
     pub fn read_varint(&mut self) -> Result<u64> {
-        let value = self.data.read_varint(&self.data[self.position..])?;
-        self.position += self.data.varint_size(&self.data[self.position..]);
-        Ok(value)
+        self.reader.varint()
     }
 
+    /// Reads an integer of serial type 1 to 6, sign-extending its big-endian
+    /// two's-complement bytes to 64 bits
     pub fn read_integer(&mut self, type_code: u64) -> Result<i64> {
         let size = match type_code {
             1 => 1,
@@ -121,17 +140,275 @@ impl<'a> Record<'a> {
             6 => 8,
             _ => return Err(anyhow!("Invalid integer type code")),
         };
+        Ok(decode_integer(self.reader.bytes(size)?))
+    }
 
-        let mut bytes = [0u8; 8];
-        bytes[..size].copy_from_slice(&self.data[self.position..self.position + size]);
-        self.position += size;
+    pub fn read_float(&mut self) -> Result<f64> {
+        Ok(f64::from_be_bytes(self.reader.bytes(8)?.try_into()?))
+    }
+}
 
-        Ok(i64::from_be_bytes(bytes))
+/// Splits a record into `(serial type, bytes)` per column
+///
+/// `record` is the complete payload, starting at the header size varint. Serial
+/// types 10 and 11 are reserved and rejected.
+pub fn record_fields(record: &[u8]) -> Result<Vec<(u64, &[u8])>> {
+    let mut header = Reader::new(record, 0);
+    let header_size = header.varint()? as usize;
+    let mut body = Reader::new(record, header_size);
+    let mut fields = Vec::new();
+
+    while header.position() < header_size {
+        let offset = header.position();
+        let serial_type = header.varint()?;
+        let size = serial_type_size(serial_type)
+            .ok_or_else(|| corrupt(offset, format!("invalid serial type {}", serial_type)))?;
+        fields.push((serial_type, body.bytes(size)?));
     }
+    Ok(fields)
+}
 
-    pub fn read_float(&mut self) -> Result<f64> {
-        let bytes = self.data[self.position..self.position + 8].try_into()?;
-        self.position += 8;
-        Ok(f64::from_be_bytes(bytes))
+/// Number of bytes a field of the given serial type takes in the record body,
+/// or None for the reserved types 10 and 11
+pub fn serial_type_size(serial_type: u64) -> Option<usize> {
+    match serial_type {
+        0 | 8 | 9 => Some(0),
+        1..=4 => Some(serial_type as usize),
+        5 => Some(6),
+        6 | 7 => Some(8),
+        n if n >= 12 => Some(((n - 12) / 2) as usize),
+        _ => None,
+    }
+}
+
+/// Decodes a big-endian two's-complement integer of 1 to 8 bytes
+pub fn decode_integer(bytes: &[u8]) -> i64 {
+    let sign = if bytes.first().map_or(false, |b| b & 0x80 != 0) {
+        -1i64
+    } else {
+        0
+    };
+    bytes
+        .iter()
+        .fold(sign, |value, &byte| value << 8 | byte as i64)
+}
+
+/// Decodes one field of a record, as returned by `record_fields`
+pub fn decode_value(serial_type: u64, bytes: &[u8], header: &DatabaseHeader) -> Value {
+    match serial_type {
+        0 => Value::Null,
+        1..=6 => Value::Integer(decode_integer(bytes)),
+        7 => Value::Real(f64::from_be_bytes(bytes.try_into().unwrap_or_default())),
+        8 => Value::Integer(0),
+        9 => Value::Integer(1),
+        n if n % 2 == 0 => Value::Blob(bytes.to_vec()),
+        _ => Value::Text(header.decode_text(bytes)),
+    }
+}
+
+/// Decodes every field of a record into values
+pub fn decode_record(record: &[u8], header: &DatabaseHeader) -> Result<Vec<Value>> {
+    Ok(record_fields(record)?
+        .into_iter()
+        .map(|(serial_type, bytes)| decode_value(serial_type, bytes, header))
+        .collect())
+}
+
+/// Encodes values as a record: a header of serial types followed by the data
+///
+/// Text is written as UTF-8; see [`RecordBuilder`] for other encodings.
+pub fn encode_record(values: &[Value]) -> Vec<u8> {
+    let mut builder = RecordBuilder::new();
+    for value in values {
+        builder.push(value);
+    }
+    builder.build()
+}
+
+/// Serializes values into a record, the inverse of [`Record`]
+///
+/// Integers use the smallest serial type that holds them, including the
+/// zero-length types 8 and 9 for 0 and 1.
+#[derive(Debug, Default)]
+pub struct RecordBuilder<'a> {
+    /// Serial types of the values pushed so far, as varints
+    types: Vec<u8>,
+    /// Data of the values pushed so far
+    body: Vec<u8>,
+    /// Header of the database the record is for, or None to write text as UTF-8
+    header: Option<&'a DatabaseHeader>,
+}
+
+impl<'a> RecordBuilder<'a> {
+    /// Starts an empty record whose text is written as UTF-8
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Starts an empty record whose text is written in the database encoding
+    pub fn for_database(header: &'a DatabaseHeader) -> Self {
+        Self {
+            header: Some(header),
+            ..Self::default()
+        }
+    }
+
+    /// Appends a value as the next column of the record
+    pub fn push(&mut self, value: &Value) -> &mut Self {
+        let serial_type = match value {
+            Value::Null => 0,
+            Value::Integer(0) => 8,
+            Value::Integer(1) => 9,
+            Value::Integer(i) => {
+                let (serial_type, len) = match *i {
+                    -0x80..=0x7f => (1, 1),
+                    -0x8000..=0x7fff => (2, 2),
+                    -0x80_0000..=0x7f_ffff => (3, 3),
+                    -0x8000_0000..=0x7fff_ffff => (4, 4),
+                    -0x8000_0000_0000..=0x7fff_ffff_ffff => (5, 6),
+                    _ => (6, 8),
+                };
+                self.body.extend_from_slice(&i.to_be_bytes()[8 - len..]);
+                serial_type
+            }
+            Value::Real(r) => {
+                self.body.extend_from_slice(&r.to_be_bytes());
+                7
+            }
+            Value::Text(s) => {
+                let bytes = match self.header {
+                    Some(header) => header.encode_text(s),
+                    None => s.as_bytes().to_vec(),
+                };
+                self.body.extend_from_slice(&bytes);
+                bytes.len() as u64 * 2 + 13
+            }
+            Value::Blob(b) => {
+                self.body.extend_from_slice(b);
+                b.len() as u64 * 2 + 12
+            }
+        };
+        encode_varint(serial_type, &mut self.types);
+        self
+    }
+
+    /// Returns the serialized record of the values pushed so far
+    pub fn build(&self) -> Vec<u8> {
+        // The header size counts its own varint, whose length depends on the size
+        let mut size_len = 1;
+        while varint_len((self.types.len() + size_len) as u64) > size_len {
+            size_len += 1;
+        }
+        let header_size = self.types.len() + size_len;
+        let mut record = Vec::with_capacity(header_size + self.body.len());
+        encode_varint(header_size as u64, &mut record);
+        record.extend_from_slice(&self.types);
+        record.extend_from_slice(&self.body);
+        record
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sqlite::storage::db::SQLiteDatabase;
+    use crate::sqlite::testing::{sqlite3, TempFile};
+
+    /// Serial types 1 to 6 with their byte lengths and value ranges
+    const INTEGER_TYPES: [(u64, usize, i64, i64); 6] = [
+        (1, 1, -0x80, 0x7f),
+        (2, 2, -0x8000, 0x7fff),
+        (3, 3, -0x80_0000, 0x7f_ffff),
+        (4, 4, -0x8000_0000, 0x7fff_ffff),
+        (5, 6, -0x8000_0000_0000, 0x7fff_ffff_ffff),
+        (6, 8, i64::MIN, i64::MAX),
+    ];
+
+    fn header() -> DatabaseHeader {
+        DatabaseHeader::parse(&DatabaseHeader::new_database(4096)).unwrap()
+    }
+
+    fn read_integer(type_code: u64, bytes: &[u8]) -> Result<i64> {
+        let header = header();
+        let mut record = Record::new(bytes, &header);
+        let value = record.read_integer(type_code)?;
+        assert_eq!(record.position(), bytes.len());
+        Ok(value)
+    }
+
+    #[test]
+    fn integer_types_sign_extend_their_negative_extremes() {
+        for (type_code, len, min, max) in INTEGER_TYPES {
+            let mut bytes = vec![0; len];
+            bytes[0] = 0x80;
+            assert_eq!(read_integer(type_code, &bytes).unwrap(), min);
+            assert_eq!(read_integer(type_code, &vec![0xff; len]).unwrap(), -1);
+
+            let mut bytes = vec![0xff; len];
+            bytes[0] = 0x7f;
+            assert_eq!(read_integer(type_code, &bytes).unwrap(), max);
+        }
+    }
+
+    #[test]
+    fn integers_round_trip_through_the_smallest_type() {
+        for (type_code, len, min, max) in INTEGER_TYPES {
+            for value in [min, max] {
+                let record = encode_record(&[Value::Integer(value)]);
+                assert_eq!(record_fields(&record).unwrap()[0].0, type_code);
+                assert_eq!(record.len(), 2 + len);
+                assert_eq!(
+                    decode_record(&record, &header()).unwrap(),
+                    [Value::Integer(value)]
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn decodes_negative_integers_written_by_sqlite3() {
+        let values = [
+            -1,
+            -128,
+            -32768,
+            -8388608,
+            i64::from(i32::MIN),
+            -(1 << 47),
+            i64::MIN,
+        ];
+        let file = TempFile::new("record-negative-integers");
+        let rows: Vec<String> = values.iter().map(|value| format!("({})", value)).collect();
+        let sql = format!("CREATE TABLE t(v); INSERT INTO t VALUES {}", rows.join(","));
+        if sqlite3(&file, &sql).is_none() {
+            return;
+        }
+
+        let mut db = SQLiteDatabase::open(&file.0).unwrap();
+        let root_page = db.find_table_root_page("t").unwrap();
+        let mut cursor = db.cursor(root_page).unwrap();
+        let mut valid = cursor.first().unwrap();
+        for value in values {
+            assert!(valid, "sqlite3 wrote too few rows");
+            let payload = cursor.payload().unwrap();
+            // sqlite3 picks the smallest serial type that holds the value
+            let &(type_code, ..) = INTEGER_TYPES
+                .iter()
+                .find(|&&(_, _, min, max)| (min..=max).contains(&value))
+                .unwrap();
+            let fields = record_fields(&payload).unwrap();
+            assert_eq!(fields.len(), 1);
+            assert_eq!(fields[0].0, type_code, "serial type of {}", value);
+            assert_eq!(read_integer(type_code, fields[0].1).unwrap(), value);
+            assert_eq!(&*payload, encode_record(&[Value::Integer(value)]));
+            valid = cursor.next().unwrap();
+        }
+        assert!(!valid, "sqlite3 wrote too many rows");
+    }
+
+    #[test]
+    fn read_integer_rejects_other_types_and_short_fields() {
+        assert!(read_integer(0, &[]).is_err());
+        assert!(read_integer(7, &[0; 8]).is_err());
+        assert!(read_integer(5, &[0x80; 4]).is_err());
     }
 }
@@ -1,61 +1,447 @@
-use anyhow::Result;
-use tracing::info;
+use crate::sqlite::core::collation::Collation;
+use crate::sqlite::core::value::Value;
+use crate::sqlite::parser::expression::Expression;
+use crate::sqlite::parser::keyword;
+use anyhow::{anyhow, Result};
+use tracing::debug;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct TableSchema {
     pub name: String,
     pub columns: Vec<ColumnDef>,
     pub sql: String,
+    /// True for a WITHOUT ROWID table, whose rows are stored in an index B-tree
+    /// keyed by the primary key instead of in a table B-tree keyed by rowid
+    pub without_rowid: bool,
+    /// Positions in `columns` of the PRIMARY KEY columns, in key order
+    pub primary_key: Vec<usize>,
+    /// The columns of each PRIMARY KEY and UNIQUE constraint, with whether each
+    /// is descending, in declaration order; an INTEGER PRIMARY KEY is left
+    /// out, since the rowid needs no index
+    pub unique_keys: Vec<Vec<(usize, bool)>>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct ColumnDef {
     pub name: String,
+    /// Declared type, which may span several words (`UNSIGNED BIG INT`) and
+    /// carry a size (`VARCHAR(255)`); empty if none was given
     pub column_type: String,
+    /// Affinity derived from `column_type`
+    pub affinity: Affinity,
+    /// Collation named by a COLLATE clause; None means BINARY
+    pub collation: Option<String>,
+    /// True for an INTEGER PRIMARY KEY column, which stores the rowid
+    pub rowid_alias: bool,
+    /// True for a NOT NULL column
+    pub not_null: bool,
+    /// The expression of the DEFAULT clause, which gives the value of rows
+    /// that leave the column out, or the error parsing it; None for no
+    /// DEFAULT clause, which means NULL
+    ///
+    /// A value that cannot be parsed is only an error for the rows that need
+    /// it, so that the table can still be read.
+    pub default: Option<Result<Expression, String>>,
+}
+
+/// The type a column prefers for its values, derived from its declared type
+///
+/// Values are converted towards a column's affinity when stored and when
+/// compared with values that have a weaker one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Affinity {
+    Text,
+    Numeric,
+    Integer,
+    Real,
+    Blob,
+}
+
+impl Affinity {
+    /// Derives the affinity of a declared type, using the first of SQLite's
+    /// rules that matches:
+    ///
+    /// 1. Contains "INT": INTEGER
+    /// 2. Contains "CHAR", "CLOB" or "TEXT": TEXT
+    /// 3. Contains "BLOB", or is empty: BLOB
+    /// 4. Contains "REAL", "FLOA" or "DOUB": REAL
+    /// 5. Otherwise: NUMERIC
+    pub fn of_declared_type(declared: &str) -> Self {
+        let declared = declared.to_ascii_uppercase();
+        let has = |part: &str| declared.contains(part);
+        if has("INT") {
+            Affinity::Integer
+        } else if has("CHAR") || has("CLOB") || has("TEXT") {
+            Affinity::Text
+        } else if has("BLOB") || declared.is_empty() {
+            Affinity::Blob
+        } else if has("REAL") || has("FLOA") || has("DOUB") {
+            Affinity::Real
+        } else {
+            Affinity::Numeric
+        }
+    }
+
+    /// Returns true for the affinities that prefer numbers
+    pub fn is_numeric(self) -> bool {
+        matches!(self, Affinity::Numeric | Affinity::Integer | Affinity::Real)
+    }
+}
+
+impl ColumnDef {
+    /// Returns the collation the column's values compare under: the one its
+    /// COLLATE clause names if that is built in, else BINARY
+    pub fn collating_sequence(&self) -> Collation {
+        self.collation
+            .as_deref()
+            .and_then(Collation::from_name)
+            .unwrap_or_default()
+    }
+
+    /// Returns the expression of the column's DEFAULT clause, if it has one
+    pub fn default_expression(&self) -> Result<Option<&Expression>> {
+        match &self.default {
+            None => Ok(None),
+            Some(Ok(expr)) => Ok(Some(expr)),
+            Some(Err(error)) => Err(anyhow!(
+                "unsupported default value for column {}: {}",
+                self.name,
+                error
+            )),
+        }
+    }
+
+    /// Converts a value assigned to the column into the value its records
+    /// store: the value with the column's affinity, with integral reals stored
+    /// as integers in columns of a numeric affinity, as SQLite does (REAL
+    /// columns turn them back into reals when read). An INTEGER PRIMARY KEY
+    /// stores NULL in favour of the rowid.
+    pub fn stored_value(&self, value: &Value) -> Value {
+        if self.rowid_alias {
+            return Value::Null;
+        }
+        match value.with_affinity(self.affinity) {
+            Value::Real(r)
+                if self.affinity.is_numeric()
+                    && r == r.trunc()
+                    && (i64::MIN as f64..i64::MAX as f64).contains(&r) =>
+            {
+                Value::Integer(r as i64)
+            }
+            value => value,
+        }
+    }
 }
 
 impl TableSchema {
+    /// Returns the keys SQLite creates automatic indexes for, each with its
+    /// number N in the index name `sqlite_autoindex_<table>_<N>`: the unique
+    /// keys in order, leaving out those on the same columns as an earlier one
+    ///
+    /// The primary key of a WITHOUT ROWID table takes its number, though the
+    /// table itself serves as its index.
+    pub fn automatic_index_keys(&self) -> Vec<(usize, &[(usize, bool)])> {
+        let mut keys: Vec<(usize, &[(usize, bool)])> = Vec::new();
+        for key in &self.unique_keys {
+            let same_columns = |(_, other): &(usize, &[(usize, bool)])| {
+                other.len() == key.len() && other.iter().zip(key).all(|(a, b)| a.0 == b.0)
+            };
+            if !keys.iter().any(same_columns) {
+                keys.push((keys.len() + 1, key));
+            }
+        }
+        keys
+    }
+
     pub fn parse(name: String, sql: String) -> Result<Self> {
-        info!("Parsing schema for table '{}': {}", name, sql);
+        debug!("Parsing schema for table '{}': {}", name, sql);
 
         // Extract column definitions
-        let columns = if let Some(start_idx) = sql.find('(') {
+        let mut columns = Vec::new();
+        // PRIMARY KEY and UNIQUE constraints in declaration order: whether each
+        // is the primary key, and its columns with whether each is descending
+        let mut keys: Vec<(bool, Vec<(String, bool)>)> = Vec::new();
+        let mut without_rowid = false;
+        // Whether the primary key was declared as a table constraint
+        let mut table_key = false;
+        if let Some(start_idx) = sql.find('(') {
             // Get everything between first ( and last )
             let end_idx = sql.rfind(')').unwrap_or(sql.len());
             let columns_str = &sql[start_idx + 1..end_idx];
 
-            info!("Parsing columns: {}", columns_str);
+            debug!("Parsing columns: {}", columns_str);
+
+            // Table options follow the closing parenthesis
+            let options: Vec<String> = sql
+                .get(end_idx + 1..)
+                .unwrap_or_default()
+                .split(|c: char| c == ',' || c.is_whitespace())
+                .map(str::to_uppercase)
+                .collect();
+            without_rowid = options
+                .windows(2)
+                .any(|w| w[0] == "WITHOUT" && w[1] == "ROWID");
+
+            // Split on commas outside parentheses and parse each column definition
+            for col in split_definitions(columns_str) {
+                let col = col.trim();
 
-            // Split on commas and parse each column definition
-            columns_str
-                .split(',')
-                .filter_map(|col| {
-                    let col = col.trim();
-                    if col.is_empty() {
-                        return None;
+                // Split on whitespace and get column name and type
+                let parts: Vec<&str> = col.split_whitespace().collect();
+                if parts.is_empty() {
+                    continue;
+                }
+
+                // Table constraints follow the columns
+                let words: Vec<String> = parts.iter().map(|p| p.to_uppercase()).collect();
+                if ["CONSTRAINT", "PRIMARY", "UNIQUE", "CHECK", "FOREIGN"]
+                    .contains(&words[0].split('(').next().unwrap_or_default())
+                {
+                    let open = col.find('(').unwrap_or(col.len());
+                    let head: Vec<&str> = col[..open].split_whitespace().collect();
+                    let has = |keyword: &str| head.iter().any(|w| w.eq_ignore_ascii_case(keyword));
+                    let primary = has("PRIMARY");
+                    if primary || has("UNIQUE") {
+                        table_key |= primary;
+                        let close = col.rfind(')').unwrap_or(col.len()).max(open);
+                        let key_columns = split_definitions(col.get(open + 1..close).unwrap_or(""))
+                            .into_iter()
+                            .filter_map(|term| {
+                                let mut term = term.split_whitespace();
+                                let name = unquote(term.next()?);
+                                Some((name, term.any(|word| word.eq_ignore_ascii_case("DESC"))))
+                            })
+                            .collect();
+                        keys.push((primary, key_columns));
                     }
+                    continue;
+                }
+
+                let name = unquote(parts[0]);
+                // The type is every word up to the first column constraint
+                let col_type = parts[1..]
+                    .iter()
+                    .take_while(|word| {
+                        let word = word.split('(').next().unwrap_or(word);
+                        keyword::can_be_name(word) && !word.eq_ignore_ascii_case("GENERATED")
+                    })
+                    .copied()
+                    .collect::<Vec<_>>()
+                    .join(" ");
 
-                    // Split on whitespace and get column name and type
-                    let parts: Vec<&str> = col.split_whitespace().collect();
-                    if parts.is_empty() {
-                        return None;
+                let mut primary_key = false;
+                let mut descending = false;
+                for (i, word) in words.iter().enumerate() {
+                    let next = words.get(i + 1).map(String::as_str);
+                    if word == "PRIMARY" && next == Some("KEY") {
+                        primary_key = true;
+                        descending = words.get(i + 2).map(String::as_str) == Some("DESC");
+                        keys.push((true, vec![(name.clone(), descending)]));
+                    } else if word == "UNIQUE" {
+                        keys.push((false, vec![(name.clone(), false)]));
                     }
+                }
+                let not_null = words
+                    .windows(2)
+                    .any(|pair| pair[0] == "NOT" && pair[1] == "NULL");
+                let collation = words
+                    .iter()
+                    .position(|word| word == "COLLATE")
+                    .and_then(|i| parts.get(i + 1))
+                    .map(|collation| unquote(collation));
+                let rowid_alias =
+                    col_type.eq_ignore_ascii_case("INTEGER") && primary_key && !descending;
 
-                    let name = parts[0].trim_matches('"').to_string();
-                    let col_type = parts.get(1).map_or("".to_string(), |s| s.to_string());
+                debug!("Found column: {} (type: {})", name, col_type);
 
-                    info!("Found column: {} (type: {})", name, col_type);
+                columns.push(ColumnDef {
+                    name,
+                    affinity: Affinity::of_declared_type(&col_type),
+                    column_type: col_type,
+                    collation,
+                    rowid_alias,
+                    not_null,
+                    default: default_clause(col)
+                        .map(|text| parse_default(text).map_err(|error| error.to_string())),
+                });
+            }
+        }
 
-                    Some(ColumnDef {
-                        name,
-                        column_type: col_type,
-                    })
-                })
-                .collect()
-        } else {
-            Vec::new()
+        let position = |name: &str| {
+            columns
+                .iter()
+                .position(|column: &ColumnDef| column.name.eq_ignore_ascii_case(name))
         };
+        let mut unique_keys: Vec<(bool, Vec<(usize, bool)>)> = keys
+            .iter()
+            .map(|(primary, key)| {
+                let key = key
+                    .iter()
+                    .filter_map(|(name, desc)| Some((position(name)?, *desc)))
+                    .collect();
+                (*primary, key)
+            })
+            .collect();
+        let primary_key: Vec<usize> = unique_keys
+            .iter()
+            .find(|(primary, _)| *primary)
+            .map(|(_, key)| key.iter().map(|&(position, _)| position).collect())
+            .unwrap_or_default();
+        // Only a lone INTEGER PRIMARY KEY of a rowid table stores the rowid,
+        // whether it is declared on the column or as a table constraint
+        if without_rowid || primary_key.len() > 1 {
+            columns
+                .iter_mut()
+                .for_each(|column| column.rowid_alias = false);
+        } else if let [key] = primary_key[..] {
+            if table_key && columns[key].column_type.eq_ignore_ascii_case("INTEGER") {
+                columns[key].rowid_alias = true;
+            }
+            // The rowid needs no index of its own
+            if columns[key].rowid_alias {
+                unique_keys.retain(|(primary, _)| !primary);
+            }
+        }
+
+        Ok(TableSchema {
+            name,
+            columns,
+            sql,
+            without_rowid,
+            primary_key,
+            unique_keys: unique_keys.into_iter().map(|(_, key)| key).collect(),
+        })
+    }
+}
+
+/// Strips the quotes from a quoted identifier
+fn unquote(name: &str) -> String {
+    name.trim_matches(|c| matches!(c, '"' | '`' | '[' | ']'))
+        .to_string()
+}
+
+/// Splits a column list on the commas that are not inside parentheses or
+/// quotes, such as the ones in `DECIMAL(10, 2)` and `DEFAULT 'a, b'`
+pub(crate) fn split_definitions(columns: &str) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut depth = 0usize;
+    let mut start = 0;
+    let mut i = 0;
+    while let Some(c) = columns[i..].chars().next() {
+        match c {
+            '\'' | '"' | '`' | '[' => {
+                i = quoted_end(columns, i);
+                continue;
+            }
+            '(' => depth += 1,
+            ')' => depth = depth.saturating_sub(1),
+            ',' if depth == 0 => {
+                parts.push(&columns[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+        i += c.len_utf8();
+    }
+    parts.push(&columns[start..]);
+    parts
+}
 
-        Ok(TableSchema { name, columns, sql })
+/// Returns the index just past the quoted string or name starting at `start`;
+/// a doubled quote stands for itself
+fn quoted_end(text: &str, start: usize) -> usize {
+    let close = match text.as_bytes()[start] {
+        b'[' => b']',
+        quote => quote,
+    };
+    let bytes = text.as_bytes();
+    let mut i = start + 1;
+    while i < bytes.len() {
+        if bytes[i] == close {
+            if close != b']' && bytes.get(i + 1) == Some(&close) {
+                i += 2;
+                continue;
+            }
+            return i + 1;
+        }
+        i += 1;
     }
+    bytes.len()
+}
+
+/// Returns the text of the value following DEFAULT in a column definition: a
+/// literal, possibly signed, a name or a parenthesized expression
+fn default_clause(definition: &str) -> Option<&str> {
+    // Each term is a word, a quoted string or name, or a parenthesized group
+    let bytes = definition.as_bytes();
+    let mut terms: Vec<(usize, usize)> = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        let start = i;
+        i = match bytes[i] {
+            c if c.is_ascii_whitespace() => {
+                i += 1;
+                continue;
+            }
+            b'\'' | b'"' | b'`' | b'[' => quoted_end(definition, i),
+            b'(' => {
+                let mut depth = 0;
+                while i < bytes.len() {
+                    match bytes[i] {
+                        b'\'' | b'"' | b'`' | b'[' => {
+                            i = quoted_end(definition, i);
+                            continue;
+                        }
+                        b'(' => depth += 1,
+                        b')' => depth -= 1,
+                        _ => {}
+                    }
+                    i += 1;
+                    if depth == 0 {
+                        break;
+                    }
+                }
+                i
+            }
+            // A BLOB literal such as X'CAFE'
+            b'x' | b'X' if bytes.get(i + 1) == Some(&b'\'') => quoted_end(definition, i + 1),
+            _ => {
+                while i < bytes.len()
+                    && !bytes[i].is_ascii_whitespace()
+                    && !matches!(bytes[i], b'\'' | b'"' | b'`' | b'[' | b'(')
+                {
+                    i += 1;
+                }
+                i
+            }
+        };
+        terms.push((start, i));
+    }
+
+    let position = terms
+        .iter()
+        .position(|&(start, end)| definition[start..end].eq_ignore_ascii_case("DEFAULT"))?;
+    let (start, mut end) = *terms.get(position + 1)?;
+    // A sign may be written apart from its number
+    if matches!(&definition[start..end], "-" | "+") {
+        end = terms.get(position + 2).map_or(end, |&(_, end)| end);
+    }
+    Some(&definition[start..end])
+}
+
+/// Parses the value of a DEFAULT clause; a bare name is a string, as in SQLite,
+/// except for TRUE and FALSE
+fn parse_default(text: &str) -> Result<Expression> {
+    Ok(match Expression::parse(text)? {
+        Expression::Column { table: None, name } => {
+            Expression::Literal(if name.eq_ignore_ascii_case("TRUE") {
+                Value::Integer(1)
+            } else if name.eq_ignore_ascii_case("FALSE") {
+                Value::Integer(0)
+            } else {
+                Value::Text(name)
+            })
+        }
+        expr => expr,
+    })
 }
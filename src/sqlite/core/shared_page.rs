@@ -0,0 +1,59 @@
+//! Shared Page Bytes
+//!
+//! A [`SharedPage`] holds the bytes of one page where any number of readers
+//! can share them without copying. Most pages are buffers of their own, read
+//! from the file or decoded; a page of a memory-mapped file is instead a range
+//! of the mapping, which stays mapped for as long as any page of it is held.
+
+use std::fmt;
+use std::ops::{Deref, Range};
+use std::sync::Arc;
+
+/// The bytes of a page, cheap to clone and shared between readers
+#[derive(Clone)]
+pub struct SharedPage(Bytes);
+
+#[derive(Clone)]
+enum Bytes {
+    /// A buffer holding just the page
+    Owned(Arc<[u8]>),
+    /// The page's range of a larger region, such as a file mapping
+    Slice {
+        region: Arc<dyn AsRef<[u8]> + Send + Sync>,
+        range: Range<usize>,
+    },
+}
+
+impl SharedPage {
+    /// Shares the bytes in `range` of `region` as a page, or returns none if
+    /// the range reaches past the end of the region
+    pub fn slice(region: Arc<dyn AsRef<[u8]> + Send + Sync>, range: Range<usize>) -> Option<Self> {
+        (*region).as_ref().get(range.clone())?;
+        Some(Self(Bytes::Slice { region, range }))
+    }
+}
+
+impl Deref for SharedPage {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match &self.0 {
+            Bytes::Owned(page) => page,
+            Bytes::Slice { region, range } => &(**region).as_ref()[range.clone()],
+        }
+    }
+}
+
+impl From<Vec<u8>> for SharedPage {
+    fn from(page: Vec<u8>) -> Self {
+        Self(Bytes::Owned(page.into()))
+    }
+}
+
+impl fmt::Debug for SharedPage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SharedPage")
+            .field("len", &self.len())
+            .finish()
+    }
+}
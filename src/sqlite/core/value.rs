@@ -0,0 +1,287 @@
+//! SQLite Value Representation
+//!
+//! Every value stored in or computed by SQLite has one of five storage classes:
+//!
+//! - NULL
+//! - INTEGER: signed 64-bit integer
+//! - REAL: IEEE 754 64-bit float
+//! - TEXT: string in the database encoding
+//! - BLOB: raw bytes, stored exactly as given
+//!
+//! Values of different classes sort as NULL < INTEGER/REAL < TEXT < BLOB.
+//! Integers and reals compare by numeric value, text and blobs bytewise.
+
+use crate::sqlite::core::schema::Affinity;
+use std::cmp::Ordering;
+use std::fmt::Display;
+
+/// A single SQLite value
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Null,
+    Integer(i64),
+    Real(f64),
+    Text(String),
+    Blob(Vec<u8>),
+}
+
+impl Value {
+    /// Returns true if the value is NULL
+    pub fn is_null(&self) -> bool {
+        matches!(self, Value::Null)
+    }
+
+    /// Converts the value to a number the way SQLite's arithmetic operators do
+    ///
+    /// Text and blobs are read as the longest numeric prefix, or 0 if there is
+    /// none. NULL stays NULL.
+    pub fn to_numeric(&self) -> Value {
+        match self {
+            Value::Null | Value::Integer(_) | Value::Real(_) => self.clone(),
+            Value::Text(s) => parse_numeric_prefix(s).0,
+            Value::Blob(b) => parse_numeric_prefix(&String::from_utf8_lossy(b)).0,
+        }
+    }
+
+    /// Converts text that is entirely a well-formed number (ignoring surrounding
+    /// whitespace) to that number, leaving every other value unchanged
+    pub fn with_numeric_affinity(&self) -> Value {
+        match self {
+            Value::Text(s) => {
+                let trimmed = s.trim();
+                match parse_numeric_prefix(trimmed) {
+                    (number, len) if len > 0 && len == trimmed.len() => number,
+                    _ => self.clone(),
+                }
+            }
+            _ => self.clone(),
+        }
+    }
+
+    /// Converts the value towards a column affinity
+    ///
+    /// TEXT turns numbers into their text; NUMERIC and INTEGER turn text that
+    /// is a well-formed number into that number; REAL does the same and then
+    /// stores integers as reals. BLOB changes nothing, and NULL stays NULL.
+    pub fn with_affinity(&self, affinity: Affinity) -> Value {
+        match (affinity, self) {
+            (Affinity::Text, Value::Integer(_) | Value::Real(_)) => Value::Text(self.to_string()),
+            (Affinity::Numeric | Affinity::Integer, _) => self.with_numeric_affinity(),
+            (Affinity::Real, _) => match self.with_numeric_affinity() {
+                Value::Integer(i) => Value::Real(i as f64),
+                value => value,
+            },
+            _ => self.clone(),
+        }
+    }
+
+    /// Converts the value to an integer, truncating reals; None for NULL
+    pub fn to_integer(&self) -> Option<i64> {
+        match self.to_numeric() {
+            Value::Integer(i) => Some(i),
+            Value::Real(r) => Some(r as i64),
+            _ => None,
+        }
+    }
+
+    /// Interprets the value as a truth value: None (unknown) for NULL, otherwise
+    /// true if its numeric value is non-zero
+    pub fn truth(&self) -> Option<bool> {
+        match self.to_numeric() {
+            Value::Integer(i) => Some(i != 0),
+            Value::Real(r) => Some(r != 0.0),
+            _ => None,
+        }
+    }
+
+    /// Represents a truth value as 1, 0 or NULL
+    pub fn from_truth(truth: Option<bool>) -> Value {
+        match truth {
+            Some(truth) => Value::Integer(truth as i64),
+            None => Value::Null,
+        }
+    }
+
+    /// Compares two values the way SQL comparison operators do, returning None
+    /// if either is NULL
+    pub fn compare(&self, other: &Value) -> Option<Ordering> {
+        match (self, other) {
+            (Value::Null, _) | (_, Value::Null) => None,
+            _ => Some(self.sort_cmp(other)),
+        }
+    }
+
+    /// Orders two values for sorting, with NULL before every other value
+    ///
+    /// This is the single ordering used by ORDER BY, min() and max() and the
+    /// comparison operators, so they always agree with each other. Text is
+    /// compared as UTF-8; [`Collation::compare`] compares it as the database
+    /// stores it.
+    ///
+    /// [`Collation::compare`]: crate::sqlite::core::collation::Collation::compare
+    pub fn sort_cmp(&self, other: &Value) -> Ordering {
+        match (self, other) {
+            (Value::Integer(l), Value::Integer(r)) => l.cmp(r),
+            (Value::Integer(l), Value::Real(r)) => compare_integer_real(*l, *r),
+            (Value::Real(l), Value::Integer(r)) => compare_integer_real(*r, *l).reverse(),
+            (Value::Real(l), Value::Real(r)) => l.partial_cmp(r).unwrap_or(Ordering::Equal),
+            (Value::Text(l), Value::Text(r)) => l.as_bytes().cmp(r.as_bytes()),
+            (Value::Blob(l), Value::Blob(r)) => l.cmp(r),
+            (l, r) => l.class_rank().cmp(&r.class_rank()),
+        }
+    }
+
+    /// Position of the value's storage class in the cross-type sort order
+    fn class_rank(&self) -> u8 {
+        match self {
+            Value::Null => 0,
+            Value::Integer(_) | Value::Real(_) => 1,
+            Value::Text(_) => 2,
+            Value::Blob(_) => 3,
+        }
+    }
+
+    /// Converts the value to text; None for NULL
+    pub fn to_text(&self) -> Option<String> {
+        match self {
+            Value::Null => None,
+            Value::Blob(b) => Some(String::from_utf8_lossy(b).into_owned()),
+            value => Some(value.to_string()),
+        }
+    }
+}
+
+/// Compares an integer with a real exactly
+///
+/// Converting the integer to f64 would round integers beyond 2^53, so the real is
+/// split into its integer and fractional parts instead.
+fn compare_integer_real(int: i64, real: f64) -> Ordering {
+    if real.is_nan() {
+        return Ordering::Equal;
+    }
+    // i64 spans [-2^63, 2^63); both bounds are exact in f64
+    if real < -9_223_372_036_854_775_808.0 {
+        return Ordering::Greater;
+    }
+    if real >= 9_223_372_036_854_775_808.0 {
+        return Ordering::Less;
+    }
+    let whole = real.trunc();
+    int.cmp(&(whole as i64))
+        .then_with(|| 0.0.partial_cmp(&(real - whole)).unwrap_or(Ordering::Equal))
+}
+
+/// Parses the longest prefix of `s` that looks like a number, returning the
+/// number (0 if there is none) and the length of the prefix including leading
+/// whitespace
+///
+/// Prefixes without a decimal point or exponent that fit in 64 bits are integers;
+/// everything else is a real.
+fn parse_numeric_prefix(s: &str) -> (Value, usize) {
+    let skipped = s.len() - s.trim_start().len();
+    let s = s.trim_start();
+    let bytes = s.as_bytes();
+    let digits_from = |mut i: usize| {
+        while i < bytes.len() && bytes[i].is_ascii_digit() {
+            i += 1;
+        }
+        i
+    };
+
+    let mut end = usize::from(matches!(bytes.first(), Some(b'+' | b'-')));
+    let int_start = end;
+    end = digits_from(end);
+    let mut digits = end - int_start;
+    let mut is_real = false;
+
+    if bytes.get(end) == Some(&b'.') {
+        let frac_end = digits_from(end + 1);
+        digits += frac_end - end - 1;
+        if digits > 0 {
+            end = frac_end;
+            is_real = true;
+        }
+    }
+    if digits == 0 {
+        return (Value::Integer(0), 0);
+    }
+    if matches!(bytes.get(end), Some(b'e' | b'E')) {
+        let exp_start = end + 1 + usize::from(matches!(bytes.get(end + 1), Some(b'+' | b'-')));
+        let exp_end = digits_from(exp_start);
+        if exp_end > exp_start {
+            end = exp_end;
+            is_real = true;
+        }
+    }
+
+    let prefix = &s[..end];
+    if !is_real {
+        if let Ok(i) = prefix.parse() {
+            return (Value::Integer(i), skipped + end);
+        }
+    }
+    (Value::Real(prefix.parse().unwrap_or(0.0)), skipped + end)
+}
+
+/// Significant digits SQLite shows for a REAL
+const REAL_DIGITS: i32 = 15;
+
+/// Formats a real the way SQLite's `%!.15g` does
+///
+/// The value is rounded to 15 significant digits and trailing zeros are dropped,
+/// but a decimal point and at least one digit after it are always kept, so reals
+/// never look like integers: 3.0, 0.1, 1.0e+20, 1.5e-07.
+fn format_real(r: f64) -> String {
+    if r.is_infinite() {
+        return if r < 0.0 { "-Inf" } else { "Inf" }.to_string();
+    }
+    // SQLite shows negative zero without its sign
+    if r == 0.0 {
+        return "0.0".to_string();
+    }
+
+    // Rounding to 15 digits may carry into the exponent, so read it back from
+    // the rounded form rather than computing log10
+    let scientific = format!("{:.*e}", (REAL_DIGITS - 1) as usize, r);
+    let (mantissa, exponent) = scientific.split_once('e').unwrap_or((&scientific, "0"));
+    let exponent: i32 = exponent.parse().unwrap_or(0);
+
+    if !(-4..REAL_DIGITS).contains(&exponent) {
+        let sign = if exponent < 0 { '-' } else { '+' };
+        format!(
+            "{}e{}{:02}",
+            trim_fraction(mantissa),
+            sign,
+            exponent.unsigned_abs()
+        )
+    } else {
+        let decimals = (REAL_DIGITS - 1 - exponent) as usize;
+        trim_fraction(&format!("{:.*}", decimals, r))
+    }
+}
+
+/// Drops trailing zeros after the decimal point, keeping at least one digit
+fn trim_fraction(digits: &str) -> String {
+    match digits.split_once('.') {
+        Some((whole, fraction)) => {
+            let fraction = fraction.trim_end_matches('0');
+            let fraction = if fraction.is_empty() { "0" } else { fraction };
+            format!("{}.{}", whole, fraction)
+        }
+        None => format!("{}.0", digits),
+    }
+}
+
+/// Shows a value for logs and debugging, with NULL spelled out; how results
+/// are printed is up to the program printing them
+impl Display for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Value::Null => write!(f, "NULL"),
+            Value::Integer(i) => write!(f, "{}", i),
+            Value::Real(r) => write!(f, "{}", format_real(*r)),
+            Value::Text(s) => write!(f, "{}", s),
+            Value::Blob(b) => write!(f, "{}", String::from_utf8_lossy(b)),
+        }
+    }
+}
@@ -1,35 +1,137 @@
+//! SQLite Variable-Length Integers
+//!
+//! Varints store 64-bit unsigned integers in 1 to 9 bytes, big-endian. Each of
+//! the first eight bytes carries seven bits, with its high bit set if another
+//! byte follows; a ninth byte, if present, carries all eight bits. Signed
+//! values such as rowids are stored as their two's-complement bits.
+//!
+//! | Bytes | Largest value |
+//! |-------|---------------|
+//! | 1 | 0x7f |
+//! | 2 | 0x3fff |
+//! | n <= 8 | 2^(7n) - 1 |
+//! | 9 | 0xffff_ffff_ffff_ffff |
+
+use super::checked;
 use anyhow::Result;
 
-/// Utility functions for handling SQLite variable-length integers (varints)
-pub trait Varint {
-    /// Read a varint from a byte slice
-    fn read_varint(&self, bytes: &[u8]) -> Result<u64>;
+/// Longest encoding of a varint in bytes
+pub const MAX_VARINT_LEN: usize = 9;
 
-    /// Get the size of a varint in bytes
-    fn varint_size(&self, bytes: &[u8]) -> usize;
+/// Decodes a big-endian varint at `offset`, returning its value and length in bytes
+///
+/// Each of the first eight bytes contributes its low seven bits, most significant
+/// group first; a ninth byte, if reached, contributes all eight bits.
+pub fn decode_varint(data: &[u8], offset: usize) -> Result<(u64, usize)> {
+    let mut value = 0u64;
+    for i in 0..8 {
+        let byte = checked::byte(data, offset + i)?;
+        value = value << 7 | (byte & 0x7f) as u64;
+        if byte & 0x80 == 0 {
+            return Ok((value, i + 1));
+        }
+    }
+    let byte = checked::byte(data, offset + 8)?;
+    Ok((value << 8 | byte as u64, MAX_VARINT_LEN))
 }
 
-impl Varint for [u8] {
-    fn read_varint(&self, bytes: &[u8]) -> Result<u64> {
-        let mut result = 0u64;
-        let mut shift = 0;
-
-        for &byte in bytes.iter() {
-            result |= ((byte & 0x7f) as u64) << shift;
-            if byte & 0x80 == 0 {
-                break;
-            }
-            shift += 7;
+/// Appends `value` to `out` as a big-endian varint, the inverse of `decode_varint`
+pub fn encode_varint(value: u64, out: &mut Vec<u8>) {
+    // Values needing more than 56 bits use the nine-byte form, whose last byte
+    // carries eight bits
+    if value >> 56 != 0 {
+        let high = value >> 8;
+        for i in (0..8).rev() {
+            out.push((high >> (7 * i)) as u8 & 0x7f | 0x80);
         }
+        out.push(value as u8);
+        return;
+    }
+
+    for i in (0..varint_len(value)).rev() {
+        let byte = (value >> (7 * i)) as u8 & 0x7f;
+        out.push(if i > 0 { byte | 0x80 } else { byte });
+    }
+}
+
+/// Returns the number of bytes `encode_varint` uses for `value`
+pub fn varint_len(value: u64) -> usize {
+    if value >> 56 != 0 {
+        return MAX_VARINT_LEN;
+    }
+    (1..=8).find(|&n| value >> (7 * n) == 0).unwrap_or(8)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        Ok(result)
+    fn encode(value: u64) -> Vec<u8> {
+        let mut out = Vec::new();
+        encode_varint(value, &mut out);
+        out
     }
 
-    fn varint_size(&self, bytes: &[u8]) -> usize {
-        let mut size = 0;
-        while size < bytes.len() && bytes[size] & 0x80 != 0 {
-            size += 1;
+    fn round_trip(value: u64) -> usize {
+        let bytes = encode(value);
+        assert_eq!(bytes.len(), varint_len(value), "length of {:#x}", value);
+        assert_eq!(decode_varint(&bytes, 0).unwrap(), (value, bytes.len()));
+        bytes.len()
+    }
+
+    #[test]
+    fn every_length_round_trips_at_its_bounds() {
+        for n in 1..=8 {
+            let largest = (1u64 << (7 * n)) - 1;
+            assert_eq!(round_trip(largest), n);
+            assert_eq!(round_trip(largest + 1), n + 1);
+        }
+        assert_eq!(round_trip(1 << 56), 9);
+        assert_eq!(round_trip(u64::MAX), 9);
+    }
+
+    #[test]
+    fn one_byte_ends_at_0x7f() {
+        assert_eq!(encode(0), [0x00]);
+        assert_eq!(encode(0x7f), [0x7f]);
+        assert_eq!(encode(0x80), [0x81, 0x00]);
+        assert_eq!(encode(0x3fff), [0xff, 0x7f]);
+        assert_eq!(encode(0x4000), [0x81, 0x80, 0x00]);
+    }
+
+    #[test]
+    fn signed_extremes_take_nine_bytes() {
+        for value in [i64::MIN, i64::MAX, -1] {
+            assert_eq!(round_trip(value as u64), 9);
         }
-        size + 1
+        assert_eq!(encode(-1i64 as u64), [0xff; 9]);
+        assert_eq!(
+            encode(i64::MIN as u64),
+            [0xc0, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x00]
+        );
+        assert_eq!(
+            encode(i64::MAX as u64),
+            [0xbf, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]
+        );
+    }
+
+    #[test]
+    fn ninth_byte_carries_eight_bits() {
+        let bytes = [0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x81, 0xff];
+        assert_eq!(decode_varint(&bytes, 0).unwrap(), (0x1ff, 9));
+
+        let value = 1 << 56 | 0xff;
+        let bytes = encode(value);
+        assert_eq!(bytes[8], 0xff);
+        assert_eq!(round_trip(value), 9);
+    }
+
+    #[test]
+    fn decodes_at_an_offset_and_rejects_truncated_input() {
+        let bytes = [0x00, 0x81, 0x00, 0x7f];
+        assert_eq!(decode_varint(&bytes, 1).unwrap(), (0x80, 2));
+        assert_eq!(decode_varint(&bytes, 3).unwrap(), (0x7f, 1));
+        assert!(decode_varint(&[0x81], 0).is_err());
+        assert!(decode_varint(&[0xff; 8], 0).is_err());
     }
 }
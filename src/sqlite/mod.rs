@@ -2,3 +2,5 @@ pub mod core;
 pub mod parser;
 pub mod query;
 pub mod storage;
+#[cfg(test)]
+pub(crate) mod testing;
@@ -1,19 +1,387 @@
+use crate::sqlite::core::value::Value;
+use crate::sqlite::parser::statement::Statement;
+use crate::sqlite::query::functions;
+
 /// Represents a SQL function call
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct FunctionCall {
     /// Name of the function (e.g., "COUNT")
     pub name: String,
     /// Arguments to the function
     pub args: Vec<Expression>,
+    /// `FILTER (WHERE ...)` condition limiting the rows an aggregate sees
+    pub filter: Option<Box<Expression>>,
+}
+
+/// Binary operators
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryOperator {
+    /// `=` or `==`
+    Eq,
+    /// `!=` or `<>`
+    NotEq,
+    Lt,
+    LtEq,
+    Gt,
+    GtEq,
+    /// `IS`, equality that treats two NULLs as equal
+    Is,
+    /// `IS NOT`
+    IsNot,
+    BitAnd,
+    BitOr,
+    ShiftLeft,
+    ShiftRight,
+    Add,
+    Subtract,
+    /// `*`, tokenized as [`Token::Asterisk`](super::token::Token::Asterisk)
+    Multiply,
+    Divide,
+    Modulo,
+    /// `||` string concatenation
+    Concat,
+    And,
+    Or,
+}
+
+impl BinaryOperator {
+    /// Precedence of `=`, which IN, IS and BETWEEN share
+    pub const EQUALITY_PRECEDENCE: u8 = 4;
+
+    /// Precedence of prefix NOT, between AND and the comparisons
+    pub const NOT_PRECEDENCE: u8 = 3;
+
+    /// Maps an operator token to its operator
+    pub fn from_symbol(symbol: &str) -> Option<Self> {
+        match symbol {
+            "=" | "==" => Some(BinaryOperator::Eq),
+            "!=" | "<>" => Some(BinaryOperator::NotEq),
+            "<" => Some(BinaryOperator::Lt),
+            "<=" => Some(BinaryOperator::LtEq),
+            ">" => Some(BinaryOperator::Gt),
+            ">=" => Some(BinaryOperator::GtEq),
+            "&" => Some(BinaryOperator::BitAnd),
+            "|" => Some(BinaryOperator::BitOr),
+            "<<" => Some(BinaryOperator::ShiftLeft),
+            ">>" => Some(BinaryOperator::ShiftRight),
+            "+" => Some(BinaryOperator::Add),
+            "-" => Some(BinaryOperator::Subtract),
+            "/" => Some(BinaryOperator::Divide),
+            "%" => Some(BinaryOperator::Modulo),
+            "||" => Some(BinaryOperator::Concat),
+            _ => None,
+        }
+    }
+
+    /// Maps an operator keyword to its operator
+    pub fn from_keyword(keyword: &str) -> Option<Self> {
+        match keyword.to_uppercase().as_str() {
+            "AND" => Some(BinaryOperator::And),
+            "OR" => Some(BinaryOperator::Or),
+            _ => None,
+        }
+    }
+
+    /// Binding strength, following SQLite's precedence table; higher binds tighter
+    ///
+    /// All binary operators are left-associative.
+    pub fn precedence(self) -> u8 {
+        match self {
+            BinaryOperator::Or => 1,
+            BinaryOperator::And => 2,
+            BinaryOperator::Eq
+            | BinaryOperator::NotEq
+            | BinaryOperator::Is
+            | BinaryOperator::IsNot => Self::EQUALITY_PRECEDENCE,
+            BinaryOperator::Lt
+            | BinaryOperator::LtEq
+            | BinaryOperator::Gt
+            | BinaryOperator::GtEq => 5,
+            BinaryOperator::BitAnd
+            | BinaryOperator::BitOr
+            | BinaryOperator::ShiftLeft
+            | BinaryOperator::ShiftRight => 7,
+            BinaryOperator::Add | BinaryOperator::Subtract => 8,
+            BinaryOperator::Multiply | BinaryOperator::Divide | BinaryOperator::Modulo => 9,
+            BinaryOperator::Concat => 10,
+        }
+    }
+
+    /// Returns true for operators that compare their operands
+    pub fn is_comparison(self) -> bool {
+        matches!(
+            self,
+            BinaryOperator::Eq
+                | BinaryOperator::NotEq
+                | BinaryOperator::Lt
+                | BinaryOperator::LtEq
+                | BinaryOperator::Gt
+                | BinaryOperator::GtEq
+                | BinaryOperator::Is
+                | BinaryOperator::IsNot
+        )
+    }
+}
+
+/// Prefix operators
+///
+/// `~` and `-` bind tighter than any binary operator; NOT binds more loosely
+/// than the comparisons, so `NOT a = b` means `NOT (a = b)`. Prefix `+` is a
+/// no-op and is dropped by the parser.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnaryOperator {
+    /// `~` bitwise complement
+    BitNot,
+    /// `-` arithmetic negation
+    Negate,
+    /// Logical NOT
+    Not,
 }
 
 /// Represents different types of SQL expressions
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum Expression {
     /// A function call like COUNT(*)
     Function(FunctionCall),
     /// A wildcard selector *
     Asterisk,
-    /// A column reference
-    Column(String),
+    /// A column reference, optionally qualified by a table name or alias
+    Column { table: Option<String>, name: String },
+    /// A constant value
+    Literal(Value),
+    /// A parameter by its 1-based number, whose value is bound before the
+    /// statement runs; unbound parameters are NULL
+    Parameter(usize),
+    /// A binary operation like `a = 1`
+    Binary {
+        left: Box<Expression>,
+        op: BinaryOperator,
+        right: Box<Expression>,
+    },
+    /// A prefix operation like `~flags`
+    Unary {
+        op: UnaryOperator,
+        expr: Box<Expression>,
+    },
+    /// A row value like `(a, b)`, only valid in comparisons and IN
+    Row(Vec<Expression>),
+    /// `CASE [operand] WHEN ... THEN ... [ELSE ...] END`
+    ///
+    /// Without an operand each WHEN is a condition; with one, each WHEN is a value
+    /// compared to the operand with `=`.
+    Case {
+        operand: Option<Box<Expression>>,
+        branches: Vec<(Expression, Expression)>,
+        else_result: Option<Box<Expression>>,
+    },
+    /// `expr IN (a, b, ...)` or `expr IN (VALUES ...)`, with one list entry per
+    /// value or VALUES row; `NOT IN` parses as NOT applied to this
+    ///
+    /// The entries stay expressions rather than being folded to values, so a
+    /// list of constants can later be turned into one index lookup per entry.
+    InList {
+        expr: Box<Expression>,
+        list: Vec<Expression>,
+    },
+    /// `expr BETWEEN low AND high`, true when `low <= expr <= high`; `NOT BETWEEN`
+    /// parses as NOT applied to this
+    Between {
+        expr: Box<Expression>,
+        low: Box<Expression>,
+        high: Box<Expression>,
+    },
+    /// `expr LIKE pattern [ESCAPE escape]`, matching `%` to any run of
+    /// characters and `_` to any one, ignoring ASCII case; `NOT LIKE` parses
+    /// as NOT applied to this
+    Like {
+        expr: Box<Expression>,
+        pattern: Box<Expression>,
+        escape: Option<Box<Expression>>,
+    },
+    /// `EXISTS (SELECT ...)`, true if the subquery returns any row
+    ///
+    /// The subquery may refer to columns of the query it appears in.
+    Exists(Box<Statement>),
+}
+
+impl Expression {
+    /// Returns true if evaluating the expression twice on the same row gives the same value
+    pub fn is_deterministic(&self) -> bool {
+        match self {
+            Expression::Function(FunctionCall { name, args, filter }) => {
+                functions::is_deterministic(name)
+                    && args.iter().all(Expression::is_deterministic)
+                    && filter
+                        .as_ref()
+                        .map_or(true, |filter| filter.is_deterministic())
+            }
+            Expression::Binary { left, right, .. } => {
+                left.is_deterministic() && right.is_deterministic()
+            }
+            Expression::Unary { expr, .. } => expr.is_deterministic(),
+            Expression::Row(items) => items.iter().all(Expression::is_deterministic),
+            Expression::InList { expr, list } => {
+                expr.is_deterministic() && list.iter().all(Expression::is_deterministic)
+            }
+            Expression::Between { expr, low, high } => {
+                expr.is_deterministic() && low.is_deterministic() && high.is_deterministic()
+            }
+            Expression::Like {
+                expr,
+                pattern,
+                escape,
+            } => {
+                expr.is_deterministic()
+                    && pattern.is_deterministic()
+                    && escape
+                        .as_ref()
+                        .map_or(true, |escape| escape.is_deterministic())
+            }
+            Expression::Exists(subquery) => subquery.is_deterministic(),
+            Expression::Case {
+                operand,
+                branches,
+                else_result,
+            } => {
+                operand
+                    .iter()
+                    .chain(else_result)
+                    .all(|e| e.is_deterministic())
+                    && branches
+                        .iter()
+                        .all(|(when, then)| when.is_deterministic() && then.is_deterministic())
+            }
+            Expression::Asterisk
+            | Expression::Column { .. }
+            | Expression::Literal(_)
+            | Expression::Parameter(_) => true,
+        }
+    }
+
+    /// Calls `visit` on this expression and then on every expression inside it
+    ///
+    /// Subqueries are not entered, since their expressions belong to another
+    /// statement.
+    pub fn walk<'a>(&'a self, visit: &mut impl FnMut(&'a Expression)) {
+        visit(self);
+        match self {
+            Expression::Function(call) => {
+                call.args.iter().for_each(|arg| arg.walk(visit));
+                if let Some(filter) = &call.filter {
+                    filter.walk(visit);
+                }
+            }
+            Expression::Binary { left, right, .. } => {
+                left.walk(visit);
+                right.walk(visit);
+            }
+            Expression::Unary { expr, .. } => expr.walk(visit),
+            Expression::Row(items) => items.iter().for_each(|item| item.walk(visit)),
+            Expression::InList { expr, list } => {
+                expr.walk(visit);
+                list.iter().for_each(|item| item.walk(visit));
+            }
+            Expression::Between { expr, low, high } => {
+                expr.walk(visit);
+                low.walk(visit);
+                high.walk(visit);
+            }
+            Expression::Like {
+                expr,
+                pattern,
+                escape,
+            } => {
+                expr.walk(visit);
+                pattern.walk(visit);
+                if let Some(escape) = escape {
+                    escape.walk(visit);
+                }
+            }
+            Expression::Case {
+                operand,
+                branches,
+                else_result,
+            } => {
+                if let Some(operand) = operand {
+                    operand.walk(visit);
+                }
+                for (when, then) in branches {
+                    when.walk(visit);
+                    then.walk(visit);
+                }
+                if let Some(else_result) = else_result {
+                    else_result.walk(visit);
+                }
+            }
+            Expression::Asterisk
+            | Expression::Column { .. }
+            | Expression::Literal(_)
+            | Expression::Parameter(_)
+            | Expression::Exists(_) => {}
+        }
+    }
+
+    /// Calls `visit` on this expression and then on every expression inside
+    /// it, allowing each to be changed; children are visited as they are after
+    /// `visit` returns
+    ///
+    /// Like [`walk`](Self::walk), this does not enter subqueries.
+    pub fn walk_mut(&mut self, visit: &mut impl FnMut(&mut Expression)) {
+        visit(self);
+        match self {
+            Expression::Function(call) => {
+                call.args.iter_mut().for_each(|arg| arg.walk_mut(visit));
+                if let Some(filter) = &mut call.filter {
+                    filter.walk_mut(visit);
+                }
+            }
+            Expression::Binary { left, right, .. } => {
+                left.walk_mut(visit);
+                right.walk_mut(visit);
+            }
+            Expression::Unary { expr, .. } => expr.walk_mut(visit),
+            Expression::Row(items) => items.iter_mut().for_each(|item| item.walk_mut(visit)),
+            Expression::InList { expr, list } => {
+                expr.walk_mut(visit);
+                list.iter_mut().for_each(|item| item.walk_mut(visit));
+            }
+            Expression::Between { expr, low, high } => {
+                expr.walk_mut(visit);
+                low.walk_mut(visit);
+                high.walk_mut(visit);
+            }
+            Expression::Like {
+                expr,
+                pattern,
+                escape,
+            } => {
+                expr.walk_mut(visit);
+                pattern.walk_mut(visit);
+                if let Some(escape) = escape {
+                    escape.walk_mut(visit);
+                }
+            }
+            Expression::Case {
+                operand,
+                branches,
+                else_result,
+            } => {
+                if let Some(operand) = operand {
+                    operand.walk_mut(visit);
+                }
+                for (when, then) in branches {
+                    when.walk_mut(visit);
+                    then.walk_mut(visit);
+                }
+                if let Some(else_result) = else_result {
+                    else_result.walk_mut(visit);
+                }
+            }
+            Expression::Asterisk
+            | Expression::Column { .. }
+            | Expression::Literal(_)
+            | Expression::Parameter(_)
+            | Expression::Exists(_) => {}
+        }
+    }
 }
@@ -1,3 +1,4 @@
 pub mod expression;
+pub mod keyword;
 pub mod statement;
 pub mod token;
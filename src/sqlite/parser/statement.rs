@@ -5,30 +5,413 @@
 //! 1. Lexical analysis (tokenization)
 //! 2. Parsing tokens into a Statement AST
 //!
+//! `parse` accepts a single statement; `parse_all` accepts a script of
+//! statements separated by semicolons. [`Statement`] is a SELECT; statements
+//! of every kind are parsed with [`Command::parse`] and
+//! [`Command::parse_all`], which tell them apart by their first keyword.
+//!
+//! Expressions are parsed by one recursive-descent parser with precedence
+//! climbing, wherever they appear: result columns, ON, WHERE, ORDER BY, function
+//! arguments and subqueries. Function names are ordinary identifiers; a name
+//! followed by `(` is a call. From loosest to tightest binding:
+//!
+//! 1. `OR`
+//! 2. `AND`
+//! 3. prefix `NOT`
+//! 4. `=`, `!=`, `IS [NOT]`, `[NOT] IN`, `[NOT] BETWEEN`, `[NOT] LIKE`, and the postfix
+//!    `ISNULL`, `NOTNULL` and `NOT NULL`
+//! 5. `<`, `<=`, `>`, `>=`
+//! 6. `&`, `|`, `<<`, `>>`
+//! 7. `+`, `-`
+//! 8. `*`, `/`, `%`
+//! 9. `||`
+//! 10. prefix `~`, `-` and `+`, then literals, parameters, columns, calls, CASE
+//!     and parenthesized groups
+//!
 //! # Example
 //! ```
 //! let sql = "SELECT COUNT(*) FROM apples";
 //! let stmt = Statement::parse(sql)?;
 //! ```
 
-use crate::sqlite::parser::expression::{Expression, FunctionCall};
+use crate::sqlite::core::value::Value;
+use crate::sqlite::parser::expression::{BinaryOperator, Expression, FunctionCall, UnaryOperator};
+use crate::sqlite::parser::keyword;
 use crate::sqlite::parser::token::Token;
 use anyhow::{anyhow, Result};
+use std::iter::Peekable;
+use std::str::Chars;
+use std::vec::IntoIter;
+
+/// Largest parameter number a statement may use, as in SQLite
+pub const MAX_PARAMETERS: usize = 32766;
 
 /// Represents a parsed SQL statement
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Statement {
+    /// True for `SELECT DISTINCT`, which drops duplicate result rows
+    pub distinct: bool,
     /// The expressions to select
-    pub selections: Vec<Expression>,
-    /// The table name to apply the selections to
-    pub from_table: String,
+    pub selections: Vec<ResultColumn>,
+    /// The table name to apply the selections to; None for a statement without
+    /// FROM, which evaluates its selections once
+    pub from_table: Option<String>,
+    /// Name given to the table with `FROM table AS alias`; when present,
+    /// qualified column references must use it instead of the table name
+    pub table_alias: Option<String>,
+    /// Tables joined to `from_table`, in the order they are written
+    pub joins: Vec<Join>,
+    /// WHERE condition; rows for which it is false or NULL are skipped
+    pub where_clause: Option<Expression>,
+    /// GROUP BY terms; rows with equal values for all of them form one group,
+    /// which produces one result row. An integer literal refers to a result
+    /// column by position
+    pub group_by: Vec<Expression>,
+    /// HAVING condition, evaluated once per group after aggregation
+    pub having: Option<Expression>,
+    /// ORDER BY terms, most significant first
+    pub order_by: Vec<OrderingTerm>,
+    /// LIMIT clause, applied after sorting
+    pub limit: Option<Limit>,
+    /// Name of each parameter, indexed by its number less one; None for
+    /// numbers only plain `?` uses, or none at all. Subqueries share the
+    /// numbering of the statement they appear in, which holds every name
+    pub parameters: Vec<Option<String>>,
+}
+
+/// A parsed SQL statement of any kind
+#[derive(Debug, Clone)]
+pub enum Command {
+    Select(Statement),
+    Insert(Insert),
+    Update(Update),
+    Delete(Delete),
+    CreateTable(CreateTable),
+}
+
+/// `INSERT [OR conflict] INTO table [(column, ...)] VALUES (expr, ...), ... [upsert]`
+/// or `INSERT ... SELECT ...`, or `REPLACE INTO ...`
+#[derive(Debug, Clone)]
+pub struct Insert {
+    /// The table the rows are added to
+    pub table: String,
+    /// The columns given values, in the order of `values`; empty when every
+    /// column is, in table order
+    pub columns: Vec<String>,
+    /// The rows to add
+    pub source: InsertSource,
+    /// What to do when a row would duplicate an existing one
+    pub on_conflict: ConflictResolution,
+    /// The ON CONFLICT clause, which takes precedence over `on_conflict` for
+    /// the constraint it names
+    pub upsert: Option<Upsert>,
+    /// Name of each parameter, numbered as in [`Statement::parameters`]
+    pub parameters: Vec<Option<String>>,
+}
+
+/// Where the rows of an INSERT come from
+#[derive(Debug, Clone)]
+pub enum InsertSource {
+    /// `VALUES (expr, ...), ...`: constant expressions, the same number for
+    /// every row
+    Values(Vec<Vec<Expression>>),
+    /// `SELECT ...`: one row per result row
+    Select(Box<Statement>),
+}
+
+/// How an INSERT resolves a row that would break a PRIMARY KEY or UNIQUE
+/// constraint, chosen with `INSERT OR ...`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ConflictResolution {
+    /// Fail the statement, leaving the file untouched; the default
+    #[default]
+    Abort,
+    /// Skip the new row, keeping the existing one
+    Ignore,
+    /// Delete the existing row, then insert the new one; `REPLACE INTO` is
+    /// short for `INSERT OR REPLACE INTO`
+    Replace,
+}
+
+/// `ON CONFLICT [(column, ...)] DO NOTHING | DO UPDATE SET ... [WHERE ...]`
+#[derive(Debug, Clone)]
+pub struct Upsert {
+    /// The columns of the PRIMARY KEY or UNIQUE constraint the clause handles;
+    /// empty for any constraint
+    pub target: Vec<String>,
+    pub action: UpsertAction,
+}
+
+/// What an ON CONFLICT clause does with the existing row
+#[derive(Debug, Clone)]
+pub enum UpsertAction {
+    /// Skip the new row
+    Nothing,
+    /// Update the existing row instead, as an UPDATE of that one row would;
+    /// the new row's values are available as `excluded.column`
+    Update {
+        assignments: Vec<(String, Expression)>,
+        /// Condition on the existing row; the new row is skipped if it is
+        /// false or NULL
+        where_clause: Option<Expression>,
+    },
+}
+
+/// `UPDATE table SET column = expr, ... [WHERE condition]`
+#[derive(Debug, Clone)]
+pub struct Update {
+    /// The table whose rows are changed
+    pub table: String,
+    /// Each column assigned and its new value, in the order written; the
+    /// values are evaluated against the row as it was before the update
+    pub assignments: Vec<(String, Expression)>,
+    /// WHERE condition; every row is updated if there is none
+    pub where_clause: Option<Expression>,
+    /// Name of each parameter, numbered as in [`Statement::parameters`]
+    pub parameters: Vec<Option<String>>,
+}
+
+/// `DELETE FROM table [WHERE condition]`
+#[derive(Debug, Clone)]
+pub struct Delete {
+    /// The table whose rows are deleted
+    pub table: String,
+    /// WHERE condition; every row is deleted if there is none
+    pub where_clause: Option<Expression>,
+    /// Name of each parameter, numbered as in [`Statement::parameters`]
+    pub parameters: Vec<Option<String>>,
+}
+
+/// `CREATE TABLE [IF NOT EXISTS] table (column-def, ...) [WITHOUT ROWID]`
+#[derive(Debug, Clone)]
+pub struct CreateTable {
+    /// The name of the new table
+    pub table: String,
+    /// True with IF NOT EXISTS, which makes an existing table no error
+    pub if_not_exists: bool,
+    /// The statement as recorded in sqlite_schema: `CREATE TABLE` followed by
+    /// the rest as written, without IF NOT EXISTS; spacing is normalized
+    pub sql: String,
+}
+
+/// How a joined table's rows combine with the rows to its left
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JoinKind {
+    /// `JOIN`, `INNER JOIN`, `CROSS JOIN` or a comma: only matching combinations
+    Inner,
+    /// `LEFT [OUTER] JOIN`: as inner, plus each left row that matched nothing,
+    /// with NULL for the joined table's columns
+    Left,
+}
+
+/// One table joined in the FROM clause
+#[derive(Debug, Clone)]
+pub struct Join {
+    pub kind: JoinKind,
+    pub table: String,
+    /// Name given with `JOIN table AS alias`
+    pub alias: Option<String>,
+    /// ON condition; None joins every combination of rows
+    pub constraint: Option<Expression>,
+}
+
+/// One selected expression
+#[derive(Debug, Clone)]
+pub struct ResultColumn {
+    pub expr: Expression,
+    /// Name given with `expr AS alias`, which ORDER BY terms can refer to
+    pub alias: Option<String>,
+    /// The expression as written, which names the column when it has no alias
+    /// and is not a column reference; spacing is normalized and identifiers
+    /// lose their quotes
+    pub text: String,
+}
+
+/// One term of an ORDER BY clause
+#[derive(Debug, Clone)]
+pub struct OrderingTerm {
+    /// Sort key; an integer literal refers to a result column by position
+    pub expr: Expression,
+    /// True for DESC
+    pub descending: bool,
+}
+
+/// A LIMIT clause: `LIMIT count [OFFSET offset]` or `LIMIT offset, count`
+///
+/// Both are constant expressions; a negative count means no limit.
+#[derive(Debug, Clone)]
+pub struct Limit {
+    pub count: Expression,
+    /// Number of rows to skip before the first one returned
+    pub offset: Option<Expression>,
+}
+
+impl Command {
+    /// Parses a SQL string holding one statement, optionally followed by semicolons
+    pub fn parse(sql: &str) -> Result<Self> {
+        let mut commands = Self::parse_all(sql)?;
+        match commands.len() {
+            1 => Ok(commands.remove(0)),
+            0 => Err(anyhow!("incomplete input")),
+            n => Err(anyhow!("expected one statement, found {}", n)),
+        }
+    }
+
+    /// Parses a script of statements separated by semicolons
+    pub fn parse_all(sql: &str) -> Result<Vec<Self>> {
+        Statement::split_statements(sql)?
+            .into_iter()
+            .map(Self::parse_tokens)
+            .collect()
+    }
+
+    /// Parses the tokens of one statement, choosing the parser by the first
+    /// keyword
+    fn parse_tokens(mut tokens: Vec<Token>) -> Result<Self> {
+        let first = match tokens.first() {
+            Some(Token::Keyword(k)) => k.to_ascii_uppercase(),
+            _ => String::new(),
+        };
+        if !matches!(
+            first.as_str(),
+            "INSERT" | "REPLACE" | "UPDATE" | "DELETE" | "CREATE"
+        ) {
+            return Statement::parse_tokens(tokens).map(Command::Select);
+        }
+        if first == "CREATE" {
+            if tokens
+                .iter()
+                .any(|token| matches!(token, Token::Parameter { .. }))
+            {
+                return Err(anyhow!("parameters are not allowed in CREATE TABLE"));
+            }
+            return Statement::parse_create_table(tokens).map(Command::CreateTable);
+        }
+        let parameters = Statement::number_parameters(&mut tokens)?;
+        let mut iter = tokens.into_iter().peekable();
+        let command = match first.as_str() {
+            "INSERT" | "REPLACE" => {
+                let mut insert = Statement::parse_insert(&mut iter)?;
+                insert.parameters = parameters;
+                Command::Insert(insert)
+            }
+            "UPDATE" => {
+                let mut update = Statement::parse_update(&mut iter)?;
+                update.parameters = parameters;
+                Command::Update(update)
+            }
+            _ => {
+                let mut delete = Statement::parse_delete(&mut iter)?;
+                delete.parameters = parameters;
+                Command::Delete(delete)
+            }
+        };
+        if let Some(token) = iter.next() {
+            return Err(anyhow!("Unexpected token: {:?}", token));
+        }
+        Ok(command)
+    }
+}
+
+impl Expression {
+    /// Parses an expression written on its own, such as the value of a
+    /// column's DEFAULT clause
+    pub fn parse(sql: &str) -> Result<Self> {
+        let tokens = Statement::tokenize(sql)?;
+        if tokens
+            .iter()
+            .any(|token| matches!(token, Token::Parameter { .. }))
+        {
+            return Err(anyhow!("parameters are not allowed here"));
+        }
+        let mut iter = tokens.into_iter().peekable();
+        let expr = Statement::parse_expression(&mut iter)?;
+        if let Some(token) = iter.next() {
+            return Err(anyhow!("Unexpected token: {:?}", token));
+        }
+        Ok(expr)
+    }
 }
 
 impl Statement {
-    /// Parses a SQL string into a Statement struct
+    /// Returns true if running the statement twice on the same data gives the same result
+    pub fn is_deterministic(&self) -> bool {
+        self.expressions().all(Expression::is_deterministic)
+    }
+
+    /// Returns the statement's top-level expressions: result columns, ON
+    /// conditions, the WHERE clause, GROUP BY terms, the HAVING clause, ORDER BY
+    /// terms and the LIMIT clause
+    pub fn expressions(&self) -> impl Iterator<Item = &Expression> {
+        self.selections
+            .iter()
+            .map(|column| &column.expr)
+            .chain(
+                self.joins
+                    .iter()
+                    .filter_map(|join| join.constraint.as_ref()),
+            )
+            .chain(self.where_clause.iter())
+            .chain(self.group_by.iter())
+            .chain(self.having.iter())
+            .chain(self.order_by.iter().map(|term| &term.expr))
+            .chain(
+                self.limit
+                    .iter()
+                    .flat_map(|limit| std::iter::once(&limit.count).chain(&limit.offset)),
+            )
+    }
+
+    /// Returns the statement's top-level expressions, as
+    /// [`expressions`](Self::expressions) does, for changing in place
+    pub fn expressions_mut(&mut self) -> impl Iterator<Item = &mut Expression> {
+        self.selections
+            .iter_mut()
+            .map(|column| &mut column.expr)
+            .chain(
+                self.joins
+                    .iter_mut()
+                    .filter_map(|join| join.constraint.as_mut()),
+            )
+            .chain(self.where_clause.iter_mut())
+            .chain(self.group_by.iter_mut())
+            .chain(self.having.iter_mut())
+            .chain(self.order_by.iter_mut().map(|term| &mut term.expr))
+            .chain(
+                self.limit
+                    .iter_mut()
+                    .flat_map(|limit| std::iter::once(&mut limit.count).chain(&mut limit.offset)),
+            )
+    }
+
+    /// Parses a SQL string holding one statement, optionally followed by semicolons
     pub fn parse(sql: &str) -> Result<Self> {
-        let tokens = Self::tokenize(sql)?;
-        Self::parse_tokens(tokens)
+        let mut statements = Self::parse_all(sql)?;
+        match statements.len() {
+            1 => Ok(statements.remove(0)),
+            0 => Err(anyhow!("incomplete input")),
+            n => Err(anyhow!("expected one statement, found {}", n)),
+        }
+    }
+
+    /// Parses a script of statements separated by semicolons
+    ///
+    /// Splitting happens after tokenizing, so semicolons inside string literals
+    /// and comments do not end a statement. Empty statements are skipped.
+    pub fn parse_all(sql: &str) -> Result<Vec<Self>> {
+        Self::split_statements(sql)?
+            .into_iter()
+            .map(Self::parse_tokens)
+            .collect()
+    }
+
+    /// Splits a script into the tokens of each statement, skipping empty ones
+    fn split_statements(sql: &str) -> Result<Vec<Vec<Token>>> {
+        Ok(Self::tokenize(sql)?
+            .split(|token| *token == Token::Symbol(';'))
+            .filter(|tokens| !tokens.is_empty())
+            .map(|tokens| tokens.to_vec())
+            .collect())
     }
 
     /// Converts a SQL string into a vector of tokens
@@ -43,11 +426,34 @@ impl Statement {
                     chars.next();
                 }
 
+                // BLOB literals: X'CAFE'
+                'x' | 'X' if Self::next_char_is(&chars, '\'') => {
+                    chars.next();
+                    chars.next();
+                    let mut hex = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('\'') => break,
+                            Some(c) => hex.push(c),
+                            None => return Err(anyhow!("unrecognized token: \"{}'{}\"", c, hex)),
+                        }
+                    }
+                    let bytes = (0..hex.len())
+                        .step_by(2)
+                        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+                        .collect::<Option<Vec<u8>>>()
+                        .filter(|_| hex.chars().all(|c| c.is_ascii_hexdigit()));
+                    match bytes {
+                        Some(bytes) => tokens.push(Token::Blob(bytes)),
+                        None => return Err(anyhow!("unrecognized token: \"{}'{}'\"", c, hex)),
+                    }
+                }
+
                 // Handle identifiers and keywords
-                c if c.is_alphabetic() => {
+                c if c.is_alphabetic() || c == '_' => {
                     let mut word = String::new();
                     while let Some(&c) = chars.peek() {
-                        if c.is_alphanumeric() {
+                        if c.is_alphanumeric() || c == '_' {
                             word.push(c);
                             chars.next();
                         } else {
@@ -55,24 +461,158 @@ impl Statement {
                         }
                     }
 
-                    let token = match word.to_uppercase().as_str() {
-                        "SELECT" | "FROM" => Token::Keyword(word),
-                        "COUNT" => Token::Function(word),
-                        _ => Token::Identifier(word),
+                    let token = if keyword::is_keyword(&word) {
+                        Token::Keyword(word)
+                    } else {
+                        Token::Identifier(word)
                     };
                     tokens.push(token);
                 }
 
+                // Handle numeric literals: 42, 1.5, .5, 1e10, 2.5E-3, 0x1F
+                c if c.is_ascii_digit() || (c == '.' && Self::digit_follows(&chars)) => {
+                    let mut literal = String::new();
+                    Self::take_digits(&mut chars, &mut literal, 10);
+                    if literal == "0" && matches!(chars.peek(), Some('x' | 'X')) {
+                        literal.extend(chars.next());
+                        Self::take_digits(&mut chars, &mut literal, 16);
+                    } else {
+                        if chars.peek() == Some(&'.') {
+                            literal.extend(chars.next());
+                            Self::take_digits(&mut chars, &mut literal, 10);
+                        }
+                        if matches!(chars.peek(), Some('e' | 'E')) {
+                            literal.extend(chars.next());
+                            if matches!(chars.peek(), Some('+' | '-')) {
+                                literal.extend(chars.next());
+                            }
+                            if !Self::take_digits(&mut chars, &mut literal, 10) {
+                                return Err(anyhow!("unrecognized token: \"{}\"", literal));
+                            }
+                        }
+                    }
+                    // A number running into a word, like 12abc, is one bad token
+                    if chars
+                        .peek()
+                        .map_or(false, |&c| c.is_alphanumeric() || c == '_')
+                    {
+                        while let Some(c) = chars.next_if(|&c| c.is_alphanumeric() || c == '_') {
+                            literal.push(c);
+                        }
+                        return Err(anyhow!("unrecognized token: \"{}\"", literal));
+                    }
+                    tokens.push(Token::Number(literal));
+                }
+
+                // Handle parameters: ?, ?NNN, :name, @name and $name
+                '?' | ':' | '@' | '$' => {
+                    chars.next();
+                    let mut name = c.to_string();
+                    if c == '?' {
+                        Self::take_digits(&mut chars, &mut name, 10);
+                    } else {
+                        while let Some(c) = chars.next_if(|&c| c.is_alphanumeric() || c == '_') {
+                            name.push(c);
+                        }
+                        if name.len() == 1 {
+                            return Err(anyhow!("unrecognized token: \"{}\"", name));
+                        }
+                    }
+                    tokens.push(Token::Parameter { name, index: 0 });
+                }
+
                 // Handle special characters
                 '*' => {
                     tokens.push(Token::Asterisk);
                     chars.next();
                 }
-                '(' | ')' => {
+                '(' | ')' | ',' | '.' | ';' => {
                     tokens.push(Token::Symbol(c));
                     chars.next();
                 }
 
+                // Handle quoted identifiers: "name", [name] and `name`, where a
+                // doubled closing quote stands for one; quoting makes a keyword
+                // usable as a name
+                '"' | '[' | '`' => {
+                    chars.next();
+                    let close = if c == '[' { ']' } else { c };
+                    let mut name = String::new();
+                    loop {
+                        match chars.next() {
+                            Some(q)
+                                if q == close && close != ']' && chars.peek() == Some(&close) =>
+                            {
+                                name.push(close);
+                                chars.next();
+                            }
+                            Some(q) if q == close => break,
+                            Some(c) => name.push(c),
+                            None => return Err(anyhow!("unrecognized token: \"{}{}\"", c, name)),
+                        }
+                    }
+                    tokens.push(Token::Identifier(name));
+                }
+
+                // Handle string literals, where '' stands for one quote
+                '\'' => {
+                    chars.next();
+                    let mut text = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('\'') if chars.peek() == Some(&'\'') => {
+                                text.push('\'');
+                                chars.next();
+                            }
+                            Some('\'') => break,
+                            Some(c) => text.push(c),
+                            None => return Err(anyhow!("Unterminated string literal")),
+                        }
+                    }
+                    tokens.push(Token::String(text));
+                }
+
+                // Skip `-- ...` comments to the end of the line
+                '-' if Self::next_char_is(&chars, '-') => {
+                    while chars.next_if(|&c| c != '\n').is_some() {}
+                }
+
+                // Skip `/* ... */` comments; an unterminated one runs to the end
+                '/' if Self::next_char_is(&chars, '*') => {
+                    chars.next();
+                    chars.next();
+                    let mut previous = ' ';
+                    for c in chars.by_ref() {
+                        if previous == '*' && c == '/' {
+                            break;
+                        }
+                        previous = c;
+                    }
+                }
+
+                // Handle operators, preferring the longest match
+                '=' | '<' | '>' | '!' | '|' | '&' | '%' | '~' | '+' | '-' | '/' => {
+                    chars.next();
+                    let mut op = c.to_string();
+                    if let Some(&next) = chars.peek() {
+                        if matches!(
+                            (c, next),
+                            ('=', '=')
+                                | ('<', '=' | '>' | '<')
+                                | ('>', '=' | '>')
+                                | ('!', '=')
+                                | ('|', '|')
+                        ) {
+                            op.push(next);
+                            chars.next();
+                        }
+                    }
+                    if op == "!" {
+                        return Err(anyhow!("Unexpected character: !"));
+                    }
+                    tokens.push(Token::Operator(op));
+                }
+
                 _ => return Err(anyhow!("Unexpected character: {}", c)),
             }
         }
@@ -80,9 +620,427 @@ impl Statement {
         Ok(tokens)
     }
 
+    /// Returns true if the character after the next one is a digit
+    fn digit_follows(chars: &Peekable<Chars>) -> bool {
+        let mut ahead = chars.clone();
+        ahead.next();
+        ahead.next().map_or(false, |c| c.is_ascii_digit())
+    }
+
+    /// Returns true if the character after the next one is `expected`
+    fn next_char_is(chars: &Peekable<Chars>, expected: char) -> bool {
+        let mut ahead = chars.clone();
+        ahead.next();
+        ahead.next() == Some(expected)
+    }
+
+    /// Moves digits in the given radix from `chars` to `literal`, returning
+    /// false if there were none
+    fn take_digits(chars: &mut Peekable<Chars>, literal: &mut String, radix: u32) -> bool {
+        let start = literal.len();
+        while let Some(c) = chars.next_if(|c| c.is_digit(radix)) {
+            literal.push(c);
+        }
+        literal.len() > start
+    }
+
     /// Parses a vector of tokens into a Statement struct
-    fn parse_tokens(tokens: Vec<Token>) -> Result<Self> {
+    fn parse_tokens(mut tokens: Vec<Token>) -> Result<Self> {
+        let parameters = Self::number_parameters(&mut tokens)?;
+        let mut iter = tokens.into_iter().peekable();
+        let mut statement = Self::parse_select(&mut iter)?;
+        if let Some(token) = iter.next() {
+            return Err(anyhow!("Unexpected token: {:?}", token));
+        }
+        statement.parameters = parameters;
+        Ok(statement)
+    }
+
+    /// Parses an INSERT or REPLACE statement
+    fn parse_insert(iter: &mut Peekable<IntoIter<Token>>) -> Result<Insert> {
+        let on_conflict = if Self::next_is_keyword(iter, "REPLACE") {
+            ConflictResolution::Replace
+        } else if !Self::next_is_keyword(iter, "INSERT") {
+            return Err(anyhow!("Expected INSERT keyword"));
+        } else if Self::next_is_keyword(iter, "OR") {
+            match iter.next() {
+                Some(Token::Keyword(k)) if k.eq_ignore_ascii_case("ABORT") => {
+                    ConflictResolution::Abort
+                }
+                Some(Token::Keyword(k)) if k.eq_ignore_ascii_case("IGNORE") => {
+                    ConflictResolution::Ignore
+                }
+                Some(Token::Keyword(k)) if k.eq_ignore_ascii_case("REPLACE") => {
+                    ConflictResolution::Replace
+                }
+                Some(Token::Keyword(k))
+                    if k.eq_ignore_ascii_case("FAIL") || k.eq_ignore_ascii_case("ROLLBACK") =>
+                {
+                    return Err(anyhow!(
+                        "INSERT OR {} is not supported",
+                        k.to_ascii_uppercase()
+                    ))
+                }
+                _ => return Err(anyhow!("Expected conflict resolution after OR")),
+            }
+        } else {
+            ConflictResolution::Abort
+        };
+        if !Self::next_is_keyword(iter, "INTO") {
+            return Err(anyhow!("Expected INTO after INSERT"));
+        }
+        let Some(table) = Self::next_name(iter, keyword::can_be_name) else {
+            return Err(anyhow!("Expected table name after INTO"));
+        };
+
+        let mut columns = Vec::new();
+        if iter.next_if_eq(&Token::Symbol('(')).is_some() {
+            loop {
+                let Some(column) = Self::next_name(iter, keyword::can_be_name) else {
+                    return Err(anyhow!("Expected column name in column list"));
+                };
+                columns.push(column);
+                if iter.next_if_eq(&Token::Symbol(',')).is_none() {
+                    break;
+                }
+            }
+            if iter.next_if_eq(&Token::Symbol(')')).is_none() {
+                return Err(anyhow!("Expected ) after column list"));
+            }
+        }
+
+        let source = if Self::next_is_keyword(iter, "VALUES") {
+            let mut rows: Vec<Vec<Expression>> = Vec::new();
+            loop {
+                if iter.next_if_eq(&Token::Symbol('(')).is_none() {
+                    return Err(anyhow!("Expected ( before VALUES row"));
+                }
+                let row = Self::parse_expression_list(iter)?;
+                if rows.first().map_or(false, |first| first.len() != row.len()) {
+                    return Err(anyhow!("all VALUES must have the same number of terms"));
+                }
+                rows.push(row);
+                if iter.next_if_eq(&Token::Symbol(',')).is_none() {
+                    break;
+                }
+            }
+            InsertSource::Values(rows)
+        } else if matches!(iter.peek(), Some(Token::Keyword(k)) if k.eq_ignore_ascii_case("SELECT"))
+        {
+            InsertSource::Select(Box::new(Self::parse_select(iter)?))
+        } else {
+            return Err(anyhow!("Expected VALUES or SELECT"));
+        };
+        let upsert = Self::parse_upsert(iter)?;
+        Ok(Insert {
+            table,
+            columns,
+            source,
+            on_conflict,
+            upsert,
+            parameters: Vec::new(),
+        })
+    }
+
+    /// Parses the ON CONFLICT clause of an INSERT, if there is one
+    fn parse_upsert(iter: &mut Peekable<IntoIter<Token>>) -> Result<Option<Upsert>> {
+        if !Self::next_is_keyword(iter, "ON") {
+            return Ok(None);
+        }
+        if !Self::next_is_keyword(iter, "CONFLICT") {
+            return Err(anyhow!("Expected CONFLICT after ON"));
+        }
+        let mut target = Vec::new();
+        if iter.next_if_eq(&Token::Symbol('(')).is_some() {
+            loop {
+                let Some(column) = Self::next_name(iter, keyword::can_be_name) else {
+                    return Err(anyhow!("Expected column name in ON CONFLICT"));
+                };
+                target.push(column);
+                if iter.next_if_eq(&Token::Symbol(',')).is_none() {
+                    break;
+                }
+            }
+            if iter.next_if_eq(&Token::Symbol(')')).is_none() {
+                return Err(anyhow!("Expected ) after ON CONFLICT columns"));
+            }
+            if Self::next_is_keyword(iter, "WHERE") {
+                return Err(anyhow!("partial index conflict targets are not supported"));
+            }
+        }
+        if !Self::next_is_keyword(iter, "DO") {
+            return Err(anyhow!("Expected DO after ON CONFLICT"));
+        }
+        let action = if Self::next_is_keyword(iter, "NOTHING") {
+            UpsertAction::Nothing
+        } else if Self::next_is_keyword(iter, "UPDATE") {
+            if !Self::next_is_keyword(iter, "SET") {
+                return Err(anyhow!("Expected SET after DO UPDATE"));
+            }
+            let assignments = Self::parse_assignments(iter)?;
+            let where_clause = if Self::next_is_keyword(iter, "WHERE") {
+                Some(Self::parse_expression(iter)?)
+            } else {
+                None
+            };
+            UpsertAction::Update {
+                assignments,
+                where_clause,
+            }
+        } else {
+            return Err(anyhow!("Expected NOTHING or UPDATE after DO"));
+        };
+        if Self::next_is_keyword(iter, "ON") {
+            return Err(anyhow!("multiple ON CONFLICT clauses are not supported"));
+        }
+        Ok(Some(Upsert { target, action }))
+    }
+
+    /// Parses an UPDATE statement
+    fn parse_update(iter: &mut Peekable<IntoIter<Token>>) -> Result<Update> {
+        if !Self::next_is_keyword(iter, "UPDATE") {
+            return Err(anyhow!("Expected UPDATE keyword"));
+        }
+        let Some(table) = Self::next_name(iter, keyword::can_be_name) else {
+            return Err(anyhow!("Expected table name after UPDATE"));
+        };
+        if !Self::next_is_keyword(iter, "SET") {
+            return Err(anyhow!("Expected SET after table name"));
+        }
+
+        let assignments = Self::parse_assignments(iter)?;
+        let where_clause = if Self::next_is_keyword(iter, "WHERE") {
+            Some(Self::parse_expression(iter)?)
+        } else {
+            None
+        };
+        Ok(Update {
+            table,
+            assignments,
+            where_clause,
+            parameters: Vec::new(),
+        })
+    }
+
+    /// Parses the `column = expr, ...` list of a SET clause
+    fn parse_assignments(
+        iter: &mut Peekable<IntoIter<Token>>,
+    ) -> Result<Vec<(String, Expression)>> {
+        let mut assignments = Vec::new();
+        loop {
+            let Some(column) = Self::next_name(iter, keyword::can_be_name) else {
+                return Err(anyhow!("Expected column name in SET"));
+            };
+            if iter.next_if_eq(&Token::Operator("=".to_string())).is_none() {
+                return Err(anyhow!("Expected = after {}", column));
+            }
+            assignments.push((column, Self::parse_expression(iter)?));
+            if iter.next_if_eq(&Token::Symbol(',')).is_none() {
+                break;
+            }
+        }
+        Ok(assignments)
+    }
+
+    /// Parses a DELETE statement
+    fn parse_delete(iter: &mut Peekable<IntoIter<Token>>) -> Result<Delete> {
+        if !Self::next_is_keyword(iter, "DELETE") {
+            return Err(anyhow!("Expected DELETE keyword"));
+        }
+        if !Self::next_is_keyword(iter, "FROM") {
+            return Err(anyhow!("Expected FROM after DELETE"));
+        }
+        let Some(table) = Self::next_name(iter, keyword::can_be_name) else {
+            return Err(anyhow!("Expected table name after FROM"));
+        };
+        let where_clause = if Self::next_is_keyword(iter, "WHERE") {
+            Some(Self::parse_expression(iter)?)
+        } else {
+            None
+        };
+        Ok(Delete {
+            table,
+            where_clause,
+            parameters: Vec::new(),
+        })
+    }
+
+    /// Parses a CREATE TABLE statement; the column definitions are only
+    /// checked for balanced parentheses here and parsed from `sql` by
+    /// [`TableSchema::parse`] when the table is created
+    ///
+    /// [`TableSchema::parse`]: crate::sqlite::core::schema::TableSchema::parse
+    fn parse_create_table(tokens: Vec<Token>) -> Result<CreateTable> {
         let mut iter = tokens.into_iter().peekable();
+        if !Self::next_is_keyword(&mut iter, "CREATE") {
+            return Err(anyhow!("Expected CREATE keyword"));
+        }
+        match iter.next() {
+            Some(Token::Keyword(k)) if k.eq_ignore_ascii_case("TABLE") => {}
+            Some(Token::Keyword(k))
+                if k.eq_ignore_ascii_case("TEMP") || k.eq_ignore_ascii_case("TEMPORARY") =>
+            {
+                return Err(anyhow!("temporary tables are not supported"))
+            }
+            Some(Token::Keyword(k)) => {
+                return Err(anyhow!(
+                    "CREATE {} is not supported",
+                    k.to_ascii_uppercase()
+                ))
+            }
+            _ => return Err(anyhow!("Expected TABLE after CREATE")),
+        }
+        let if_not_exists = Self::next_is_keyword(&mut iter, "IF");
+        if if_not_exists
+            && !(Self::next_is_keyword(&mut iter, "NOT")
+                && Self::next_is_keyword(&mut iter, "EXISTS"))
+        {
+            return Err(anyhow!("Expected NOT EXISTS after IF"));
+        }
+
+        let rest: Vec<Token> = iter.collect();
+        let mut iter = rest.clone().into_iter().peekable();
+        let Some(table) = Self::next_name(&mut iter, keyword::can_be_name) else {
+            return Err(anyhow!("Expected table name after CREATE TABLE"));
+        };
+        if Self::next_is_keyword(&mut iter, "AS") {
+            return Err(anyhow!("CREATE TABLE ... AS SELECT is not supported"));
+        }
+        if iter.next_if_eq(&Token::Symbol('(')).is_none() {
+            return Err(anyhow!("Expected ( after table name"));
+        }
+        if iter.peek() == Some(&Token::Symbol(')')) {
+            return Err(anyhow!("Expected column definition"));
+        }
+        let mut depth = 1;
+        while depth > 0 {
+            match iter.next() {
+                Some(Token::Symbol('(')) => depth += 1,
+                Some(Token::Symbol(')')) => depth -= 1,
+                Some(_) => {}
+                None => return Err(anyhow!("Expected ) after column definitions")),
+            }
+        }
+        // ROWID is not a keyword, so it comes as an identifier
+        if Self::next_is_keyword(&mut iter, "WITHOUT")
+            && iter
+                .next_if(|token| matches!(token, Token::Identifier(word) if word.eq_ignore_ascii_case("ROWID")))
+                .is_none()
+        {
+            return Err(anyhow!("Expected ROWID after WITHOUT"));
+        }
+        if let Some(token) = iter.next() {
+            return Err(anyhow!("Unexpected token: {:?}", token));
+        }
+
+        Ok(CreateTable {
+            table,
+            if_not_exists,
+            sql: format!("CREATE TABLE {}", Self::schema_text(&rest)),
+        })
+    }
+
+    /// Writes the tokens of a definition back out as SQL for sqlite_schema,
+    /// spaced the way such definitions are usually written and with
+    /// identifiers quoted where they need to be
+    fn schema_text(tokens: &[Token]) -> String {
+        let mut text = String::new();
+        let mut previous: Option<&Token> = None;
+        // True after a sign that is unary, which is written against its operand
+        let mut unary = false;
+        for token in tokens {
+            let space = match (previous, token) {
+                (None, _) => false,
+                _ if unary => false,
+                (_, Token::Symbol(')' | ',' | '.')) => false,
+                (Some(Token::Symbol('(' | '.')), _) => false,
+                (Some(previous), Token::Symbol('(')) => !previous.is_word(),
+                _ => true,
+            };
+            unary = matches!(token, Token::Operator(op) if op == "-" || op == "+")
+                && matches!(
+                    previous,
+                    None | Some(Token::Symbol(_) | Token::Operator(_) | Token::Keyword(_))
+                );
+            if space {
+                text.push(' ');
+            }
+            match token {
+                Token::Identifier(name)
+                    if keyword::is_keyword(name)
+                        || name.is_empty()
+                        || name.starts_with(|c: char| c.is_ascii_digit())
+                        || !name.chars().all(|c| c.is_alphanumeric() || c == '_') =>
+                {
+                    text.push_str(&format!("\"{}\"", name.replace('"', "\"\"")));
+                }
+                token => text.push_str(&token.to_string()),
+            }
+            previous = Some(token);
+        }
+        text
+    }
+
+    /// Numbers the parameters of one statement the way SQLite does, returning
+    /// the name of each number
+    ///
+    /// `?` takes the number after the largest so far and `?NNN` takes NNN. A
+    /// named parameter takes the next number the first time it appears and the
+    /// same number every time after.
+    fn number_parameters(tokens: &mut [Token]) -> Result<Vec<Option<String>>> {
+        let mut names: Vec<Option<String>> = Vec::new();
+        for token in tokens {
+            let Token::Parameter { name, index } = token else {
+                continue;
+            };
+            *index = if name == "?" {
+                names.len() + 1
+            } else if let Some(digits) = name.strip_prefix('?') {
+                match digits.parse::<usize>() {
+                    Ok(n) if (1..=MAX_PARAMETERS).contains(&n) => n,
+                    _ => {
+                        return Err(anyhow!(
+                            "variable number must be between ?1 and ?{}",
+                            MAX_PARAMETERS
+                        ))
+                    }
+                }
+            } else {
+                match names.iter().position(|n| n.as_ref() == Some(name)) {
+                    Some(position) => position + 1,
+                    None => names.len() + 1,
+                }
+            };
+            if *index > MAX_PARAMETERS {
+                return Err(anyhow!("too many SQL variables"));
+            }
+            if names.len() < *index {
+                names.resize(*index, None);
+            }
+            if name != "?" {
+                names[*index - 1].get_or_insert_with(|| name.clone());
+            }
+        }
+        Ok(names)
+    }
+
+    /// Writes tokens back out as SQL, with a space only between two words
+    fn source_text(tokens: impl Iterator<Item = Token>) -> String {
+        let mut text = String::new();
+        let mut previous: Option<Token> = None;
+        for token in tokens {
+            if previous.map_or(false, |previous| previous.is_word() && token.is_word()) {
+                text.push(' ');
+            }
+            text.push_str(&token.to_string());
+            previous = Some(token);
+        }
+        text
+    }
+
+    /// Parses a SELECT statement, stopping at the first token that cannot
+    /// continue it
+    fn parse_select(iter: &mut Peekable<IntoIter<Token>>) -> Result<Self> {
         let mut selections = Vec::new();
 
         // Expect SELECT
@@ -90,52 +1048,603 @@ impl Statement {
             Some(Token::Keyword(k)) if k.to_uppercase() == "SELECT" => {}
             _ => return Err(anyhow!("Expected SELECT keyword")),
         }
+        let distinct = Self::next_is_keyword(iter, "DISTINCT");
+        if !distinct {
+            Self::next_is_keyword(iter, "ALL");
+        }
 
-        // Parse selections
-        while let Some(token) = iter.next() {
-            match token {
-                Token::Asterisk => {
-                    selections.push(Expression::Asterisk);
-                }
-                Token::Function(name) => {
-                    // Handle function call
-                    match iter.next() {
-                        Some(Token::Symbol('(')) => {}
-                        _ => return Err(anyhow!("Expected opening parenthesis after function")),
-                    }
+        // Parse comma-separated selections
+        loop {
+            let start = iter.clone();
+            let expr = Self::parse_expression(iter)?;
+            let consumed = start.len() - iter.len();
+            let text = Self::source_text(start.take(consumed));
+            let alias = Self::parse_alias(iter)?;
+            selections.push(ResultColumn { expr, alias, text });
+            if iter.peek() != Some(&Token::Symbol(',')) {
+                break;
+            }
+            iter.next();
+        }
 
-                    match iter.next() {
-                        Some(Token::Asterisk) => {}
-                        _ => return Err(anyhow!("Expected * in function argument")),
-                    }
+        // Parse optional FROM clause
+        let (from_table, table_alias) = if Self::next_is_keyword(iter, "FROM") {
+            match Self::next_name(iter, keyword::can_be_name) {
+                Some(table) => (Some(table), Self::parse_alias(iter)?),
+                None => return Err(anyhow!("Expected table name after FROM")),
+            }
+        } else {
+            (None, None)
+        };
+        let joins = if from_table.is_some() {
+            Self::parse_joins(iter)?
+        } else {
+            Vec::new()
+        };
 
-                    match iter.next() {
-                        Some(Token::Symbol(')')) => {}
-                        _ => return Err(anyhow!("Expected closing parenthesis")),
-                    }
+        // Parse optional WHERE clause
+        let where_clause = if Self::next_is_keyword(iter, "WHERE") {
+            Some(Self::parse_expression(iter)?)
+        } else {
+            None
+        };
 
-                    selections.push(Expression::Function(FunctionCall {
-                        name,
-                        args: vec![Expression::Asterisk],
-                    }));
+        // Parse optional GROUP BY and HAVING clauses
+        let mut group_by = Vec::new();
+        if Self::next_is_keyword(iter, "GROUP") {
+            if !Self::next_is_keyword(iter, "BY") {
+                return Err(anyhow!("Expected BY after GROUP"));
+            }
+            loop {
+                group_by.push(Self::parse_expression(iter)?);
+                if iter.next_if_eq(&Token::Symbol(',')).is_none() {
+                    break;
                 }
-                Token::Identifier(column) => {
-                    selections.push(Expression::Column(column));
+            }
+        }
+        let having = if Self::next_is_keyword(iter, "HAVING") {
+            Some(Self::parse_expression(iter)?)
+        } else {
+            None
+        };
+
+        // Parse optional ORDER BY clause
+        let mut order_by = Vec::new();
+        if Self::next_is_keyword(iter, "ORDER") {
+            if !Self::next_is_keyword(iter, "BY") {
+                return Err(anyhow!("Expected BY after ORDER"));
+            }
+            loop {
+                let expr = Self::parse_expression(iter)?;
+                let descending = if Self::next_is_keyword(iter, "DESC") {
+                    true
+                } else {
+                    Self::next_is_keyword(iter, "ASC");
+                    false
+                };
+                order_by.push(OrderingTerm { expr, descending });
+                if iter.peek() != Some(&Token::Symbol(',')) {
+                    break;
                 }
-                Token::Keyword(k) if k.to_uppercase() == "FROM" => break,
-                _ => return Err(anyhow!("Unexpected token in selections")),
+                iter.next();
             }
         }
 
-        // Parse FROM clause
-        let from_table = match iter.next() {
-            Some(Token::Identifier(table)) => table,
-            _ => return Err(anyhow!("Expected table name after FROM")),
+        // Parse optional LIMIT clause
+        let limit = if Self::next_is_keyword(iter, "LIMIT") {
+            let first = Self::parse_expression(iter)?;
+            if iter.next_if_eq(&Token::Symbol(',')).is_some() {
+                Some(Limit {
+                    count: Self::parse_expression(iter)?,
+                    offset: Some(first),
+                })
+            } else if Self::next_is_keyword(iter, "OFFSET") {
+                Some(Limit {
+                    count: first,
+                    offset: Some(Self::parse_expression(iter)?),
+                })
+            } else {
+                Some(Limit {
+                    count: first,
+                    offset: None,
+                })
+            }
+        } else {
+            None
         };
 
         Ok(Statement {
+            distinct,
             selections,
             from_table,
+            table_alias,
+            joins,
+            where_clause,
+            group_by,
+            having,
+            order_by,
+            limit,
+            parameters: Vec::new(),
+        })
+    }
+
+    /// Parses the joins following the first table of the FROM clause
+    fn parse_joins(iter: &mut Peekable<IntoIter<Token>>) -> Result<Vec<Join>> {
+        let mut joins = Vec::new();
+        loop {
+            if iter.next_if_eq(&Token::Symbol(',')).is_some() {
+                joins.push(Self::parse_join_source(iter, JoinKind::Inner, false)?);
+                continue;
+            }
+            let kind = if Self::next_is_keyword(iter, "LEFT") {
+                Self::next_is_keyword(iter, "OUTER");
+                JoinKind::Left
+            } else if Self::next_is_keyword(iter, "INNER")
+                || Self::next_is_keyword(iter, "CROSS")
+                || matches!(iter.peek(), Some(Token::Keyword(k)) if k.eq_ignore_ascii_case("JOIN"))
+            {
+                JoinKind::Inner
+            } else {
+                return Ok(joins);
+            };
+            if !Self::next_is_keyword(iter, "JOIN") {
+                return Err(anyhow!("Expected JOIN"));
+            }
+            joins.push(Self::parse_join_source(iter, kind, true)?);
+        }
+    }
+
+    /// Parses `table [[AS] alias]`, followed by `ON condition` if `allow_on`
+    fn parse_join_source(
+        iter: &mut Peekable<IntoIter<Token>>,
+        kind: JoinKind,
+        allow_on: bool,
+    ) -> Result<Join> {
+        let Some(table) = Self::next_name(iter, keyword::can_be_name) else {
+            return Err(anyhow!("Expected table name after JOIN"));
+        };
+        let alias = Self::parse_alias(iter)?;
+        let constraint = if allow_on && Self::next_is_keyword(iter, "ON") {
+            Some(Self::parse_expression(iter)?)
+        } else {
+            None
+        };
+        Ok(Join {
+            kind,
+            table,
+            alias,
+            constraint,
         })
     }
+
+    /// Parses an optional `[AS] alias`, where an alias after AS may also be a
+    /// string literal
+    fn parse_alias(iter: &mut Peekable<IntoIter<Token>>) -> Result<Option<String>> {
+        if Self::next_is_keyword(iter, "AS") {
+            if let Some(Token::String(alias)) = iter.next_if(|t| matches!(t, Token::String(_))) {
+                return Ok(Some(alias));
+            }
+            return match Self::next_name(iter, keyword::can_be_name) {
+                Some(alias) => Ok(Some(alias)),
+                None => Err(anyhow!("Expected alias after AS")),
+            };
+        }
+        Ok(Self::next_name(iter, keyword::can_be_alias))
+    }
+
+    /// Consumes the next token if it is a name: an identifier, or a keyword
+    /// that `allowed` accepts as one
+    fn next_name(
+        iter: &mut Peekable<IntoIter<Token>>,
+        allowed: fn(&str) -> bool,
+    ) -> Option<String> {
+        match iter.next_if(|token| match token {
+            Token::Identifier(_) => true,
+            Token::Keyword(k) => allowed(k),
+            _ => false,
+        }) {
+            Some(Token::Identifier(name)) | Some(Token::Keyword(name)) => Some(name),
+            _ => None,
+        }
+    }
+
+    /// Consumes the next token if it is the given keyword
+    fn next_is_keyword(iter: &mut Peekable<IntoIter<Token>>, keyword: &str) -> bool {
+        let matched =
+            matches!(iter.peek(), Some(Token::Keyword(k)) if k.eq_ignore_ascii_case(keyword));
+        if matched {
+            iter.next();
+        }
+        matched
+    }
+
+    /// Parses a complete expression
+    fn parse_expression(iter: &mut Peekable<IntoIter<Token>>) -> Result<Expression> {
+        Self::parse_binary(iter, 0)
+    }
+
+    /// Parses a chain of binary operators binding at least as tightly as `min_precedence`
+    ///
+    /// Operands of a left-associative operator at precedence `p` are parsed at
+    /// `p + 1`, so `a % b % c` groups as `(a % b) % c`.
+    fn parse_binary(
+        iter: &mut Peekable<IntoIter<Token>>,
+        min_precedence: u8,
+    ) -> Result<Expression> {
+        let mut left = Self::parse_unary(iter)?;
+
+        loop {
+            match iter.peek() {
+                Some(Token::Keyword(k))
+                    if ["IN", "NOT", "BETWEEN", "LIKE", "ISNULL", "NOTNULL"]
+                        .iter()
+                        .any(|keyword| k.eq_ignore_ascii_case(keyword))
+                        && BinaryOperator::EQUALITY_PRECEDENCE >= min_precedence =>
+                {
+                    // Postfix null tests are shorthand for IS [NOT] NULL
+                    let null_test = if Self::next_is_keyword(iter, "ISNULL") {
+                        Some(BinaryOperator::Is)
+                    } else if Self::next_is_keyword(iter, "NOTNULL") {
+                        Some(BinaryOperator::IsNot)
+                    } else {
+                        None
+                    };
+                    let negated = null_test.is_none() && Self::next_is_keyword(iter, "NOT");
+                    let null_test = if negated && Self::next_is_keyword(iter, "NULL") {
+                        Some(BinaryOperator::IsNot)
+                    } else {
+                        null_test
+                    };
+                    if let Some(op) = null_test {
+                        left = Expression::Binary {
+                            left: Box::new(left),
+                            op,
+                            right: Box::new(Expression::Literal(Value::Null)),
+                        };
+                        continue;
+                    }
+
+                    left = if Self::next_is_keyword(iter, "IN") {
+                        Expression::InList {
+                            expr: Box::new(left),
+                            list: Self::parse_in_list(iter)?,
+                        }
+                    } else if Self::next_is_keyword(iter, "BETWEEN") {
+                        // Bounds bind tighter than AND, so the AND here is BETWEEN's own
+                        let bound_precedence = BinaryOperator::EQUALITY_PRECEDENCE + 1;
+                        let low = Self::parse_binary(iter, bound_precedence)?;
+                        if !Self::next_is_keyword(iter, "AND") {
+                            return Err(anyhow!("Expected AND in BETWEEN"));
+                        }
+                        let high = Self::parse_binary(iter, bound_precedence)?;
+                        Expression::Between {
+                            expr: Box::new(left),
+                            low: Box::new(low),
+                            high: Box::new(high),
+                        }
+                    } else if Self::next_is_keyword(iter, "LIKE") {
+                        let operand_precedence = BinaryOperator::EQUALITY_PRECEDENCE + 1;
+                        let pattern = Self::parse_binary(iter, operand_precedence)?;
+                        let escape = if Self::next_is_keyword(iter, "ESCAPE") {
+                            Some(Box::new(Self::parse_binary(iter, operand_precedence)?))
+                        } else {
+                            None
+                        };
+                        Expression::Like {
+                            expr: Box::new(left),
+                            pattern: Box::new(pattern),
+                            escape,
+                        }
+                    } else {
+                        return Err(anyhow!("Expected IN, BETWEEN or LIKE after NOT"));
+                    };
+                    if negated {
+                        left = Expression::Unary {
+                            op: UnaryOperator::Not,
+                            expr: Box::new(left),
+                        };
+                    }
+                }
+                Some(Token::Keyword(k))
+                    if k.to_uppercase() == "IS"
+                        && BinaryOperator::EQUALITY_PRECEDENCE >= min_precedence =>
+                {
+                    iter.next();
+                    let op = match iter.peek() {
+                        Some(Token::Keyword(k)) if k.to_uppercase() == "NOT" => {
+                            iter.next();
+                            BinaryOperator::IsNot
+                        }
+                        _ => BinaryOperator::Is,
+                    };
+                    let right = Self::parse_binary(iter, op.precedence() + 1)?;
+                    left = Expression::Binary {
+                        left: Box::new(left),
+                        op,
+                        right: Box::new(right),
+                    };
+                }
+                Some(token) => {
+                    let op = match token {
+                        Token::Operator(symbol) => BinaryOperator::from_symbol(symbol)
+                            .ok_or_else(|| anyhow!("Unexpected operator: {}", symbol))?,
+                        Token::Asterisk => BinaryOperator::Multiply,
+                        Token::Keyword(k) => match BinaryOperator::from_keyword(k) {
+                            Some(op) => op,
+                            None => break,
+                        },
+                        _ => break,
+                    };
+                    if op.precedence() < min_precedence {
+                        break;
+                    }
+                    iter.next();
+                    let right = Self::parse_binary(iter, op.precedence() + 1)?;
+                    left = Expression::Binary {
+                        left: Box::new(left),
+                        op,
+                        right: Box::new(right),
+                    };
+                }
+                _ => break,
+            }
+        }
+
+        Ok(left)
+    }
+
+    /// Parses an operand with any prefix operators
+    fn parse_unary(iter: &mut Peekable<IntoIter<Token>>) -> Result<Expression> {
+        if matches!(iter.peek(), Some(Token::Keyword(k)) if k.to_uppercase() == "NOT") {
+            iter.next();
+            let expr = Self::parse_binary(iter, BinaryOperator::NOT_PRECEDENCE)?;
+            return Ok(Expression::Unary {
+                op: UnaryOperator::Not,
+                expr: Box::new(expr),
+            });
+        }
+        let op = match iter.peek() {
+            Some(Token::Operator(op)) if op == "~" => UnaryOperator::BitNot,
+            Some(Token::Operator(op)) if op == "-" => UnaryOperator::Negate,
+            Some(Token::Operator(op)) if op == "+" => {
+                iter.next();
+                return Self::parse_unary(iter);
+            }
+            _ => return Self::parse_primary(iter),
+        };
+        iter.next();
+        // -9223372036854775808 is the one integer whose magnitude is not an integer
+        if let (UnaryOperator::Negate, Some(Token::Number(literal))) = (op, iter.peek()) {
+            if let Ok(min) = format!("-{}", literal).parse::<i64>() {
+                if min == i64::MIN {
+                    iter.next();
+                    return Ok(Expression::Literal(Value::Integer(min)));
+                }
+            }
+        }
+        let expr = Self::parse_unary(iter)?;
+        Ok(Expression::Unary {
+            op,
+            expr: Box::new(expr),
+        })
+    }
+
+    /// Parses the parenthesized list after IN: `(1, 2, 3)`, `()` or
+    /// `(VALUES (...), (...))`
+    fn parse_in_list(iter: &mut Peekable<IntoIter<Token>>) -> Result<Vec<Expression>> {
+        if iter.next() != Some(Token::Symbol('(')) {
+            return Err(anyhow!("Expected ( after IN"));
+        }
+        if Self::next_is_keyword(iter, "VALUES") {
+            return Self::parse_values_rows(iter);
+        }
+        if iter.next_if_eq(&Token::Symbol(')')).is_some() {
+            return Ok(Vec::new());
+        }
+        Self::parse_expression_list(iter)
+    }
+
+    /// Parses the `(...), (...))` after VALUES, turning multi-column rows into
+    /// row values
+    fn parse_values_rows(iter: &mut Peekable<IntoIter<Token>>) -> Result<Vec<Expression>> {
+        let mut list = Vec::new();
+        loop {
+            if iter.next() != Some(Token::Symbol('(')) {
+                return Err(anyhow!("Expected ( before VALUES row"));
+            }
+            let mut row = Self::parse_expression_list(iter)?;
+            list.push(if row.len() == 1 {
+                row.remove(0)
+            } else {
+                Expression::Row(row)
+            });
+            match iter.next() {
+                Some(Token::Symbol(',')) => {}
+                Some(Token::Symbol(')')) => return Ok(list),
+                _ => return Err(anyhow!("Expected closing parenthesis")),
+            }
+        }
+    }
+
+    /// Parses comma-separated expressions up to and including the closing parenthesis
+    fn parse_expression_list(iter: &mut Peekable<IntoIter<Token>>) -> Result<Vec<Expression>> {
+        let mut items = Vec::new();
+        loop {
+            items.push(Self::parse_expression(iter)?);
+            match iter.next() {
+                Some(Token::Symbol(',')) => {}
+                Some(Token::Symbol(')')) => return Ok(items),
+                None => return Err(anyhow!("incomplete input")),
+                _ => return Err(anyhow!("Expected closing parenthesis")),
+            }
+        }
+    }
+
+    /// Converts a numeric literal token to its value
+    ///
+    /// Literals with a decimal point or exponent are reals, as are decimal
+    /// integers too large for 64 bits. Hex literals are the two's-complement bits
+    /// of an integer, so 0xffffffffffffffff is -1.
+    fn number_value(literal: &str) -> Result<Value> {
+        if let Some(hex) = literal.strip_prefix("0x").or(literal.strip_prefix("0X")) {
+            return match u64::from_str_radix(hex, 16) {
+                Ok(bits) => Ok(Value::Integer(bits as i64)),
+                Err(_) if hex.is_empty() => Err(anyhow!("unrecognized token: \"{}\"", literal)),
+                Err(_) => Err(anyhow!("hex literal too big: {}", literal)),
+            };
+        }
+        if !literal.contains(['.', 'e']) {
+            if let Ok(n) = literal.parse() {
+                return Ok(Value::Integer(n));
+            }
+        }
+        literal
+            .parse()
+            .map(Value::Real)
+            .map_err(|_| anyhow!("unrecognized token: \"{}\"", literal))
+    }
+
+    /// Parses the rest of a CASE expression after the CASE keyword
+    fn parse_case(iter: &mut Peekable<IntoIter<Token>>) -> Result<Expression> {
+        let operand = match iter.peek() {
+            Some(Token::Keyword(k)) if k.eq_ignore_ascii_case("WHEN") => None,
+            _ => Some(Box::new(Self::parse_expression(iter)?)),
+        };
+
+        let mut branches = Vec::new();
+        while Self::next_is_keyword(iter, "WHEN") {
+            let when = Self::parse_expression(iter)?;
+            if !Self::next_is_keyword(iter, "THEN") {
+                return Err(anyhow!("Expected THEN after WHEN"));
+            }
+            branches.push((when, Self::parse_expression(iter)?));
+        }
+        if branches.is_empty() {
+            return Err(anyhow!("Expected WHEN in CASE"));
+        }
+
+        let else_result = if Self::next_is_keyword(iter, "ELSE") {
+            Some(Box::new(Self::parse_expression(iter)?))
+        } else {
+            None
+        };
+        if !Self::next_is_keyword(iter, "END") {
+            return Err(anyhow!("Expected END after CASE"));
+        }
+
+        Ok(Expression::Case {
+            operand,
+            branches,
+            else_result,
+        })
+    }
+
+    /// Rewrites `iif(cond, a, b)` as `CASE WHEN cond THEN a ELSE b END`
+    fn iif_to_case(args: Vec<Expression>) -> Result<Expression> {
+        let Ok([condition, then, otherwise]) = <[Expression; 3]>::try_from(args) else {
+            return Err(anyhow!("wrong number of arguments to function iif()"));
+        };
+        Ok(Expression::Case {
+            operand: None,
+            branches: vec![(condition, then)],
+            else_result: Some(Box::new(otherwise)),
+        })
+    }
+
+    /// Parses the `(WHERE ...)` following FILTER
+    fn parse_filter(iter: &mut Peekable<IntoIter<Token>>) -> Result<Expression> {
+        if iter.next() != Some(Token::Symbol('(')) || !Self::next_is_keyword(iter, "WHERE") {
+            return Err(anyhow!("Expected (WHERE ...) after FILTER"));
+        }
+        let condition = Self::parse_expression(iter)?;
+        if iter.next() != Some(Token::Symbol(')')) {
+            return Err(anyhow!("Expected closing parenthesis"));
+        }
+        Ok(condition)
+    }
+
+    /// Parses one operand: `*`, a literal, a column, a function call, CASE,
+    /// EXISTS, CURRENT_TIMESTAMP and the like, or a parenthesized expression or
+    /// row value
+    fn parse_primary(iter: &mut Peekable<IntoIter<Token>>) -> Result<Expression> {
+        // Keywords with a meaning of their own here (NULL, CASE, EXISTS) are
+        // reserved, so any other keyword is a name
+        let token = match iter.next() {
+            // CURRENT_DATE, CURRENT_TIME and CURRENT_TIMESTAMP are calls
+            // without parentheses
+            Some(Token::Keyword(k)) if k.to_ascii_uppercase().starts_with("CURRENT_") => {
+                return Ok(Expression::Function(FunctionCall {
+                    name: k.to_ascii_uppercase(),
+                    args: Vec::new(),
+                    filter: None,
+                }));
+            }
+            Some(Token::Keyword(k)) if keyword::can_be_name(&k) => Some(Token::Identifier(k)),
+            token => token,
+        };
+        match token {
+            Some(Token::Asterisk) => Ok(Expression::Asterisk),
+            Some(Token::Blob(bytes)) => Ok(Expression::Literal(Value::Blob(bytes))),
+            Some(Token::Number(literal)) => Ok(Expression::Literal(Self::number_value(&literal)?)),
+            Some(Token::String(text)) => Ok(Expression::Literal(Value::Text(text))),
+            Some(Token::Parameter { index, .. }) => Ok(Expression::Parameter(index)),
+            Some(Token::Keyword(k)) if k.to_uppercase() == "NULL" => {
+                Ok(Expression::Literal(Value::Null))
+            }
+            Some(Token::Symbol('(')) => {
+                let mut items = Self::parse_expression_list(iter)?;
+                Ok(if items.len() == 1 {
+                    items.remove(0)
+                } else {
+                    Expression::Row(items)
+                })
+            }
+            Some(Token::Keyword(k)) if k.eq_ignore_ascii_case("CASE") => Self::parse_case(iter),
+            Some(Token::Keyword(k)) if k.eq_ignore_ascii_case("EXISTS") => {
+                if iter.next() != Some(Token::Symbol('(')) {
+                    return Err(anyhow!("Expected ( after EXISTS"));
+                }
+                let subquery = Self::parse_select(iter)?;
+                match iter.next() {
+                    Some(Token::Symbol(')')) => Ok(Expression::Exists(Box::new(subquery))),
+                    None => Err(anyhow!("incomplete input")),
+                    _ => Err(anyhow!("Expected closing parenthesis")),
+                }
+            }
+            // Any name followed by ( is a call; without it, even count is a column
+            Some(Token::Identifier(name)) if iter.peek() == Some(&Token::Symbol('(')) => {
+                iter.next();
+                let args = if iter.peek() == Some(&Token::Symbol(')')) {
+                    iter.next();
+                    Vec::new()
+                } else {
+                    Self::parse_expression_list(iter)?
+                };
+                let filter = if Self::next_is_keyword(iter, "FILTER") {
+                    Some(Box::new(Self::parse_filter(iter)?))
+                } else {
+                    None
+                };
+                if name.eq_ignore_ascii_case("iif") && filter.is_none() {
+                    return Self::iif_to_case(args);
+                }
+                Ok(Expression::Function(FunctionCall { name, args, filter }))
+            }
+            Some(Token::Identifier(name)) => {
+                if iter.peek() != Some(&Token::Symbol('.')) {
+                    return Ok(Expression::Column { table: None, name });
+                }
+                iter.next();
+                match Self::next_name(iter, keyword::can_be_name) {
+                    Some(column) => Ok(Expression::Column {
+                        table: Some(name),
+                        name: column,
+                    }),
+                    None => Err(anyhow!("Expected column name after {}.", name)),
+                }
+            }
+            None => Err(anyhow!("incomplete input")),
+            _ => Err(anyhow!("Expected expression")),
+        }
+    }
 }
@@ -7,8 +7,56 @@ pub enum Token {
     Identifier(String),
     /// Special characters and operators
     Symbol(char),
-    /// Function names
-    Function(String),
     /// The wildcard operator *
     Asterisk,
+    /// Numeric literals as written, such as 42, 1.5e3 or 0x1F
+    Number(String),
+    /// String literals, with quotes removed and '' unescaped
+    String(String),
+    /// BLOB literals such as X'CAFE', as their bytes
+    Blob(Vec<u8>),
+    /// Operators like = and ||
+    Operator(String),
+    /// A bound parameter: `?`, `?NNN`, `:name`, `@name` or `$name`, as written,
+    /// with its 1-based number; the tokenizer leaves the number 0 and the
+    /// parser assigns it
+    Parameter { name: String, index: usize },
+}
+
+impl Token {
+    /// Returns true for tokens that need a space to separate them from a
+    /// neighbouring word: keywords, identifiers, literals and parameters
+    pub fn is_word(&self) -> bool {
+        matches!(
+            self,
+            Token::Keyword(_)
+                | Token::Identifier(_)
+                | Token::Number(_)
+                | Token::String(_)
+                | Token::Blob(_)
+                | Token::Parameter { .. }
+        )
+    }
+}
+
+impl std::fmt::Display for Token {
+    /// Writes the token as SQL; identifiers lose any quotes they had
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Token::Keyword(word) | Token::Identifier(word) => write!(f, "{}", word),
+            Token::Symbol(c) => write!(f, "{}", c),
+            Token::Asterisk => write!(f, "*"),
+            Token::Number(literal) => write!(f, "{}", literal),
+            Token::String(text) => write!(f, "'{}'", text.replace('\'', "''")),
+            Token::Blob(bytes) => {
+                write!(f, "X'")?;
+                bytes
+                    .iter()
+                    .try_for_each(|byte| write!(f, "{:02X}", byte))?;
+                write!(f, "'")
+            }
+            Token::Operator(op) => write!(f, "{}", op),
+            Token::Parameter { name, .. } => write!(f, "{}", name),
+        }
+    }
 }
@@ -0,0 +1,382 @@
+//! Aggregate Functions
+//!
+//! A query whose result columns call an aggregate function collapses all rows of
+//! the table into a single result row. Each aggregate call gets an accumulator,
+//! which is stepped with the call's arguments for every row that passes its
+//! `FILTER (WHERE ...)` clause and then finished into the call's value.
+//!
+//! Bare columns in an aggregate query take their values from one source row: the
+//! row that produced the result of a lone min() or max(), otherwise the first row.
+
+use crate::sqlite::core::collation::Collation;
+use crate::sqlite::core::header::TextEncoding;
+use crate::sqlite::core::value::Value;
+use crate::sqlite::parser::expression::{Expression, FunctionCall};
+use crate::sqlite::query::eval::{evaluate, operand_collation, RowContext};
+use crate::sqlite::query::functions::{self, Rng};
+use anyhow::{anyhow, Result};
+use std::cmp::Ordering;
+
+/// Running state of one aggregate call
+#[derive(Debug)]
+enum Accumulator {
+    /// count(*) when `star`, otherwise count(x) of non-NULL values
+    Count { star: bool, count: i64 },
+    /// sum(), total() and avg(), which share their running sums
+    Sum {
+        function: SumFunction,
+        int_sum: i64,
+        real_sum: f64,
+        /// Set once a non-integer value has been added
+        approx: bool,
+        /// Set if the integer sum overflowed before any non-integer value
+        overflow: bool,
+        count: i64,
+    },
+    /// min() or max() of non-NULL values
+    Extreme { max: bool, value: Option<Value> },
+    /// group_concat() of non-NULL values
+    GroupConcat { text: Option<String> },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SumFunction {
+    Sum,
+    Total,
+    Avg,
+}
+
+impl Accumulator {
+    /// Creates the accumulator for an aggregate call, checking its arguments
+    fn new(call: &FunctionCall) -> Result<Self> {
+        let name = call.name.to_uppercase();
+        let arity_ok = match name.as_str() {
+            "COUNT" => call.args.len() <= 1,
+            "GROUP_CONCAT" => matches!(call.args.len(), 1 | 2),
+            _ => call.args.len() == 1,
+        };
+        if !arity_ok {
+            return Err(anyhow!(
+                "wrong number of arguments to function {}()",
+                call.name
+            ));
+        }
+
+        let sum = |function| Accumulator::Sum {
+            function,
+            int_sum: 0,
+            real_sum: 0.0,
+            approx: false,
+            overflow: false,
+            count: 0,
+        };
+        Ok(match name.as_str() {
+            "COUNT" => Accumulator::Count {
+                star: matches!(call.args.as_slice(), [] | [Expression::Asterisk]),
+                count: 0,
+            },
+            "SUM" => sum(SumFunction::Sum),
+            "TOTAL" => sum(SumFunction::Total),
+            "AVG" => sum(SumFunction::Avg),
+            "MIN" | "MAX" => Accumulator::Extreme {
+                max: name == "MAX",
+                value: None,
+            },
+            "GROUP_CONCAT" => Accumulator::GroupConcat { text: None },
+            _ => return Err(anyhow!("no such function: {}", call.name)),
+        })
+    }
+
+    /// Adds one row's arguments, returning true if a min() or max() changed;
+    /// text is compared under `collation` as it is stored in `encoding`
+    fn step(
+        &mut self,
+        args: &[Value],
+        collation: Collation,
+        encoding: TextEncoding,
+    ) -> Result<bool> {
+        let value = args.first().unwrap_or(&Value::Null);
+        match self {
+            Accumulator::Count { star, count } => {
+                if *star || !value.is_null() {
+                    *count += 1;
+                }
+            }
+            Accumulator::Sum {
+                int_sum,
+                real_sum,
+                approx,
+                overflow,
+                count,
+                ..
+            } => {
+                if value.is_null() {
+                    return Ok(false);
+                }
+                *count += 1;
+                match value.with_numeric_affinity() {
+                    Value::Integer(i) => {
+                        *real_sum += i as f64;
+                        if !*approx && !*overflow {
+                            match int_sum.checked_add(i) {
+                                Some(sum) => *int_sum = sum,
+                                None => *overflow = true,
+                            }
+                        }
+                    }
+                    other => {
+                        *approx = true;
+                        if let Value::Real(r) = other.to_numeric() {
+                            *real_sum += r;
+                        }
+                    }
+                }
+            }
+            Accumulator::Extreme { max, value: best } => {
+                if value.is_null() {
+                    return Ok(false);
+                }
+                let wanted = if *max {
+                    Ordering::Greater
+                } else {
+                    Ordering::Less
+                };
+                let better = best.as_ref().map_or(true, |best| {
+                    collation.compare(value, best, encoding) == wanted
+                });
+                if better {
+                    *best = Some(value.clone());
+                }
+                return Ok(better);
+            }
+            Accumulator::GroupConcat { text } => {
+                let Some(item) = value.to_text() else {
+                    return Ok(false);
+                };
+                match text {
+                    Some(text) => {
+                        let separator = match args.get(1) {
+                            Some(separator) => separator.to_text().unwrap_or_default(),
+                            None => ",".to_string(),
+                        };
+                        text.push_str(&separator);
+                        text.push_str(&item);
+                    }
+                    None => *text = Some(item),
+                }
+            }
+        }
+        Ok(false)
+    }
+
+    /// Produces the aggregate's value
+    fn finish(self) -> Result<Value> {
+        Ok(match self {
+            Accumulator::Count { count, .. } => Value::Integer(count),
+            Accumulator::Sum {
+                function,
+                int_sum,
+                real_sum,
+                approx,
+                overflow,
+                count,
+            } => match function {
+                SumFunction::Total => Value::Real(real_sum),
+                _ if count == 0 => Value::Null,
+                SumFunction::Avg => Value::Real(real_sum / count as f64),
+                SumFunction::Sum if approx => Value::Real(real_sum),
+                SumFunction::Sum if overflow => return Err(anyhow!("integer overflow")),
+                SumFunction::Sum => Value::Integer(int_sum),
+            },
+            Accumulator::Extreme { value, .. } => value.unwrap_or(Value::Null),
+            Accumulator::GroupConcat { text } => text.map_or(Value::Null, Value::Text),
+        })
+    }
+
+    fn is_extreme(&self) -> bool {
+        matches!(self, Accumulator::Extreme { .. })
+    }
+
+    /// Approximate bytes the accumulator holds outside itself
+    fn heap_size(&self) -> usize {
+        match self {
+            Accumulator::Extreme {
+                value: Some(Value::Text(text)),
+                ..
+            } => text.len(),
+            Accumulator::Extreme {
+                value: Some(Value::Blob(blob)),
+                ..
+            } => blob.len(),
+            Accumulator::GroupConcat { text: Some(text) } => text.capacity(),
+            _ => 0,
+        }
+    }
+}
+
+/// The value of each aggregate call, keyed by the call itself
+pub type AggregateValues<'a> = Vec<(&'a FunctionCall, Value)>;
+
+/// The aggregate calls of a query and their accumulators
+pub struct Aggregates<'a> {
+    calls: Vec<(&'a FunctionCall, Accumulator)>,
+    /// Index of the row bare columns are taken from
+    source_row: Option<usize>,
+}
+
+/// Fails if `expr` calls an aggregate function, for clauses evaluated once per
+/// row such as WHERE
+pub fn reject_aggregates(expr: &Expression) -> Result<()> {
+    let mut found = Vec::new();
+    find_aggregates(expr, &mut found);
+    match found.first() {
+        Some(call) => Err(anyhow!("misuse of aggregate: {}()", call.name)),
+        None => Ok(()),
+    }
+}
+
+impl<'a> Aggregates<'a> {
+    /// Finds the aggregate calls in `exprs`
+    pub fn collect(exprs: impl IntoIterator<Item = &'a Expression>) -> Result<Self> {
+        let mut found = Vec::new();
+        for expr in exprs {
+            find_aggregates(expr, &mut found);
+        }
+        let calls = found
+            .into_iter()
+            .map(|call| Ok((call, Accumulator::new(call)?)))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self {
+            calls,
+            source_row: None,
+        })
+    }
+
+    /// Returns true if the query has no aggregate calls
+    pub fn is_empty(&self) -> bool {
+        self.calls.is_empty()
+    }
+
+    /// Approximate bytes of memory the accumulators use
+    pub fn memory_size(&self) -> usize {
+        std::mem::size_of::<Self>()
+            + self
+                .calls
+                .iter()
+                .map(|(_, accumulator)| {
+                    std::mem::size_of::<(&FunctionCall, Accumulator)>() + accumulator.heap_size()
+                })
+                .sum::<usize>()
+    }
+
+    /// Feeds the `row_index`-th row to every aggregate whose filter it passes
+    pub fn step(&mut self, row_index: usize, ctx: &RowContext, rng: &mut Rng) -> Result<()> {
+        let lone_extreme = self.calls.len() == 1 && self.calls[0].1.is_extreme();
+        if self.source_row.is_none() {
+            self.source_row = Some(row_index);
+        }
+
+        for (call, accumulator) in &mut self.calls {
+            if let Some(filter) = &call.filter {
+                // Rows whose filter is NULL are skipped like false ones
+                if evaluate(filter, ctx, rng)?.truth() != Some(true) {
+                    continue;
+                }
+            }
+            let args = match call.args.as_slice() {
+                [Expression::Asterisk] => Vec::new(),
+                args => args
+                    .iter()
+                    .map(|arg| evaluate(arg, ctx, rng))
+                    .collect::<Result<Vec<_>>>()?,
+            };
+            // min() and max() compare under the collation of their argument
+            let collation = call
+                .args
+                .first()
+                .and_then(|arg| operand_collation(arg, ctx))
+                .unwrap_or_default();
+            if accumulator.step(&args, collation, ctx.encoding)? && lone_extreme {
+                self.source_row = Some(row_index);
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the index of the source row for bare columns, if there were any
+    /// rows, and the value of each aggregate call
+    pub fn finish(self) -> Result<(Option<usize>, AggregateValues<'a>)> {
+        let values = self
+            .calls
+            .into_iter()
+            .map(|(call, accumulator)| Ok((call, accumulator.finish()?)))
+            .collect::<Result<Vec<_>>>()?;
+        Ok((self.source_row, values))
+    }
+}
+
+/// Collects the outermost aggregate calls of `expr`
+///
+/// Aggregates nested inside another aggregate's arguments are left for the
+/// evaluator to reject.
+fn find_aggregates<'a>(expr: &'a Expression, found: &mut Vec<&'a FunctionCall>) {
+    match expr {
+        Expression::Function(call) if functions::is_aggregate(&call.name, call.args.len()) => {
+            found.push(call)
+        }
+        Expression::Function(call) => {
+            for arg in &call.args {
+                find_aggregates(arg, found);
+            }
+        }
+        Expression::Unary { expr, .. } => find_aggregates(expr, found),
+        Expression::Binary { left, right, .. } => {
+            find_aggregates(left, found);
+            find_aggregates(right, found);
+        }
+        Expression::Row(items) => {
+            for item in items {
+                find_aggregates(item, found);
+            }
+        }
+        Expression::InList { expr, list } => {
+            find_aggregates(expr, found);
+            for item in list {
+                find_aggregates(item, found);
+            }
+        }
+        Expression::Between { expr, low, high } => {
+            for expr in [expr, low, high] {
+                find_aggregates(expr, found);
+            }
+        }
+        Expression::Like {
+            expr,
+            pattern,
+            escape,
+        } => {
+            for expr in [expr, pattern].into_iter().chain(escape) {
+                find_aggregates(expr, found);
+            }
+        }
+        Expression::Case {
+            operand,
+            branches,
+            else_result,
+        } => {
+            for expr in operand.iter().chain(else_result) {
+                find_aggregates(expr, found);
+            }
+            for (when, then) in branches {
+                find_aggregates(when, found);
+                find_aggregates(then, found);
+            }
+        }
+        // A subquery's aggregates belong to the subquery
+        Expression::Asterisk
+        | Expression::Column { .. }
+        | Expression::Literal(_)
+        | Expression::Parameter(_)
+        | Expression::Exists(_) => {}
+    }
+}
@@ -0,0 +1,206 @@
+//! Query Result Cache
+//!
+//! An optional per-connection cache of query results. Entries are keyed by the
+//! normalized SQL text, the bound parameters and the database's file change
+//! counter, so any write to the file (which bumps the counter) makes older entries
+//! unreachable. SQLite does not bump the counter for commits to the WAL, so in
+//! WAL mode the cache is cleared whenever the WAL has changed, or may have.
+
+use crate::sqlite::parser::statement::{Command, Statement};
+use crate::sqlite::query::execute::ExecuteResult;
+use crate::sqlite::storage::db::SQLiteDatabase;
+use crate::sqlite::storage::metrics::Metrics;
+use anyhow::Result;
+use std::collections::{HashMap, VecDeque};
+use tracing::debug;
+
+/// Identifies a cached result
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CacheKey {
+    /// SQL text after normalization
+    pub sql: String,
+    /// Rendered values of the bound parameters
+    pub params: Vec<String>,
+    /// File change counter when the result was computed
+    pub change_counter: u32,
+}
+
+impl CacheKey {
+    pub fn new(sql: &str, params: Vec<String>, change_counter: u32) -> Self {
+        Self {
+            sql: normalize_sql(sql),
+            params,
+            change_counter,
+        }
+    }
+}
+
+/// Normalizes SQL so trivially different spellings share a cache entry
+///
+/// Outside string literals, runs of whitespace collapse to one space and letters
+/// are lowercased; trailing semicolons are dropped.
+pub fn normalize_sql(sql: &str) -> String {
+    let mut normalized = String::with_capacity(sql.len());
+    let mut in_string = false;
+    let mut pending_space = false;
+
+    for c in sql.trim().trim_end_matches(';').trim_end().chars() {
+        if in_string {
+            normalized.push(c);
+            if c == '\'' {
+                in_string = false;
+            }
+        } else if c.is_whitespace() {
+            pending_space = true;
+        } else {
+            if pending_space {
+                normalized.push(' ');
+                pending_space = false;
+            }
+            if c == '\'' {
+                in_string = true;
+            }
+            normalized.extend(c.to_lowercase());
+        }
+    }
+
+    normalized
+}
+
+/// Bounded least-recently-used cache of query results
+pub struct ResultCache {
+    capacity: usize,
+    entries: HashMap<CacheKey, ExecuteResult>,
+    /// Keys from least to most recently used
+    recency: VecDeque<CacheKey>,
+}
+
+impl ResultCache {
+    /// Creates a cache holding at most `capacity` results
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: HashMap::new(),
+            recency: VecDeque::new(),
+        }
+    }
+
+    /// Returns the number of cached results
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Returns true if nothing is cached
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Looks up a result, marking it as recently used
+    pub fn get(&mut self, key: &CacheKey) -> Option<ExecuteResult> {
+        let result = self.entries.get(key)?.clone();
+        self.touch(key);
+        Some(result)
+    }
+
+    /// Stores a result, evicting the least recently used entry if full
+    pub fn insert(&mut self, key: CacheKey, result: ExecuteResult) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.entries.insert(key.clone(), result).is_some() {
+            self.touch(&key);
+            return;
+        }
+        self.recency.push_back(key);
+        while self.entries.len() > self.capacity {
+            if let Some(oldest) = self.recency.pop_front() {
+                self.entries.remove(&oldest);
+            }
+        }
+    }
+
+    /// Drops every cached result
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.recency.clear();
+    }
+
+    fn touch(&mut self, key: &CacheKey) {
+        if let Some(position) = self.recency.iter().position(|k| k == key) {
+            let key = self.recency.remove(position).unwrap();
+            self.recency.push_back(key);
+        }
+    }
+}
+
+impl SQLiteDatabase {
+    /// Enables the result cache with room for `capacity` results
+    pub fn enable_result_cache(&mut self, capacity: usize) {
+        self.result_cache = Some(ResultCache::new(capacity));
+    }
+
+    /// Disables the result cache and drops its contents
+    pub fn disable_result_cache(&mut self) {
+        self.result_cache = None;
+    }
+
+    /// Returns the file change counter as of the last header refresh
+    pub fn file_change_counter(&self) -> u32 {
+        self.header.file_change_counter
+    }
+
+    /// Parses and executes SQL, answering SELECTs from the result cache when
+    /// possible
+    pub fn query(&mut self, sql: &str) -> Result<ExecuteResult> {
+        self.traced_query(sql, |db| match Command::parse(sql)? {
+            Command::Select(statement) => db.run_statement(sql, &statement, Vec::new()),
+            command => Ok((db.execute_command(&command)?, false)),
+        })
+    }
+
+    /// Parses and executes SQL as `query` does, returning the counts for it
+    /// along with its result
+    pub fn query_with_stats(&mut self, sql: &str) -> Result<(ExecuteResult, Metrics)> {
+        let result = self.query(sql)?;
+        Ok((result, self.last_query_stats))
+    }
+
+    /// Executes a parsed statement written as `sql` with the rendered bound
+    /// values `params`, returning its result and whether it was served from
+    /// the cache
+    pub(crate) fn run_statement(
+        &mut self,
+        sql: &str,
+        statement: &Statement,
+        params: Vec<String>,
+    ) -> Result<(ExecuteResult, bool)> {
+        debug!("Statement: {:?}", statement);
+        self.refresh_header()?;
+
+        // Virtual table contents live outside the file, so the change counter
+        // says nothing about their freshness; results of random() must not repeat
+        if self.result_cache.is_none()
+            || statement
+                .from_table
+                .as_deref()
+                .map_or(false, |table| self.virtual_table(table).is_some())
+            || !statement.is_deterministic()
+        {
+            return Ok((self.execute(statement)?, false));
+        }
+
+        let key = CacheKey::new(sql, params, self.file_change_counter());
+        if let Some(result) = self.result_cache.as_mut().and_then(|cache| cache.get(&key)) {
+            debug!("Result cache hit for: {}", key.sql);
+            self.metrics.cache_hits += 1;
+            return Ok((result, true));
+        }
+
+        self.metrics.cache_misses += 1;
+        let result = self.execute(statement)?;
+        if let Some(cache) = self.result_cache.as_mut() {
+            cache.insert(key, result.clone());
+        }
+        Ok((result, false))
+    }
+}
@@ -0,0 +1,148 @@
+//! Result Column Metadata
+//!
+//! Each result column gets a name and, when it reads a table column directly,
+//! that column's declared type. Names follow SQLite's defaults:
+//!
+//! - `expr AS alias` is named by its alias
+//! - a column reference is named by the column as the table declares it, so
+//!   `SELECT NAME FROM t` and `SELECT t.name FROM t` both give `name`; the rowid
+//!   is named by its INTEGER PRIMARY KEY column if it has one, and `rowid`
+//!   otherwise
+//! - `*` expands to every column of every table in the FROM clause
+//! - any other expression is named by its text
+//!
+//! An aliased column reference keeps the declared type of the column it reads.
+
+use crate::sqlite::parser::expression::Expression;
+use crate::sqlite::parser::statement::Statement;
+use crate::sqlite::query::eval::is_rowid_alias;
+use crate::sqlite::storage::db::SQLiteDatabase;
+use anyhow::Result;
+
+/// Name and declared type of one result column
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ColumnInfo {
+    pub name: String,
+    /// Declared type of the table column the result column reads, as written
+    /// in CREATE TABLE; None for expressions and columns declared without one
+    pub declared_type: Option<String>,
+}
+
+/// A table in the FROM clause, as seen by column references
+struct SourceTable {
+    /// The table's alias, or its name if it has none
+    name: String,
+    /// Each column's name and declared type
+    columns: Vec<ColumnInfo>,
+    /// Position of the INTEGER PRIMARY KEY column, which names the rowid
+    rowid_alias: Option<usize>,
+}
+
+impl SQLiteDatabase {
+    /// Describes the result columns of a statement, in order
+    pub(crate) fn result_columns(&mut self, stmt: &Statement) -> Result<Vec<ColumnInfo>> {
+        let mut tables = Vec::new();
+        let from = stmt
+            .from_table
+            .iter()
+            .map(|table| (table, stmt.table_alias.as_ref()));
+        let joins = stmt
+            .joins
+            .iter()
+            .map(|join| (&join.table, join.alias.as_ref()));
+        for (table, alias) in from.chain(joins) {
+            tables.push(self.source_table(table, alias)?);
+        }
+
+        let mut columns = Vec::new();
+        for selection in &stmt.selections {
+            match &selection.expr {
+                Expression::Asterisk => columns.extend(
+                    tables
+                        .iter()
+                        .flat_map(|table| table.columns.iter().cloned()),
+                ),
+                Expression::Column { table, name } => {
+                    let mut column =
+                        column_info(&tables, table.as_deref(), name).unwrap_or_else(|| {
+                            ColumnInfo {
+                                name: selection.text.clone(),
+                                declared_type: None,
+                            }
+                        });
+                    if let Some(alias) = &selection.alias {
+                        column.name = alias.clone();
+                    }
+                    columns.push(column);
+                }
+                _ => columns.push(ColumnInfo {
+                    name: selection
+                        .alias
+                        .clone()
+                        .unwrap_or_else(|| selection.text.clone()),
+                    declared_type: None,
+                }),
+            }
+        }
+        Ok(columns)
+    }
+
+    /// Looks up the columns of a table in the FROM clause
+    fn source_table(&mut self, table: &str, alias: Option<&String>) -> Result<SourceTable> {
+        let name = alias.map_or(table, String::as_str).to_string();
+        if let Some(virtual_table) = self.virtual_table(table) {
+            return Ok(SourceTable {
+                name,
+                columns: virtual_table
+                    .columns()
+                    .into_iter()
+                    .map(|name| ColumnInfo {
+                        name,
+                        declared_type: None,
+                    })
+                    .collect(),
+                rowid_alias: None,
+            });
+        }
+        let (schema, _) = self.find_table(table)?;
+        Ok(SourceTable {
+            name,
+            rowid_alias: schema.columns.iter().position(|column| column.rowid_alias),
+            columns: schema
+                .columns
+                .into_iter()
+                .map(|column| ColumnInfo {
+                    name: column.name,
+                    declared_type: Some(column.column_type).filter(|t| !t.is_empty()),
+                })
+                .collect(),
+        })
+    }
+}
+
+/// Resolves a column reference to the column it reads, or None if no table in
+/// the FROM clause has it
+fn column_info(tables: &[SourceTable], qualifier: Option<&str>, name: &str) -> Option<ColumnInfo> {
+    let mut tables = tables.iter().filter(|table| {
+        qualifier.map_or(true, |qualifier| {
+            qualifier.eq_ignore_ascii_case(&table.name)
+        })
+    });
+    tables.find_map(|table| {
+        let column = table
+            .columns
+            .iter()
+            .find(|column| column.name.eq_ignore_ascii_case(name));
+        match column {
+            Some(column) => Some(column.clone()),
+            None if is_rowid_alias(name) => Some(match table.rowid_alias {
+                Some(position) => table.columns[position].clone(),
+                None => ColumnInfo {
+                    name: "rowid".to_string(),
+                    declared_type: None,
+                },
+            }),
+            None => None,
+        }
+    })
+}
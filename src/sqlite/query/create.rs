@@ -0,0 +1,119 @@
+//! CREATE TABLE Statements
+//!
+//! Creating a table allocates the root page of its B-tree, an index B-tree for
+//! a WITHOUT ROWID table, and adds its row to sqlite_schema with the statement
+//! as its `sql`. The definition is read back with [`TableSchema::parse`], as it
+//! will be every time the table is used, so a table that could not be read is
+//! never created.
+//!
+//! [`TableSchema::parse`]: crate::sqlite::core::schema::TableSchema::parse
+//!
+//! Every PRIMARY KEY and UNIQUE constraint gets an automatic index, as in
+//! SQLite: an empty index B-tree and a row in sqlite_schema named
+//! `sqlite_autoindex_<table>_<N>`, numbered in declaration order, with no
+//! `sql`. A constraint on the same columns as an earlier one gets no index and
+//! no number. The primary key of a WITHOUT ROWID table takes its number but
+//! needs no index, since the table is keyed by it; neither does an INTEGER
+//! PRIMARY KEY, which stores the rowid.
+//!
+//! Writing to sqlite_schema bumps the schema cookie as the transaction commits.
+
+use crate::sqlite::core::record::RecordBuilder;
+use crate::sqlite::core::schema::TableSchema;
+use crate::sqlite::core::value::Value;
+use crate::sqlite::parser::statement::CreateTable;
+use crate::sqlite::storage::db::SQLiteDatabase;
+use anyhow::{anyhow, Result};
+use tracing::debug;
+
+impl SQLiteDatabase {
+    /// Runs a CREATE TABLE statement
+    pub fn create_table(&mut self, create: &CreateTable) -> Result<()> {
+        self.write_transaction(|db| db.apply_create_table(create))
+    }
+
+    /// Creates the table without committing
+    fn apply_create_table(&mut self, create: &CreateTable) -> Result<()> {
+        let name = &create.table;
+        if name
+            .get(..7)
+            .map_or(false, |prefix| prefix.eq_ignore_ascii_case("sqlite_"))
+        {
+            return Err(anyhow!("object name reserved for internal use: {}", name));
+        }
+        for entry in self.schema_entries()? {
+            // Columns: type, name, tbl_name, rootpage, sql
+            let [Value::Text(kind), Value::Text(existing), ..] = entry.values.as_slice() else {
+                continue;
+            };
+            if !existing.eq_ignore_ascii_case(name) {
+                continue;
+            }
+            if kind == "index" {
+                return Err(anyhow!("there is already an index named {}", name));
+            }
+            if create.if_not_exists {
+                return Ok(());
+            }
+            return Err(anyhow!("{} {} already exists", kind, name));
+        }
+
+        let schema = TableSchema::parse(name.clone(), create.sql.clone())?;
+        if schema.columns.is_empty() {
+            return Err(anyhow!("table {} has no columns", name));
+        }
+        for (i, column) in schema.columns.iter().enumerate() {
+            if schema.columns[..i]
+                .iter()
+                .any(|earlier| earlier.name.eq_ignore_ascii_case(&column.name))
+            {
+                return Err(anyhow!("duplicate column name: {}", column.name));
+            }
+            column.default_expression()?;
+        }
+        if schema.without_rowid && schema.primary_key.is_empty() {
+            return Err(anyhow!("PRIMARY KEY missing on table {}", name));
+        }
+
+        debug!("Creating table {}", name);
+        let root_page = self.create_btree(schema.without_rowid)?;
+        self.add_schema_row(
+            "table",
+            name,
+            name,
+            root_page,
+            Value::Text(create.sql.clone()),
+        )?;
+
+        for (number, key) in schema.automatic_index_keys() {
+            let columns: Vec<usize> = key.iter().map(|&(position, _)| position).collect();
+            if schema.without_rowid && columns == schema.primary_key {
+                continue;
+            }
+            let index_name = format!("sqlite_autoindex_{}_{}", name, number);
+            let index_root = self.create_btree(true)?;
+            self.add_schema_row("index", &index_name, name, index_root, Value::Null)?;
+        }
+        Ok(())
+    }
+
+    /// Adds a row to sqlite_schema for a table or index on `table`
+    fn add_schema_row(
+        &mut self,
+        kind: &str,
+        name: &str,
+        table: &str,
+        root_page: u32,
+        sql: Value,
+    ) -> Result<()> {
+        let mut builder = RecordBuilder::for_database(&self.header);
+        builder.push(&Value::Text(kind.to_string()));
+        builder.push(&Value::Text(name.to_string()));
+        builder.push(&Value::Text(table.to_string()));
+        builder.push(&Value::Integer(root_page as i64));
+        builder.push(&sql);
+        let record = builder.build();
+        let rowid = self.next_rowid(1)?;
+        self.insert_record(1, rowid, &record)
+    }
+}
@@ -0,0 +1,49 @@
+//! DELETE Statements
+//!
+//! A DELETE runs in two passes, like an UPDATE. The first finds the rows the
+//! WHERE clause accepts, using the same scans and lookups as a SELECT; the
+//! second removes each of those rows from the table B-tree and its entries
+//! from the table's indexes. Both finish before the transaction commits, so an
+//! error part way leaves the file untouched.
+//!
+//! A DELETE without a WHERE clause skips the scan and truncates the table
+//! B-tree instead, and each index B-tree with it: every page but the root goes
+//! on the freelist and the root is emptied, as SQLite's truncate optimization
+//! does.
+//!
+//! Not supported yet: WITHOUT ROWID tables, tables with partial or expression
+//! indexes and EXISTS subqueries.
+
+use crate::sqlite::parser::statement::Delete;
+use crate::sqlite::query::update::check_write_expression;
+use crate::sqlite::storage::db::SQLiteDatabase;
+use anyhow::Result;
+use tracing::debug;
+
+impl SQLiteDatabase {
+    /// Runs a DELETE statement, returning the number of rows it deleted
+    pub fn delete(&mut self, delete: &Delete) -> Result<usize> {
+        self.write_transaction(|db| db.apply_delete(delete))
+    }
+
+    /// Deletes the matching rows without committing
+    fn apply_delete(&mut self, delete: &Delete) -> Result<usize> {
+        let (schema, root_page, indexes) = self.writable_table(&delete.table, "DELETE")?;
+        let Some(condition) = &delete.where_clause else {
+            debug!("Truncating {}", schema.name);
+            for index in &indexes {
+                self.clear_table(index.root_page)?;
+            }
+            return Ok(self.clear_table(root_page)? as usize);
+        };
+        check_write_expression(condition, "DELETE")?;
+
+        let (_, rows) = self.scan_table(&delete.table, Some(condition), None)?;
+        debug!("Deleting {} rows of {}", rows.len(), schema.name);
+        for row in &rows {
+            self.remove_index_entries(&schema, &indexes, row)?;
+            self.delete_record(root_page, row.rowid)?;
+        }
+        Ok(rows.len())
+    }
+}
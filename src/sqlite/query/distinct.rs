@@ -0,0 +1,35 @@
+//! SELECT DISTINCT
+//!
+//! Duplicate result rows are dropped right after projection, before ORDER BY
+//! and LIMIT see them, by remembering a hash of every row already produced.
+//! Rows are compared by their typed values the way GROUP BY compares keys: 1
+//! and 1.0 are duplicates and so are two NULLs, but 1 and '1' are not. Each
+//! result column that is a column of a table compares under its collation, so
+//! 'a' and 'A' are duplicates in a NOCASE column, and the first row is kept.
+
+use crate::sqlite::core::collation::Collation;
+use crate::sqlite::core::value::Value;
+use crate::sqlite::query::group::encode_key;
+use std::collections::HashSet;
+
+/// Remembers the result rows seen so far
+#[derive(Default)]
+pub struct Dedup {
+    seen: HashSet<Vec<u8>>,
+    /// Collation of each result column; BINARY past its end
+    collations: Vec<Collation>,
+}
+
+impl Dedup {
+    pub fn new(collations: Vec<Collation>) -> Self {
+        Self {
+            seen: HashSet::new(),
+            collations,
+        }
+    }
+
+    /// Returns true if no equal row was offered before
+    pub fn insert(&mut self, row: &[Value]) -> bool {
+        self.seen.insert(encode_key(row, &self.collations))
+    }
+}
@@ -0,0 +1,1004 @@
+//! Expression Evaluation
+//!
+//! Evaluates parsed expressions against a single table row, producing a `Value`.
+//! Function arguments are evaluated left to right before the function is called.
+//!
+//! Comparisons yield 1, 0 or NULL. Either side may be a row value such as
+//! `(a, b)`, compared element by element: `=` and `!=` are decided by any unequal
+//! pair, the ordering operators by the first unequal pair, and a NULL met before
+//! the result is decided makes the whole comparison NULL. `IS` and `IS NOT` treat
+//! NULL as a value equal only to itself, so they never yield NULL.
+//!
+//! NULL means "unknown" throughout: operators and comparisons with a NULL operand
+//! yield NULL, while AND, OR and NOT follow Kleene logic, so `NULL AND 0` is 0 and
+//! `NULL OR 1` is 1. AND and OR skip their right operand once the left decides the
+//! result. Callers filtering rows must treat a NULL result as false.
+//!
+//! Text compares under the collation of the left operand if it is a column,
+//! else of the right operand if that is, else under BINARY; `x IN (...)` uses
+//! the collation of `x` alone. BINARY compares text as the database encodes it.
+//!
+//! CASE evaluates only the branch it selects. A WHEN condition that is NULL does
+//! not match, and neither does a WHEN value compared to a NULL operand.
+//!
+//! `EXISTS (SELECT ...)` probes rows read before the query started (see
+//! `SubqueryRows`) and stops at the first one passing the subquery's WHERE
+//! clause. Columns the subquery's tables lack are looked up in the row of the
+//! enclosing query, which makes correlated subqueries work.
+
+use crate::sqlite::core::collation::Collation;
+use crate::sqlite::core::header::TextEncoding;
+use crate::sqlite::core::schema::{Affinity, ColumnDef, TableSchema};
+use crate::sqlite::core::value::Value;
+use crate::sqlite::parser::expression::{BinaryOperator, Expression, FunctionCall, UnaryOperator};
+use crate::sqlite::parser::statement::Statement;
+use crate::sqlite::query::functions::{self, Rng};
+use crate::sqlite::query::limit::Bounds;
+use crate::sqlite::query::scan::Row;
+use anyhow::{anyhow, Result};
+use std::cmp::Ordering;
+
+/// Where one table of a join sits in a joined row
+///
+/// A joined row holds the columns of every table side by side, in FROM clause
+/// order, followed by one rowid per table.
+#[derive(Debug)]
+pub struct JoinedTable {
+    /// The name qualified references use: the table's alias, or else its name
+    pub name: String,
+    /// Index of the table's first column in the joined row
+    pub first_column: usize,
+    pub column_count: usize,
+    /// Index of the table's rowid in the joined row
+    pub rowid_index: usize,
+}
+
+/// The rows a subquery's FROM clause produces, read once before the query
+/// containing the subquery runs
+pub struct SubqueryRows<'a> {
+    pub statement: &'a Statement,
+    pub schema: TableSchema,
+    /// Rows in the joined row layout of `tables`; a subquery without FROM has a
+    /// single column-less row
+    pub rows: Vec<Row>,
+    pub tables: Vec<JoinedTable>,
+    /// True for a subquery that aggregates, which returns exactly one row
+    /// whatever its WHERE clause rejects
+    pub single_row: bool,
+    /// The subquery's evaluated LIMIT clause
+    pub bounds: Option<Bounds>,
+}
+
+/// The row an expression is evaluated against
+pub struct RowContext<'a> {
+    pub schema: &'a TableSchema,
+    /// Encoding of the database the row was read from, which orders text
+    pub encoding: TextEncoding,
+    /// The current row; None for the result row of an aggregate query over no rows,
+    /// where every column is NULL
+    pub row: Option<&'a Row>,
+    /// Values of the aggregate calls, once aggregation has finished
+    pub aggregates: &'a [(&'a FunctionCall, Value)],
+    /// The tables making up a joined row; empty when reading a single table
+    pub tables: &'a [JoinedTable],
+    /// Rows of the EXISTS subqueries the expression may contain
+    pub subqueries: &'a [SubqueryRows<'a>],
+    /// The row of the enclosing query, when evaluating inside a subquery
+    pub outer: Option<&'a RowContext<'a>>,
+}
+
+impl<'a> RowContext<'a> {
+    /// Creates the context for evaluating expressions against a row of a
+    /// database storing text in `encoding`
+    pub fn new(schema: &'a TableSchema, row: &'a Row, encoding: TextEncoding) -> Self {
+        Self {
+            schema,
+            encoding,
+            row: Some(row),
+            aggregates: &[],
+            tables: &[],
+            subqueries: &[],
+            outer: None,
+        }
+    }
+
+    /// Treats the row as a joined row made up of `tables`
+    pub fn joined(self, tables: &'a [JoinedTable]) -> Self {
+        Self { tables, ..self }
+    }
+
+    /// Makes the rows of the statement's subqueries available to EXISTS
+    pub fn with_subqueries(self, subqueries: &'a [SubqueryRows<'a>]) -> Self {
+        Self { subqueries, ..self }
+    }
+
+    /// Looks up the value of an aggregate call
+    fn aggregate(&self, call: &FunctionCall) -> Result<Value> {
+        self.aggregates
+            .iter()
+            .find(|(aggregate, _)| std::ptr::eq(*aggregate, call))
+            .map(|(_, value)| value.clone())
+            .ok_or_else(|| anyhow!("misuse of aggregate function {}()", call.name))
+    }
+
+    /// Looks up a column by case-insensitive name, falling back to the rowid
+    /// aliases and then to the rows of enclosing queries
+    fn column(&self, qualifier: Option<&str>, name: &str) -> Result<Value> {
+        let mut ctx = Some(self);
+        while let Some(current) = ctx {
+            if let Some(value) = current.own_column(qualifier, name)? {
+                return Ok(value);
+            }
+            ctx = current.outer;
+        }
+        Err(match qualifier {
+            Some(qualifier) => anyhow!("no such column: {}.{}", qualifier, name),
+            None => anyhow!("no such column: {}", name),
+        })
+    }
+
+    /// Looks up a column of this context's own row, returning None if it has no
+    /// such column
+    ///
+    /// In a joined row an unqualified name must belong to exactly one table.
+    /// Otherwise the qualifier has already been checked against the FROM clause.
+    fn own_column(&self, qualifier: Option<&str>, name: &str) -> Result<Option<Value>> {
+        if !self.tables.is_empty() {
+            return self.joined_column(qualifier, name);
+        }
+        if let Some(index) = self
+            .schema
+            .columns
+            .iter()
+            .position(|col| col.name.eq_ignore_ascii_case(name))
+        {
+            return Ok(Some(
+                self.row
+                    .and_then(|row| row.values.get(index).cloned())
+                    .unwrap_or(Value::Null),
+            ));
+        }
+        if is_rowid_alias(name) && !self.schema.columns.is_empty() && !self.schema.without_rowid {
+            return Ok(Some(
+                self.row
+                    .map_or(Value::Null, |row| Value::Integer(row.rowid)),
+            ));
+        }
+        Ok(None)
+    }
+
+    /// Returns the affinity of a column, searching the same rows as `column`
+    ///
+    /// The rowid and its aliases have INTEGER affinity. Unknown and ambiguous
+    /// names are left for `column` to report.
+    fn column_affinity(&self, qualifier: Option<&str>, name: &str) -> Option<Affinity> {
+        self.column_def(qualifier, name)
+            .map(|column| column.map_or(Affinity::Integer, |column| column.affinity))
+    }
+
+    /// Returns the collation of a column, searching the same rows as `column`
+    ///
+    /// The rowid and its aliases, and columns naming a collation that is not
+    /// built in, compare with BINARY. Unknown and ambiguous names are left for
+    /// `column` to report.
+    fn column_collation(&self, qualifier: Option<&str>, name: &str) -> Option<Collation> {
+        self.column_def(qualifier, name)
+            .map(|column| column.map_or(Collation::Binary, ColumnDef::collating_sequence))
+    }
+
+    /// Finds the definition of a column, searching the same rows as `column`;
+    /// Some(None) for the rowid and its aliases
+    fn column_def(&self, qualifier: Option<&str>, name: &str) -> Option<Option<&ColumnDef>> {
+        let mut ctx = Some(self);
+        while let Some(current) = ctx {
+            let columns = &current.schema.columns;
+            let (column, searched) = if current.tables.is_empty() {
+                find_column([columns.as_slice()], name)
+            } else {
+                let tables = current
+                    .tables
+                    .iter()
+                    .filter(|table| qualifier.map_or(true, |q| table.name.eq_ignore_ascii_case(q)))
+                    .map(|table| {
+                        &columns[table.first_column..table.first_column + table.column_count]
+                    });
+                find_column(tables, name)
+            };
+            if column.is_some() {
+                return Some(column);
+            }
+            if is_rowid_alias(name) && searched {
+                return Some(None);
+            }
+            ctx = current.outer;
+        }
+        None
+    }
+
+    /// Looks up a column of a joined row
+    fn joined_column(&self, qualifier: Option<&str>, name: &str) -> Result<Option<Value>> {
+        let mut found = None;
+        for table in self.tables {
+            if qualifier.map_or(false, |q| !table.name.eq_ignore_ascii_case(q)) {
+                continue;
+            }
+            let columns =
+                &self.schema.columns[table.first_column..table.first_column + table.column_count];
+            let index = match columns
+                .iter()
+                .position(|col| col.name.eq_ignore_ascii_case(name))
+            {
+                Some(index) => table.first_column + index,
+                // As in SQLite, a bare rowid names no column once there are
+                // several tables to choose from
+                None if is_rowid_alias(name) && (qualifier.is_some() || self.tables.len() == 1) => {
+                    table.rowid_index
+                }
+                None => continue,
+            };
+            if found.replace(index).is_some() {
+                return Err(match qualifier {
+                    Some(qualifier) => anyhow!("ambiguous column name: {}.{}", qualifier, name),
+                    None => anyhow!("ambiguous column name: {}", name),
+                });
+            }
+        }
+        Ok(found.map(|index| {
+            self.row
+                .and_then(|row| row.values.get(index).cloned())
+                .unwrap_or(Value::Null)
+        }))
+    }
+
+    /// Reports whether a subquery returns any row for the current row
+    fn exists(&self, subquery: &Statement, rng: &mut Rng) -> Result<bool> {
+        let found = self
+            .subqueries
+            .iter()
+            .find(|rows| std::ptr::eq(rows.statement, subquery))
+            .ok_or_else(|| anyhow!("subqueries are not supported here"))?;
+        // The subquery returns a row once more rows match than OFFSET skips
+        let needed = match found.bounds {
+            Some(bounds) if bounds.count == 0 => return Ok(false),
+            Some(bounds) => bounds.offset + 1,
+            None => 1,
+        };
+        if found.single_row {
+            return Ok(needed == 1);
+        }
+        let mut matches = 0;
+        for row in &found.rows {
+            let inner = RowContext {
+                schema: &found.schema,
+                encoding: self.encoding,
+                row: Some(row),
+                aggregates: &[],
+                tables: &found.tables,
+                subqueries: self.subqueries,
+                outer: Some(self),
+            };
+            let matched = match &subquery.where_clause {
+                // NULL counts as false
+                Some(condition) => evaluate(condition, &inner, rng)?.truth() == Some(true),
+                None => true,
+            };
+            if matched {
+                matches += 1;
+                if matches == needed {
+                    return Ok(true);
+                }
+            }
+        }
+        Ok(false)
+    }
+}
+
+/// Finds a column by case-insensitive name among the columns of some tables,
+/// also returning whether there were any columns to search
+fn find_column<'c>(
+    tables: impl IntoIterator<Item = &'c [ColumnDef]>,
+    name: &str,
+) -> (Option<&'c ColumnDef>, bool) {
+    let mut searched = false;
+    for columns in tables {
+        searched |= !columns.is_empty();
+        if let Some(column) = columns
+            .iter()
+            .find(|column| column.name.eq_ignore_ascii_case(name))
+        {
+            return (Some(column), true);
+        }
+    }
+    (None, searched)
+}
+
+/// Returns true for the names that refer to the rowid: rowid, oid and _rowid_
+pub(crate) fn is_rowid_alias(name: &str) -> bool {
+    ["rowid", "oid", "_rowid_"]
+        .iter()
+        .any(|alias| alias.eq_ignore_ascii_case(name))
+}
+
+/// Evaluates an expression that refers to no column, such as a value of VALUES
+/// or a DEFAULT clause, against no row
+pub fn evaluate_constant(
+    expr: &Expression,
+    encoding: TextEncoding,
+    rng: &mut Rng,
+) -> Result<Value> {
+    let no_table = TableSchema {
+        name: String::new(),
+        columns: Vec::new(),
+        sql: String::new(),
+        without_rowid: false,
+        primary_key: Vec::new(),
+        unique_keys: Vec::new(),
+    };
+    let no_row = Row {
+        rowid: 0,
+        values: Vec::new(),
+    };
+    let ctx = RowContext::new(&no_table, &no_row, encoding);
+    evaluate(expr, &ctx, rng)
+}
+
+/// Evaluates `expr` for one row
+pub fn evaluate(expr: &Expression, ctx: &RowContext, rng: &mut Rng) -> Result<Value> {
+    match expr {
+        Expression::Literal(value) => Ok(value.clone()),
+        // Bound parameters are replaced by literals before the statement runs
+        Expression::Parameter(_) => Ok(Value::Null),
+        Expression::Column { table, name } => ctx.column(table.as_deref(), name),
+        Expression::Function(call) if functions::is_aggregate(&call.name, call.args.len()) => {
+            ctx.aggregate(call)
+        }
+        Expression::Function(FunctionCall { name, args, filter }) => {
+            if filter.is_some() {
+                return Err(anyhow!(
+                    "FILTER may not be used with non-aggregate {}()",
+                    name
+                ));
+            }
+            // Functions that compare their arguments, such as min(), use the
+            // collation of the first argument that is a column
+            let collation = if functions::compares_arguments(name) {
+                args.iter().find_map(|arg| operand_collation(arg, ctx))
+            } else {
+                None
+            };
+            let collation = collation.unwrap_or_default();
+            let args = args
+                .iter()
+                .map(|arg| evaluate(arg, ctx, rng))
+                .collect::<Result<Vec<_>>>()?;
+            functions::call_scalar(name, &args, collation, ctx.encoding, rng)
+        }
+        Expression::Unary { op, expr } => {
+            let value = evaluate(expr, ctx, rng)?;
+            Ok(match op {
+                UnaryOperator::BitNot => value
+                    .to_integer()
+                    .map_or(Value::Null, |i| Value::Integer(!i)),
+                UnaryOperator::Not => Value::from_truth(value.truth().map(|truth| !truth)),
+                UnaryOperator::Negate => match value.to_numeric() {
+                    Value::Integer(i) => i
+                        .checked_neg()
+                        .map_or(Value::Real(-(i as f64)), Value::Integer),
+                    Value::Real(r) => Value::Real(-r),
+                    _ => Value::Null,
+                },
+            })
+        }
+        Expression::Binary {
+            left,
+            op: op @ (BinaryOperator::And | BinaryOperator::Or),
+            right,
+        } => {
+            // The value that decides the result on its own: false for AND, true for OR
+            let decisive = *op == BinaryOperator::Or;
+            let left = evaluate(left, ctx, rng)?.truth();
+            if left == Some(decisive) {
+                return Ok(Value::from_truth(left));
+            }
+            let right = evaluate(right, ctx, rng)?.truth();
+            Ok(Value::from_truth(match (left, right) {
+                (_, Some(r)) if r == decisive => Some(decisive),
+                (Some(_), Some(_)) => Some(!decisive),
+                _ => None,
+            }))
+        }
+        Expression::Binary { left, op, right } if !op.is_comparison() => {
+            let left = evaluate(left, ctx, rng)?;
+            let right = evaluate(right, ctx, rng)?;
+            Ok(apply_operator(*op, &left, &right))
+        }
+        Expression::Binary {
+            left: left_expr,
+            op,
+            right: right_expr,
+        } => {
+            let mut left = evaluate_operand(left_expr, ctx, rng)?;
+            let mut right = evaluate_operand(right_expr, ctx, rng)?;
+            if left.len() != right.len() {
+                return Err(anyhow!("row value misused"));
+            }
+            coerce_operands(&mut left, left_expr, &mut right, right_expr, ctx);
+            let collation = |i| comparison_collation(left_expr, right_expr, i, ctx);
+            Ok(Value::from_truth(compare_rows(
+                &left,
+                *op,
+                &right,
+                collation,
+                ctx.encoding,
+            )))
+        }
+        // True if any entry is equal, else NULL if the value or any entry is
+        // NULL, else false; an empty list is always false
+        Expression::InList { expr, list } => {
+            let value = evaluate_operand(expr, ctx, rng)?;
+            let collation = |i| {
+                operand_item(expr, i)
+                    .and_then(|item| operand_collation(item, ctx))
+                    .unwrap_or_default()
+            };
+            let mut saw_null = false;
+            for item in list {
+                let mut left = value.clone();
+                let mut right = evaluate_operand(item, ctx, rng)?;
+                if left.len() != right.len() {
+                    return Err(anyhow!(
+                        "IN list has {} values, expected {}",
+                        right.len(),
+                        left.len()
+                    ));
+                }
+                coerce_operands(&mut left, expr, &mut right, item, ctx);
+                match compare_rows(&left, BinaryOperator::Eq, &right, collation, ctx.encoding) {
+                    Some(true) => return Ok(Value::Integer(1)),
+                    Some(false) => {}
+                    None => saw_null = true,
+                }
+            }
+            Ok(Value::from_truth(if saw_null { None } else { Some(false) }))
+        }
+        // `low <= expr AND expr <= high`, with expr evaluated once
+        Expression::Between {
+            expr,
+            low: low_expr,
+            high: high_expr,
+        } => {
+            let value = evaluate_operand(expr, ctx, rng)?;
+            let mut low = evaluate_operand(low_expr, ctx, rng)?;
+            let mut high = evaluate_operand(high_expr, ctx, rng)?;
+            if value.len() != low.len() || value.len() != high.len() {
+                return Err(anyhow!("row value misused"));
+            }
+            let mut above_value = value.clone();
+            coerce_operands(&mut above_value, expr, &mut low, low_expr, ctx);
+            let mut below_value = value;
+            coerce_operands(&mut below_value, expr, &mut high, high_expr, ctx);
+            let above = compare_rows(
+                &above_value,
+                BinaryOperator::GtEq,
+                &low,
+                |i| comparison_collation(expr, low_expr, i, ctx),
+                ctx.encoding,
+            );
+            let below = compare_rows(
+                &below_value,
+                BinaryOperator::LtEq,
+                &high,
+                |i| comparison_collation(expr, high_expr, i, ctx),
+                ctx.encoding,
+            );
+            Ok(Value::from_truth(match (above, below) {
+                (Some(false), _) | (_, Some(false)) => Some(false),
+                (Some(true), Some(true)) => Some(true),
+                _ => None,
+            }))
+        }
+        Expression::Like {
+            expr,
+            pattern,
+            escape,
+        } => {
+            let text = evaluate(expr, ctx, rng)?;
+            let pattern = evaluate(pattern, ctx, rng)?;
+            let escape = match escape {
+                Some(escape) => match evaluate(escape, ctx, rng)?.to_text() {
+                    Some(escape) => {
+                        let mut chars = escape.chars();
+                        match (chars.next(), chars.next()) {
+                            (Some(c), None) => Some(c),
+                            _ => {
+                                return Err(anyhow!("ESCAPE expression must be a single character"))
+                            }
+                        }
+                    }
+                    None => return Ok(Value::Null),
+                },
+                None => None,
+            };
+            Ok(match (text.to_text(), pattern.to_text()) {
+                (Some(text), Some(pattern)) => Value::from_truth(Some(like(
+                    &pattern.chars().collect::<Vec<_>>(),
+                    &text.chars().collect::<Vec<_>>(),
+                    escape,
+                ))),
+                _ => Value::Null,
+            })
+        }
+        Expression::Case {
+            operand,
+            branches,
+            else_result,
+        } => {
+            let operand = match operand {
+                Some(operand) => Some((operand, evaluate(operand, ctx, rng)?)),
+                None => None,
+            };
+            for (when_expr, then) in branches {
+                let when = evaluate(when_expr, ctx, rng)?;
+                let matched = match &operand {
+                    // Compared like `operand = when`
+                    Some((operand_expr, operand)) => {
+                        let mut left = [operand.clone()];
+                        let mut right = [when];
+                        coerce_operands(&mut left, operand_expr, &mut right, when_expr, ctx);
+                        let collation = |i| comparison_collation(operand_expr, when_expr, i, ctx);
+                        let equal = compare_rows(
+                            &left,
+                            BinaryOperator::Eq,
+                            &right,
+                            collation,
+                            ctx.encoding,
+                        );
+                        equal == Some(true)
+                    }
+                    None => when.truth() == Some(true),
+                };
+                if matched {
+                    return evaluate(then, ctx, rng);
+                }
+            }
+            match else_result {
+                Some(else_result) => evaluate(else_result, ctx, rng),
+                None => Ok(Value::Null),
+            }
+        }
+        Expression::Exists(subquery) => Ok(Value::from_truth(Some(ctx.exists(subquery, rng)?))),
+        Expression::Row(_) => Err(anyhow!("row value misused")),
+        Expression::Asterisk => Err(anyhow!("* is only allowed as a result column")),
+    }
+}
+
+/// Applies a non-comparison binary operator; NULL operands give NULL
+///
+/// Bitwise operators work on integers, `%` on integers but yields a real if
+/// either operand is real, `+ - * /` on numbers and `||` on text.
+fn apply_operator(op: BinaryOperator, left: &Value, right: &Value) -> Value {
+    if left.is_null() || right.is_null() {
+        return Value::Null;
+    }
+
+    match op {
+        BinaryOperator::Concat => match (left.to_text(), right.to_text()) {
+            (Some(l), Some(r)) => Value::Text(l + &r),
+            _ => Value::Null,
+        },
+        BinaryOperator::Add
+        | BinaryOperator::Subtract
+        | BinaryOperator::Multiply
+        | BinaryOperator::Divide => arithmetic(op, left.to_numeric(), right.to_numeric()),
+        BinaryOperator::Modulo => {
+            let (left, right) = (left.to_numeric(), right.to_numeric());
+            let (Some(l), Some(r)) = (left.to_integer(), right.to_integer()) else {
+                return Value::Null;
+            };
+            if r == 0 {
+                return Value::Null;
+            }
+            // i64::MIN % -1 overflows, but the remainder is 0
+            let remainder = l.checked_rem(r).unwrap_or(0);
+            if matches!(left, Value::Real(_)) || matches!(right, Value::Real(_)) {
+                Value::Real(remainder as f64)
+            } else {
+                Value::Integer(remainder)
+            }
+        }
+        _ => {
+            let (Some(l), Some(r)) = (left.to_integer(), right.to_integer()) else {
+                return Value::Null;
+            };
+            Value::Integer(match op {
+                BinaryOperator::BitAnd => l & r,
+                BinaryOperator::BitOr => l | r,
+                BinaryOperator::ShiftLeft => shift_left(l, r),
+                BinaryOperator::ShiftRight => shift_left(l, r.saturating_neg()),
+                _ => return Value::Null,
+            })
+        }
+    }
+}
+
+/// Applies `+ - * /` to two numbers
+///
+/// Integer operands give an integer unless the result overflows, in which case
+/// it is computed as a real; integer division truncates. Dividing by zero, or a
+/// real result that is not a number, gives NULL.
+fn arithmetic(op: BinaryOperator, left: Value, right: Value) -> Value {
+    if let (Value::Integer(l), Value::Integer(r)) = (&left, &right) {
+        let result = match op {
+            BinaryOperator::Add => l.checked_add(*r),
+            BinaryOperator::Subtract => l.checked_sub(*r),
+            BinaryOperator::Multiply => l.checked_mul(*r),
+            _ if *r == 0 => return Value::Null,
+            _ => l.checked_div(*r),
+        };
+        if let Some(result) = result {
+            return Value::Integer(result);
+        }
+    }
+
+    let as_real = |value: &Value| match value {
+        Value::Integer(i) => *i as f64,
+        Value::Real(r) => *r,
+        _ => 0.0,
+    };
+    let (l, r) = (as_real(&left), as_real(&right));
+    let result = match op {
+        BinaryOperator::Add => l + r,
+        BinaryOperator::Subtract => l - r,
+        BinaryOperator::Multiply => l * r,
+        _ if r == 0.0 => return Value::Null,
+        _ => l / r,
+    };
+    if result.is_nan() {
+        Value::Null
+    } else {
+        Value::Real(result)
+    }
+}
+
+/// Shifts `value` left by `amount` bits, or right (arithmetically) if negative
+fn shift_left(value: i64, amount: i64) -> i64 {
+    match amount {
+        0..=63 => value << amount,
+        64.. => 0,
+        -63..=-1 => value >> -amount,
+        _ => {
+            if value < 0 {
+                -1
+            } else {
+                0
+            }
+        }
+    }
+}
+
+/// Evaluates one side of a comparison, which may be a row value
+fn evaluate_operand(expr: &Expression, ctx: &RowContext, rng: &mut Rng) -> Result<Vec<Value>> {
+    match expr {
+        Expression::Row(items) => items.iter().map(|item| evaluate(item, ctx, rng)).collect(),
+        expr => Ok(vec![evaluate(expr, ctx, rng)?]),
+    }
+}
+
+/// Returns the affinity an operand brings to a comparison: its column's for a
+/// column reference, none for any other expression
+fn operand_affinities(expr: &Expression, ctx: &RowContext) -> Vec<Option<Affinity>> {
+    let affinity = |expr: &Expression| match expr {
+        Expression::Column { table, name } => ctx.column_affinity(table.as_deref(), name),
+        _ => None,
+    };
+    match expr {
+        Expression::Row(items) => items.iter().map(affinity).collect(),
+        expr => vec![affinity(expr)],
+    }
+}
+
+/// Returns the collation an operand brings to a comparison: its column's for a
+/// column reference, none for any other expression
+pub(crate) fn operand_collation(expr: &Expression, ctx: &RowContext) -> Option<Collation> {
+    match expr {
+        Expression::Column { table, name } => ctx.column_collation(table.as_deref(), name),
+        _ => None,
+    }
+}
+
+/// Returns the `i`-th value of an operand, which may be a row value
+fn operand_item(expr: &Expression, i: usize) -> Option<&Expression> {
+    match expr {
+        Expression::Row(items) => items.get(i),
+        expr => (i == 0).then_some(expr),
+    }
+}
+
+/// Returns the collation the `i`-th values of two comparison operands are
+/// compared under: the left operand's, else the right operand's, else BINARY
+fn comparison_collation(
+    left_expr: &Expression,
+    right_expr: &Expression,
+    i: usize,
+    ctx: &RowContext,
+) -> Collation {
+    [left_expr, right_expr]
+        .into_iter()
+        .find_map(|expr| operand_item(expr, i).and_then(|item| operand_collation(item, ctx)))
+        .unwrap_or_default()
+}
+
+/// Converts the values of two comparison operands the way SQLite does before
+/// comparing them, so that `id = '5'` matches an integer id of 5:
+///
+/// - If one side has INTEGER, REAL or NUMERIC affinity and the other does not,
+///   the other side gets NUMERIC affinity.
+/// - Otherwise, if one side has TEXT affinity and the other has none, the other
+///   side gets TEXT affinity.
+fn coerce_operands(
+    left: &mut [Value],
+    left_expr: &Expression,
+    right: &mut [Value],
+    right_expr: &Expression,
+    ctx: &RowContext,
+) {
+    let left_affinities = operand_affinities(left_expr, ctx);
+    let right_affinities = operand_affinities(right_expr, ctx);
+    let numeric = |affinity: Option<Affinity>| affinity.map_or(false, Affinity::is_numeric);
+    for (i, (l, r)) in left.iter_mut().zip(right.iter_mut()).enumerate() {
+        let left_affinity = left_affinities.get(i).copied().flatten();
+        let right_affinity = right_affinities.get(i).copied().flatten();
+        if numeric(left_affinity) && !numeric(right_affinity) {
+            *r = r.with_affinity(Affinity::Numeric);
+        } else if numeric(right_affinity) && !numeric(left_affinity) {
+            *l = l.with_affinity(Affinity::Numeric);
+        } else if left_affinity == Some(Affinity::Text) && right_affinity.is_none() {
+            *r = r.with_affinity(Affinity::Text);
+        } else if right_affinity == Some(Affinity::Text) && left_affinity.is_none() {
+            *l = l.with_affinity(Affinity::Text);
+        }
+    }
+}
+
+/// Compares two rows of equal length, returning None if the result is NULL
+///
+/// `collation` gives the collation of each pair of values; it is only asked
+/// for pairs of texts, the only values a collation affects.
+fn compare_rows(
+    left: &[Value],
+    op: BinaryOperator,
+    right: &[Value],
+    collation: impl Fn(usize) -> Collation,
+    encoding: TextEncoding,
+) -> Option<bool> {
+    let null_safe = matches!(op, BinaryOperator::Is | BinaryOperator::IsNot);
+    let equality = null_safe || matches!(op, BinaryOperator::Eq | BinaryOperator::NotEq);
+    let mut saw_null = false;
+
+    for (i, (l, r)) in left.iter().zip(right).enumerate() {
+        let ordering = match (l, r) {
+            (Value::Null, Value::Null) if null_safe => Some(Ordering::Equal),
+            (Value::Null, _) if null_safe => Some(Ordering::Less),
+            (_, Value::Null) if null_safe => Some(Ordering::Greater),
+            (Value::Text(_), Value::Text(_)) => Some(collation(i).compare(l, r, encoding)),
+            _ => l.compare(r),
+        };
+        match ordering {
+            Some(Ordering::Equal) => {}
+            Some(ordering) => return Some(op_holds(op, ordering)),
+            None if equality => saw_null = true,
+            None => return None,
+        }
+    }
+    if saw_null {
+        None
+    } else {
+        Some(op_holds(op, Ordering::Equal))
+    }
+}
+
+/// Returns true if `op` accepts operands that compare as `ordering`
+fn op_holds(op: BinaryOperator, ordering: Ordering) -> bool {
+    match op {
+        BinaryOperator::Eq | BinaryOperator::Is => ordering == Ordering::Equal,
+        BinaryOperator::NotEq | BinaryOperator::IsNot => ordering != Ordering::Equal,
+        BinaryOperator::Lt => ordering == Ordering::Less,
+        BinaryOperator::LtEq => ordering != Ordering::Greater,
+        BinaryOperator::Gt => ordering == Ordering::Greater,
+        BinaryOperator::GtEq => ordering != Ordering::Less,
+        _ => false,
+    }
+}
+
+/// A piece of a LIKE pattern
+#[derive(Clone, Copy)]
+enum LikeToken {
+    /// `%`: any run of characters, including none
+    Any,
+    /// `_`: exactly one character
+    One,
+    Char(char),
+}
+
+/// Matches `text` against a LIKE pattern, ignoring the case of ASCII letters
+///
+/// A character after `escape` matches itself, and a pattern that ends with the
+/// escape character matches nothing.
+fn like(pattern: &[char], text: &[char], escape: Option<char>) -> bool {
+    let mut tokens = Vec::with_capacity(pattern.len());
+    let mut chars = pattern.iter();
+    while let Some(&c) = chars.next() {
+        tokens.push(if Some(c) == escape {
+            match chars.next() {
+                Some(&escaped) => LikeToken::Char(escaped),
+                None => return false,
+            }
+        } else if c == '%' {
+            LikeToken::Any
+        } else if c == '_' {
+            LikeToken::One
+        } else {
+            LikeToken::Char(c)
+        });
+    }
+
+    // Greedy match that backs up to the most recent `%` on a mismatch, letting
+    // it swallow one more character
+    let (mut t, mut p) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None;
+    while t < text.len() {
+        match tokens.get(p) {
+            Some(LikeToken::Any) => {
+                backtrack = Some((p, t));
+                p += 1;
+                continue;
+            }
+            Some(LikeToken::One) => {
+                t += 1;
+                p += 1;
+                continue;
+            }
+            Some(LikeToken::Char(c)) if c.eq_ignore_ascii_case(&text[t]) => {
+                t += 1;
+                p += 1;
+                continue;
+            }
+            _ => {}
+        }
+        match backtrack {
+            Some((any, start)) => {
+                backtrack = Some((any, start + 1));
+                p = any + 1;
+                t = start + 1;
+            }
+            None => return false,
+        }
+    }
+    tokens[p..]
+        .iter()
+        .all(|token| matches!(token, LikeToken::Any))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sqlite::query::execute::ResultRows;
+    use crate::sqlite::storage::db::{SQLiteDatabase, MEMORY_PATH};
+    use std::path::PathBuf;
+
+    /// Evaluates `sql` against a row of `t(a, b)` where a is 1 and b is NULL
+    fn eval(sql: &str) -> Value {
+        let schema = TableSchema::parse("t".into(), "CREATE TABLE t(a, b)".into()).unwrap();
+        let row = Row {
+            rowid: 1,
+            values: vec![Value::Integer(1), Value::Null],
+        };
+        let ctx = RowContext::new(&schema, &row, TextEncoding::Utf8);
+        let expr = Expression::parse(sql).unwrap();
+        evaluate(&expr, &ctx, &mut Rng::with_seed(0)).unwrap()
+    }
+
+    #[test]
+    fn comparisons_with_null_are_null() {
+        for sql in [
+            "b = 1",
+            "b != 1",
+            "b < 1",
+            "b <= 1",
+            "b > 1",
+            "b >= 1",
+            "1 = NULL",
+            "NULL = NULL",
+            "NULL != NULL",
+            "a = b",
+            "b <> b",
+            "(a, b) = (1, 2)",
+            "b IN (1, 2)",
+            "a IN (2, NULL)",
+            "b BETWEEN 0 AND 2",
+            "b LIKE 'x'",
+        ] {
+            assert_eq!(eval(sql), Value::Null, "{}", sql);
+        }
+    }
+
+    #[test]
+    fn decided_comparisons_ignore_null() {
+        assert_eq!(eval("(a, b) = (2, 2)"), Value::Integer(0));
+        assert_eq!(eval("a IN (1, NULL)"), Value::Integer(1));
+        assert_eq!(eval("a NOT IN (1, NULL)"), Value::Integer(0));
+    }
+
+    #[test]
+    fn is_and_is_not_treat_null_as_a_value() {
+        let cases = [
+            ("b IS NULL", 1),
+            ("b IS NOT NULL", 0),
+            ("a IS NULL", 0),
+            ("a IS NOT NULL", 1),
+            ("NULL IS NULL", 1),
+            ("NULL IS NOT NULL", 0),
+            ("b IS 1", 0),
+            ("b IS NOT 1", 1),
+            ("a IS 1", 1),
+            ("a IS b", 0),
+            ("b IS b", 1),
+        ];
+        for (sql, expected) in cases {
+            assert_eq!(eval(sql), Value::Integer(expected), "{}", sql);
+        }
+    }
+
+    #[test]
+    fn logic_with_null_is_three_valued() {
+        let cases = [
+            ("NOT b", Value::Null),
+            ("NOT (b = 1)", Value::Null),
+            ("b AND 1", Value::Null),
+            ("b AND 0", Value::Integer(0)),
+            ("b OR 0", Value::Null),
+            ("b OR 1", Value::Integer(1)),
+            ("b + 1", Value::Null),
+            ("-b", Value::Null),
+        ];
+        for (sql, expected) in cases {
+            assert_eq!(eval(sql), expected, "{}", sql);
+        }
+    }
+
+    #[test]
+    fn where_skips_rows_whose_condition_is_null() {
+        let mut db = SQLiteDatabase::open(&PathBuf::from(MEMORY_PATH)).unwrap();
+        db.query("CREATE TABLE t(a, b)").unwrap();
+        for values in ["(1, NULL)", "(2, 1)", "(3, 2)", "(4, NULL)"] {
+            db.query(&format!("INSERT INTO t VALUES {}", values))
+                .unwrap();
+        }
+        let mut select = |condition: &str| {
+            let sql = format!("SELECT a FROM t WHERE {} ORDER BY a", condition);
+            match db.query(&sql).unwrap().rows {
+                ResultRows::Values(rows) => rows
+                    .into_iter()
+                    .map(|row| row[0].clone())
+                    .collect::<Vec<_>>(),
+                ResultRows::Count(_) => panic!("{} returned a count", sql),
+            }
+        };
+        let ints = |values: &[i64]| {
+            values
+                .iter()
+                .copied()
+                .map(Value::Integer)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(select("b = NULL"), ints(&[]));
+        assert_eq!(select("b != 1"), ints(&[3]));
+        assert_eq!(select("NOT (b = 1)"), ints(&[3]));
+        assert_eq!(select("b"), ints(&[2, 3]));
+        assert_eq!(select("b IS NULL"), ints(&[1, 4]));
+        assert_eq!(select("b IS NOT NULL"), ints(&[2, 3]));
+        assert_eq!(select("b IS NOT 1"), ints(&[1, 3, 4]));
+        assert_eq!(select("b = 1 OR a = 4"), ints(&[2, 4]));
+    }
+}
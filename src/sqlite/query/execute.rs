@@ -4,333 +4,928 @@
 //! It implements the logic to traverse B-tree pages and process records according
 //! to the SQLite file format specification.
 
-use crate::sqlite::core::btree::BTreePage;
-use crate::sqlite::core::record::Record;
-use crate::sqlite::core::varint::Varint;
-use crate::sqlite::parser::expression::{Expression, FunctionCall};
-use crate::sqlite::parser::statement::Statement;
+use crate::sqlite::core::btree::Payload;
+use crate::sqlite::core::collation::{compare_index_keys, KeyColumn};
+use crate::sqlite::core::error::{corrupt, CorruptPage};
+use crate::sqlite::core::record::{decode_value, record_fields, Record};
+use crate::sqlite::core::schema::{Affinity, ColumnDef, TableSchema};
+use crate::sqlite::core::value::Value;
+use crate::sqlite::parser::expression::{BinaryOperator, Expression, FunctionCall};
+use crate::sqlite::parser::statement::{Command, Statement};
+use crate::sqlite::query::aggregate::{reject_aggregates, Aggregates};
+use crate::sqlite::query::columns::ColumnInfo;
+use crate::sqlite::query::distinct::Dedup;
+use crate::sqlite::query::eval::{evaluate, operand_collation, RowContext, SubqueryRows};
+use crate::sqlite::query::functions::Rng;
+use crate::sqlite::query::group::Groups;
+use crate::sqlite::query::index::{rowid_range, Access};
+use crate::sqlite::query::limit::{Bounds, TopK};
+use crate::sqlite::query::scan::{missing_value, Row};
 use crate::sqlite::storage::db::SQLiteDatabase;
-use crate::sqlite::storage::table::TableReader;
+use crate::sqlite::storage::vtab::{ConstraintOp, IndexConstraint, IndexInfo};
 use anyhow::{anyhow, Result};
-use std::fmt::Display;
-use std::io::Read;
-use std::io::Seek;
-use std::io::SeekFrom;
-use tracing::info;
+use std::ops::RangeInclusive;
+use tracing::debug;
 
 /// Result of executing a SQL statement
-#[derive(Debug)]
-pub enum ExecuteResult {
+///
+/// Values keep their storage class; turning them into text is left to the
+/// caller.
+#[derive(Debug, Clone)]
+pub struct ExecuteResult {
+    /// Name and declared type of each result column, in order
+    pub columns: Vec<ColumnInfo>,
+    pub rows: ResultRows,
+}
+
+/// The rows a statement produced
+#[derive(Debug, Clone)]
+pub enum ResultRows {
     /// Count result, used for COUNT(*) queries
     Count(u32),
-    /// Values result, used for SELECT queries
-    Values(Vec<String>),
+    /// Result rows of SELECT queries, one value per result column
+    Values(Vec<Vec<Value>>),
+}
+
+impl ExecuteResult {
+    /// Returns the result of a statement that produces no rows, such as UPDATE
+    pub fn empty() -> Self {
+        Self {
+            columns: Vec::new(),
+            rows: ResultRows::Values(Vec::new()),
+        }
+    }
+
+    /// Returns the number of rows in the result
+    pub fn row_count(&self) -> usize {
+        match &self.rows {
+            ResultRows::Count(_) => 1,
+            ResultRows::Values(values) => values.len(),
+        }
+    }
 }
 
-impl Display for ExecuteResult {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        match self {
-            ExecuteResult::Count(count) => write!(f, "{}", count),
-            ExecuteResult::Values(values) => {
-                for value in values {
-                    writeln!(f, "{}", value)?;
+/// How an ORDER BY term obtains its key
+enum SortKey<'a> {
+    /// A result column, referenced by its 1-based position in the term
+    Column(usize),
+    /// An expression evaluated against the source row
+    Expression(&'a Expression),
+}
+
+impl<'a> SortKey<'a> {
+    /// Resolves the `term_index`-th (0-based) ORDER BY term
+    ///
+    /// `aliases` maps result column aliases to result column indexes; a bare
+    /// name matching one refers to that column even if the table has a column
+    /// of the same name.
+    fn resolve(
+        term_index: usize,
+        expr: &'a Expression,
+        result_columns: usize,
+        aliases: &[(&str, usize)],
+    ) -> Result<Self> {
+        match expr {
+            Expression::Column { table: None, name } => Ok(aliases
+                .iter()
+                .find(|(alias, _)| alias.eq_ignore_ascii_case(name))
+                .map_or(SortKey::Expression(expr), |&(_, index)| {
+                    SortKey::Column(index)
+                })),
+            Expression::Literal(Value::Integer(ordinal)) => {
+                if *ordinal < 1 || *ordinal as usize > result_columns {
+                    return Err(anyhow!(
+                        "{} ORDER BY term out of range - should be between 1 and {}",
+                        ordinal_name(term_index + 1),
+                        result_columns
+                    ));
                 }
-                Ok(())
+                Ok(SortKey::Column(*ordinal as usize - 1))
+            }
+            expr => Ok(SortKey::Expression(expr)),
+        }
+    }
+}
+
+/// Checks that every qualified column reference names one of the statement's
+/// tables, by its alias if it has one, or a table of an enclosing statement
+fn check_qualifiers(stmt: &Statement, outer_names: &[&String]) -> Result<()> {
+    let visible_names: Vec<&String> = stmt
+        .table_alias
+        .as_ref()
+        .or(stmt.from_table.as_ref())
+        .into_iter()
+        .chain(
+            stmt.joins
+                .iter()
+                .map(|join| join.alias.as_ref().unwrap_or(&join.table)),
+        )
+        .chain(outer_names.iter().copied())
+        .collect();
+    let mut result = Ok(());
+    let mut check = |expr: &Expression| {
+        if result.is_err() {
+            return;
+        }
+        match expr {
+            Expression::Column {
+                table: Some(table),
+                name,
+            } if !visible_names.iter().any(|v| v.eq_ignore_ascii_case(table)) => {
+                result = Err(anyhow!("no such column: {}.{}", table, name));
             }
+            Expression::Exists(subquery) => result = check_qualifiers(subquery, &visible_names),
+            _ => {}
+        }
+    };
+    for expr in stmt.expressions() {
+        expr.walk(&mut check);
+    }
+    result
+}
+
+/// Spells out a position as 1st, 2nd, 3rd, ...
+pub(crate) fn ordinal_name(n: usize) -> String {
+    let suffix = match (n % 10, n % 100) {
+        (_, 11..=13) => "th",
+        (1, _) => "st",
+        (2, _) => "nd",
+        (3, _) => "rd",
+        _ => "th",
+    };
+    format!("{}{}", n, suffix)
+}
+
+/// Evaluates the result columns and sort keys of one result row
+///
+/// `*` expands to all columns of the table.
+fn project(
+    stmt: &Statement,
+    sort_keys: &[SortKey],
+    ctx: &RowContext,
+    rng: &mut Rng,
+) -> Result<(Vec<Value>, Vec<Value>)> {
+    let mut columns = Vec::new();
+    for selection in &stmt.selections {
+        match &selection.expr {
+            Expression::Asterisk => match ctx.row {
+                Some(row) => {
+                    columns.extend(row.values.iter().take(ctx.schema.columns.len()).cloned())
+                }
+                None => columns.extend(ctx.schema.columns.iter().map(|_| Value::Null)),
+            },
+            expr => columns.push(evaluate(expr, ctx, rng)?),
+        }
+    }
+    let keys = sort_keys
+        .iter()
+        .map(|key| match key {
+            SortKey::Column(index) => Ok(columns[*index].clone()),
+            SortKey::Expression(expr) => evaluate(expr, ctx, rng),
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Ok((keys, columns))
+}
+
+/// Splits a condition into the terms ANDed together in it, left to right
+fn conjuncts(condition: &Expression) -> Vec<&Expression> {
+    match condition {
+        Expression::Binary {
+            left,
+            op: BinaryOperator::And,
+            right,
+        } => {
+            let mut terms = conjuncts(left);
+            terms.extend(conjuncts(right));
+            terms
+        }
+        _ => vec![condition],
+    }
+}
+
+/// Returns the constraint a comparison of a column of a virtual table, which
+/// the query may call `alias`, with a literal places on it, along with the
+/// literal
+fn virtual_constraint<'e>(
+    term: &'e Expression,
+    schema: &TableSchema,
+    alias: Option<&str>,
+) -> Option<(IndexConstraint, &'e Value)> {
+    let Expression::Binary { left, op, right } = term else {
+        return None;
+    };
+    let (qualifier, name, op, value) = match (left.as_ref(), right.as_ref()) {
+        (Expression::Column { table, name }, Expression::Literal(value)) => {
+            (table, name, *op, value)
+        }
+        // `5 < n` is `n > 5`
+        (Expression::Literal(value), Expression::Column { table, name }) => {
+            let op = match op {
+                BinaryOperator::Lt => BinaryOperator::Gt,
+                BinaryOperator::LtEq => BinaryOperator::GtEq,
+                BinaryOperator::Gt => BinaryOperator::Lt,
+                BinaryOperator::GtEq => BinaryOperator::LtEq,
+                op => *op,
+            };
+            (table, name, op, value)
         }
+        _ => return None,
+    };
+    if qualifier.as_ref().map_or(false, |qualifier| {
+        !qualifier.eq_ignore_ascii_case(alias.unwrap_or(&schema.name))
+    }) {
+        return None;
     }
+    let op = match op {
+        BinaryOperator::Eq => ConstraintOp::Eq,
+        BinaryOperator::Lt => ConstraintOp::Lt,
+        BinaryOperator::LtEq => ConstraintOp::Le,
+        BinaryOperator::Gt => ConstraintOp::Gt,
+        BinaryOperator::GtEq => ConstraintOp::Ge,
+        _ => return None,
+    };
+    let column = schema
+        .columns
+        .iter()
+        .position(|column| column.name.eq_ignore_ascii_case(name))?;
+    let constraint = IndexConstraint {
+        column,
+        op,
+        usable: true,
+    };
+    Some((constraint, value))
 }
 
 impl SQLiteDatabase {
     /// Executes a parsed SQL statement and returns the result
     pub fn execute(&mut self, stmt: &Statement) -> Result<ExecuteResult> {
-        match &stmt.selections[0] {
-            Expression::Function(FunctionCall { name, args }) => {
-                if name.to_uppercase() == "COUNT" && args.len() == 1 {
-                    if let Expression::Asterisk = args[0] {
-                        return self.execute_count_all(&stmt.from_table);
-                    }
-                }
-                Err(anyhow!("Unsupported function: {}", name))
+        let rows = self.execute_rows(stmt)?;
+        Ok(ExecuteResult {
+            columns: self.result_columns(stmt)?,
+            rows,
+        })
+    }
+
+    /// Executes a parsed statement of any kind; statements other than SELECT
+    /// produce no rows
+    pub fn execute_command(&mut self, command: &Command) -> Result<ExecuteResult> {
+        self.refresh_header()?;
+        match command {
+            Command::Select(stmt) => self.execute(stmt),
+            Command::Insert(insert) => {
+                self.insert(insert)?;
+                Ok(ExecuteResult::empty())
+            }
+            Command::Update(update) => {
+                self.update(update)?;
+                Ok(ExecuteResult::empty())
+            }
+            Command::Delete(delete) => {
+                self.delete(delete)?;
+                Ok(ExecuteResult::empty())
+            }
+            Command::CreateTable(create) => {
+                self.create_table(create)?;
+                Ok(ExecuteResult::empty())
             }
-            Expression::Column(column_name) => self.read_column(&stmt.from_table, column_name),
-            Expression::Asterisk => self.read_all_columns(&stmt.from_table),
         }
     }
 
-    /// Executes COUNT(*) by counting all records in a table
-    fn execute_count_all(&mut self, table_name: &str) -> Result<ExecuteResult> {
-        // First, find the root page for this table from sqlite_schema
-        let root_page = self.find_table_root_page(table_name)?;
+    /// Executes a parsed SQL statement and returns its rows
+    fn execute_rows(&mut self, stmt: &Statement) -> Result<ResultRows> {
+        check_qualifiers(stmt, &[])?;
+        let Some(table_name) = &stmt.from_table else {
+            return self.execute_projection(stmt);
+        };
+        if self.virtual_table(table_name).is_some() {
+            if !stmt.joins.is_empty() {
+                return Err(anyhow!("virtual tables cannot be joined"));
+            }
+            return self.execute_projection(stmt);
+        }
+        if let Some(join) = stmt
+            .joins
+            .iter()
+            .find(|join| self.virtual_table(&join.table).is_some())
+        {
+            return Err(anyhow!("virtual table {} cannot be joined", join.table));
+        }
 
-        // Count records starting from the root page
-        let count = self.count_records_in_btree(root_page)?;
+        // The readers below only understand rowid tables
+        let (schema, _) = self.find_table(table_name)?;
+        let plain_scan = !schema.without_rowid
+            && stmt.joins.is_empty()
+            && !stmt.distinct
+            && stmt.where_clause.is_none()
+            && stmt.limit.is_none()
+            && stmt.group_by.is_empty()
+            && stmt.having.is_none()
+            && stmt.order_by.is_empty();
+        // The single-column and `*` readers apply a WHERE clause themselves, as
+        // long as it needs no subquery rows
+        let filtered_scan = !schema.without_rowid
+            && stmt.joins.is_empty()
+            && !stmt.distinct
+            && stmt.group_by.is_empty()
+            && stmt.limit.is_none()
+            && stmt.having.is_none()
+            && stmt.order_by.is_empty()
+            && stmt.where_clause.as_ref().map_or(true, |condition| {
+                let mut subquery = false;
+                condition.walk(&mut |expr| subquery |= matches!(expr, Expression::Exists(_)));
+                !subquery
+            });
+        if let Some(condition) = &stmt.where_clause {
+            reject_aggregates(condition)?;
+        }
+        // Those readers always walk the whole table, so leave conditions the
+        // planner can answer with a lookup to the general path
+        let filtered_scan = filtered_scan
+            && match &stmt.where_clause {
+                Some(condition) => {
+                    matches!(self.plan_access(&schema, Some(condition))?, Access::Scan)
+                }
+                None => true,
+            };
+        if let [selection] = stmt.selections.as_slice() {
+            match &selection.expr {
+                Expression::Function(FunctionCall {
+                    name,
+                    args,
+                    filter: None,
+                }) if name.to_uppercase() == "COUNT"
+                    && plain_scan
+                    && args.len() == 1
+                    && matches!(args[0], Expression::Asterisk) =>
+                {
+                    return self.execute_count_all(table_name);
+                }
+                Expression::Column {
+                    name: column_name, ..
+                } if filtered_scan => {
+                    return self.read_column(table_name, column_name, stmt.where_clause.as_ref())
+                }
+                Expression::Asterisk if filtered_scan => {
+                    return self.read_all_columns(table_name, stmt.where_clause.as_ref())
+                }
+                _ => {}
+            }
+        }
 
-        Ok(ExecuteResult::Count(count))
+        self.execute_projection(stmt)
     }
 
-    /// Finds the root page number for a given table by reading sqlite_schema
-    fn find_table_root_page(&mut self, table_name: &str) -> Result<u32> {
-        info!("Finding root page for table: {}", table_name);
-        let page_size = self.get_info()?.page_size() as usize;
-        info!("Page size: {}", page_size);
-
-        // Read first page which contains sqlite_schema
-        let mut page = vec![0; page_size];
-        self.file.seek(SeekFrom::Start(0))?;
-        self.file.read_exact(&mut page)?;
-
-        // Skip database header
-        let header_size = 100;
-
-        // Read B-tree page header
-        let num_cells = u16::from_be_bytes([page[header_size + 3], page[header_size + 4]]);
-        info!("Number of cells in sqlite_schema: {}", num_cells);
-
-        // Read cell pointer array
-        let mut cell_pointers = Vec::with_capacity(num_cells as usize);
-        let array_start = header_size + 8;
-
-        for i in 0..num_cells {
-            let offset = array_start + (i as usize * 2);
-            let ptr = u16::from_be_bytes([page[offset], page[offset + 1]]) as usize;
-            cell_pointers.push(ptr);
+    /// Evaluates the selected expressions for every row of the table, or of the
+    /// joined tables, that passes the WHERE clause, drops duplicate results for
+    /// DISTINCT, then sorts the results by the ORDER BY terms
+    ///
+    /// Queries calling aggregate functions produce a single row instead.
+    fn execute_projection(&mut self, stmt: &Statement) -> Result<ResultRows> {
+        if let Some(condition) = &stmt.where_clause {
+            reject_aggregates(condition)?;
+        }
+        let aggregate_exprs: Vec<&Expression> = stmt
+            .selections
+            .iter()
+            .map(|selection| &selection.expr)
+            .chain(stmt.having.iter())
+            .chain(stmt.order_by.iter().map(|term| &term.expr))
+            .collect();
+        let mut aggregates = Aggregates::collect(aggregate_exprs.iter().copied())?;
+        if stmt.having.is_some() && aggregates.is_empty() && stmt.group_by.is_empty() {
+            return Err(anyhow!("HAVING clause on a non-aggregate query"));
         }
-        info!("Cell pointers: {:?}", cell_pointers);
-
-        // Process each cell looking for our table
-        for (i, &ptr) in cell_pointers.iter().enumerate() {
-            info!("Processing cell {}", i);
-            let mut pos = ptr;
-
-            // Skip payload length
-            pos += page[pos..].varint_size(&page[pos..]);
-            info!("After payload length, pos: {}", pos);
-
-            // Skip rowid
-            pos += page[pos..].varint_size(&page[pos..]);
-            info!("After rowid, pos: {}", pos);
-
-            // Read header size
-            let header_size = page[pos..].read_varint(&page[pos..])? as usize;
-            pos += page[pos..].varint_size(&page[pos..]);
-            let header_end = pos + header_size - page[pos - 1..].varint_size(&page[pos - 1..]);
-            info!(
-                "Header size: {}, pos: {}, header_end: {}",
-                header_size, pos, header_end
-            );
-
-            // Read serial types
-            let mut serial_types = Vec::new();
-            while pos < header_end {
-                let serial_type = page[pos..].read_varint(&page[pos..])?;
-                pos += page[pos..].varint_size(&page[pos..]);
-                serial_types.push(serial_type);
+        let bounds = Bounds::of(stmt, &mut self.rng)?;
+        let subqueries = self.read_subqueries(stmt)?;
+        let (schema, rows, tables) = match &stmt.from_table {
+            Some(table_name) if self.virtual_table(table_name).is_some() => {
+                if !subqueries.is_empty() {
+                    return Err(anyhow!("subqueries are not supported on virtual tables"));
+                }
+                let alias = stmt.table_alias.as_deref();
+                let condition = stmt.where_clause.as_ref();
+                let (schema, rows) = self.scan_virtual(table_name, alias, condition)?;
+                (schema, rows, Vec::new())
             }
-            info!("Serial types: {:?}", serial_types);
-
-            // Skip type field
-            if let Some(&type_code) = serial_types.get(0) {
-                if type_code >= 13 {
-                    pos += ((type_code - 13) / 2) as usize;
+            Some(_) if !stmt.joins.is_empty() || !subqueries.is_empty() => {
+                self.join_tables(stmt, &subqueries)?
+            }
+            Some(table_name) => {
+                // Rows come out in scan order unless they are sorted, grouped or
+                // deduplicated, so the scan can stop once the limit is reached
+                let scan_limit = bounds
+                    .filter(|_| {
+                        stmt.order_by.is_empty()
+                            && stmt.group_by.is_empty()
+                            && aggregates.is_empty()
+                            && !stmt.distinct
+                    })
+                    .map(|bounds| bounds.end());
+                // Aggregates read every row, so the scan may be split
+                let (schema, rows) = if aggregates.is_empty() && stmt.group_by.is_empty() {
+                    self.scan_table(table_name, stmt.where_clause.as_ref(), scan_limit)?
+                } else {
+                    self.scan_table_parallel(table_name, stmt.where_clause.as_ref())?
+                };
+                (schema, rows, Vec::new())
+            }
+            None => {
+                let (schema, rows) = self.constant_row(stmt, &subqueries)?;
+                (schema, rows, Vec::new())
+            }
+        };
+
+        let encoding = self.header.encoding();
+        // Resolves column names to their collations, before any row is read
+        let names = RowContext {
+            schema: &schema,
+            encoding,
+            row: None,
+            aggregates: &[],
+            tables: &tables,
+            subqueries: &subqueries,
+            outer: None,
+        };
+        // The collation of each result column, which is BINARY unless it is a
+        // column of a table
+        let mut collations = Vec::new();
+        let mut aliases = Vec::new();
+        for selection in &stmt.selections {
+            if let Some(alias) = &selection.alias {
+                aliases.push((alias.as_str(), collations.len()));
+            }
+            match &selection.expr {
+                Expression::Asterisk => {
+                    collations.extend(schema.columns.iter().map(ColumnDef::collating_sequence))
                 }
+                expr => collations.push(operand_collation(expr, &names).unwrap_or_default()),
             }
-            info!("After type field, pos: {}", pos);
-
-            // Read table name
-            if let Some(&name_type) = serial_types.get(2) {
-                if name_type >= 13 {
-                    let name_size = ((name_type - 13) / 2) as usize;
-                    if let Ok(name) = String::from_utf8(page[pos..pos + name_size].to_vec()) {
-                        info!("Found table name: {}", name);
-                        if name == table_name {
-                            info!("Found matching table!");
-
-                            // Skip past table name and tbl_name fields
-                            pos += name_size * 2; // Skip both name and tbl_name
-
-                            // Now we're at the rootpage field
-                            if let Some(&root_type) = serial_types.get(3) {
-                                info!("Root page type: {}", root_type);
-                                // Read the root page number based on its type
-                                let root_page = match root_type {
-                                    1 => page[pos] as u32,
-                                    2 => u16::from_be_bytes([page[pos], page[pos + 1]]) as u32,
-                                    3 => u32::from_be_bytes([
-                                        0,
-                                        page[pos],
-                                        page[pos + 1],
-                                        page[pos + 2],
-                                    ]),
-                                    4 => u32::from_be_bytes([
-                                        page[pos],
-                                        page[pos + 1],
-                                        page[pos + 2],
-                                        page[pos + 3],
-                                    ]),
-                                    _ => {
-                                        return Err(anyhow!(
-                                            "Invalid root page type: {}",
-                                            root_type
-                                        ))
-                                    }
-                                };
-                                info!("Raw root page bytes: {:?}", &page[pos..pos + 4]);
-                                info!("Found root page: {}", root_page);
-                                return Ok(root_page);
-                            }
+        }
+        let result_columns = collations.len();
+        let sort_keys = stmt
+            .order_by
+            .iter()
+            .enumerate()
+            .map(|(i, term)| SortKey::resolve(i, &term.expr, result_columns, &aliases))
+            .collect::<Result<Vec<_>>>()?;
+        let order: Vec<KeyColumn> = sort_keys
+            .iter()
+            .zip(&stmt.order_by)
+            .map(|(key, term)| KeyColumn {
+                collation: match key {
+                    SortKey::Column(index) => collations[*index],
+                    SortKey::Expression(expr) => {
+                        operand_collation(expr, &names).unwrap_or_default()
+                    }
+                },
+                desc: term.descending,
+                encoding,
+            })
+            .collect();
+
+        let mut results = Vec::with_capacity(rows.len());
+        let mut dedup = stmt.distinct.then(|| Dedup::new(collations));
+        let mut is_new = |(_, columns): &(Vec<Value>, Vec<Value>)| {
+            dedup.as_mut().map_or(true, |dedup| dedup.insert(columns))
+        };
+        if aggregates.is_empty() && stmt.group_by.is_empty() {
+            match bounds {
+                Some(bounds) if !stmt.order_by.is_empty() => {
+                    let mut top = TopK::new(bounds.end(), &order);
+                    for row in &rows {
+                        let ctx = RowContext::new(&schema, row, encoding)
+                            .joined(&tables)
+                            .with_subqueries(&subqueries);
+                        let result = project(stmt, &sort_keys, &ctx, &mut self.rng)?;
+                        if is_new(&result) {
+                            top.push(result);
+                        }
+                    }
+                    results = top.into_sorted();
+                }
+                _ => {
+                    for row in &rows {
+                        if bounds.map_or(false, |bounds| results.len() >= bounds.end()) {
+                            break;
+                        }
+                        let ctx = RowContext::new(&schema, row, encoding)
+                            .joined(&tables)
+                            .with_subqueries(&subqueries);
+                        let result = project(stmt, &sort_keys, &ctx, &mut self.rng)?;
+                        if is_new(&result) {
+                            results.push(result);
                         }
                     }
                 }
             }
+        } else {
+            let groups = if stmt.group_by.is_empty() {
+                for (i, row) in rows.iter().enumerate() {
+                    let ctx = RowContext::new(&schema, row, encoding)
+                        .joined(&tables)
+                        .with_subqueries(&subqueries);
+                    aggregates.step(i, &ctx, &mut self.rng)?;
+                }
+                vec![aggregates.finish()?]
+            } else {
+                let memory_limit = self.group_memory_limit;
+                let mut groups = Groups::new(stmt, &names, aggregate_exprs, memory_limit)?;
+                let row_context = |i: usize| {
+                    RowContext::new(&schema, &rows[i], encoding)
+                        .joined(&tables)
+                        .with_subqueries(&subqueries)
+                };
+                for i in 0..rows.len() {
+                    groups.step(i, &row_context(i), &mut self.rng)?;
+                }
+                groups.finish(row_context, &mut self.rng)?
+            };
+            for (source_row, values) in groups {
+                let ctx = RowContext {
+                    schema: &schema,
+                    encoding,
+                    row: source_row.map(|i| &rows[i]),
+                    aggregates: &values,
+                    tables: &tables,
+                    subqueries: &subqueries,
+                    outer: None,
+                };
+                if let Some(having) = &stmt.having {
+                    // NULL counts as false
+                    if evaluate(having, &ctx, &mut self.rng)?.truth() != Some(true) {
+                        continue;
+                    }
+                }
+                let result = project(stmt, &sort_keys, &ctx, &mut self.rng)?;
+                if is_new(&result) {
+                    results.push(result);
+                }
+            }
         }
 
-        Err(anyhow!("Table not found: {}", table_name))
-    }
+        if !stmt.order_by.is_empty() {
+            // Stable, so rows with equal keys keep their rowid order
+            results.sort_by(|(a, _), (b, _)| compare_index_keys(a, b, &order));
+        }
+        if let Some(bounds) = bounds {
+            results = results
+                .into_iter()
+                .skip(bounds.offset)
+                .take(bounds.count)
+                .collect();
+        }
 
-    /// Recursively counts records in a B-tree starting from given page
-    fn count_records_in_btree(&mut self, page_num: u32) -> Result<u32> {
-        info!("Counting records in page: {}", page_num);
-        let page_size = self.get_info()?.page_size();
+        let values = results.into_iter().map(|(_, columns)| columns).collect();
+        Ok(ResultRows::Values(values))
+    }
 
-        let page = BTreePage::read(&mut self.file, page_num, page_size)?;
+    /// Returns the single column-less row a statement without FROM is evaluated
+    /// against, or no row if the WHERE clause rejects it
+    fn constant_row(
+        &mut self,
+        stmt: &Statement,
+        subqueries: &[SubqueryRows],
+    ) -> Result<(TableSchema, Vec<Row>)> {
+        if stmt
+            .selections
+            .iter()
+            .any(|selection| matches!(selection.expr, Expression::Asterisk))
+        {
+            return Err(anyhow!("no tables specified"));
+        }
+        let schema = TableSchema {
+            name: String::new(),
+            columns: Vec::new(),
+            sql: String::new(),
+            without_rowid: false,
+            primary_key: Vec::new(),
+            unique_keys: Vec::new(),
+        };
+        let row = Row {
+            rowid: 0,
+            values: Vec::new(),
+        };
+        let keep = match &stmt.where_clause {
+            Some(condition) => {
+                let ctx = RowContext::new(&schema, &row, self.header.encoding())
+                    .with_subqueries(subqueries);
+                evaluate(condition, &ctx, &mut self.rng)?.truth() == Some(true)
+            }
+            None => true,
+        };
+        let rows = if keep { vec![row] } else { Vec::new() };
+        Ok((schema, rows))
+    }
 
-        match page.page_type() {
-            13 => {
-                // Leaf page
-                info!("Leaf page, returning count: {}", page.num_cells());
-                Ok(page.num_cells() as u32)
+    /// Reads the rows of a virtual table, which the query may call `alias`,
+    /// for which `condition` is true
+    ///
+    /// The comparisons of a column with a literal ANDed together in the
+    /// condition are offered to `best_index`, and `filter` is given the
+    /// literals the table asks for. The rest of the condition is checked
+    /// against every row the cursor returns, along with the constraints the
+    /// table does not promise to enforce.
+    fn scan_virtual(
+        &mut self,
+        table_name: &str,
+        alias: Option<&str>,
+        condition: Option<&Expression>,
+    ) -> Result<(TableSchema, Vec<Row>)> {
+        let table = self
+            .virtual_table(table_name)
+            .ok_or_else(|| anyhow!("no such table: {}", table_name))?;
+        let columns = table
+            .columns()
+            .into_iter()
+            .map(|name| ColumnDef {
+                name,
+                column_type: String::new(),
+                affinity: Affinity::Blob,
+                collation: None,
+                rowid_alias: false,
+                not_null: false,
+                default: None,
+            })
+            .collect();
+        let schema = TableSchema {
+            name: table_name.to_string(),
+            columns,
+            sql: String::new(),
+            without_rowid: false,
+            primary_key: Vec::new(),
+            unique_keys: Vec::new(),
+        };
+
+        let terms = condition.map_or_else(Vec::new, conjuncts);
+        let offered: Vec<(usize, IndexConstraint, &Value)> = terms
+            .iter()
+            .enumerate()
+            .filter_map(|(i, term)| {
+                let (constraint, value) = virtual_constraint(term, &schema, alias)?;
+                Some((i, constraint, value))
+            })
+            .collect();
+        let mut index_info = IndexInfo::new(
+            offered
+                .iter()
+                .map(|(_, constraint, _)| constraint.clone())
+                .collect(),
+        );
+        table.best_index(&mut index_info)?;
+
+        let mut checked = vec![true; terms.len()];
+        let mut args = Vec::new();
+        for ((term, _, value), usage) in offered.iter().zip(&index_info.constraint_usage) {
+            if usage.argv_index > 0 {
+                args.push((usage.argv_index, (*value).clone()));
+                // As in SQLite, only a constraint whose value the table gets
+                // can be left to it
+                checked[*term] &= !usage.omit;
             }
-            5 => {
-                // Interior page
-                info!("Interior page, traversing children");
-                let mut total = 0;
-
-                for child_page in page.get_child_pages()? {
-                    info!("Following child page: {}", child_page);
-                    total += self.count_records_in_btree(child_page)?;
-                }
+        }
+        args.sort_by_key(|(argv_index, _)| *argv_index);
+        if args
+            .iter()
+            .enumerate()
+            .any(|(i, (argv_index, _))| *argv_index != i + 1)
+        {
+            return Err(anyhow!(
+                "best_index of {} gave constraints invalid argv_index values",
+                table_name
+            ));
+        }
+        let args: Vec<Value> = args.into_iter().map(|(_, value)| value).collect();
 
-                info!("Total count for this subtree: {}", total);
-                Ok(total)
+        let mut cursor = table.open()?;
+        cursor.filter(index_info.idx_num, &args)?;
+        let mut rows = Vec::new();
+        while !cursor.eof() {
+            let values = (0..schema.columns.len())
+                .map(|index| cursor.column(index))
+                .collect::<Result<Vec<_>>>()?;
+            rows.push(Row {
+                rowid: cursor.rowid()?,
+                values,
+            });
+            cursor.next()?;
+        }
+        drop(cursor);
+
+        let residual: Vec<&Expression> = terms
+            .into_iter()
+            .zip(checked)
+            .filter_map(|(term, checked)| checked.then_some(term))
+            .collect();
+        if residual.is_empty() {
+            return Ok((schema, rows));
+        }
+        let encoding = self.header.encoding();
+        let mut matching = Vec::new();
+        for row in rows {
+            let ctx = RowContext::new(&schema, &row, encoding);
+            let mut keep = true;
+            for term in &residual {
+                if evaluate(term, &ctx, &mut self.rng)?.truth() != Some(true) {
+                    keep = false;
+                    break;
+                }
             }
-            pt => {
-                info!("Invalid page type encountered: {}", pt);
-                Err(anyhow!("Invalid page type: {}", pt))
+            if keep {
+                matching.push(row);
             }
         }
+        Ok((schema, matching))
+    }
+
+    /// Executes COUNT(*) by counting all records in a table
+    fn execute_count_all(&mut self, table_name: &str) -> Result<ResultRows> {
+        // First, find the root page for this table from sqlite_schema
+        let root_page = self.find_table_root_page(table_name)?;
+
+        // Count records starting from the root page
+        let count = match self.count_rows_parallel(root_page)? {
+            Some(count) => count,
+            None => self.count_records_in_btree(root_page)?,
+        };
+
+        Ok(ResultRows::Count(count))
+    }
+
+    /// Finds the root page number for a given table by reading sqlite_schema
+    pub(crate) fn find_table_root_page(&mut self, table_name: &str) -> Result<u32> {
+        debug!("Finding root page for table: {}", table_name);
+        Ok(self.find_table(table_name)?.1)
+    }
+
+    /// Counts the rows of the table B-tree rooted at `root_page`
+    fn count_records_in_btree(&mut self, root_page: u32) -> Result<u32> {
+        let mut cursor = self.cursor(root_page)?;
+        let mut total = 0;
+        let mut valid = cursor.first()?;
+        while valid {
+            total += 1;
+            valid = cursor.next()?;
+        }
+        self.metrics.cells_visited += u64::from(total);
+        debug!("Counted {} rows from root page {}", total, root_page);
+        Ok(total)
     }
-    /// Reads column values from a table
-    fn read_column(&mut self, table_name: &str, column_name: &str) -> Result<ExecuteResult> {
+
+    /// Collects the rows of a table B-tree whose rowids are in `rowids`, in
+    /// rowid order, as the page each row is on, its rowid and its whole
+    /// payload, including any part on overflow pages
+    ///
+    /// Payloads stored whole on their page are ranges of the shared page
+    /// data, so collecting them copies no record bytes.
+    fn collect_leaf_cells(
+        &mut self,
+        root_page: u32,
+        rowids: &RangeInclusive<i64>,
+    ) -> Result<Vec<(u32, i64, Payload)>> {
+        let mut cursor = self.cursor(root_page)?;
+        let mut cells = Vec::new();
+        cursor.seek_rowid(*rowids.start())?;
+        while cursor.is_valid() && cursor.rowid()? <= *rowids.end() {
+            cells.push((cursor.page_num()?, cursor.rowid()?, cursor.payload()?));
+            cursor.next()?;
+        }
+        self.metrics.cells_visited += cells.len() as u64;
+        Ok(cells)
+    }
+
+    /// Reads column values from the rows of a table for which `condition` is
+    /// true, or from every row if there is no condition
+    fn read_column(
+        &mut self,
+        table_name: &str,
+        column_name: &str,
+        condition: Option<&Expression>,
+    ) -> Result<ResultRows> {
         // First get the schema to find column position
-        let mut table_reader = TableReader::new(&mut self.file, self.header.page_size as usize);
-        let schema = table_reader.get_table_schema(table_name)?;
-        info!("Retrieved schema for {}: {:?}", table_name, schema);
+        let (schema, root_page) = self.find_table(table_name)?;
+        debug!("Retrieved schema for {}: {:?}", table_name, schema);
 
         // Find column index
         let column_index = schema
             .columns
             .iter()
-            .position(|col| col.name == column_name)
-            .ok_or_else(|| anyhow!("Column {} not found in table {}", column_name, table_name))?;
+            .position(|col| col.name.eq_ignore_ascii_case(column_name))
+            .ok_or_else(|| anyhow!("no such column: {}", column_name))?;
+        let rowid_alias = schema.columns[column_index].rowid_alias;
+        let affinity = schema.columns[column_index].affinity;
 
-        info!("Found column {} at index {}", column_name, column_index);
+        debug!("Found column {} at index {}", column_name, column_index);
 
         // Now read the actual data
-        let root_page = self.find_table_root_page(table_name)?;
-        let page_size = self.get_info()?.page_size();
+        let rowids = condition
+            .and_then(|condition| rowid_range(condition, &schema))
+            .unwrap_or(i64::MIN..=i64::MAX);
         let mut values = Vec::new();
 
-        let page = BTreePage::read(&mut self.file, root_page, page_size)?;
-
-        if page.page_type() == 13 {
-            // Process cells in forward order using original unsorted pointers
-            for i in 0..page.num_cells() {
-                let cell_data = page.get_cell_data(i)?;
-                let mut record = Record::new(&cell_data);
-
-                // Skip payload length and rowid
-                record.read_varint()?; // payload length
-                record.read_varint()?; // rowid
-
-                // Read header to get serial types
-                let serial_types = record.read_header()?;
-
-                // Skip first serial type (internal)
-                for (idx, &type_code) in serial_types.iter().skip(1).enumerate() {
-                    if idx == column_index {
-                        let value = match type_code {
-                            0 => "NULL".to_string(),
-                            1..=6 => record.read_integer(type_code)?.to_string(),
-                            7 => record.read_float()?.to_string(),
-                            n if n >= 13 => record
-                                .read_string_field(type_code)?
-                                .unwrap_or_else(|| "NULL".to_string()),
-                            _ => "?".to_string(),
-                        };
-                        values.push(value);
-                        break;
-                    } else {
-                        // Skip other columns based on their type
-                        record.skip_fields(1, &[type_code]);
-                    }
+        for (page_num, rowid, payload) in self.collect_leaf_cells(root_page, &rowids)? {
+            if let Some(condition) = condition {
+                if !self
+                    .payload_matches(rowid, &payload, &schema, condition)
+                    .on_page(page_num)?
+                {
+                    continue;
                 }
             }
+
+            // Split the record into fields but decode only the requested one
+            let fields = record_fields(&payload).on_page(page_num)?;
+            self.metrics.rows_decoded += 1;
+            self.metrics.bytes_decoded += payload.len() as u64;
+
+            // Columns added by ALTER TABLE are missing from older records
+            let value = match fields.get(column_index) {
+                Some(&(serial_type, bytes)) => decode_value(serial_type, bytes, &self.header),
+                None => missing_value(&schema.columns[column_index], self.header.encoding())?,
+            };
+            // INTEGER PRIMARY KEY columns are stored as NULL in favour of the rowid
+            let value = match value {
+                Value::Null if rowid_alias => Value::Integer(rowid),
+                // REAL columns store integral values as integers to save space
+                Value::Integer(i) if affinity == Affinity::Real => Value::Real(i as f64),
+                value => value,
+            };
+            values.push(vec![value]);
         }
 
-        values.reverse(); // Restore original order
-        Ok(ExecuteResult::Values(values))
+        Ok(ResultRows::Values(values))
     }
 
-    /// Reads all columns from a table
-    fn read_all_columns(&mut self, table_name: &str) -> Result<ExecuteResult> {
-        let root_page = self.find_table_root_page(table_name)?;
-        let page_size = self.get_info()?.page_size();
-
-        let page = BTreePage::read(&mut self.file, root_page, page_size)?;
+    /// Reads all columns from the rows of a table for which `condition` is true,
+    /// or from every row if there is no condition
+    fn read_all_columns(
+        &mut self,
+        table_name: &str,
+        condition: Option<&Expression>,
+    ) -> Result<ResultRows> {
+        let (schema, root_page) = self.find_table(table_name)?;
+        let rowids = condition
+            .and_then(|condition| rowid_range(condition, &schema))
+            .unwrap_or(i64::MIN..=i64::MAX);
         let mut rows = Vec::new();
 
-        // Read cells in reverse order since they're stored from end to start
-        for i in (0..page.num_cells()).rev() {
-            let cell_data = page.get_cell_data(i)?;
-            let mut record = Record::new(&cell_data);
-
-            // Read and skip the payload length
-            let payload_length = record.read_varint()?;
-            info!("Payload length: {}", payload_length);
-
-            // Read and skip the rowid
-            let rowid = record.read_varint()?;
-            info!("Row ID: {}", rowid);
+        for (page_num, rowid, payload) in self.collect_leaf_cells(root_page, &rowids)? {
+            if let Some(condition) = condition {
+                if !self
+                    .payload_matches(rowid, &payload, &schema, condition)
+                    .on_page(page_num)?
+                {
+                    continue;
+                }
+            }
+            let mut record = Record::new(&payload, &self.header);
+            debug!("Row ID: {}", rowid);
 
             // Read header
-            let serial_types = record.read_header()?;
-            info!("Serial types: {:?}", serial_types);
+            let serial_types = record.read_header().on_page(page_num)?;
+            debug!("Serial types: {:?}", serial_types);
+            self.metrics.rows_decoded += 1;
+            self.metrics.bytes_decoded += payload.len() as u64;
 
             let mut row = Vec::new();
-            row.push(rowid.to_string()); // Add rowid as first column
-
-            // Skip first serial type as it's for internal use
-            for &type_code in serial_types.iter().skip(1) {
+            for &type_code in &serial_types {
                 let value = match type_code {
-                    0 => "NULL".to_string(),
-                    1..=6 => record.read_integer(type_code)?.to_string(),
-                    7 => record.read_float()?.to_string(),
-                    n if n >= 13 => {
-                        if let Some(s) = record.read_string_field(type_code)? {
-                            s
-                        } else {
-                            "NULL".to_string()
-                        }
+                    0 => Value::Null,
+                    1..=6 => Value::Integer(record.read_integer(type_code).on_page(page_num)?),
+                    7 => Value::Real(record.read_float().on_page(page_num)?),
+                    8 => Value::Integer(0),
+                    9 => Value::Integer(1),
+                    n if n >= 12 && n % 2 == 0 => {
+                        Value::Blob(record.read_blob_field(type_code).on_page(page_num)?)
+                    }
+                    n if n >= 13 => match record.read_string_field(type_code).on_page(page_num)? {
+                        Some(s) => Value::Text(s),
+                        None => Value::Null,
+                    },
+                    n => {
+                        return Err(corrupt(0, format!("invalid serial type {}", n)))
+                            .on_page(page_num)
                     }
-                    _ => "?".to_string(),
                 };
                 row.push(value);
             }
+            // Columns added by ALTER TABLE are missing from older records
+            for column in schema.columns.iter().skip(row.len()) {
+                row.push(missing_value(column, self.header.encoding())?);
+            }
+            for (column, value) in schema.columns.iter().zip(row.iter_mut()) {
+                match value {
+                    // An INTEGER PRIMARY KEY column stores NULL and reads the rowid
+                    _ if column.rowid_alias => *value = Value::Integer(rowid),
+                    Value::Integer(i) if column.affinity == Affinity::Real => {
+                        *value = Value::Real(*i as f64);
+                    }
+                    _ => {}
+                }
+            }
 
-            rows.push(row.join("|"));
+            rows.push(row);
         }
 
-        Ok(ExecuteResult::Values(rows))
+        Ok(ResultRows::Values(rows))
     }
 }
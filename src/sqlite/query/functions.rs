@@ -0,0 +1,448 @@
+//! Built-in Scalar Functions
+//!
+//! Functions are looked up by case-insensitive name and called with their already
+//! evaluated arguments. Besides the random-number functions, these cover SQLite's
+//! core text, numeric and NULL-handling functions: abs, coalesce, hex, ifnull,
+//! iif, instr, length, lower, ltrim, max, min, nullif, replace, round, rtrim,
+//! substr, trim, typeof and upper.
+//!
+//! CURRENT_DATE, CURRENT_TIME and CURRENT_TIMESTAMP give the current UTC date
+//! and time as text, `YYYY-MM-DD`, `HH:MM:SS` and `YYYY-MM-DD HH:MM:SS`.
+//!
+//! The random-number functions draw from the connection's `Rng`, which can be
+//! reseeded with `SQLiteDatabase::set_random_seed` to make their output
+//! reproducible.
+
+use crate::sqlite::core::collation::Collation;
+use crate::sqlite::core::header::TextEncoding;
+use crate::sqlite::core::value::Value;
+use crate::sqlite::storage::db::SQLiteDatabase;
+use anyhow::{anyhow, Result};
+use std::cmp::Ordering;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Aggregate functions, which cannot be called per row
+const AGGREGATES: &[&str] = &["AVG", "COUNT", "GROUP_CONCAT", "MAX", "MIN", "SUM", "TOTAL"];
+
+/// Scalar functions whose result is not determined by their arguments
+const NON_DETERMINISTIC: &[&str] = &[
+    "CURRENT_DATE",
+    "CURRENT_TIME",
+    "CURRENT_TIMESTAMP",
+    "RANDOM",
+    "RANDOMBLOB",
+];
+
+/// Largest blob a function will create, matching SQLite's default SQLITE_MAX_LENGTH
+const MAX_BLOB_LEN: i64 = 1_000_000_000;
+
+/// Pseudo-random number generator (SplitMix64)
+///
+/// Not suitable for cryptographic use; it only needs to be fast and seedable.
+#[derive(Debug, Clone)]
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    /// Creates a generator producing a fixed sequence for `seed`
+    pub fn with_seed(seed: u64) -> Self {
+        Self { state: seed }
+    }
+
+    /// Creates a generator seeded from the process's hash randomness
+    pub fn from_entropy() -> Self {
+        Self::with_seed(RandomState::new().build_hasher().finish())
+    }
+
+    /// Returns the next 64 random bits
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Fills `bytes` with random data
+    pub fn fill_bytes(&mut self, bytes: &mut [u8]) {
+        for chunk in bytes.chunks_mut(8) {
+            let random = self.next_u64().to_le_bytes();
+            chunk.copy_from_slice(&random[..chunk.len()]);
+        }
+    }
+}
+
+impl Default for Rng {
+    fn default() -> Self {
+        Self::from_entropy()
+    }
+}
+
+/// Returns true if calling `name` with `arg_count` arguments is an aggregate
+///
+/// min() and max() are aggregates with one argument and scalar functions with
+/// more.
+pub fn is_aggregate(name: &str, arg_count: usize) -> bool {
+    AGGREGATES.iter().any(|f| f.eq_ignore_ascii_case(name))
+        && !(arg_count > 1 && ["MIN", "MAX"].iter().any(|f| f.eq_ignore_ascii_case(name)))
+}
+
+/// Returns true if calls to `name` always give the same result for the same arguments
+pub fn is_deterministic(name: &str) -> bool {
+    !NON_DETERMINISTIC
+        .iter()
+        .any(|f| f.eq_ignore_ascii_case(name))
+}
+
+/// Returns true if the scalar function `name` compares its arguments, and so
+/// needs the collation to compare them under
+pub fn compares_arguments(name: &str) -> bool {
+    ["MIN", "MAX", "NULLIF"]
+        .iter()
+        .any(|f| f.eq_ignore_ascii_case(name))
+}
+
+/// Calls the scalar function `name` with evaluated arguments, comparing text
+/// under `collation` as it is stored in `encoding`
+///
+/// Aggregate functions are rejected by the caller before their arguments are evaluated.
+pub fn call_scalar(
+    name: &str,
+    args: &[Value],
+    collation: Collation,
+    encoding: TextEncoding,
+    rng: &mut Rng,
+) -> Result<Value> {
+    match name.to_uppercase().as_str() {
+        "RANDOM" => {
+            expect_args(name, args, 0)?;
+            Ok(Value::Integer(rng.next_u64() as i64))
+        }
+        "RANDOMBLOB" => {
+            expect_args(name, args, 1)?;
+            // Lengths below one still produce a single byte
+            let len = args[0].to_integer().unwrap_or(0).max(1);
+            let mut bytes = vec![0u8; blob_len(len)?];
+            rng.fill_bytes(&mut bytes);
+            Ok(Value::Blob(bytes))
+        }
+        "ZEROBLOB" => {
+            expect_args(name, args, 1)?;
+            let len = args[0].to_integer().unwrap_or(0).max(0);
+            Ok(Value::Blob(vec![0u8; blob_len(len)?]))
+        }
+        "ABS" => {
+            expect_args(name, args, 1)?;
+            match &args[0] {
+                Value::Null => Ok(Value::Null),
+                Value::Integer(i) => i
+                    .checked_abs()
+                    .map(Value::Integer)
+                    .ok_or_else(|| anyhow!("integer overflow")),
+                Value::Real(r) => Ok(Value::Real(r.abs())),
+                // Text and blobs are read as reals
+                value => Ok(Value::Real(match value.to_numeric() {
+                    Value::Integer(i) => (i as f64).abs(),
+                    Value::Real(r) => r.abs(),
+                    _ => 0.0,
+                })),
+            }
+        }
+        "COALESCE" => {
+            if args.len() < 2 {
+                return Err(anyhow!("wrong number of arguments to function {}()", name));
+            }
+            Ok(first_non_null(args))
+        }
+        "IFNULL" => {
+            expect_args(name, args, 2)?;
+            Ok(first_non_null(args))
+        }
+        "NULLIF" => {
+            expect_args(name, args, 2)?;
+            // Two NULLs order as equal, but a NULL first argument is returned
+            // as NULL anyway
+            Ok(match collation.compare(&args[0], &args[1], encoding) {
+                Ordering::Equal => Value::Null,
+                _ => args[0].clone(),
+            })
+        }
+        "IIF" => {
+            expect_args(name, args, 3)?;
+            Ok(match args[0].truth() {
+                Some(true) => args[1].clone(),
+                _ => args[2].clone(),
+            })
+        }
+        // With two or more arguments these are scalar functions rather than
+        // aggregates
+        "MIN" | "MAX" => {
+            if args.len() < 2 {
+                return Err(anyhow!("wrong number of arguments to function {}()", name));
+            }
+            if args.iter().any(Value::is_null) {
+                return Ok(Value::Null);
+            }
+            let want = if name.eq_ignore_ascii_case("MIN") {
+                Ordering::Less
+            } else {
+                Ordering::Greater
+            };
+            Ok(args[1..]
+                .iter()
+                .fold(&args[0], |best, arg| {
+                    if collation.compare(arg, best, encoding) == want {
+                        arg
+                    } else {
+                        best
+                    }
+                })
+                .clone())
+        }
+        "TYPEOF" => {
+            expect_args(name, args, 1)?;
+            let type_name = match &args[0] {
+                Value::Null => "null",
+                Value::Integer(_) => "integer",
+                Value::Real(_) => "real",
+                Value::Text(_) => "text",
+                Value::Blob(_) => "blob",
+            };
+            Ok(Value::Text(type_name.to_string()))
+        }
+        "LENGTH" => {
+            expect_args(name, args, 1)?;
+            Ok(match &args[0] {
+                Value::Null => Value::Null,
+                Value::Blob(b) => Value::Integer(b.len() as i64),
+                value => Value::Integer(value.to_string().chars().count() as i64),
+            })
+        }
+        "LOWER" | "UPPER" => {
+            expect_args(name, args, 1)?;
+            let upper = name.eq_ignore_ascii_case("UPPER");
+            // Only ASCII letters change case, as in SQLite without ICU
+            Ok(match args[0].to_text() {
+                Some(text) if upper => Value::Text(text.to_ascii_uppercase()),
+                Some(text) => Value::Text(text.to_ascii_lowercase()),
+                None => Value::Null,
+            })
+        }
+        "HEX" => {
+            expect_args(name, args, 1)?;
+            let bytes = match &args[0] {
+                Value::Null => Vec::new(),
+                Value::Blob(b) => b.clone(),
+                value => value.to_string().into_bytes(),
+            };
+            Ok(Value::Text(
+                bytes.iter().map(|byte| format!("{:02X}", byte)).collect(),
+            ))
+        }
+        "ROUND" => {
+            if args.is_empty() || args.len() > 2 {
+                return Err(anyhow!("wrong number of arguments to function {}()", name));
+            }
+            let digits = match args.get(1) {
+                Some(Value::Null) => return Ok(Value::Null),
+                Some(digits) => digits.to_integer().unwrap_or(0).clamp(0, 30) as i32,
+                None => 0,
+            };
+            let x = match args[0].to_numeric() {
+                Value::Null => return Ok(Value::Null),
+                Value::Integer(i) => i as f64,
+                Value::Real(r) => r,
+                _ => 0.0,
+            };
+            // Reals this large have no fractional part to round
+            if x.abs() >= 4_503_599_627_370_496.0 {
+                return Ok(Value::Real(x));
+            }
+            let scale = 10f64.powi(digits);
+            Ok(Value::Real((x * scale).round() / scale))
+        }
+        "SUBSTR" | "SUBSTRING" => {
+            if args.len() < 2 || args.len() > 3 {
+                return Err(anyhow!("wrong number of arguments to function {}()", name));
+            }
+            if args.iter().any(Value::is_null) {
+                return Ok(Value::Null);
+            }
+            let start = args[1].to_integer().unwrap_or(0);
+            let count = args.get(2).map(|count| count.to_integer().unwrap_or(0));
+            Ok(match &args[0] {
+                Value::Blob(b) => {
+                    let (from, len) = substr_range(b.len(), start, count);
+                    Value::Blob(b[from..from + len].to_vec())
+                }
+                value => {
+                    let chars: Vec<char> = value.to_string().chars().collect();
+                    let (from, len) = substr_range(chars.len(), start, count);
+                    Value::Text(chars[from..from + len].iter().collect())
+                }
+            })
+        }
+        "TRIM" | "LTRIM" | "RTRIM" => {
+            if args.is_empty() || args.len() > 2 {
+                return Err(anyhow!("wrong number of arguments to function {}()", name));
+            }
+            let (Some(text), Some(set)) = (
+                args[0].to_text(),
+                args.get(1).map_or(Some(" ".to_string()), Value::to_text),
+            ) else {
+                return Ok(Value::Null);
+            };
+            let trimmed = |c: char| set.contains(c);
+            let upper = name.to_uppercase();
+            let mut result = text.as_str();
+            if upper != "RTRIM" {
+                result = result.trim_start_matches(trimmed);
+            }
+            if upper != "LTRIM" {
+                result = result.trim_end_matches(trimmed);
+            }
+            Ok(Value::Text(result.to_string()))
+        }
+        "REPLACE" => {
+            expect_args(name, args, 3)?;
+            let (Some(text), Some(pattern), Some(replacement)) =
+                (args[0].to_text(), args[1].to_text(), args[2].to_text())
+            else {
+                return Ok(Value::Null);
+            };
+            if pattern.is_empty() {
+                return Ok(args[0].clone());
+            }
+            Ok(Value::Text(text.replace(&pattern, &replacement)))
+        }
+        "INSTR" => {
+            expect_args(name, args, 2)?;
+            Ok(match (&args[0], &args[1]) {
+                (Value::Null, _) | (_, Value::Null) => Value::Null,
+                (Value::Blob(haystack), Value::Blob(needle)) => Value::Integer(
+                    haystack
+                        .windows(needle.len().max(1))
+                        .position(|window| window == needle.as_slice())
+                        .map_or(0, |i| i as i64 + 1),
+                ),
+                (haystack, needle) => {
+                    let haystack = haystack.to_string();
+                    Value::Integer(
+                        haystack
+                            .find(&needle.to_string())
+                            .map_or(0, |i| haystack[..i].chars().count() as i64 + 1),
+                    )
+                }
+            })
+        }
+        "CURRENT_DATE" | "CURRENT_TIME" | "CURRENT_TIMESTAMP" => {
+            expect_args(name, args, 0)?;
+            let (date, time) = current_utc();
+            Ok(Value::Text(match name.to_uppercase().as_str() {
+                "CURRENT_DATE" => date,
+                "CURRENT_TIME" => time,
+                _ => format!("{} {}", date, time),
+            }))
+        }
+        _ => Err(anyhow!("no such function: {}", name)),
+    }
+}
+
+/// Returns the current UTC date and time, as `YYYY-MM-DD` and `HH:MM:SS`
+fn current_utc() -> (String, String) {
+    let seconds = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs() as i64);
+    let (days, time) = (seconds.div_euclid(86_400), seconds.rem_euclid(86_400));
+
+    // Civil date from days since 1970-01-01, by eras of 400 years
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    (
+        format!("{:04}-{:02}-{:02}", year, month, day),
+        format!(
+            "{:02}:{:02}:{:02}",
+            time / 3600,
+            time % 3600 / 60,
+            time % 60
+        ),
+    )
+}
+
+/// Returns the first argument that is not NULL, or NULL
+fn first_non_null(args: &[Value]) -> Value {
+    args.iter()
+        .find(|arg| !arg.is_null())
+        .cloned()
+        .unwrap_or(Value::Null)
+}
+
+/// Converts substr()'s 1-based start, which counts from the end when
+/// negative, and optional length, which extends to the left when negative,
+/// into a start index and length within a value of `len` characters
+fn substr_range(len: usize, start: i64, count: Option<i64>) -> (usize, usize) {
+    let len = len as i64;
+    let (mut p1, mut p2) = (start, count.map_or(MAX_BLOB_LEN, i64::abs));
+    if p1 < 0 {
+        p1 += len;
+        if p1 < 0 {
+            p2 = (p2 + p1).max(0);
+            p1 = 0;
+        }
+    } else if p1 > 0 {
+        p1 -= 1;
+    } else if p2 > 0 {
+        p2 -= 1;
+    }
+    if count.map_or(false, |count| count < 0) {
+        p1 -= p2;
+        if p1 < 0 {
+            p2 += p1;
+            p1 = 0;
+        }
+    }
+    let from = p1.min(len);
+    let to = p1.saturating_add(p2).min(len);
+    (from as usize, (to - from).max(0) as usize)
+}
+
+/// Checks the number of arguments passed to a function
+fn expect_args(name: &str, args: &[Value], expected: usize) -> Result<()> {
+    if args.len() != expected {
+        return Err(anyhow!("wrong number of arguments to function {}()", name));
+    }
+    Ok(())
+}
+
+/// Validates a requested blob length
+fn blob_len(len: i64) -> Result<usize> {
+    if len > MAX_BLOB_LEN {
+        return Err(anyhow!("string or blob too big"));
+    }
+    Ok(len as usize)
+}
+
+impl SQLiteDatabase {
+    /// Reseeds the generator behind random() and randomblob()
+    ///
+    /// After reseeding with the same value, queries produce the same random values.
+    pub fn set_random_seed(&mut self, seed: u64) {
+        self.rng = Rng::with_seed(seed);
+    }
+}
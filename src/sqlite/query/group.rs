@@ -0,0 +1,259 @@
+//! GROUP BY
+//!
+//! Rows that pass the WHERE clause are bucketed by the values of the GROUP BY
+//! terms in a hash map, and each bucket gets its own set of aggregate
+//! accumulators, stepped as its rows arrive. Once every row has been read, the
+//! groups are finished into one result row each, after which the executor
+//! applies HAVING.
+//!
+//! Keys compare the way `=` does, under the collation of each term that is a
+//! column and BINARY otherwise, except that NULLs fall into a single group: 1
+//! and 1.0 share a group, and 'a' and 'A' do unless the term is a NOCASE
+//! column. A group's key takes the values of its first row.
+//!
+//! Groups are emitted in ascending key order, under the same collations and
+//! with BINARY text in the order of the database's encoding, which is the
+//! order SQLite produces them in when there is no ORDER BY.
+//!
+//! The keys and accumulators of the groups are kept within a memory budget,
+//! [`DEFAULT_GROUP_MEMORY_LIMIT`] unless the connection sets another. Once it is
+//! used up, rows of groups that already exist are still aggregated in memory,
+//! while rows that would start a new group are handed to a
+//! [`Spill`](super::spill::Spill) and aggregated partition by partition at the
+//! end. A partition is aggregated in memory whatever its size.
+
+use crate::sqlite::core::collation::{compare_index_keys, Collation, KeyColumn};
+use crate::sqlite::core::header::TextEncoding;
+use crate::sqlite::core::record::encode_record;
+use crate::sqlite::core::value::Value;
+use crate::sqlite::parser::expression::Expression;
+use crate::sqlite::parser::statement::Statement;
+use crate::sqlite::query::aggregate::{AggregateValues, Aggregates};
+use crate::sqlite::query::eval::{evaluate, is_rowid_alias, operand_collation, RowContext};
+use crate::sqlite::query::execute::ordinal_name;
+use crate::sqlite::query::functions::Rng;
+use crate::sqlite::query::spill::Spill;
+use crate::sqlite::storage::db::SQLiteDatabase;
+use anyhow::{anyhow, Result};
+use std::collections::HashMap;
+use tracing::debug;
+
+/// Bytes of keys and accumulators GROUP BY keeps in memory before spilling
+pub const DEFAULT_GROUP_MEMORY_LIMIT: usize = 64 * 1024 * 1024;
+
+/// Bytes a group is assumed to use besides its key and accumulators, for the
+/// hash map entry and the vector slot
+const GROUP_OVERHEAD: usize = 64;
+
+/// Rows bucketed by their GROUP BY key, each group with its own accumulators
+pub struct Groups<'a> {
+    terms: Vec<&'a Expression>,
+    /// Collation of each term
+    collations: Vec<Collation>,
+    /// Encoding of the database the rows were read from
+    encoding: TextEncoding,
+    /// Expressions whose aggregate calls every group accumulates
+    aggregate_exprs: Vec<&'a Expression>,
+    /// Encoded key to index into `groups`
+    index: HashMap<Vec<u8>, usize>,
+    groups: Vec<(Vec<Value>, Aggregates<'a>)>,
+    /// Approximate bytes used by `index` and `groups`
+    memory: usize,
+    /// Bytes the groups may use before new groups are spilled
+    memory_limit: usize,
+    /// Rows of the groups that did not fit, once the limit has been reached
+    spill: Option<Spill>,
+}
+
+impl<'a> Groups<'a> {
+    /// Resolves the statement's GROUP BY terms against the rows `ctx` is for
+    ///
+    /// An integer literal refers to a result column by position, and a bare
+    /// name that is not a column of the table refers to the result column with
+    /// that alias.
+    pub fn new(
+        stmt: &'a Statement,
+        ctx: &RowContext,
+        aggregate_exprs: Vec<&'a Expression>,
+        memory_limit: usize,
+    ) -> Result<Self> {
+        let schema = ctx.schema;
+        let mut terms = Vec::with_capacity(stmt.group_by.len());
+        for (i, term) in stmt.group_by.iter().enumerate() {
+            let resolved = match term {
+                Expression::Literal(Value::Integer(ordinal)) => {
+                    let columns = stmt.selections.len();
+                    if *ordinal < 1 || *ordinal as usize > columns {
+                        return Err(anyhow!(
+                            "{} GROUP BY term out of range - should be between 1 and {}",
+                            ordinal_name(i + 1),
+                            columns
+                        ));
+                    }
+                    &stmt.selections[*ordinal as usize - 1].expr
+                }
+                Expression::Column { table: None, name }
+                    if !is_rowid_alias(name)
+                        && !schema
+                            .columns
+                            .iter()
+                            .any(|column| column.name.eq_ignore_ascii_case(name)) =>
+                {
+                    stmt.selections
+                        .iter()
+                        .find(|selection| {
+                            selection
+                                .alias
+                                .as_ref()
+                                .map_or(false, |alias| alias.eq_ignore_ascii_case(name))
+                        })
+                        .map_or(term, |selection| &selection.expr)
+                }
+                term => term,
+            };
+            if !Aggregates::collect([resolved])?.is_empty() {
+                return Err(anyhow!(
+                    "aggregate functions are not allowed in the GROUP BY clause"
+                ));
+            }
+            terms.push(resolved);
+        }
+        let collations = terms
+            .iter()
+            .map(|term| operand_collation(term, ctx).unwrap_or_default())
+            .collect();
+        Ok(Self {
+            terms,
+            collations,
+            encoding: ctx.encoding,
+            aggregate_exprs,
+            index: HashMap::new(),
+            groups: Vec::new(),
+            memory: 0,
+            memory_limit,
+            spill: None,
+        })
+    }
+
+    /// Adds the `row_index`-th row to its group, creating the group if needed,
+    /// or sets the row aside if the group does not exist and there is no room
+    /// for it
+    pub fn step(&mut self, row_index: usize, ctx: &RowContext, rng: &mut Rng) -> Result<()> {
+        let key = self
+            .terms
+            .iter()
+            .map(|term| evaluate(term, ctx, rng))
+            .collect::<Result<Vec<_>>>()?;
+        let encoded = encode_key(&key, &self.collations);
+        let group = match self.index.get(&encoded) {
+            Some(&group) => group,
+            None if self.spill.is_some() || self.memory > self.memory_limit => {
+                if self.spill.is_none() {
+                    debug!(
+                        "GROUP BY passed {} bytes in {} groups, spilling new groups to disk",
+                        self.memory_limit,
+                        self.groups.len()
+                    );
+                }
+                let spill = match &mut self.spill {
+                    Some(spill) => spill,
+                    None => self.spill.insert(Spill::new()?),
+                };
+                return spill.write(&encoded, row_index);
+            }
+            None => {
+                let aggregates = Aggregates::collect(self.aggregate_exprs.iter().copied())?;
+                // The key is held twice: as values and encoded in the index
+                self.memory += GROUP_OVERHEAD + 2 * encoded.len() + aggregates.memory_size();
+                self.groups.push((key, aggregates));
+                self.index.insert(encoded, self.groups.len() - 1);
+                self.groups.len() - 1
+            }
+        };
+        let aggregates = &mut self.groups[group].1;
+        let before = aggregates.memory_size();
+        aggregates.step(row_index, ctx, rng)?;
+        self.memory = (self.memory + aggregates.memory_size()).saturating_sub(before);
+        Ok(())
+    }
+
+    /// Finishes every group in ascending key order, returning the index of its
+    /// source row for bare columns and the value of each aggregate call
+    ///
+    /// Spilled rows are aggregated here, with `row_context` giving the context
+    /// of the row with a given index.
+    pub fn finish<'r>(
+        self,
+        row_context: impl Fn(usize) -> RowContext<'r>,
+        rng: &mut Rng,
+    ) -> Result<Vec<(Option<usize>, AggregateValues<'a>)>> {
+        let mut finished = Vec::with_capacity(self.groups.len());
+        for (key, aggregates) in self.groups {
+            finished.push((key, aggregates.finish()?));
+        }
+        if let Some(spill) = self.spill {
+            for rows in spill.into_partitions() {
+                let mut partition = Groups {
+                    terms: self.terms.clone(),
+                    collations: self.collations.clone(),
+                    encoding: self.encoding,
+                    aggregate_exprs: self.aggregate_exprs.clone(),
+                    index: HashMap::new(),
+                    groups: Vec::new(),
+                    memory: 0,
+                    memory_limit: usize::MAX,
+                    spill: None,
+                };
+                for row_index in rows? {
+                    partition.step(row_index, &row_context(row_index), rng)?;
+                }
+                for (key, aggregates) in partition.groups {
+                    finished.push((key, aggregates.finish()?));
+                }
+            }
+        }
+
+        let order: Vec<KeyColumn> = self
+            .collations
+            .iter()
+            .map(|&collation| KeyColumn {
+                collation,
+                desc: false,
+                encoding: self.encoding,
+            })
+            .collect();
+        finished.sort_by(|(a, _), (b, _)| compare_index_keys(a, b, &order));
+        Ok(finished.into_iter().map(|(_, group)| group).collect())
+    }
+}
+
+impl SQLiteDatabase {
+    /// Sets how many bytes of keys and accumulators GROUP BY may keep in memory
+    /// before it spills rows to temporary files
+    pub fn set_group_memory_limit(&mut self, bytes: usize) {
+        self.group_memory_limit = bytes;
+    }
+}
+
+/// Encodes a group key so that values `=` considers equal under the collation
+/// of their term encode the same; terms past `collations` use BINARY
+///
+/// Reals holding an integer are stored as that integer, so 1.0 and 1 (and 0.0
+/// and -0.0) land in one group.
+pub(crate) fn encode_key(key: &[Value], collations: &[Collation]) -> Vec<u8> {
+    let normalized: Vec<Value> = key
+        .iter()
+        .enumerate()
+        .map(|(i, value)| match value {
+            Value::Real(r) if r.fract() == 0.0 && (-9.2e18..9.2e18).contains(r) => {
+                Value::Integer(*r as i64)
+            }
+            value => collations
+                .get(i)
+                .copied()
+                .unwrap_or_default()
+                .canonical(value),
+        })
+        .collect();
+    encode_record(&normalized)
+}
@@ -0,0 +1,187 @@
+//! Logical Content Hash
+//!
+//! Computes a SHA-1 digest of what a database contains rather than how it is laid
+//! out, so two files holding the same schema and rows hash the same regardless of
+//! page size, freelist state or vacuum history. The digest matches SQLite's
+//! `dbhash` tool.
+//!
+//! ## What Is Hashed
+//!
+//! 1. The rows of every ordinary table, in rowid order, with tables taken in
+//!    case-insensitive name order. Internal `sqlite_` tables and virtual tables
+//!    are skipped.
+//! 2. The type, name, tbl_name and sql of every sqlite_schema entry, in
+//!    case-insensitive name order.
+//!
+//! Each value is fed to the hash as a one-byte type tag followed by its data:
+//!
+//! - NULL: `0`
+//! - INTEGER: `I` and the 8-byte big-endian value
+//! - REAL: `F` and the 8-byte big-endian IEEE 754 bits
+//! - TEXT: `T` and the UTF-8 bytes
+//! - BLOB: `B` and the bytes
+
+use crate::sqlite::core::collation::Collation;
+use crate::sqlite::core::value::Value;
+use crate::sqlite::query::scan::Row;
+use crate::sqlite::storage::db::SQLiteDatabase;
+use anyhow::Result;
+
+impl SQLiteDatabase {
+    /// Returns the hex SHA-1 digest of the database's logical content
+    pub fn content_hash(&mut self) -> Result<String> {
+        let mut entries = self.schema_entries()?;
+        // Columns: type, name, tbl_name, rootpage, sql
+        let name = |entry: &Row| entry.values.get(1).unwrap_or(&Value::Null).clone();
+        let encoding = self.header.encoding();
+        entries.sort_by(|a, b| Collation::NoCase.compare(&name(a), &name(b), encoding));
+
+        let mut sha = Sha1::new();
+        for entry in &entries {
+            match entry.values.as_slice() {
+                [Value::Text(kind), Value::Text(name), _, _, Value::Text(sql)]
+                    if kind == "table"
+                        && !name.to_lowercase().starts_with("sqlite_")
+                        && !sql.to_uppercase().starts_with("CREATE VIRTUAL") =>
+                {
+                    let (_, rows) = self.scan_table(name, None, None)?;
+                    for row in &rows {
+                        row.values
+                            .iter()
+                            .for_each(|value| hash_value(&mut sha, value));
+                    }
+                }
+                _ => {}
+            }
+        }
+        for entry in &entries {
+            for column in [0, 1, 2, 4] {
+                hash_value(&mut sha, entry.values.get(column).unwrap_or(&Value::Null));
+            }
+        }
+
+        Ok(sha
+            .finish()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect())
+    }
+}
+
+/// Feeds one value to the hash as its type tag and data
+fn hash_value(sha: &mut Sha1, value: &Value) {
+    match value {
+        Value::Null => sha.update(b"0"),
+        Value::Integer(i) => {
+            sha.update(b"I");
+            sha.update(&i.to_be_bytes());
+        }
+        Value::Real(r) => {
+            sha.update(b"F");
+            sha.update(&r.to_bits().to_be_bytes());
+        }
+        Value::Text(s) => {
+            sha.update(b"T");
+            sha.update(s.as_bytes());
+        }
+        Value::Blob(b) => {
+            sha.update(b"B");
+            sha.update(b);
+        }
+    }
+}
+
+/// Incremental SHA-1 (FIPS 180-4)
+struct Sha1 {
+    state: [u32; 5],
+    /// Bytes not yet forming a complete 64-byte block
+    pending: Vec<u8>,
+    /// Total bytes hashed
+    length: u64,
+}
+
+impl Sha1 {
+    fn new() -> Self {
+        Self {
+            state: [
+                0x6745_2301,
+                0xefcd_ab89,
+                0x98ba_dcfe,
+                0x1032_5476,
+                0xc3d2_e1f0,
+            ],
+            pending: Vec::with_capacity(64),
+            length: 0,
+        }
+    }
+
+    fn update(&mut self, mut data: &[u8]) {
+        self.length += data.len() as u64;
+        if !self.pending.is_empty() {
+            let take = data.len().min(64 - self.pending.len());
+            self.pending.extend_from_slice(&data[..take]);
+            data = &data[take..];
+            if self.pending.len() < 64 {
+                return;
+            }
+            let block = std::mem::take(&mut self.pending);
+            self.compress(&block);
+        }
+        let mut blocks = data.chunks_exact(64);
+        for block in &mut blocks {
+            self.compress(block);
+        }
+        self.pending.extend_from_slice(blocks.remainder());
+    }
+
+    fn finish(mut self) -> [u8; 20] {
+        let bit_length = self.length.wrapping_mul(8);
+        self.update(&[0x80]);
+        while self.pending.len() != 56 {
+            self.update(&[0]);
+        }
+        self.update(&bit_length.to_be_bytes());
+
+        let mut digest = [0; 20];
+        for (chunk, word) in digest.chunks_exact_mut(4).zip(self.state) {
+            chunk.copy_from_slice(&word.to_be_bytes());
+        }
+        digest
+    }
+
+    /// Mixes one 64-byte block into the state
+    fn compress(&mut self, block: &[u8]) {
+        let mut w = [0u32; 80];
+        for (i, word) in block.chunks_exact(4).enumerate() {
+            w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e] = self.state;
+        for (i, &word) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5a82_7999),
+                20..=39 => (b ^ c ^ d, 0x6ed9_eba1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8f1b_bcdc),
+                _ => (b ^ c ^ d, 0xca62_c1d6),
+            };
+            let temp = a
+                .rotate_left(5)
+                .wrapping_add(f)
+                .wrapping_add(e)
+                .wrapping_add(k)
+                .wrapping_add(word);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+
+        for (state, value) in self.state.iter_mut().zip([a, b, c, d, e]) {
+            *state = state.wrapping_add(value);
+        }
+    }
+}
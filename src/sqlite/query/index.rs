@@ -0,0 +1,730 @@
+//! Index Lookups
+//!
+//! Before a table is scanned, its WHERE clause is searched for equality
+//! constraints between a column and a literal. If the rowid is pinned, or the
+//! leading columns of an index are, the planner weighs a direct lookup against
+//! a full scan using the costs in [`planner`](super::planner), and the cheaper
+//! access path is used to find the candidate rows.
+//!
+//! An index seek descends the index B-tree to the first entry whose key starts
+//! with the constrained values and collects the rowids of every entry that
+//! does. Those rowids are then looked up in the table in ascending order, so
+//! rows come out in the same order a full scan would produce them. The WHERE
+//! clause is still evaluated against every row found this way; the access path
+//! only narrows down which rows are read.
+//!
+//! Indexes come from CREATE INDEX statements and from the UNIQUE and PRIMARY
+//! KEY constraints of the table, whose automatic indexes have no SQL and take
+//! their columns from the table definition. A seek can use any leading part of
+//! a multi-column key: an index on `(a, b, c)` serves `a = 1` and `a = 1 AND
+//! b = 2`, though not `b = 2` alone. Partial indexes are not used, and an index
+//! column that is an expression or uses a collation that is not built in ends
+//! the usable part of its key.
+//!
+//! Entries are compared with the key in index order, as described in
+//! [`collation`](crate::sqlite::core::collation): each column under its own
+//! collation and direction. Under NOCASE or RTRIM a seek may find entries that
+//! differ from the key in case or trailing spaces; the WHERE clause drops
+//! those that `=` does not accept.
+//!
+//! Comparisons of the rowid with constants bound the rowids a full scan needs
+//! to read: the keys of interior table pages tell which subtrees hold rowids
+//! in range, and the others are skipped without being read.
+//!
+//! A `column LIKE 'abc%'` on a TEXT column can bound the index column after the
+//! equality prefix to a range: the entries that start with `abc`. Since LIKE
+//! ignores ASCII case, there is one such range for each way of writing the
+//! prefix's letters in upper or lower case. Only the first few letters are
+//! expanded, so a long prefix seeks a wider range than it needs to rather than
+//! seeking a great many narrow ones. A NOCASE column keeps all the case
+//! variants together, so one range covers them; an RTRIM column gets no range.
+
+use crate::sqlite::core::btree::{search_cells, BTreePage, PayloadLayout, MAX_DEPTH};
+use crate::sqlite::core::checked;
+use crate::sqlite::core::collation::{compare_index_keys, Collation, KeyColumn};
+use crate::sqlite::core::error::{corrupt, CorruptPage};
+use crate::sqlite::core::header::TextEncoding;
+use crate::sqlite::core::record::decode_record;
+use crate::sqlite::core::schema::{split_definitions, Affinity, TableSchema};
+use crate::sqlite::core::value::Value;
+use crate::sqlite::core::varint::decode_varint;
+use crate::sqlite::parser::expression::{BinaryOperator, Expression};
+use crate::sqlite::query::eval::is_rowid_alias;
+use crate::sqlite::query::planner::{AccessPath, IndexCandidate, Planner};
+use crate::sqlite::storage::db::SQLiteDatabase;
+use anyhow::Result;
+use std::cmp::Ordering;
+use std::ops::RangeInclusive;
+use tracing::debug;
+
+/// Letters of a LIKE prefix whose case variants get a seek each; the prefix is
+/// cut off after this many
+const MAX_CASE_LETTERS: usize = 4;
+
+/// An index on a table, as described by its CREATE INDEX statement
+#[derive(Debug, Clone)]
+pub struct IndexDef {
+    pub name: String,
+    pub root_page: u32,
+    pub unique: bool,
+    /// Number of columns in the index key
+    pub key_columns: usize,
+    /// The leading key columns a seek can use: the position of each in the
+    /// table schema and how it is ordered
+    pub columns: Vec<(usize, KeyColumn)>,
+}
+
+/// How the rows of a table are read
+#[derive(Debug, Clone)]
+pub enum Access {
+    /// Visit every row
+    Scan,
+    /// Read the single row with this rowid, or no row if the constraint can
+    /// never match one
+    Rowid(Option<i64>),
+    /// Read the rows whose index entries match any of `keys`
+    Index { index: IndexDef, keys: Vec<SeekKey> },
+}
+
+/// The index entries a seek reads
+#[derive(Debug, Clone)]
+pub struct SeekKey {
+    /// Values the leading index columns must equal
+    pub equal: Vec<Value>,
+    /// Text the next index column must start with
+    pub prefix: Option<String>,
+}
+
+impl SQLiteDatabase {
+    /// Chooses how to read the rows of `table` that `condition` may accept
+    pub(crate) fn plan_access(
+        &mut self,
+        table: &TableSchema,
+        condition: Option<&Expression>,
+    ) -> Result<Access> {
+        let Some(condition) = condition.filter(|_| !table.without_rowid) else {
+            return Ok(Access::Scan);
+        };
+        let constraints = equality_constraints(condition, table);
+        let prefixes = like_prefixes(condition, table);
+        if constraints.is_empty() && prefixes.is_empty() {
+            return Ok(Access::Scan);
+        }
+
+        let rowid = constraints
+            .iter()
+            .find(|(column, _)| column.is_none())
+            .map(|(_, value)| value);
+        let indexes = self.table_indexes(table)?;
+        // Each index's key: the constrained values of its leading columns,
+        // then a LIKE prefix on the column after them
+        let keys: Vec<SeekKey> = indexes
+            .iter()
+            .map(|index| {
+                let equal: Vec<Value> = index
+                    .columns
+                    .iter()
+                    .map_while(|&(position, _)| {
+                        constraints
+                            .iter()
+                            .find(|(column, _)| *column == Some(position))
+                            .map(|(_, value)| value.with_affinity(table.columns[position].affinity))
+                    })
+                    .collect();
+                let prefix = index
+                    .columns
+                    .get(equal.len())
+                    .filter(|(_, column)| column.collation != Collation::RTrim)
+                    .and_then(|&(position, _)| {
+                        prefixes
+                            .iter()
+                            .find(|(column, _)| *column == position)
+                            .map(|(_, prefix)| prefix.clone())
+                    });
+                SeekKey { equal, prefix }
+            })
+            .collect();
+        let candidates: Vec<IndexCandidate> = indexes
+            .iter()
+            .zip(&keys)
+            .map(|(index, key)| IndexCandidate {
+                name: index.name.clone(),
+                eq_columns: key.equal.len(),
+                range: key.prefix.is_some(),
+                unique: index.unique,
+                key_columns: index.key_columns,
+            })
+            .collect();
+
+        let stats = self.load_statistics()?;
+        let path =
+            Planner::new(&stats).choose_access_path(&table.name, rowid.is_some(), &candidates);
+        debug!("Access path for {}: {:?}", table.name, path);
+
+        Ok(match path {
+            AccessPath::FullScan => Access::Scan,
+            AccessPath::RowidLookup => Access::Rowid(
+                rowid.and_then(|value| as_rowid(&value.with_affinity(Affinity::Integer))),
+            ),
+            AccessPath::IndexSeek { index, .. } => {
+                let position = indexes
+                    .iter()
+                    .position(|candidate| candidate.name == index)
+                    .unwrap_or_default();
+                let index = &indexes[position];
+                let key = &keys[position];
+                let binary_prefix = index
+                    .columns
+                    .get(key.equal.len())
+                    .map_or(false, |(_, column)| column.collation == Collation::Binary);
+                let keys = match &key.prefix {
+                    Some(prefix) if binary_prefix => case_variants(prefix)
+                        .into_iter()
+                        .map(|prefix| SeekKey {
+                            equal: key.equal.clone(),
+                            prefix: Some(prefix),
+                        })
+                        .collect(),
+                    _ => vec![key.clone()],
+                };
+                Access::Index {
+                    index: index.clone(),
+                    keys,
+                }
+            }
+        })
+    }
+
+    /// Reads the definitions of the indexes on a table from sqlite_schema,
+    /// through the schema cache
+    pub(crate) fn table_indexes(&mut self, table: &TableSchema) -> Result<Vec<IndexDef>> {
+        let encoding = self.header.encoding();
+        let cache = self.schema_cache()?;
+        if let Some(indexes) = cache.indexes(&table.name) {
+            return Ok(indexes.to_vec());
+        }
+        let mut indexes = Vec::new();
+        for entry in cache.entries() {
+            // Columns: type, name, tbl_name, rootpage, sql
+            if let [Value::Text(kind), Value::Text(name), Value::Text(tbl_name), Value::Integer(root_page), sql] =
+                entry.values.as_slice()
+            {
+                if kind != "index" || !tbl_name.eq_ignore_ascii_case(&table.name) {
+                    continue;
+                }
+                let root_page = u32::try_from(*root_page)
+                    .map_err(|_| corrupt(0, format!("invalid root page {}", root_page)))
+                    .on_page(1)?;
+                let index = match sql {
+                    Value::Text(sql) => parse_index(name, root_page, sql, table, encoding),
+                    _ => automatic_index(name, root_page, table, encoding),
+                };
+                if let Some(index) = index {
+                    indexes.push(index);
+                }
+            }
+        }
+        cache.insert_indexes(&table.name, indexes.clone());
+        Ok(indexes)
+    }
+
+    /// Returns the rowids of the index entries that match `key`
+    pub(crate) fn seek_index(&mut self, index: &IndexDef, key: &SeekKey) -> Result<Vec<i64>> {
+        let mut rowids = Vec::new();
+        self.pager.count_seek();
+        self.seek_subtree(index.root_page, index, key, &mut rowids, 0)?;
+        Ok(rowids)
+    }
+
+    /// Appends the rowids of matching entries in the subtree rooted at
+    /// `page_num` to `rowids`, returning true once an entry past the key has
+    /// been seen
+    fn seek_subtree(
+        &mut self,
+        page_num: u32,
+        index: &IndexDef,
+        key: &SeekKey,
+        rowids: &mut Vec<i64>,
+        depth: usize,
+    ) -> Result<bool> {
+        if depth > MAX_DEPTH {
+            return Err(corrupt(0, "index B-tree is too deep")).on_page(page_num);
+        }
+
+        let page = self.pager.read_btree_page(page_num)?;
+        if !page.is_index() {
+            return Err(corrupt(
+                page.header_offset(),
+                format!("invalid page type in index B-tree: {}", page.page_type()),
+            ))
+            .on_page(page_num);
+        }
+        let num_cells = page.num_cells() as usize;
+
+        // Binary search for the first entry that is not before the key
+        let low = search_cells(num_cells, |i| {
+            let (_, entry) = self.index_cell(&page, i)?;
+            Ok(compare_prefix(&entry, key, index) == Ordering::Less)
+        })
+        .on_page(page_num)?;
+
+        for i in low..num_cells {
+            let (child, entry) = self.index_cell(&page, i).on_page(page_num)?;
+            // Entries equal to the key can also sit at the end of the subtree to
+            // the left of the first one
+            if let Some(child) = child {
+                if self.seek_subtree(child, index, key, rowids, depth + 1)? {
+                    return Ok(true);
+                }
+            }
+            if compare_prefix(&entry, key, index) != Ordering::Equal {
+                return Ok(true);
+            }
+            match entry.last() {
+                Some(Value::Integer(rowid)) => rowids.push(*rowid),
+                _ => return Err(corrupt(0, "index entry has no rowid")).on_page(page_num),
+            }
+        }
+        if page.is_interior() {
+            let right_most = page.right_child()?;
+            return self.seek_subtree(right_most, index, key, rowids, depth + 1);
+        }
+        Ok(false)
+    }
+
+    /// Decodes the `i`-th cell of an index page into its left child, for
+    /// interior pages, and its entry: the key columns followed by the rowid
+    pub(crate) fn index_cell(
+        &mut self,
+        btree_page: &BTreePage,
+        i: usize,
+    ) -> Result<(Option<u32>, Vec<Value>)> {
+        let page = btree_page.data();
+        let mut pos = btree_page.cell_pointer(i)?;
+        let child = if btree_page.is_interior() {
+            pos += 4;
+            Some(checked::be_u32(page, pos - 4)?)
+        } else {
+            None
+        };
+
+        let (payload_size, len) = decode_varint(page, pos)?;
+        let layout = PayloadLayout::new(self.usable_size()?, btree_page.page_type());
+        let pager = &mut self.pager;
+        let payload =
+            layout.read_payload(page, pos + len, payload_size as usize, &mut |page_num| {
+                pager.read_page(page_num)
+            })?;
+        self.metrics.cells_visited += 1;
+        self.metrics.bytes_decoded += payload.len() as u64;
+        Ok((child, decode_record(&payload, &self.header)?))
+    }
+}
+
+/// Compares the leading columns of an index entry with a seek key, in index
+/// order: Equal if the entry matches, Less if it comes before every entry that
+/// does and Greater if after
+fn compare_prefix(entry: &[Value], key: &SeekKey, index: &IndexDef) -> Ordering {
+    let columns: Vec<KeyColumn> = index.columns.iter().map(|&(_, column)| column).collect();
+    let equal = compare_index_keys(entry, &key.equal, &columns);
+    let n = key.equal.len();
+    match (&key.prefix, entry.get(n), columns.get(n)) {
+        (Some(prefix), Some(value), Some(column)) if equal.is_eq() => match value {
+            // Text starting with the prefix sorts after the prefix itself and
+            // before any text that does not
+            Value::Text(text) if column.collation.starts_with(text, prefix) => Ordering::Equal,
+            value => column.compare(value, &Value::Text(prefix.clone())),
+        },
+        _ => equal,
+    }
+}
+
+/// Returns every way of writing the ASCII letters of a LIKE prefix in upper or
+/// lower case, cutting the prefix off after its first few letters
+fn case_variants(prefix: &str) -> Vec<String> {
+    let mut variants = vec![String::new()];
+    let mut letters = 0;
+    for c in prefix.chars() {
+        if !c.is_ascii_alphabetic() {
+            variants.iter_mut().for_each(|variant| variant.push(c));
+            continue;
+        }
+        if letters == MAX_CASE_LETTERS {
+            break;
+        }
+        letters += 1;
+        variants = variants
+            .into_iter()
+            .flat_map(|variant| {
+                [c.to_ascii_lowercase(), c.to_ascii_uppercase()].map(|c| {
+                    let mut variant = variant.clone();
+                    variant.push(c);
+                    variant
+                })
+            })
+            .collect();
+    }
+    variants
+}
+
+/// Finds the `column = literal` terms ANDed together in a condition, as the
+/// position of the column in the table schema (None for the rowid) and the
+/// literal
+fn equality_constraints<'e>(
+    condition: &'e Expression,
+    table: &TableSchema,
+) -> Vec<(Option<usize>, &'e Value)> {
+    let mut constraints = Vec::new();
+    let mut pending = vec![condition];
+    while let Some(expr) = pending.pop() {
+        let Expression::Binary { left, op, right } = expr else {
+            continue;
+        };
+        let (column, value) = match (op, left.as_ref(), right.as_ref()) {
+            (BinaryOperator::And, _, _) => {
+                pending.push(left);
+                pending.push(right);
+                continue;
+            }
+            (
+                BinaryOperator::Eq,
+                Expression::Column {
+                    table: qualifier,
+                    name,
+                },
+                Expression::Literal(value),
+            )
+            | (
+                BinaryOperator::Eq,
+                Expression::Literal(value),
+                Expression::Column {
+                    table: qualifier,
+                    name,
+                },
+            ) => {
+                if qualifier.as_ref().map_or(false, |qualifier| {
+                    !qualifier.eq_ignore_ascii_case(&table.name)
+                }) {
+                    continue;
+                }
+                (name, value)
+            }
+            _ => continue,
+        };
+        match table
+            .columns
+            .iter()
+            .position(|def| def.name.eq_ignore_ascii_case(column))
+        {
+            Some(position) if table.columns[position].rowid_alias => {
+                constraints.push((None, value))
+            }
+            Some(position) => constraints.push((Some(position), value)),
+            None if is_rowid_alias(column) => constraints.push((None, value)),
+            None => {}
+        }
+    }
+    constraints
+}
+
+/// Finds the range the rowid must fall in for `condition` to be true, from the
+/// comparisons and BETWEENs of the rowid with literals ANDed together in it,
+/// or None if nothing limits the rowid
+///
+/// Literals are converted as the INTEGER rowid would convert them; those that
+/// are not numbers after that place no limit.
+pub(crate) fn rowid_range(
+    condition: &Expression,
+    table: &TableSchema,
+) -> Option<RangeInclusive<i64>> {
+    let (mut low, mut high) = (i64::MIN, i64::MAX);
+    let mut pending = vec![condition];
+    while let Some(expr) = pending.pop() {
+        let (op, value) = match expr {
+            Expression::Binary {
+                left,
+                op: BinaryOperator::And,
+                right,
+            } => {
+                pending.push(left);
+                pending.push(right);
+                continue;
+            }
+            Expression::Binary { left, op, right } => match (left.as_ref(), right.as_ref()) {
+                (column, Expression::Literal(value)) if is_rowid(column, table) => (*op, value),
+                // `5 < rowid` is `rowid > 5`
+                (Expression::Literal(value), column) if is_rowid(column, table) => {
+                    let op = match op {
+                        BinaryOperator::Lt => BinaryOperator::Gt,
+                        BinaryOperator::LtEq => BinaryOperator::GtEq,
+                        BinaryOperator::Gt => BinaryOperator::Lt,
+                        BinaryOperator::GtEq => BinaryOperator::LtEq,
+                        op => *op,
+                    };
+                    (op, value)
+                }
+                _ => continue,
+            },
+            Expression::Between {
+                expr,
+                low: from,
+                high: to,
+            } if is_rowid(expr, table) => {
+                if let Expression::Literal(from) = from.as_ref() {
+                    narrow_rowids(&mut low, &mut high, BinaryOperator::GtEq, from);
+                }
+                if let Expression::Literal(to) = to.as_ref() {
+                    narrow_rowids(&mut low, &mut high, BinaryOperator::LtEq, to);
+                }
+                continue;
+            }
+            _ => continue,
+        };
+        narrow_rowids(&mut low, &mut high, op, value);
+    }
+    (low != i64::MIN || high != i64::MAX).then_some(low..=high)
+}
+
+/// Narrows the inclusive rowid range `low..=high` to the rowids for which
+/// `rowid op value` can be true
+fn narrow_rowids(low: &mut i64, high: &mut i64, op: BinaryOperator, value: &Value) {
+    // Reals bound the integers on either side of them
+    let (floor, ceil) = match value.with_affinity(Affinity::Integer) {
+        Value::Integer(i) => (i, i),
+        Value::Real(r) if !r.is_nan() => (r.floor() as i64, r.ceil() as i64),
+        _ => return,
+    };
+    match op {
+        BinaryOperator::Eq if floor == ceil => {
+            *low = (*low).max(floor);
+            *high = (*high).min(ceil);
+        }
+        BinaryOperator::Gt => *low = (*low).max(floor.saturating_add(1)),
+        BinaryOperator::GtEq => *low = (*low).max(ceil),
+        BinaryOperator::Lt => *high = (*high).min(ceil.saturating_sub(1)),
+        BinaryOperator::LtEq => *high = (*high).min(floor),
+        _ => {}
+    }
+}
+
+/// Returns true if `expr` refers to the rowid of `table`, by one of its names
+/// or through an INTEGER PRIMARY KEY column
+fn is_rowid(expr: &Expression, table: &TableSchema) -> bool {
+    let Expression::Column {
+        table: qualifier,
+        name,
+    } = expr
+    else {
+        return false;
+    };
+    if qualifier.as_ref().map_or(false, |qualifier| {
+        !qualifier.eq_ignore_ascii_case(&table.name)
+    }) {
+        return false;
+    }
+    match table
+        .columns
+        .iter()
+        .find(|def| def.name.eq_ignore_ascii_case(name))
+    {
+        Some(column) => column.rowid_alias,
+        None => is_rowid_alias(name),
+    }
+}
+
+/// Finds the `column LIKE 'prefix%'` terms ANDed together in a condition whose
+/// column has TEXT affinity, as the position of the column and the text before
+/// the first wildcard
+///
+/// Patterns with an ESCAPE clause or a leading wildcard are left out. Values
+/// of any other type convert to text that a range of text entries would miss,
+/// so other affinities are left out too.
+fn like_prefixes(condition: &Expression, table: &TableSchema) -> Vec<(usize, String)> {
+    let mut prefixes = Vec::new();
+    let mut pending = vec![condition];
+    while let Some(expr) = pending.pop() {
+        match expr {
+            Expression::Binary {
+                left,
+                op: BinaryOperator::And,
+                right,
+            } => {
+                pending.push(left);
+                pending.push(right);
+            }
+            Expression::Like {
+                expr,
+                pattern,
+                escape: None,
+            } => {
+                let (
+                    Expression::Column {
+                        table: qualifier,
+                        name,
+                    },
+                    Expression::Literal(Value::Text(pattern)),
+                ) = (expr.as_ref(), pattern.as_ref())
+                else {
+                    continue;
+                };
+                if qualifier.as_ref().map_or(false, |qualifier| {
+                    !qualifier.eq_ignore_ascii_case(&table.name)
+                }) {
+                    continue;
+                }
+                let prefix: String = pattern
+                    .chars()
+                    .take_while(|c| !matches!(c, '%' | '_'))
+                    .collect();
+                let position = table
+                    .columns
+                    .iter()
+                    .position(|def| def.name.eq_ignore_ascii_case(name));
+                if let Some(position) = position.filter(|&position| {
+                    table.columns[position].affinity == Affinity::Text && !prefix.is_empty()
+                }) {
+                    prefixes.push((position, prefix));
+                }
+            }
+            _ => {}
+        }
+    }
+    prefixes
+}
+
+/// Returns the rowid a value equal to the rowid must be, if there is one
+fn as_rowid(value: &Value) -> Option<i64> {
+    match value {
+        Value::Integer(i) => Some(*i),
+        Value::Real(r) if r.fract() == 0.0 && (-9.2e18..9.2e18).contains(r) => Some(*r as i64),
+        _ => None,
+    }
+}
+
+/// Describes an index SQLite created for a PRIMARY KEY or UNIQUE constraint,
+/// named `sqlite_autoindex_<table>_<N>` for the N-th such constraint
+fn automatic_index(
+    name: &str,
+    root_page: u32,
+    table: &TableSchema,
+    encoding: TextEncoding,
+) -> Option<IndexDef> {
+    let number: usize = name.rsplit('_').next()?.parse().ok()?;
+    let (_, key) = table
+        .automatic_index_keys()
+        .into_iter()
+        .find(|&(n, _)| n == number)?;
+    let columns: Vec<(usize, KeyColumn)> = key
+        .iter()
+        .map_while(|&(position, desc)| {
+            let collation = column_collation(table.columns[position].collation.as_deref())?;
+            let key_column = KeyColumn {
+                collation,
+                desc,
+                encoding,
+            };
+            Some((position, key_column))
+        })
+        .collect();
+    if columns.is_empty() {
+        return None;
+    }
+    Some(IndexDef {
+        name: name.to_string(),
+        root_page,
+        unique: true,
+        key_columns: key.len(),
+        columns,
+    })
+}
+
+/// Returns the collation named by a COLLATE clause, BINARY if there is none,
+/// or None if it is not built in and so cannot be compared with
+fn column_collation(name: Option<&str>) -> Option<Collation> {
+    name.map_or(Some(Collation::Binary), Collation::from_name)
+}
+
+/// Parses `CREATE [UNIQUE] INDEX name ON table (column, ...)`, returning None
+/// for partial indexes and indexes whose first column cannot be sought
+fn parse_index(
+    name: &str,
+    root_page: u32,
+    sql: &str,
+    table: &TableSchema,
+    encoding: TextEncoding,
+) -> Option<IndexDef> {
+    let open = sql.find('(')?;
+    let mut depth = 0usize;
+    let close = open
+        + sql[open..].find(|c| {
+            match c {
+                '(' => depth += 1,
+                ')' => depth -= 1,
+                _ => {}
+            }
+            depth == 0
+        })?;
+    if !sql[close + 1..].trim().is_empty() {
+        debug!("Not using partial index {}", name);
+        return None;
+    }
+    let unique = sql[..open]
+        .split_whitespace()
+        .take_while(|word| !word.eq_ignore_ascii_case("INDEX"))
+        .any(|word| word.eq_ignore_ascii_case("UNIQUE"));
+
+    let terms = split_definitions(&sql[open + 1..close]);
+    let mut columns = Vec::new();
+    for term in &terms {
+        let words: Vec<&str> = term.split_whitespace().collect();
+        let Some((column, rest)) = words.split_first() else {
+            break;
+        };
+        let column = column.trim_matches(|c| matches!(c, '"' | '`' | '[' | ']'));
+        let Some(position) = table
+            .columns
+            .iter()
+            .position(|def| def.name.eq_ignore_ascii_case(column))
+        else {
+            break;
+        };
+        // Any other word makes the term an expression
+        let expression = rest.iter().enumerate().any(|(i, word)| {
+            let keyword = ["ASC", "DESC", "COLLATE"]
+                .iter()
+                .any(|keyword| word.eq_ignore_ascii_case(keyword));
+            let collation = i > 0 && rest[i - 1].eq_ignore_ascii_case("COLLATE");
+            !keyword && !collation
+        });
+        if expression {
+            break;
+        }
+        // An index column uses the column's collation unless it names its own
+        let collation = rest
+            .iter()
+            .position(|word| word.eq_ignore_ascii_case("COLLATE"))
+            .and_then(|i| rest.get(i + 1).copied())
+            .or(table.columns[position].collation.as_deref());
+        let Some(collation) = column_collation(collation) else {
+            break;
+        };
+        let desc = rest.iter().any(|word| word.eq_ignore_ascii_case("DESC"));
+        let key_column = KeyColumn {
+            collation,
+            desc,
+            encoding,
+        };
+        columns.push((position, key_column));
+    }
+    if columns.is_empty() {
+        return None;
+    }
+
+    Some(IndexDef {
+        name: name.to_string(),
+        root_page,
+        unique,
+        key_columns: terms.len(),
+        columns,
+    })
+}
@@ -0,0 +1,534 @@
+//! INSERT Statements
+//!
+//! An INSERT adds one row for each row of its VALUES list or of the result of
+//! its SELECT: every column named in the column list, or every column in table
+//! order if there is none, takes its value from the source row, and the
+//! columns left out take the value of their DEFAULT clause, or NULL if they
+//! have none. Values are stored as [`ColumnDef::stored_value`] says, so they
+//! take the affinity of their column.
+//!
+//! [`ColumnDef::stored_value`]: crate::sqlite::core::schema::ColumnDef::stored_value
+//!
+//! Every source row is read before the first new row is added, so a SELECT
+//! from the table being filled does not see its own output.
+//!
+//! All rows are added in one transaction: the pages they change stay with the
+//! pager until the statement finishes, then are written and synced once, so a
+//! bulk load costs one sync rather than one per row.
+//!
+//! ## Rowids
+//!
+//! A new row's rowid may be given directly, as `rowid`, `oid` or `_rowid_` in
+//! the column list, or as the value of the table's INTEGER PRIMARY KEY column,
+//! which stores the rowid rather than a value of its own. It must be an integer
+//! or convert to one, or the row fails with "datatype mismatch". Otherwise,
+//! or if it is NULL, the row takes one more than the largest rowid in the
+//! table, or 1 if the table is empty; once the largest possible rowid is
+//! taken, random unused ones are tried instead.
+//!
+//! ## Conflicts
+//!
+//! A new row conflicts with an existing one when they share a rowid, or a
+//! PRIMARY KEY or UNIQUE key. The statement's conflict resolution decides
+//! what happens: ABORT, the default, fails the statement; IGNORE skips the
+//! new row; REPLACE deletes every row the new one conflicts with, then inserts
+//! it.
+//!
+//! A new row breaking a NOT NULL constraint is resolved the same way, except
+//! that REPLACE gives the column its DEFAULT value instead, and fails if it has
+//! none; ON CONFLICT clauses do not apply to it. Failures are
+//! [`ConstraintViolation`] errors.
+//!
+//! [`ConstraintViolation`]: crate::sqlite::core::error::ConstraintViolation
+//!
+//! Unique keys are checked by seeking the new row's key in the automatic
+//! index of each PRIMARY KEY and UNIQUE constraint, and in each UNIQUE index.
+//!
+//! An ON CONFLICT clause takes precedence for the constraint it names, or for
+//! any constraint if it names none, which must be the table's PRIMARY KEY or
+//! one of its UNIQUE constraints. DO NOTHING skips the new row. DO UPDATE
+//! updates the existing row instead, as an UPDATE of that row alone would,
+//! with `excluded.column` giving the value the new row would have stored.
+//!
+//! Each new row also gets an entry in every index on the table, and a row
+//! that REPLACE deletes loses its entries.
+//!
+//! Not supported yet: WITHOUT ROWID tables and tables with partial or
+//! expression indexes.
+
+use crate::sqlite::core::error::ConstraintViolation;
+use crate::sqlite::core::schema::{Affinity, TableSchema};
+use crate::sqlite::core::value::Value;
+use crate::sqlite::parser::expression::Expression;
+use crate::sqlite::parser::statement::{
+    ConflictResolution, Insert, InsertSource, Upsert, UpsertAction,
+};
+use crate::sqlite::query::eval::{evaluate, evaluate_constant, is_rowid_alias, RowContext};
+use crate::sqlite::query::execute::ResultRows;
+use crate::sqlite::query::index::{IndexDef, SeekKey};
+use crate::sqlite::query::scan::Row;
+use crate::sqlite::query::update::{assigned_columns, check_not_null, check_write_expression};
+use crate::sqlite::storage::db::SQLiteDatabase;
+use anyhow::{anyhow, Result};
+use tracing::debug;
+
+impl SQLiteDatabase {
+    /// Runs an INSERT statement, returning the number of rows it added
+    pub fn insert(&mut self, insert: &Insert) -> Result<usize> {
+        self.write_transaction(|db| db.apply_insert(insert))
+    }
+
+    /// Adds the rows without committing
+    fn apply_insert(&mut self, insert: &Insert) -> Result<usize> {
+        let (schema, root_page, indexes) = self.writable_table(&insert.table, "INSERT")?;
+        if let InsertSource::Values(rows) = &insert.source {
+            for expr in rows.iter().flatten() {
+                check_write_expression(expr, "INSERT")?;
+            }
+        }
+        if let Some(upsert) = &insert.upsert {
+            check_upsert(&schema, upsert)?;
+        }
+
+        let (width, rows) = match &insert.source {
+            InsertSource::Values(rows) => {
+                let values = rows
+                    .iter()
+                    .map(|row| {
+                        row.iter()
+                            .map(|expr| self.evaluate_constant(expr))
+                            .collect::<Result<Vec<_>>>()
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                (rows.first().map_or(0, Vec::len), values)
+            }
+            // The whole result is read before the first row is added, so a
+            // SELECT from the same table sees none of the new rows
+            InsertSource::Select(select) => {
+                let result = self.execute(select)?;
+                let rows = match result.rows {
+                    ResultRows::Values(rows) => rows,
+                    ResultRows::Count(count) => vec![vec![Value::Integer(count as i64)]],
+                };
+                (result.columns.len(), rows)
+            }
+        };
+
+        let mut positions = Positions {
+            columns: vec![None; schema.columns.len()],
+            rowid: None,
+        };
+        if insert.columns.is_empty() {
+            if width != schema.columns.len() {
+                return Err(anyhow!(
+                    "table {} has {} columns but {} values were supplied",
+                    schema.name,
+                    schema.columns.len(),
+                    width
+                ));
+            }
+            positions.columns = (0..schema.columns.len()).map(Some).collect();
+        } else {
+            if width != insert.columns.len() {
+                return Err(anyhow!(
+                    "{} values for {} columns",
+                    width,
+                    insert.columns.len()
+                ));
+            }
+            for (i, name) in insert.columns.iter().enumerate() {
+                match schema
+                    .columns
+                    .iter()
+                    .position(|column| column.name.eq_ignore_ascii_case(name))
+                {
+                    Some(position) => positions.columns[position] = Some(i),
+                    None if is_rowid_alias(name) => positions.rowid = Some(i),
+                    None => {
+                        return Err(anyhow!(
+                            "table {} has no column named {}",
+                            schema.name,
+                            name
+                        ))
+                    }
+                }
+            }
+        }
+        // An INTEGER PRIMARY KEY column is another name for the rowid
+        if let Some(alias) = schema.columns.iter().position(|column| column.rowid_alias) {
+            positions.rowid = positions.columns[alias].or(positions.rowid);
+        }
+
+        debug!("Inserting {} rows into {}", rows.len(), schema.name);
+        let mut changes = 0;
+        for values in &rows {
+            changes += self.insert_row(&schema, root_page, &indexes, insert, &positions, values)?;
+        }
+        Ok(changes)
+    }
+
+    /// Evaluates an expression of VALUES or DEFAULT, which is constant, against
+    /// no row
+    fn evaluate_constant(&mut self, expr: &Expression) -> Result<Value> {
+        evaluate_constant(expr, self.header.encoding(), &mut self.rng)
+    }
+
+    /// Adds one row, given the values of a source row and where each value of
+    /// the new row is among them, returning the number of rows changed
+    fn insert_row(
+        &mut self,
+        schema: &TableSchema,
+        root_page: u32,
+        indexes: &[IndexDef],
+        insert: &Insert,
+        positions: &Positions,
+        values: &[Value],
+    ) -> Result<usize> {
+        let rowid = match positions.rowid.map(|i| &values[i]) {
+            None | Some(Value::Null) => self.next_rowid(root_page)?,
+            Some(value) => explicit_rowid(value)?,
+        };
+        let mut stored = Vec::with_capacity(schema.columns.len());
+        for (column, position) in schema.columns.iter().zip(&positions.columns) {
+            // An INTEGER PRIMARY KEY reads as the rowid, as in stored rows
+            if column.rowid_alias {
+                stored.push(Value::Integer(rowid));
+                continue;
+            }
+            let value = match *position {
+                Some(i) => values[i].clone(),
+                None => match column.default_expression()? {
+                    Some(expr) => self.evaluate_constant(expr)?,
+                    None => Value::Null,
+                },
+            };
+            stored.push(column.stored_value(&value));
+        }
+        let mut row = Row {
+            rowid,
+            values: stored,
+        };
+
+        if let Err(violation) = check_not_null(schema, &row) {
+            match insert.on_conflict {
+                ConflictResolution::Abort => return Err(violation.into()),
+                ConflictResolution::Ignore => return Ok(0),
+                ConflictResolution::Replace => self.default_nulls(schema, &mut row)?,
+            }
+        }
+
+        while let Some(conflict) = self.find_conflict(schema, root_page, indexes, &row)? {
+            if let Some(upsert) = &insert.upsert {
+                if upsert.target.is_empty() || same_columns(schema, &upsert.target, &conflict.key) {
+                    return self.apply_upsert(
+                        schema,
+                        root_page,
+                        indexes,
+                        upsert,
+                        &row,
+                        conflict.rowid,
+                    );
+                }
+            }
+            match insert.on_conflict {
+                ConflictResolution::Abort => return Err(conflict.violation(schema).into()),
+                ConflictResolution::Ignore => return Ok(0),
+                ConflictResolution::Replace => {
+                    debug!("Replacing row {} of {}", conflict.rowid, schema.name);
+                    let Some(old) = self.read_row(schema, root_page, conflict.rowid)? else {
+                        return Err(anyhow!("no row with rowid {}", conflict.rowid));
+                    };
+                    self.remove_index_entries(schema, indexes, &old)?;
+                    self.delete_record(root_page, conflict.rowid)?;
+                }
+            }
+        }
+
+        debug!("Inserting row {}", rowid);
+        let record = self.encode_row(schema, &row);
+        self.insert_record(root_page, rowid, &record)?;
+        self.add_index_entries(schema, indexes, &row)?;
+        Ok(1)
+    }
+
+    /// Gives the NOT NULL columns of a new row that hold NULL their DEFAULT
+    /// value, as REPLACE resolves a NOT NULL violation, failing if a column has
+    /// no default to take
+    fn default_nulls(&mut self, schema: &TableSchema, row: &mut Row) -> Result<()> {
+        for (column, value) in schema.columns.iter().zip(row.values.iter_mut()) {
+            if column.not_null && matches!(value, Value::Null) {
+                if let Some(expr) = column.default_expression()? {
+                    *value = column.stored_value(&self.evaluate_constant(expr)?);
+                }
+            }
+        }
+        Ok(check_not_null(schema, row)?)
+    }
+
+    /// Finds a row that `row` would conflict with: one with its rowid, or one
+    /// of the rows [`unique_conflict`](Self::unique_conflict) finds
+    fn find_conflict(
+        &mut self,
+        schema: &TableSchema,
+        root_page: u32,
+        indexes: &[IndexDef],
+        row: &Row,
+    ) -> Result<Option<Conflict>> {
+        if self.cursor(root_page)?.seek_rowid(row.rowid)? {
+            let key = schema
+                .columns
+                .iter()
+                .position(|column| column.rowid_alias)
+                .into_iter()
+                .collect();
+            return Ok(Some(Conflict {
+                rowid: row.rowid,
+                key,
+            }));
+        }
+        self.unique_conflict(schema, indexes, row)
+    }
+
+    /// Finds a row other than `row` with the same values for the columns of
+    /// one of the table's unique `indexes`, by seeking those values in the
+    /// index
+    ///
+    /// A key holding NULL never conflicts, since no two NULLs are equal. Keys
+    /// are compared under the collations of their index, so a NOCASE key
+    /// conflicts with one differing only in case.
+    pub(crate) fn unique_conflict(
+        &mut self,
+        schema: &TableSchema,
+        indexes: &[IndexDef],
+        row: &Row,
+    ) -> Result<Option<Conflict>> {
+        for index in indexes.iter().filter(|index| index.unique) {
+            let key: Vec<usize> = index
+                .columns
+                .iter()
+                .map(|&(position, _)| position)
+                .collect();
+            let equal: Vec<Value> = key
+                .iter()
+                .map(|&position| row.values[position].clone())
+                .collect();
+            if equal.iter().any(|value| matches!(value, Value::Null)) {
+                continue;
+            }
+            let seek = SeekKey {
+                equal,
+                prefix: None,
+            };
+            if let Some(rowid) = self
+                .seek_index(index, &seek)?
+                .into_iter()
+                .find(|&rowid| rowid != row.rowid)
+            {
+                debug!(
+                    "Row {} of {} conflicts in {}",
+                    rowid, schema.name, index.name
+                );
+                return Ok(Some(Conflict { rowid, key }));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Carries out the ON CONFLICT clause for a new row that conflicts with the
+    /// row with the given rowid, returning the number of rows changed
+    fn apply_upsert(
+        &mut self,
+        schema: &TableSchema,
+        root_page: u32,
+        indexes: &[IndexDef],
+        upsert: &Upsert,
+        new_row: &Row,
+        rowid: i64,
+    ) -> Result<usize> {
+        let UpsertAction::Update {
+            assignments,
+            where_clause,
+        } = &upsert.action
+        else {
+            return Ok(0);
+        };
+        let Some(existing) = self.read_row(schema, root_page, rowid)? else {
+            return Err(anyhow!("no row with rowid {}", rowid));
+        };
+
+        let assignments = assignments
+            .iter()
+            .map(|(name, expr)| Ok((name.clone(), bind_excluded(schema, expr, new_row)?)))
+            .collect::<Result<Vec<_>>>()?;
+        if let Some(condition) = where_clause {
+            let condition = bind_excluded(schema, condition, new_row)?;
+            let context = RowContext::new(schema, &existing, self.header.encoding());
+            if evaluate(&condition, &context, &mut self.rng)?.truth() != Some(true) {
+                return Ok(0);
+            }
+        }
+
+        debug!("Updating row {} of {} on conflict", rowid, schema.name);
+        let assigned = assigned_columns(schema, &assignments)?;
+        let updated = self.updated_row(schema, &existing, &assigned)?;
+        if let Some(conflict) = self.unique_conflict(schema, indexes, &updated)? {
+            return Err(conflict.violation(schema).into());
+        }
+        self.update_index_entries(schema, indexes, &existing, &updated)?;
+        let record = self.encode_row(schema, &updated);
+        self.replace_record(root_page, rowid, &record)?;
+        Ok(1)
+    }
+}
+
+/// Where the values of a new row are in its source row
+struct Positions {
+    /// The position of each column's value, in table order, or None for a
+    /// column left out
+    columns: Vec<Option<usize>>,
+    /// The position of the rowid, given directly or through an INTEGER
+    /// PRIMARY KEY column, or None to leave the choice to the table
+    rowid: Option<usize>,
+}
+
+/// Converts a value given for the rowid to an integer, as SQLite does: text
+/// and reals holding an integer are accepted, anything else is an error
+fn explicit_rowid(value: &Value) -> Result<i64> {
+    match value.with_affinity(Affinity::Integer) {
+        Value::Integer(rowid) => Ok(rowid),
+        Value::Real(r) if r == r.trunc() && (i64::MIN as f64..i64::MAX as f64).contains(&r) => {
+            Ok(r as i64)
+        }
+        _ => Err(anyhow!("datatype mismatch")),
+    }
+}
+
+/// An existing row a new row conflicts with
+pub(crate) struct Conflict {
+    rowid: i64,
+    /// Positions of the columns of the constraint the rows share values for;
+    /// empty for the rowid itself
+    key: Vec<usize>,
+}
+
+impl Conflict {
+    /// The error for the conflict, naming the constraint as SQLite does
+    pub(crate) fn violation(&self, schema: &TableSchema) -> ConstraintViolation {
+        let columns = if self.key.is_empty() {
+            vec!["rowid".to_string()]
+        } else {
+            self.key
+                .iter()
+                .map(|&position| schema.columns[position].name.clone())
+                .collect()
+        };
+        ConstraintViolation::Unique {
+            table: schema.name.clone(),
+            columns,
+        }
+    }
+}
+
+/// Checks that an ON CONFLICT clause can be carried out: its target must be the
+/// PRIMARY KEY or a UNIQUE constraint, and its expressions must be evaluable
+/// one row at a time
+fn check_upsert(schema: &TableSchema, upsert: &Upsert) -> Result<()> {
+    if let UpsertAction::Update {
+        assignments,
+        where_clause,
+    } = &upsert.action
+    {
+        assigned_columns(schema, assignments)?;
+        let no_row = Row {
+            rowid: 0,
+            values: vec![Value::Null; schema.columns.len()],
+        };
+        let expressions = assignments.iter().map(|(_, expr)| expr);
+        for expr in expressions.chain(where_clause) {
+            check_write_expression(expr, "ON CONFLICT")?;
+            bind_excluded(schema, expr, &no_row)?;
+        }
+    }
+    if upsert.target.is_empty() {
+        return Ok(());
+    }
+    for name in &upsert.target {
+        if !schema
+            .columns
+            .iter()
+            .any(|column| column.name.eq_ignore_ascii_case(name))
+        {
+            return Err(anyhow!("no such column: {}", name));
+        }
+    }
+    let rowid_key = schema
+        .columns
+        .iter()
+        .position(|column| column.rowid_alias)
+        .map(|position| vec![position]);
+    let keys = schema.unique_keys.iter().map(|key| {
+        key.iter()
+            .map(|&(position, _)| position)
+            .collect::<Vec<_>>()
+    });
+    if !rowid_key
+        .into_iter()
+        .chain(keys)
+        .any(|key| same_columns(schema, &upsert.target, &key))
+    {
+        return Err(anyhow!(
+            "ON CONFLICT clause does not match any PRIMARY KEY or UNIQUE constraint"
+        ));
+    }
+    Ok(())
+}
+
+/// Returns true if `names` name exactly the columns at `positions`, in any
+/// order
+fn same_columns(schema: &TableSchema, names: &[String], positions: &[usize]) -> bool {
+    names.len() == positions.len()
+        && positions.iter().all(|&position| {
+            names
+                .iter()
+                .any(|name| schema.columns[position].name.eq_ignore_ascii_case(name))
+        })
+}
+
+/// Replaces the `excluded.column` references of an ON CONFLICT expression
+/// with the values of the new row
+fn bind_excluded(schema: &TableSchema, expr: &Expression, new_row: &Row) -> Result<Expression> {
+    let mut expr = expr.clone();
+    let mut missing = None;
+    expr.walk_mut(&mut |expr| {
+        let Expression::Column {
+            table: Some(table),
+            name,
+        } = expr
+        else {
+            return;
+        };
+        if !table.eq_ignore_ascii_case("excluded") {
+            if !table.eq_ignore_ascii_case(&schema.name) {
+                missing.get_or_insert_with(|| format!("{}.{}", table, name));
+            }
+            return;
+        }
+        let value = match schema
+            .columns
+            .iter()
+            .position(|column| column.name.eq_ignore_ascii_case(name))
+        {
+            Some(position) => new_row.values[position].clone(),
+            None if is_rowid_alias(name) => Value::Integer(new_row.rowid),
+            None => {
+                missing.get_or_insert_with(|| format!("excluded.{}", name));
+                return;
+            }
+        };
+        *expr = Expression::Literal(value);
+    });
+    match missing {
+        Some(column) => Err(anyhow!("no such column: {}", column)),
+        None => Ok(expr),
+    }
+}
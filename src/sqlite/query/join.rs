@@ -0,0 +1,343 @@
+//! Joins
+//!
+//! Evaluates a FROM clause naming several tables with nested loops: the rows of
+//! the outermost table are extended by every row of the next that satisfies the
+//! join conditions, those by every matching row of the one after, and so on. A
+//! LEFT JOIN also keeps each row that matched nothing, with NULL for the joined
+//! table's columns. The WHERE clause is applied to the fully joined rows.
+//!
+//! The loops run in the order the planner picks from `sqlite_stat1`, smaller
+//! tables outside, or in FROM clause order when there are no statistics. The
+//! table on the right of a LEFT JOIN never moves past the tables around it.
+//!
+//! Statements with EXISTS subqueries also read their rows this way, even from
+//! a single table, so that qualified references resolve against the right
+//! table of the enclosing query.
+//!
+//! Each loop reads its table through a B-tree cursor of its own, started over
+//! for every row of the loops outside it, so only the joined rows are held in
+//! memory. No index is used and the cost is the product of the table sizes.
+//!
+//! Joined rows use the layout described on [`JoinedTable`]: every table's columns
+//! in FROM clause order, then one rowid per table. The combined schema lists the
+//! columns only, so `*` expands to them and not to the rowids.
+
+use crate::sqlite::core::schema::TableSchema;
+use crate::sqlite::core::value::Value;
+use crate::sqlite::parser::expression::Expression;
+use crate::sqlite::parser::statement::{JoinKind, Statement};
+use crate::sqlite::query::aggregate::reject_aggregates;
+use crate::sqlite::query::eval::{evaluate, is_rowid_alias, JoinedTable, RowContext, SubqueryRows};
+use crate::sqlite::query::planner::Planner;
+use crate::sqlite::query::scan::{current_row, row_cursor, Row};
+use crate::sqlite::storage::cursor::BTreeCursor;
+use crate::sqlite::storage::db::SQLiteDatabase;
+use anyhow::{anyhow, Result};
+use tracing::debug;
+
+/// One table of the FROM clause and how it joins to the tables before it
+struct Source<'a> {
+    table: &'a str,
+    alias: Option<&'a String>,
+    kind: JoinKind,
+    constraint: Option<&'a Expression>,
+}
+
+/// The tables of a join and the nested loops that read them
+struct JoinPlan<'a> {
+    /// Every table's columns, in FROM clause order
+    schema: TableSchema,
+    tables: Vec<JoinedTable>,
+    /// Each table's schema and root page, in FROM clause order
+    inputs: Vec<(TableSchema, u32)>,
+    /// The loops, outermost first
+    levels: Vec<Level<'a>>,
+}
+
+/// One loop of a join, reading one table
+struct Level<'a> {
+    /// Position of the table in the FROM clause
+    source: usize,
+    /// True for the table on the right of a LEFT JOIN
+    left: bool,
+    /// The LEFT JOIN's ON condition, which decides whether a row matched
+    on: Option<&'a Expression>,
+    /// Inner join conditions first checkable once this table is joined
+    filters: Vec<&'a Expression>,
+}
+
+impl SQLiteDatabase {
+    /// Reads the rows produced by the statement's FROM clause and WHERE clause,
+    /// along with the combined schema and the layout of the joined rows
+    pub(crate) fn join_tables(
+        &mut self,
+        stmt: &Statement,
+        subqueries: &[SubqueryRows],
+    ) -> Result<(TableSchema, Vec<Row>, Vec<JoinedTable>)> {
+        let (schema, mut rows, tables) = self.join_rows(stmt)?;
+        if let Some(condition) = &stmt.where_clause {
+            let mut kept = Vec::with_capacity(rows.len());
+            for row in rows {
+                let ctx = RowContext::new(&schema, &row, self.header.encoding())
+                    .joined(&tables)
+                    .with_subqueries(subqueries);
+                // NULL counts as false
+                if evaluate(condition, &ctx, &mut self.rng)?.truth() == Some(true) {
+                    kept.push(row);
+                }
+            }
+            rows = kept;
+        }
+        Ok((schema, rows, tables))
+    }
+
+    /// Reads the rows produced by the statement's FROM clause, ignoring its
+    /// WHERE clause
+    pub(crate) fn join_rows(
+        &mut self,
+        stmt: &Statement,
+    ) -> Result<(TableSchema, Vec<Row>, Vec<JoinedTable>)> {
+        let first = stmt
+            .from_table
+            .as_deref()
+            .ok_or_else(|| anyhow!("a join needs a FROM clause"))?;
+        let sources: Vec<Source> = std::iter::once(Source {
+            table: first,
+            alias: stmt.table_alias.as_ref(),
+            kind: JoinKind::Inner,
+            constraint: None,
+        })
+        .chain(stmt.joins.iter().map(|join| Source {
+            table: &join.table,
+            alias: join.alias.as_ref(),
+            kind: join.kind,
+            constraint: join.constraint.as_ref(),
+        }))
+        .collect();
+
+        let mut columns = Vec::new();
+        let mut tables = Vec::new();
+        let mut inputs = Vec::new();
+        for source in &sources {
+            if let Some(constraint) = source.constraint {
+                reject_aggregates(constraint)?;
+            }
+            let (schema, root_page) = self.find_table(source.table)?;
+            tables.push(JoinedTable {
+                name: source.alias.map_or(&schema.name, |alias| alias).clone(),
+                first_column: columns.len(),
+                column_count: schema.columns.len(),
+                rowid_index: 0,
+            });
+            columns.extend(schema.columns.iter().cloned());
+            inputs.push((schema, root_page));
+        }
+        for (i, table) in tables.iter_mut().enumerate() {
+            table.rowid_index = columns.len() + i;
+        }
+        let schema = TableSchema {
+            name: String::new(),
+            columns,
+            sql: String::new(),
+            without_rowid: false,
+            primary_key: Vec::new(),
+            unique_keys: Vec::new(),
+        };
+
+        let stats = self.load_statistics()?;
+        let kinds: Vec<(&str, JoinKind)> = sources
+            .iter()
+            .map(|source| (source.table, source.kind))
+            .collect();
+        let order = Planner::new(&stats).order_joins(&kinds);
+        debug!("Joining tables in order {:?}", order);
+        let levels = plan_levels(&sources, &order, &schema, &tables);
+        let plan = JoinPlan {
+            schema,
+            tables,
+            inputs,
+            levels,
+        };
+
+        // Rows start out all NULL, so the columns of tables not yet joined (and
+        // of tables a LEFT JOIN found no match in) read as NULL
+        let width = plan.schema.columns.len() + plan.tables.len();
+        let mut candidate = Row {
+            rowid: 0,
+            values: vec![Value::Null; width],
+        };
+        let mut joined = Vec::new();
+        self.join_level(&plan, 0, &mut candidate, &mut joined)?;
+        Ok((plan.schema, joined, plan.tables))
+    }
+
+    /// Extends `candidate`, which holds the rows of the tables joined at the
+    /// levels before `depth`, with every combination of rows of the remaining
+    /// tables that satisfies the join, appending the joined rows to `joined`
+    ///
+    /// The level's table is read from the start with a cursor of its own,
+    /// which is parked while the deeper levels read their tables.
+    fn join_level(
+        &mut self,
+        plan: &JoinPlan,
+        depth: usize,
+        candidate: &mut Row,
+        joined: &mut Vec<Row>,
+    ) -> Result<()> {
+        let Some(level) = plan.levels.get(depth) else {
+            joined.push(candidate.clone());
+            return Ok(());
+        };
+        let table = &plan.tables[level.source];
+        let (schema, root_page) = &plan.inputs[level.source];
+        let usable_size = self.usable_size()?;
+        let mut cursor = row_cursor(
+            &mut self.pager,
+            &self.header,
+            usable_size,
+            schema,
+            *root_page,
+        )?;
+        let mut valid = cursor.first()?;
+        let mut parked = cursor.park();
+        let mut matched = false;
+        while valid {
+            let mut cursor = BTreeCursor::resume(&mut self.pager, &self.header, parked);
+            let row = current_row(&mut cursor, schema, &self.header, &mut self.metrics)?;
+            valid = cursor.next()?;
+            parked = cursor.park();
+
+            fill(&mut candidate.values, table, &row);
+            if level.source == 0 {
+                candidate.rowid = row.rowid;
+            }
+            if !self.conditions_hold(plan, level.on, candidate)? {
+                continue;
+            }
+            matched = true;
+            if self.conditions_hold(plan, level.filters.iter().copied(), candidate)? {
+                self.join_level(plan, depth + 1, candidate, joined)?;
+            }
+        }
+        clear(&mut candidate.values, table);
+        if !matched
+            && level.left
+            && self.conditions_hold(plan, level.filters.iter().copied(), candidate)?
+        {
+            self.join_level(plan, depth + 1, candidate, joined)?;
+        }
+        Ok(())
+    }
+
+    /// Reports whether every condition is true for a joined row; NULL counts
+    /// as false
+    fn conditions_hold<'e>(
+        &mut self,
+        plan: &JoinPlan,
+        conditions: impl IntoIterator<Item = &'e Expression>,
+        candidate: &Row,
+    ) -> Result<bool> {
+        for condition in conditions {
+            let ctx = RowContext::new(&plan.schema, candidate, self.header.encoding())
+                .joined(&plan.tables);
+            if evaluate(condition, &ctx, &mut self.rng)?.truth() != Some(true) {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+}
+
+/// Assigns the tables of a join to the levels of the nested loops in `order`,
+/// and each ON condition to the level that checks it
+///
+/// A LEFT JOIN's condition is checked by its own table's level, since it
+/// decides whether a row of that table matched. An inner join's condition is
+/// no different from a WHERE condition, so it is checked as soon as its own
+/// table and every table its columns belong to are joined, which may be
+/// deeper than its own table when the order moved a table it refers to.
+fn plan_levels<'a>(
+    sources: &[Source<'a>],
+    order: &[usize],
+    schema: &TableSchema,
+    tables: &[JoinedTable],
+) -> Vec<Level<'a>> {
+    let mut depth_of = vec![0; order.len()];
+    for (depth, &source) in order.iter().enumerate() {
+        depth_of[source] = depth;
+    }
+    let mut levels: Vec<Level> = order
+        .iter()
+        .map(|&source| Level {
+            source,
+            left: sources[source].kind == JoinKind::Left,
+            on: None,
+            filters: Vec::new(),
+        })
+        .collect();
+    for (source, entry) in sources.iter().enumerate() {
+        let Some(constraint) = entry.constraint else {
+            continue;
+        };
+        if entry.kind == JoinKind::Left {
+            levels[depth_of[source]].on = Some(constraint);
+            continue;
+        }
+        let mut depth = depth_of[source];
+        constraint.walk(&mut |expr| {
+            if let Expression::Column { table, name } = expr {
+                let owners = column_owners(table.as_deref(), name, schema, tables);
+                // A column no table claims waits for every table before its own
+                let owners = if owners.is_empty() {
+                    (0..=source).collect()
+                } else {
+                    owners
+                };
+                for owner in owners {
+                    depth = depth.max(depth_of[owner]);
+                }
+            }
+        });
+        levels[depth].filters.push(constraint);
+    }
+    levels
+}
+
+/// Returns the FROM clause positions of the tables a column reference could
+/// belong to
+fn column_owners(
+    qualifier: Option<&str>,
+    name: &str,
+    schema: &TableSchema,
+    tables: &[JoinedTable],
+) -> Vec<usize> {
+    tables
+        .iter()
+        .enumerate()
+        .filter(|(_, table)| qualifier.map_or(true, |q| table.name.eq_ignore_ascii_case(q)))
+        .filter(|(_, table)| {
+            let columns =
+                &schema.columns[table.first_column..table.first_column + table.column_count];
+            columns
+                .iter()
+                .any(|column| column.name.eq_ignore_ascii_case(name))
+                || (qualifier.is_some() && is_rowid_alias(name))
+        })
+        .map(|(i, _)| i)
+        .collect()
+}
+
+/// Copies a table row into its place in a joined row
+fn fill(values: &mut [Value], table: &JoinedTable, row: &Row) {
+    let columns = &mut values[table.first_column..table.first_column + table.column_count];
+    for (slot, value) in columns.iter_mut().zip(&row.values) {
+        *slot = value.clone();
+    }
+    values[table.rowid_index] = Value::Integer(row.rowid);
+}
+
+/// Sets a table's place in a joined row back to NULL
+fn clear(values: &mut [Value], table: &JoinedTable) {
+    values[table.first_column..table.first_column + table.column_count].fill(Value::Null);
+    values[table.rowid_index] = Value::Null;
+}
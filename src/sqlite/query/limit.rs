@@ -0,0 +1,153 @@
+//! LIMIT and OFFSET
+//!
+//! A LIMIT lets the executor stop early. Without ORDER BY, the rows come out in
+//! scan order, so a scan of a single table stops once it has collected `offset +
+//! count` rows. With ORDER BY, every row still has to be read, but only the best
+//! `offset + count` are kept, in a bounded max-heap whose top is the worst row
+//! kept so far, instead of sorting them all.
+
+use crate::sqlite::core::collation::{compare_index_keys, KeyColumn};
+use crate::sqlite::core::header::TextEncoding;
+use crate::sqlite::core::schema::TableSchema;
+use crate::sqlite::core::value::Value;
+use crate::sqlite::parser::expression::Expression;
+use crate::sqlite::parser::statement::Statement;
+use crate::sqlite::query::eval::{evaluate, RowContext};
+use crate::sqlite::query::functions::Rng;
+use crate::sqlite::query::scan::Row;
+use anyhow::{anyhow, Result};
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+
+/// The evaluated LIMIT clause of a statement
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Bounds {
+    /// Most rows to return; `usize::MAX` for a negative limit
+    pub count: usize,
+    /// Rows to skip first
+    pub offset: usize,
+}
+
+impl Bounds {
+    /// Evaluates the statement's LIMIT clause, if it has one
+    pub fn of(stmt: &Statement, rng: &mut Rng) -> Result<Option<Self>> {
+        let Some(limit) = &stmt.limit else {
+            return Ok(None);
+        };
+        let count = constant_integer(&limit.count, rng)?;
+        let offset = match &limit.offset {
+            Some(offset) => constant_integer(offset, rng)?,
+            None => 0,
+        };
+        Ok(Some(Self {
+            count: usize::try_from(count).unwrap_or(usize::MAX),
+            offset: usize::try_from(offset).unwrap_or(0),
+        }))
+    }
+
+    /// Number of rows to produce before the limit is reached, counting those
+    /// the offset skips
+    pub fn end(&self) -> usize {
+        self.count.saturating_add(self.offset)
+    }
+}
+
+/// Evaluates a LIMIT or OFFSET expression, which must be an integer or convert
+/// to one exactly
+fn constant_integer(expr: &Expression, rng: &mut Rng) -> Result<i64> {
+    let schema = TableSchema {
+        name: String::new(),
+        columns: Vec::new(),
+        sql: String::new(),
+        without_rowid: false,
+        primary_key: Vec::new(),
+        unique_keys: Vec::new(),
+    };
+    let row = Row {
+        rowid: 0,
+        values: Vec::new(),
+    };
+    // Not read from any database, so its text is compared as UTF-8
+    let ctx = RowContext::new(&schema, &row, TextEncoding::Utf8);
+    match evaluate(expr, &ctx, rng)?.with_numeric_affinity() {
+        Value::Integer(i) => Ok(i),
+        Value::Real(r) if r.fract() == 0.0 && (-9.2e18..9.2e18).contains(&r) => Ok(r as i64),
+        _ => Err(anyhow!("datatype mismatch")),
+    }
+}
+
+/// Keeps the first `capacity` result rows in ORDER BY order
+pub struct TopK<'a> {
+    capacity: usize,
+    order_by: &'a [KeyColumn],
+    heap: BinaryHeap<Ranked<'a>>,
+    /// Arrival number of the next row, which breaks ties so that rows with
+    /// equal keys keep their scan order
+    next_seq: usize,
+}
+
+/// A result row and its sort keys, ordered by ORDER BY and then by arrival
+struct Ranked<'a> {
+    keys: Vec<Value>,
+    columns: Vec<Value>,
+    seq: usize,
+    order_by: &'a [KeyColumn],
+}
+
+impl Ord for Ranked<'_> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        compare_index_keys(&self.keys, &other.keys, self.order_by).then(self.seq.cmp(&other.seq))
+    }
+}
+
+impl PartialOrd for Ranked<'_> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Ranked<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Ranked<'_> {}
+
+impl<'a> TopK<'a> {
+    pub fn new(capacity: usize, order_by: &'a [KeyColumn]) -> Self {
+        Self {
+            capacity,
+            order_by,
+            heap: BinaryHeap::new(),
+            next_seq: 0,
+        }
+    }
+
+    /// Offers a result row, dropping it or the worst row kept if there are
+    /// more than `capacity`
+    pub fn push(&mut self, (keys, columns): (Vec<Value>, Vec<Value>)) {
+        let ranked = Ranked {
+            keys,
+            columns,
+            seq: self.next_seq,
+            order_by: self.order_by,
+        };
+        self.next_seq += 1;
+        if self.heap.len() < self.capacity {
+            self.heap.push(ranked);
+        } else if self.heap.peek().map_or(false, |worst| ranked < *worst) {
+            self.heap.pop();
+            self.heap.push(ranked);
+        }
+    }
+
+    /// Returns the rows kept, in ORDER BY order
+    pub fn into_sorted(self) -> Vec<(Vec<Value>, Vec<Value>)> {
+        self.heap
+            .into_sorted_vec()
+            .into_iter()
+            .map(|ranked| (ranked.keys, ranked.columns))
+            .collect()
+    }
+}
@@ -0,0 +1,207 @@
+//! Index Maintenance
+//!
+//! Every write to a table keeps its indexes in step with its rows: a new row
+//! adds an entry to each index, a deleted row takes its entries with it, and a
+//! changed row whose key columns change in an index has its entry there
+//! replaced. An entry is the row's key column values, as the table stores
+//! them, followed by its rowid; an INTEGER PRIMARY KEY column gives the rowid.
+//! Entries go where their key puts them under the collation and direction of
+//! each key column, so the index reads back as SQLite wrote it.
+//!
+//! Only indexes whose key columns are all columns of the table under built-in
+//! collations can be kept up to date; tables with partial indexes or indexes
+//! on expressions cannot be written yet.
+
+use crate::sqlite::core::collation::KeyColumn;
+use crate::sqlite::core::schema::TableSchema;
+use crate::sqlite::core::value::Value;
+use crate::sqlite::query::index::IndexDef;
+use crate::sqlite::query::scan::Row;
+use crate::sqlite::storage::db::SQLiteDatabase;
+use anyhow::Result;
+
+impl SQLiteDatabase {
+    /// Adds the entries of a new row to each of the table's indexes
+    pub(crate) fn add_index_entries(
+        &mut self,
+        schema: &TableSchema,
+        indexes: &[IndexDef],
+        row: &Row,
+    ) -> Result<()> {
+        for index in indexes {
+            let entry = index_entry(schema, index, row);
+            self.insert_index_entry(index.root_page, &entry, &key_order(index))?;
+        }
+        Ok(())
+    }
+
+    /// Removes the entries of a deleted row from each of the table's indexes
+    pub(crate) fn remove_index_entries(
+        &mut self,
+        schema: &TableSchema,
+        indexes: &[IndexDef],
+        row: &Row,
+    ) -> Result<()> {
+        for index in indexes {
+            let entry = index_entry(schema, index, row);
+            self.delete_index_entry(index.root_page, &entry, &key_order(index))?;
+        }
+        Ok(())
+    }
+
+    /// Replaces the entries of a row that changes from `old` to `new` in the
+    /// indexes whose key columns it changes
+    pub(crate) fn update_index_entries(
+        &mut self,
+        schema: &TableSchema,
+        indexes: &[IndexDef],
+        old: &Row,
+        new: &Row,
+    ) -> Result<()> {
+        for index in indexes {
+            let old_entry = index_entry(schema, index, old);
+            let new_entry = index_entry(schema, index, new);
+            if old_entry == new_entry {
+                continue;
+            }
+            let key = key_order(index);
+            self.delete_index_entry(index.root_page, &old_entry, &key)?;
+            self.insert_index_entry(index.root_page, &new_entry, &key)?;
+        }
+        Ok(())
+    }
+}
+
+/// Returns the entry of `row` in an index: its key column values as stored,
+/// then its rowid
+fn index_entry(schema: &TableSchema, index: &IndexDef, row: &Row) -> Vec<Value> {
+    let mut entry: Vec<Value> = index
+        .columns
+        .iter()
+        .map(|&(position, _)| {
+            let column = &schema.columns[position];
+            match column.rowid_alias {
+                true => Value::Integer(row.rowid),
+                false => column.stored_value(&row.values[position]),
+            }
+        })
+        .collect();
+    entry.push(Value::Integer(row.rowid));
+    entry
+}
+
+/// Returns how each key column of an index is ordered
+fn key_order(index: &IndexDef) -> Vec<KeyColumn> {
+    index.columns.iter().map(|&(_, column)| column).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sqlite::core::collation::compare_index_keys;
+    use crate::sqlite::testing::{sqlite3, TempFile};
+
+    /// Creates `t` in a new file with an automatic index for each of its
+    /// UNIQUE constraints, one of them over a NOCASE column and a descending
+    /// one, and fills it with enough rows to split the index B-trees over
+    /// several pages
+    fn indexed_table(file: &TempFile) -> SQLiteDatabase {
+        let mut db = SQLiteDatabase::open_create(&file.0).unwrap();
+        db.query(
+            "CREATE TABLE t(a INTEGER PRIMARY KEY, b TEXT COLLATE NOCASE, c, \
+             UNIQUE(b, c DESC), UNIQUE(c))",
+        )
+        .unwrap();
+        for i in 0..400 {
+            let name = if i % 2 == 0 { "Name" } else { "name" };
+            let sql = format!(
+                "INSERT INTO t VALUES ({}, '{}{}{}', {})",
+                i * 3,
+                name,
+                i % 37,
+                "x".repeat(60),
+                i
+            );
+            db.query(&sql).unwrap();
+        }
+        db
+    }
+
+    /// Scans every index on `table` and checks that it holds exactly one entry
+    /// per row of the table, in index order, then has sqlite3 check the file,
+    /// returning the number of indexes
+    fn check_indexes(db: &mut SQLiteDatabase, file: &TempFile, table: &str) -> usize {
+        let (schema, rows) = db.scan_table(table, None, None).unwrap();
+        let indexes = db.table_indexes(&schema).unwrap();
+        for index in &indexes {
+            let key = key_order(index);
+            let mut expected: Vec<Vec<Value>> = rows
+                .iter()
+                .map(|row| index_entry(&schema, index, row))
+                .collect();
+            expected.sort_by(|a, b| compare_index_keys(a, b, &key));
+
+            let mut entries = Vec::new();
+            let mut cursor = db.cursor(index.root_page).unwrap();
+            let mut valid = cursor.first().unwrap();
+            while valid {
+                entries.push(cursor.current().unwrap());
+                valid = cursor.next().unwrap();
+            }
+            assert_eq!(entries, expected, "entries of {}", index.name);
+        }
+        sqlite3_integrity_check(file);
+        indexes.len()
+    }
+
+    /// Runs sqlite3's `PRAGMA integrity_check`, which also compares every
+    /// index with its table, on the file; skipped if sqlite3 is not installed
+    fn sqlite3_integrity_check(file: &TempFile) {
+        if let Some(output) = sqlite3(file, "PRAGMA integrity_check") {
+            assert_eq!(output.trim(), "ok");
+        }
+    }
+
+    #[test]
+    fn insert_adds_an_entry_to_every_index() {
+        let file = TempFile::new("maintain-insert");
+        let mut db = indexed_table(&file);
+        assert_eq!(check_indexes(&mut db, &file, "t"), 2);
+    }
+
+    #[test]
+    fn update_replaces_the_entries_of_changed_keys() {
+        let file = TempFile::new("maintain-update");
+        let mut db = indexed_table(&file);
+        db.query("UPDATE t SET b = upper(b) WHERE a % 2 = 0")
+            .unwrap();
+        check_indexes(&mut db, &file, "t");
+        db.query("UPDATE t SET c = c + 1000 WHERE c % 3 = 0")
+            .unwrap();
+        check_indexes(&mut db, &file, "t");
+        db.query("UPDATE t SET b = NULL, c = -c WHERE a % 5 = 0")
+            .unwrap();
+        check_indexes(&mut db, &file, "t");
+    }
+
+    #[test]
+    fn delete_removes_the_entries_of_deleted_rows() {
+        let file = TempFile::new("maintain-delete");
+        let mut db = indexed_table(&file);
+        db.query("DELETE FROM t WHERE c % 3 = 1").unwrap();
+        check_indexes(&mut db, &file, "t");
+        db.query("DELETE FROM t WHERE b LIKE 'name1%'").unwrap();
+        check_indexes(&mut db, &file, "t");
+        db.query("DELETE FROM t").unwrap();
+        check_indexes(&mut db, &file, "t");
+    }
+
+    #[test]
+    fn unique_conflicts_leave_the_indexes_unchanged() {
+        let file = TempFile::new("maintain-unique");
+        let mut db = indexed_table(&file);
+        assert!(db.query("INSERT INTO t VALUES (5000, 'x', 7)").is_err());
+        assert!(db.query("UPDATE t SET c = 8 WHERE c = 9").is_err());
+        check_indexes(&mut db, &file, "t");
+    }
+}
@@ -1 +1,25 @@
+pub mod aggregate;
+pub mod cache;
+pub mod columns;
+pub mod create;
+pub mod delete;
+pub mod distinct;
+pub mod eval;
 pub mod execute;
+pub mod functions;
+pub mod group;
+pub mod hash;
+pub mod index;
+pub mod insert;
+pub mod join;
+pub mod limit;
+pub mod maintain;
+pub mod parallel;
+pub mod planner;
+pub mod prepared;
+pub mod scan;
+pub mod schema_cache;
+pub mod spill;
+pub mod subquery;
+pub mod trace;
+pub mod update;
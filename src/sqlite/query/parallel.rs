@@ -0,0 +1,222 @@
+//! Parallel Table Scans
+//!
+//! A full scan of a large table can be shared out between worker threads. The
+//! interior pages of the table B-tree are walked first to list its leaves,
+//! which all sit at the same depth; each worker then gets a contiguous run of
+//! leaves, whose rows it decodes and filters while reading pages through a
+//! [`SharedPager`]. The runs are joined back in order, so the rows come out in
+//! rowid order exactly as from a serial scan.
+//!
+//! Parallel scans are off until [`SQLiteDatabase::set_scan_threads`] allows
+//! more than one thread, and are only used for COUNT(*) and for the rows of
+//! aggregate queries, which read every row. Tables with too few leaves for
+//! every worker to get a fair share, WITHOUT ROWID tables, scans the planner
+//! can replace with lookups or narrow to a rowid range, and conditions calling
+//! random() are scanned on the calling thread instead.
+
+use crate::sqlite::core::btree::{BTreePage, MAX_DEPTH};
+use crate::sqlite::core::error::{corrupt, CorruptPage};
+use crate::sqlite::core::schema::TableSchema;
+use crate::sqlite::core::varint::decode_varint;
+use crate::sqlite::parser::expression::Expression;
+use crate::sqlite::query::eval::{evaluate, RowContext};
+use crate::sqlite::query::functions::Rng;
+use crate::sqlite::query::index::{rowid_range, Access};
+use crate::sqlite::query::scan::{decode_leaf_cell, Row};
+use crate::sqlite::storage::db::SQLiteDatabase;
+use crate::sqlite::storage::pager::{Pager, SharedPager};
+use anyhow::{anyhow, Result};
+use std::thread;
+use tracing::debug;
+
+/// Fewest leaf pages each worker must get for a scan to be split
+const MIN_LEAVES_PER_THREAD: usize = 16;
+
+impl SQLiteDatabase {
+    /// Sets how many threads a full scan may use; 1, the default, scans on the
+    /// calling thread
+    pub fn set_scan_threads(&mut self, threads: usize) {
+        self.scan_threads = threads.max(1);
+    }
+
+    /// Reads the schema and the rows of a table for which `condition` is true,
+    /// splitting the scan between threads when that is allowed and worthwhile
+    pub(crate) fn scan_table_parallel(
+        &mut self,
+        table_name: &str,
+        condition: Option<&Expression>,
+    ) -> Result<(TableSchema, Vec<Row>)> {
+        let (schema, root_page) = self.find_table(table_name)?;
+        if self.scan_threads <= 1
+            || schema.without_rowid
+            || !condition.map_or(true, Expression::is_deterministic)
+            || !matches!(self.plan_access(&schema, condition)?, Access::Scan)
+            || condition.and_then(|c| rowid_range(c, &schema)).is_some()
+        {
+            return self.scan_table(table_name, condition, None);
+        }
+        let leaves = self.leaf_pages(root_page)?;
+        let Some(threads) = self.worker_count(leaves.len()) else {
+            return self.scan_table(table_name, condition, None);
+        };
+        debug!(
+            "Scanning {} leaves of {} with {} threads",
+            leaves.len(),
+            table_name,
+            threads
+        );
+
+        let usable_size = self.usable_size()?;
+        let header = &self.header;
+        let schema_ref = &schema;
+        let leaf_rows = split_leaves(&mut self.pager, &leaves, threads, |pager, page_num| {
+            let mut rng = Rng::from_entropy();
+            let page = read_leaf(pager, page_num)?;
+            let mut rows = Vec::new();
+            let mut bytes_decoded = 0;
+            for pos in page.read_cell_pointers()? {
+                bytes_decoded += decode_varint(page.data(), pos).on_page(page_num)?.0;
+                let row = decode_leaf_cell(
+                    page.data(),
+                    pos,
+                    &schema_ref.columns,
+                    usable_size,
+                    header,
+                    &mut |page_num| pager.read_page(page_num),
+                )
+                .on_page(page_num)?;
+                let keep = match condition {
+                    Some(condition) => {
+                        let ctx = RowContext::new(schema_ref, &row, header.encoding());
+                        evaluate(condition, &ctx, &mut rng)?.truth() == Some(true)
+                    }
+                    None => true,
+                };
+                if keep {
+                    rows.push(row);
+                }
+            }
+            Ok((rows, page.num_cells() as u64, bytes_decoded))
+        })?;
+        let mut rows = Vec::new();
+        for (leaf, cells, bytes_decoded) in leaf_rows {
+            self.metrics.cells_visited += cells;
+            self.metrics.rows_decoded += cells;
+            self.metrics.bytes_decoded += bytes_decoded;
+            rows.extend(leaf);
+        }
+        Ok((schema, rows))
+    }
+
+    /// Counts the rows of the table B-tree rooted at `root_page` with several
+    /// threads, or returns None if it is not worth splitting
+    pub(crate) fn count_rows_parallel(&mut self, root_page: u32) -> Result<Option<u32>> {
+        if self.scan_threads <= 1 {
+            return Ok(None);
+        }
+        let leaves = self.leaf_pages(root_page)?;
+        let Some(threads) = self.worker_count(leaves.len()) else {
+            return Ok(None);
+        };
+        debug!("Counting {} leaves with {} threads", leaves.len(), threads);
+        let counts = split_leaves(&mut self.pager, &leaves, threads, |pager, page_num| {
+            Ok(read_leaf(pager, page_num)?.num_cells() as u32)
+        })?;
+        let total: u32 = counts.into_iter().sum();
+        self.metrics.cells_visited += u64::from(total);
+        Ok(Some(total))
+    }
+
+    /// Returns the number of threads to split `leaves` leaf pages between, or
+    /// None if there are too few to be worth it
+    fn worker_count(&self, leaves: usize) -> Option<usize> {
+        let threads = self.scan_threads.min(leaves / MIN_LEAVES_PER_THREAD);
+        (threads > 1).then_some(threads)
+    }
+
+    /// Lists the leaf pages of the table B-tree rooted at `root_page`, in order
+    ///
+    /// Every leaf is at the same depth, so the walk goes down one level at a
+    /// time and stops at the first level whose pages are leaves, without
+    /// reading the rest of them.
+    fn leaf_pages(&mut self, root_page: u32) -> Result<Vec<u32>> {
+        let mut level = vec![root_page];
+        for _ in 0..MAX_DEPTH {
+            let first = self.pager.read_btree_page(level[0])?;
+            match first.page_type() {
+                13 => return Ok(level),
+                5 => {}
+                pt => {
+                    return Err(corrupt(
+                        first.header_offset(),
+                        format!("invalid page type in table B-tree: {}", pt),
+                    ))
+                    .on_page(level[0])
+                }
+            }
+            let mut children = Vec::new();
+            for page_num in level {
+                let page = self.pager.read_btree_page(page_num)?;
+                if page.page_type() != 5 {
+                    return Err(corrupt(
+                        page.header_offset(),
+                        "table B-tree leaves are at different depths",
+                    ))
+                    .on_page(page_num);
+                }
+                children.extend(page.get_child_pages()?);
+            }
+            level = children;
+        }
+        Err(corrupt(0, "table B-tree is too deep")).on_page(root_page)
+    }
+}
+
+/// Runs `work` on every leaf page, with the leaves split into `threads` runs of
+/// consecutive pages, and returns the results in leaf order
+fn split_leaves<T: Send>(
+    pager: &mut Pager,
+    leaves: &[u32],
+    threads: usize,
+    work: impl Fn(&SharedPager, u32) -> Result<T> + Sync,
+) -> Result<Vec<T>> {
+    let pager = SharedPager::new(pager);
+    let run_length = (leaves.len() + threads - 1) / threads;
+    thread::scope(|scope| {
+        let workers: Vec<_> = leaves
+            .chunks(run_length)
+            .map(|run| {
+                let (pager, work) = (&pager, &work);
+                scope.spawn(move || {
+                    run.iter()
+                        .map(|&page_num| work(pager, page_num))
+                        .collect::<Result<Vec<T>>>()
+                })
+            })
+            .collect();
+        let mut results = Vec::with_capacity(leaves.len());
+        for worker in workers {
+            let run = worker
+                .join()
+                .map_err(|_| anyhow!("a scan worker thread panicked"))??;
+            results.extend(run);
+        }
+        Ok(results)
+    })
+}
+
+/// Reads a table leaf page
+fn read_leaf(pager: &SharedPager, page_num: u32) -> Result<BTreePage> {
+    let page = pager.read_btree_page(page_num)?;
+    if page.page_type() != 13 {
+        return Err(corrupt(
+            page.header_offset(),
+            format!(
+                "expected a table leaf page, found type {}",
+                page.page_type()
+            ),
+        ))
+        .on_page(page_num);
+    }
+    Ok(page)
+}
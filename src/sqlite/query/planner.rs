@@ -0,0 +1,330 @@
+//! Query Planner Cost Model
+//!
+//! Estimates the cost of the ways a table can be accessed so the executor can pick
+//! the cheapest one. Estimates come from the `sqlite_stat1` table written by
+//! `ANALYZE` when it exists, and from SQLite's built-in heuristics otherwise.
+//!
+//! The same estimates order the tables of a join: smaller tables run in the
+//! outer loops, so the inner ones are read fewer times. Without statistics the
+//! tables are joined in FROM clause order.
+//!
+//! ## sqlite_stat1 Format
+//!
+//! Each row is `(tbl, idx, stat)`:
+//!
+//! - `tbl`: table name
+//! - `idx`: index name, or NULL for a row describing a table without indexes
+//! - `stat`: space separated integers. The first is the number of rows in the
+//!   index; the K-th following integer is the average number of rows matching an
+//!   equality constraint on the first K index columns. Non-numeric trailing
+//!   tokens (`unordered`, `sz=N`, ...) are options and are ignored.
+
+use crate::sqlite::core::error::{corrupt, CorruptPage};
+use crate::sqlite::core::value::Value;
+use crate::sqlite::parser::statement::JoinKind;
+use crate::sqlite::storage::db::SQLiteDatabase;
+use anyhow::Result;
+use std::collections::HashMap;
+use tracing::debug;
+
+/// Row count assumed for tables without statistics (SQLite's default)
+const DEFAULT_TABLE_ROWS: u64 = 1_048_576;
+/// Rows assumed to match an equality on each leading index column
+const DEFAULT_EQ_ROWS: [u64; 5] = [10, 9, 8, 7, 6];
+/// Rows assumed to match an equality on index columns beyond the fifth
+const DEFAULT_EQ_ROWS_TAIL: u64 = 5;
+/// How many times fewer rows a range on the column after the equalities is
+/// assumed to match than the equalities alone, as for a LIKE prefix, which
+/// bounds the column from both sides
+const RANGE_DIVISOR: f64 = 64.0;
+
+/// Statistics of one index from sqlite_stat1
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IndexStats {
+    /// Number of entries in the index
+    pub row_count: u64,
+    /// Average rows matching equality on the first K columns, for K = 1, 2, ...
+    pub avg_eq_rows: Vec<u64>,
+}
+
+/// Statistics of one table
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TableStats {
+    /// Number of rows in the table, if known
+    pub row_count: Option<u64>,
+    /// Statistics of the table's indexes, keyed by lowercase index name
+    pub indexes: HashMap<String, IndexStats>,
+}
+
+/// All statistics loaded from sqlite_stat1, keyed by lowercase table name
+#[derive(Debug, Clone, Default)]
+pub struct Statistics {
+    tables: HashMap<String, TableStats>,
+}
+
+impl Statistics {
+    /// Builds statistics from `(tbl, idx, stat)` rows of sqlite_stat1
+    pub fn from_rows(rows: &[(String, Option<String>, String)]) -> Self {
+        let mut tables: HashMap<String, TableStats> = HashMap::new();
+
+        for (table, index, stat) in rows {
+            let numbers: Vec<u64> = stat
+                .split_whitespace()
+                .map_while(|token| token.parse().ok())
+                .collect();
+            let Some(&row_count) = numbers.first() else {
+                continue;
+            };
+
+            let entry = tables.entry(table.to_lowercase()).or_default();
+            // Every index covers every row, so any row gives the table size
+            entry.row_count = Some(row_count);
+            if let Some(index) = index {
+                entry.indexes.insert(
+                    index.to_lowercase(),
+                    IndexStats {
+                        row_count,
+                        avg_eq_rows: numbers[1..].to_vec(),
+                    },
+                );
+            }
+        }
+
+        Self { tables }
+    }
+
+    /// Returns true if no statistics were loaded
+    pub fn is_empty(&self) -> bool {
+        self.tables.is_empty()
+    }
+
+    /// Returns the statistics of a table, if any
+    pub fn table(&self, table: &str) -> Option<&TableStats> {
+        self.tables.get(&table.to_lowercase())
+    }
+
+    /// Returns the statistics of an index, if any
+    pub fn index(&self, table: &str, index: &str) -> Option<&IndexStats> {
+        self.table(table)?.indexes.get(&index.to_lowercase())
+    }
+}
+
+/// A way of reading rows from a table
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AccessPath {
+    /// Visit every row of the table B-tree
+    FullScan,
+    /// Seek an index on equality constraints for its first `eq_columns`
+    /// columns, and a range on the next one if the candidate has one
+    IndexSeek { index: String, eq_columns: usize },
+    /// Descend the table B-tree straight to the row with a given rowid
+    RowidLookup,
+}
+
+/// An index usable for a query, as seen by the planner
+#[derive(Debug, Clone)]
+pub struct IndexCandidate {
+    /// Index name
+    pub name: String,
+    /// Number of leading index columns constrained by equality
+    pub eq_columns: usize,
+    /// True if the column after those is constrained to a range
+    pub range: bool,
+    /// True for UNIQUE indexes, where a full-key equality matches at most one row
+    pub unique: bool,
+    /// Number of columns in the index key
+    pub key_columns: usize,
+}
+
+/// Estimated cost of an access path
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CostEstimate {
+    /// Rows the access path is expected to produce
+    pub rows: f64,
+    /// Relative cost in units of rows visited
+    pub cost: f64,
+}
+
+/// Chooses access paths and join orders from table statistics
+pub struct Planner<'a> {
+    stats: &'a Statistics,
+}
+
+impl<'a> Planner<'a> {
+    pub fn new(stats: &'a Statistics) -> Self {
+        Self { stats }
+    }
+
+    /// Estimated number of rows in a table
+    pub fn table_rows(&self, table: &str) -> u64 {
+        self.stats
+            .table(table)
+            .and_then(|stats| stats.row_count)
+            .unwrap_or(DEFAULT_TABLE_ROWS)
+    }
+
+    /// Estimated rows matching equality on the first `eq_columns` index columns
+    fn eq_rows(&self, table: &str, index: &IndexCandidate) -> f64 {
+        if index.unique && index.eq_columns >= index.key_columns {
+            return 1.0;
+        }
+        if index.eq_columns == 0 {
+            return self.table_rows(table) as f64;
+        }
+        let stats = self.stats.index(table, &index.name);
+        let rows = match stats.and_then(|stats| stats.avg_eq_rows.get(index.eq_columns - 1)) {
+            Some(&rows) => rows,
+            None => DEFAULT_EQ_ROWS
+                .get(index.eq_columns - 1)
+                .copied()
+                .unwrap_or(DEFAULT_EQ_ROWS_TAIL),
+        };
+        (rows as f64).max(1.0)
+    }
+
+    /// Estimates the cost of reading a table through the given access path
+    pub fn estimate(
+        &self,
+        table: &str,
+        path: &AccessPath,
+        indexes: &[IndexCandidate],
+    ) -> CostEstimate {
+        let table_rows = self.table_rows(table) as f64;
+        match path {
+            AccessPath::FullScan => CostEstimate {
+                rows: table_rows,
+                cost: table_rows,
+            },
+            AccessPath::RowidLookup => CostEstimate {
+                rows: 1.0,
+                cost: table_rows.max(2.0).log2(),
+            },
+            AccessPath::IndexSeek { index, .. } => {
+                let Some(candidate) = indexes.iter().find(|c| &c.name == index) else {
+                    return CostEstimate {
+                        rows: table_rows,
+                        cost: f64::INFINITY,
+                    };
+                };
+                // One descent of the index, then one table lookup per matching row
+                let depth = table_rows.max(2.0).log2();
+                let mut rows = self.eq_rows(table, candidate);
+                if candidate.range {
+                    rows = (rows / RANGE_DIVISOR).max(1.0);
+                }
+                CostEstimate {
+                    rows,
+                    cost: depth + rows * (1.0 + depth),
+                }
+            }
+        }
+    }
+
+    /// Picks the cheapest access path for a table given its usable indexes and
+    /// whether the WHERE clause pins the rowid to a constant
+    pub fn choose_access_path(
+        &self,
+        table: &str,
+        rowid_constraint: bool,
+        indexes: &[IndexCandidate],
+    ) -> AccessPath {
+        let mut best = AccessPath::FullScan;
+        let mut best_cost = self.estimate(table, &best, indexes).cost;
+
+        let seeks = indexes
+            .iter()
+            .filter(|c| c.eq_columns > 0 || c.range)
+            .map(|candidate| AccessPath::IndexSeek {
+                index: candidate.name.clone(),
+                eq_columns: candidate.eq_columns,
+            });
+        let lookup = rowid_constraint.then_some(AccessPath::RowidLookup);
+        for path in seeks.chain(lookup) {
+            let cost = self.estimate(table, &path, indexes).cost;
+            debug!("Cost of {:?} on {}: {}", path, table, cost);
+            if cost < best_cost {
+                best = path;
+                best_cost = cost;
+            }
+        }
+
+        best
+    }
+
+    /// Orders the tables of a nested-loop join, outermost first
+    ///
+    /// Each input is a table name and how it joins to the tables before it.
+    /// Smaller tables go outside so the inner loops run fewer times, but the
+    /// table on the right of a LEFT JOIN keeps its place: every table before it
+    /// is joined first and every table after it follows. Without statistics
+    /// the order is the input order.
+    pub fn order_joins(&self, inputs: &[(&str, JoinKind)]) -> Vec<usize> {
+        let mut order: Vec<usize> = (0..inputs.len()).collect();
+        if self.stats.is_empty() {
+            return order;
+        }
+        // Each LEFT JOIN starts a group of its own, ordered after its first table
+        let mut start = 0;
+        for end in 1..=inputs.len() {
+            if end < inputs.len() && inputs[end].1 != JoinKind::Left {
+                continue;
+            }
+            let first = match inputs[start].1 {
+                JoinKind::Left => start + 1,
+                JoinKind::Inner => start,
+            };
+            order[first..end].sort_by_key(|&i| self.table_rows(inputs[i].0));
+            start = end;
+        }
+        order
+    }
+}
+
+impl SQLiteDatabase {
+    /// Loads sqlite_stat1, returning empty statistics if ANALYZE was never run
+    pub fn load_statistics(&mut self) -> Result<Statistics> {
+        let analyzed = self.schema_cache()?.entries().iter().any(|entry| {
+            matches!(
+                entry.values.as_slice(),
+                [Value::Text(kind), Value::Text(name), ..]
+                    if kind == "table" && name.eq_ignore_ascii_case("sqlite_stat1")
+            )
+        });
+        if !analyzed {
+            debug!("No sqlite_stat1 table, using default estimates");
+            return Ok(Statistics::default());
+        }
+        let root_page = self.find_table_root_page("sqlite_stat1")?;
+
+        let mut rows = Vec::new();
+        self.read_stat1_rows(root_page, &mut rows)?;
+        debug!("Loaded {} sqlite_stat1 rows", rows.len());
+        Ok(Statistics::from_rows(&rows))
+    }
+
+    /// Collects the `(tbl, idx, stat)` rows of the sqlite_stat1 B-tree
+    fn read_stat1_rows(
+        &mut self,
+        root_page: u32,
+        rows: &mut Vec<(String, Option<String>, String)>,
+    ) -> Result<()> {
+        let mut cursor = self.cursor(root_page)?;
+        cursor.first()?;
+        while cursor.is_valid() {
+            let row = cursor.current()?;
+            if row.len() < 3 {
+                return Err(corrupt(0, "sqlite_stat1 row has fewer than 3 columns"))
+                    .on_page(cursor.page_num()?);
+            }
+            if let [Value::Text(table), index, Value::Text(stat), ..] = row.as_slice() {
+                let index = match index {
+                    Value::Text(index) => Some(index.clone()),
+                    _ => None,
+                };
+                rows.push((table.clone(), index, stat.clone()));
+            }
+            cursor.next()?;
+        }
+        Ok(())
+    }
+}
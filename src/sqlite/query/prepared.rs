@@ -0,0 +1,158 @@
+//! Prepared Statements
+//!
+//! A statement can be parsed once and run many times with different parameter
+//! values. [`SQLiteDatabase::prepare`] parses the SQL into a
+//! [`PreparedStatement`], values are bound to its parameters by number or by
+//! name, and [`SQLiteDatabase::execute_prepared`] runs it with the values bound
+//! at the time. Bindings last until they are replaced or cleared; unbound
+//! parameters are NULL.
+//!
+//! Each run works on a copy of the parsed statement whose parameters have been
+//! replaced by their values as literals, so the planner sees bound values just
+//! as if they had been written in the SQL and can seek indexes on them.
+//!
+//! # Example
+//! ```
+//! let mut stmt = db.prepare("SELECT name FROM apples WHERE color = :color")?;
+//! for color in ["Red", "Yellow"] {
+//!     stmt.bind_named(":color", Value::Text(color.to_string()))?;
+//!     let result = db.execute_prepared(&stmt)?;
+//! }
+//! ```
+
+use crate::sqlite::core::value::Value;
+use crate::sqlite::parser::expression::Expression;
+use crate::sqlite::parser::statement::Statement;
+use crate::sqlite::query::columns::ColumnInfo;
+use crate::sqlite::query::execute::ExecuteResult;
+use crate::sqlite::storage::db::SQLiteDatabase;
+use anyhow::{anyhow, Result};
+use tracing::debug;
+
+/// A parsed statement with the values bound to its parameters
+#[derive(Debug, Clone)]
+pub struct PreparedStatement {
+    sql: String,
+    statement: Statement,
+    columns: Vec<ColumnInfo>,
+    /// Bound values, indexed by parameter number less one
+    values: Vec<Value>,
+}
+
+impl PreparedStatement {
+    /// Returns the SQL the statement was prepared from
+    pub fn sql(&self) -> &str {
+        &self.sql
+    }
+
+    /// Describes the result columns, as they were when the statement was
+    /// prepared
+    pub fn columns(&self) -> &[ColumnInfo] {
+        &self.columns
+    }
+
+    /// Returns the largest parameter number the statement uses
+    pub fn parameter_count(&self) -> usize {
+        self.values.len()
+    }
+
+    /// Returns the name of a parameter as written, such as `:name` or `?2`, or
+    /// None if the number is only used by plain `?` or not at all
+    pub fn parameter_name(&self, index: usize) -> Option<&str> {
+        self.statement
+            .parameters
+            .get(index.checked_sub(1)?)?
+            .as_deref()
+    }
+
+    /// Returns the number of the parameter with the given name, including its
+    /// `:`, `@`, `$` or `?` prefix
+    pub fn parameter_index(&self, name: &str) -> Option<usize> {
+        let position = self
+            .statement
+            .parameters
+            .iter()
+            .position(|n| n.as_deref() == Some(name))?;
+        Some(position + 1)
+    }
+
+    /// Binds a value to the parameter with the given 1-based number
+    pub fn bind(&mut self, index: usize, value: Value) -> Result<()> {
+        let count = self.values.len();
+        match index.checked_sub(1).and_then(|i| self.values.get_mut(i)) {
+            Some(slot) => {
+                *slot = value;
+                Ok(())
+            }
+            None => Err(anyhow!(
+                "parameter index {} out of range; the statement has {}",
+                index,
+                count
+            )),
+        }
+    }
+
+    /// Binds a value to the parameter with the given name
+    pub fn bind_named(&mut self, name: &str, value: Value) -> Result<()> {
+        let index = self
+            .parameter_index(name)
+            .ok_or_else(|| anyhow!("no such parameter: {}", name))?;
+        self.bind(index, value)
+    }
+
+    /// Sets every parameter back to NULL
+    pub fn clear_bindings(&mut self) {
+        self.values.fill(Value::Null);
+    }
+
+    /// Returns a copy of the statement with the bound values in place of its
+    /// parameters
+    fn bound_statement(&self) -> Statement {
+        let mut statement = self.statement.clone();
+        bind_parameters(&mut statement, &self.values);
+        statement
+    }
+}
+
+impl SQLiteDatabase {
+    /// Parses one statement for running later, checking that its tables exist
+    pub fn prepare(&mut self, sql: &str) -> Result<PreparedStatement> {
+        let statement = Statement::parse(sql)?;
+        debug!("Prepared statement: {:?}", statement);
+        Ok(PreparedStatement {
+            sql: sql.to_string(),
+            columns: self.result_columns(&statement)?,
+            values: vec![Value::Null; statement.parameters.len()],
+            statement,
+        })
+    }
+
+    /// Executes a prepared statement with its current bindings, answering from
+    /// the result cache when possible
+    pub fn execute_prepared(&mut self, prepared: &PreparedStatement) -> Result<ExecuteResult> {
+        let statement = prepared.bound_statement();
+        // Debug formatting keeps the storage class, so 1 and '1' differ
+        let params = prepared
+            .values
+            .iter()
+            .map(|value| format!("{:?}", value))
+            .collect();
+        self.traced_query(&prepared.sql, |db| {
+            db.run_statement(&prepared.sql, &statement, params)
+        })
+    }
+}
+
+/// Replaces the parameters of a statement and its subqueries with their values
+fn bind_parameters(statement: &mut Statement, values: &[Value]) {
+    for expr in statement.expressions_mut() {
+        expr.walk_mut(&mut |expr| match expr {
+            Expression::Parameter(index) => {
+                let value = values.get(*index - 1).cloned().unwrap_or(Value::Null);
+                *expr = Expression::Literal(value);
+            }
+            Expression::Exists(subquery) => bind_parameters(subquery, values),
+            _ => {}
+        });
+    }
+}
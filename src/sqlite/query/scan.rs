@@ -0,0 +1,449 @@
+//! Table Scans
+//!
+//! Reads every row of a table by walking its B-tree in rowid order with a
+//! [`BTreeCursor`], and payloads that spill out of a leaf are reassembled from
+//! their overflow chains before the record is decoded. WITHOUT ROWID tables are
+//! stored in an index B-tree instead and are read in primary key order.
+//!
+//! A scan can be given a WHERE condition, which is evaluated as each row is
+//! decoded so that rows it rejects are never collected, and a limit, after
+//! which the walk stops without reading the remaining pages. When the condition
+//! pins the rowid or an indexed column, the planner may replace the walk with
+//! lookups of just the rows that can match.
+
+use crate::sqlite::core::btree::PayloadLayout;
+use crate::sqlite::core::error::{corrupt, CorruptPage};
+use crate::sqlite::core::header::{DatabaseHeader, TextEncoding};
+use crate::sqlite::core::record::decode_record;
+use crate::sqlite::core::schema::{Affinity, ColumnDef, TableSchema};
+use crate::sqlite::core::value::Value;
+use crate::sqlite::core::varint::decode_varint;
+use crate::sqlite::parser::expression::Expression;
+use crate::sqlite::query::eval::{evaluate, evaluate_constant, RowContext};
+use crate::sqlite::query::functions::Rng;
+use crate::sqlite::query::index::{rowid_range, Access};
+use crate::sqlite::storage::cursor::BTreeCursor;
+use crate::sqlite::storage::db::SQLiteDatabase;
+use crate::sqlite::storage::metrics::Metrics;
+use crate::sqlite::storage::pager::Pager;
+use anyhow::{anyhow, Result};
+use std::ops::RangeInclusive;
+use tracing::debug;
+
+/// Decides whether a scan keeps a row; gets the connection's random number
+/// generator for evaluating conditions
+type RowPredicate<'a> = dyn FnMut(&Row, &mut Rng) -> Result<bool> + 'a;
+
+/// A decoded table row
+#[derive(Debug, Clone, PartialEq)]
+pub struct Row {
+    pub rowid: i64,
+    /// One value per column of the table schema
+    pub values: Vec<Value>,
+}
+
+impl SQLiteDatabase {
+    /// Reads the schema and the rows of a table for which `condition` is true,
+    /// or every row if there is no condition, stopping after `limit` rows
+    pub(crate) fn scan_table(
+        &mut self,
+        table_name: &str,
+        condition: Option<&Expression>,
+        limit: Option<usize>,
+    ) -> Result<(TableSchema, Vec<Row>)> {
+        let (schema, root_page) = self.find_table(table_name)?;
+        debug!("Scanning {} from root page {}", table_name, root_page);
+
+        let mut rows = Vec::new();
+        let encoding = self.header.encoding();
+        let mut keep = |row: &Row, rng: &mut Rng| match condition {
+            Some(condition) => row_matches(condition, &schema, row, encoding, rng),
+            None => Ok(true),
+        };
+        let limit = limit.unwrap_or(usize::MAX);
+        if schema.without_rowid {
+            self.scan_key_btree(root_page, &schema, &mut keep, &mut rows, limit)?;
+            return Ok((schema, rows));
+        }
+        let rowids = match self.plan_access(&schema, condition)? {
+            Access::Scan => {
+                let rowids = condition
+                    .and_then(|condition| rowid_range(condition, &schema))
+                    .unwrap_or(i64::MIN..=i64::MAX);
+                let columns = &schema.columns;
+                self.scan_btree(root_page, columns, &rowids, &mut keep, &mut rows, limit)?;
+                return Ok((schema, rows));
+            }
+            Access::Rowid(rowid) => rowid.into_iter().collect(),
+            Access::Index { index, keys } => {
+                let mut rowids = Vec::new();
+                for key in &keys {
+                    rowids.extend(self.seek_index(&index, key)?);
+                }
+                rowids.sort_unstable();
+                rowids.dedup();
+                rowids
+            }
+        };
+        for rowid in rowids {
+            if rows.len() >= limit {
+                break;
+            }
+            if let Some(row) = self.lookup_rowid(root_page, rowid, &schema.columns)? {
+                if keep(&row, &mut self.rng)? {
+                    rows.push(row);
+                }
+            }
+        }
+        Ok((schema, rows))
+    }
+
+    /// Decodes a single table row from its rowid and whole payload and reports
+    /// whether `condition` is true for it, so that readers formatting rows
+    /// themselves can skip rejected ones
+    pub(crate) fn payload_matches(
+        &mut self,
+        rowid: i64,
+        payload: &[u8],
+        schema: &TableSchema,
+        condition: &Expression,
+    ) -> Result<bool> {
+        let row = payload_row(rowid, payload, &schema.columns, &self.header)?;
+        self.metrics.rows_decoded += 1;
+        self.metrics.bytes_decoded += payload.len() as u64;
+        let encoding = self.header.encoding();
+        row_matches(condition, schema, &row, encoding, &mut self.rng)
+    }
+
+    /// Reads the row with the given rowid from the table B-tree rooted at
+    /// `root_page`, if there is one
+    pub(crate) fn read_row(
+        &mut self,
+        schema: &TableSchema,
+        root_page: u32,
+        rowid: i64,
+    ) -> Result<Option<Row>> {
+        self.lookup_rowid(root_page, rowid, &schema.columns)
+    }
+
+    /// Looks up a table's schema and root page in sqlite_schema, through the
+    /// schema cache
+    ///
+    /// Table names are matched case-insensitively, as in SQLite.
+    pub(crate) fn find_table(&mut self, table_name: &str) -> Result<(TableSchema, u32)> {
+        let cache = self.schema_cache()?;
+        if let Some(table) = cache.table(table_name) {
+            return Ok(table.clone());
+        }
+        let mut found = None;
+        for entry in cache.entries() {
+            // Columns: type, name, tbl_name, rootpage, sql
+            match entry.values.as_slice() {
+                [Value::Text(kind), Value::Text(name), _, Value::Integer(root_page), Value::Text(sql)]
+                    if kind == "table" && name.eq_ignore_ascii_case(table_name) =>
+                {
+                    let root_page = u32::try_from(*root_page)
+                        .map_err(|_| corrupt(0, format!("invalid root page {}", root_page)))
+                        .on_page(1)?;
+                    found = Some((TableSchema::parse(name.clone(), sql.clone())?, root_page));
+                    break;
+                }
+                _ => {}
+            }
+        }
+
+        let table = found.ok_or_else(|| anyhow!("Table not found: {}", table_name))?;
+        cache.insert_table(table_name, table.clone());
+        Ok(table)
+    }
+
+    /// Returns every row of sqlite_schema, through the schema cache
+    pub(crate) fn schema_entries(&mut self) -> Result<Vec<Row>> {
+        Ok(self.schema_cache()?.entries().to_vec())
+    }
+
+    /// Reads every row of sqlite_schema, whose B-tree is rooted at page 1
+    pub(crate) fn read_schema_entries(&mut self) -> Result<Vec<Row>> {
+        let mut entries = Vec::new();
+        let all = i64::MIN..=i64::MAX;
+        self.scan_btree(1, &[], &all, &mut |_, _| Ok(true), &mut entries, usize::MAX)?;
+        Ok(entries)
+    }
+
+    /// Appends the rows of the table B-tree rooted at `root_page` whose rowids
+    /// are in `rowids` and that `keep` accepts to `rows`, until `rows` holds
+    /// `limit` rows
+    ///
+    /// The cursor seeks straight to the first rowid in range and the walk
+    /// stops after the last, so pages holding only rows outside it are never
+    /// read.
+    fn scan_btree(
+        &mut self,
+        root_page: u32,
+        columns: &[ColumnDef],
+        rowids: &RangeInclusive<i64>,
+        keep: &mut RowPredicate,
+        rows: &mut Vec<Row>,
+        limit: usize,
+    ) -> Result<()> {
+        if rows.len() >= limit {
+            return Ok(());
+        }
+        let usable_size = self.usable_size()?;
+        let mut cursor = table_cursor(&mut self.pager, &self.header, usable_size, root_page)?;
+        cursor.seek_rowid(*rowids.start())?;
+        while cursor.is_valid() && rows.len() < limit {
+            let rowid = cursor.rowid()?;
+            if rowid > *rowids.end() {
+                break;
+            }
+            let row = cursor_row(&mut cursor, rowid, columns, &self.header, &mut self.metrics)?;
+            if keep(&row, &mut self.rng)? {
+                rows.push(row);
+            }
+            cursor.next()?;
+        }
+        Ok(())
+    }
+
+    /// Appends the rows of the WITHOUT ROWID table whose index B-tree is rooted
+    /// at `root_page` that `keep` accepts to `rows`, until `rows` holds `limit`
+    /// rows
+    fn scan_key_btree(
+        &mut self,
+        root_page: u32,
+        schema: &TableSchema,
+        keep: &mut RowPredicate,
+        rows: &mut Vec<Row>,
+        limit: usize,
+    ) -> Result<()> {
+        let usable_size = self.usable_size()?;
+        let mut cursor = key_cursor(&mut self.pager, &self.header, usable_size, root_page)?;
+        let mut valid = cursor.first()?;
+        while valid && rows.len() < limit {
+            let row = key_row(&mut cursor, schema, &self.header, &mut self.metrics)?;
+            if keep(&row, &mut self.rng)? {
+                rows.push(row);
+            }
+            valid = cursor.next()?;
+        }
+        Ok(())
+    }
+
+    /// Finds the row with the given rowid in the table B-tree rooted at
+    /// `root_page`
+    fn lookup_rowid(
+        &mut self,
+        root_page: u32,
+        rowid: i64,
+        columns: &[ColumnDef],
+    ) -> Result<Option<Row>> {
+        let usable_size = self.usable_size()?;
+        let mut cursor = table_cursor(&mut self.pager, &self.header, usable_size, root_page)?;
+        if !cursor.seek_rowid(rowid)? {
+            return Ok(None);
+        }
+        let row = cursor_row(&mut cursor, rowid, columns, &self.header, &mut self.metrics)?;
+        Ok(Some(row))
+    }
+}
+
+/// Opens a cursor over the table B-tree rooted at `root_page`
+fn table_cursor<'a>(
+    pager: &'a mut Pager,
+    header: &'a DatabaseHeader,
+    usable_size: usize,
+    root_page: u32,
+) -> Result<BTreeCursor<'a>> {
+    let cursor = BTreeCursor::new(pager, header, usable_size, root_page)?;
+    if cursor.is_index() {
+        return Err(corrupt(0, "table rooted at an index B-tree page")).on_page(root_page);
+    }
+    Ok(cursor)
+}
+
+/// Opens a cursor over the index B-tree of the WITHOUT ROWID table rooted at
+/// `root_page`
+fn key_cursor<'a>(
+    pager: &'a mut Pager,
+    header: &'a DatabaseHeader,
+    usable_size: usize,
+    root_page: u32,
+) -> Result<BTreeCursor<'a>> {
+    let cursor = BTreeCursor::new(pager, header, usable_size, root_page)?;
+    if !cursor.is_index() {
+        return Err(corrupt(
+            0,
+            "WITHOUT ROWID table rooted at a table B-tree page",
+        ))
+        .on_page(root_page);
+    }
+    Ok(cursor)
+}
+
+/// Opens a cursor over the B-tree holding a table's rows, rooted at
+/// `root_page`: a table B-tree, or an index B-tree for a WITHOUT ROWID table
+pub(crate) fn row_cursor<'a>(
+    pager: &'a mut Pager,
+    header: &'a DatabaseHeader,
+    usable_size: usize,
+    schema: &TableSchema,
+    root_page: u32,
+) -> Result<BTreeCursor<'a>> {
+    if schema.without_rowid {
+        key_cursor(pager, header, usable_size, root_page)
+    } else {
+        table_cursor(pager, header, usable_size, root_page)
+    }
+}
+
+/// Decodes the row a cursor from [`row_cursor`] is on, counting it in
+/// `metrics`
+pub(crate) fn current_row(
+    cursor: &mut BTreeCursor,
+    schema: &TableSchema,
+    header: &DatabaseHeader,
+    metrics: &mut Metrics,
+) -> Result<Row> {
+    if schema.without_rowid {
+        return key_row(cursor, schema, header, metrics);
+    }
+    let rowid = cursor.rowid()?;
+    cursor_row(cursor, rowid, &schema.columns, header, metrics)
+}
+
+/// Decodes the entry a WITHOUT ROWID table's cursor is on, counting it in
+/// `metrics`
+///
+/// Each entry is a record of the primary key columns followed by the other
+/// columns in table order.
+fn key_row(
+    cursor: &mut BTreeCursor,
+    schema: &TableSchema,
+    header: &DatabaseHeader,
+    metrics: &mut Metrics,
+) -> Result<Row> {
+    let payload = cursor.payload()?;
+    let entry = decode_record(&payload, header).on_page(cursor.page_num()?)?;
+    metrics.cells_visited += 1;
+    metrics.rows_decoded += 1;
+    metrics.bytes_decoded += payload.len() as u64;
+
+    let columns = &schema.columns;
+    let stored_order = schema
+        .primary_key
+        .iter()
+        .copied()
+        .chain((0..columns.len()).filter(|i| !schema.primary_key.contains(i)));
+    let mut values = vec![Value::Null; columns.len()];
+    let mut entry = entry.into_iter();
+    for position in stored_order {
+        values[position] = match entry.next() {
+            Some(value) => value,
+            None => missing_value(&columns[position], header.encoding())?,
+        };
+    }
+    apply_real_affinity(columns, &mut values);
+    Ok(Row { rowid: 0, values })
+}
+
+/// Decodes the row a table cursor is on, whose rowid is `rowid`, counting it
+/// in `metrics`
+fn cursor_row(
+    cursor: &mut BTreeCursor,
+    rowid: i64,
+    columns: &[ColumnDef],
+    header: &DatabaseHeader,
+    metrics: &mut Metrics,
+) -> Result<Row> {
+    let payload = cursor.payload()?;
+    let row = payload_row(rowid, &payload, columns, header).on_page(cursor.page_num()?)?;
+    metrics.cells_visited += 1;
+    metrics.rows_decoded += 1;
+    metrics.bytes_decoded += payload.len() as u64;
+    Ok(row)
+}
+
+/// Decodes the table leaf cell starting at `pos`, reading any overflow pages
+/// it needs with `read_page`
+pub(crate) fn decode_leaf_cell(
+    page: &[u8],
+    pos: usize,
+    columns: &[ColumnDef],
+    usable_size: usize,
+    header: &DatabaseHeader,
+    read_page: &mut dyn FnMut(u32) -> Result<Vec<u8>>,
+) -> Result<Row> {
+    let (payload_size, len) = decode_varint(page, pos)?;
+    let (rowid, rowid_len) = decode_varint(page, pos + len)?;
+    let start = pos + len + rowid_len;
+
+    let payload = PayloadLayout::new(usable_size, 13).read_payload(
+        page,
+        start,
+        payload_size as usize,
+        read_page,
+    )?;
+
+    payload_row(rowid as i64, &payload, columns, header)
+}
+
+/// Decodes the record payload of the row with the given rowid
+fn payload_row(
+    rowid: i64,
+    payload: &[u8],
+    columns: &[ColumnDef],
+    header: &DatabaseHeader,
+) -> Result<Row> {
+    let mut values = decode_record(payload, header)?;
+
+    // Columns added by ALTER TABLE are missing from older records
+    for column in columns.iter().skip(values.len()) {
+        values.push(missing_value(column, header.encoding())?);
+    }
+    for (column, value) in columns.iter().zip(values.iter_mut()) {
+        // INTEGER PRIMARY KEY columns are stored as NULL in favour of the rowid
+        if column.rowid_alias && value.is_null() {
+            *value = Value::Integer(rowid);
+        }
+    }
+    apply_real_affinity(columns, &mut values);
+
+    Ok(Row { rowid, values })
+}
+
+/// Returns the value of a column missing from a record, as columns added by
+/// ALTER TABLE are from the records written before: its DEFAULT with the
+/// column's affinity, or NULL if it has none
+pub(crate) fn missing_value(column: &ColumnDef, encoding: TextEncoding) -> Result<Value> {
+    let Some(expr) = column.default_expression()? else {
+        return Ok(Value::Null);
+    };
+    // ADD COLUMN only accepts constant defaults, which draw no random numbers
+    let value = evaluate_constant(expr, encoding, &mut Rng::with_seed(0))?;
+    Ok(column.stored_value(&value))
+}
+
+/// Turns the integers stored in REAL columns back into reals; such columns
+/// store integral values as integers to save space
+fn apply_real_affinity(columns: &[ColumnDef], values: &mut [Value]) {
+    for (column, value) in columns.iter().zip(values.iter_mut()) {
+        if column.affinity == Affinity::Real {
+            if let Value::Integer(i) = value {
+                *value = Value::Real(*i as f64);
+            }
+        }
+    }
+}
+
+/// Evaluates a WHERE condition against a table row; NULL counts as false
+fn row_matches(
+    condition: &Expression,
+    schema: &TableSchema,
+    row: &Row,
+    encoding: TextEncoding,
+    rng: &mut Rng,
+) -> Result<bool> {
+    let ctx = RowContext::new(schema, row, encoding);
+    Ok(evaluate(condition, &ctx, rng)?.truth() == Some(true))
+}
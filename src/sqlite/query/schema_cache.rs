@@ -0,0 +1,98 @@
+//! Schema Cache
+//!
+//! Every statement looks up the table it names in sqlite_schema, and writes
+//! also need the indexes on it. Rather than scan sqlite_schema each time, a
+//! connection keeps its rows in a [`SchemaCache`], read in full by the first
+//! lookup that needs them, along with the tables and indexes parsed from them,
+//! each parsed the first time it is looked up. Names are matched regardless of
+//! ASCII case, as in SQLite.
+//!
+//! The cache is dropped when the schema cookie in the database header moves,
+//! which every committed schema change does, including those of other
+//! connections. Writes to sqlite_schema on this connection drop it as they go,
+//! since the cookie only moves once they commit, and a transaction that
+//! changed the schema drops it again if it rolls back.
+
+use crate::sqlite::core::schema::TableSchema;
+use crate::sqlite::query::index::IndexDef;
+use crate::sqlite::query::scan::Row;
+use crate::sqlite::storage::db::SQLiteDatabase;
+use anyhow::Result;
+use std::collections::HashMap;
+use tracing::debug;
+
+/// The rows of sqlite_schema, and the tables and indexes parsed from them so
+/// far
+#[derive(Debug, Default)]
+pub struct SchemaCache {
+    /// Rows of sqlite_schema, in rowid order
+    entries: Vec<Row>,
+    /// Tables looked up so far, with their root pages, by lowercase name
+    tables: HashMap<String, (TableSchema, u32)>,
+    /// The indexes on each table looked up so far, by lowercase table name
+    indexes: HashMap<String, Vec<IndexDef>>,
+}
+
+impl SchemaCache {
+    /// Creates a cache over the rows of sqlite_schema
+    pub fn new(entries: Vec<Row>) -> Self {
+        Self {
+            entries,
+            ..Self::default()
+        }
+    }
+
+    /// Returns the rows of sqlite_schema, in rowid order
+    pub fn entries(&self) -> &[Row] {
+        &self.entries
+    }
+
+    /// Returns the schema and root page of a table, if it has been looked up
+    pub fn table(&self, name: &str) -> Option<&(TableSchema, u32)> {
+        self.tables.get(&name.to_ascii_lowercase())
+    }
+
+    /// Remembers the schema and root page of a table
+    pub fn insert_table(&mut self, name: &str, table: (TableSchema, u32)) {
+        self.tables.insert(name.to_ascii_lowercase(), table);
+    }
+
+    /// Returns the indexes on a table, if they have been looked up
+    pub fn indexes(&self, table_name: &str) -> Option<&[IndexDef]> {
+        self.indexes
+            .get(&table_name.to_ascii_lowercase())
+            .map(Vec::as_slice)
+    }
+
+    /// Remembers the indexes on a table
+    pub fn insert_indexes(&mut self, table_name: &str, indexes: Vec<IndexDef>) {
+        self.indexes
+            .insert(table_name.to_ascii_lowercase(), indexes);
+    }
+}
+
+impl SQLiteDatabase {
+    /// Returns the schema cache, reading sqlite_schema into it first if it has
+    /// been dropped
+    pub(crate) fn schema_cache(&mut self) -> Result<&mut SchemaCache> {
+        if self.schema_cache.is_none() {
+            // The schema may have moved to other pages since it was pinned
+            let pin = self.pager.cache().config().pin_schema;
+            if pin {
+                self.pager.cache().unpin_all();
+            }
+            self.pager.set_pinning(pin);
+            let entries = self.read_schema_entries();
+            self.pager.set_pinning(false);
+            let entries = entries?;
+            debug!("Cached {} sqlite_schema rows", entries.len());
+            self.schema_cache = Some(SchemaCache::new(entries));
+        }
+        Ok(self.schema_cache.get_or_insert_with(SchemaCache::default))
+    }
+
+    /// Drops the schema cache, so the next lookup reads sqlite_schema again
+    pub(crate) fn clear_schema_cache(&mut self) {
+        self.schema_cache = None;
+    }
+}
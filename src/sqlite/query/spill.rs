@@ -0,0 +1,98 @@
+//! Spilled GROUP BY Partitions
+//!
+//! When the groups of a GROUP BY query outgrow their memory budget, rows whose
+//! key has no group yet stop creating new ones. Each such row is instead
+//! written to one of several temporary files, chosen by a hash of its encoded
+//! key, so that all rows of a group land in the same file. Once every row has
+//! been read, the files are aggregated one at a time, each needing memory for
+//! only its own share of the groups.
+//!
+//! The files hold row indexes rather than rows: the key and the aggregate
+//! arguments are evaluated again from the source row when a partition is
+//! aggregated. Files are removed when the partitions are read back or dropped.
+
+use anyhow::{anyhow, Result};
+use std::collections::hash_map::DefaultHasher;
+use std::fs::{self, File};
+use std::hash::{Hash, Hasher};
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Number of files rows are spread over
+pub const PARTITIONS: usize = 16;
+
+/// Distinguishes the files of spills made by one process
+static NEXT_SPILL: AtomicUsize = AtomicUsize::new(0);
+
+/// Rows set aside for later aggregation, partitioned by key
+pub struct Spill {
+    partitions: Vec<Partition>,
+}
+
+/// One temporary file of row indexes
+struct Partition {
+    path: PathBuf,
+    writer: Option<BufWriter<File>>,
+}
+
+impl Spill {
+    /// Creates the temporary files
+    pub fn new() -> Result<Self> {
+        let spill = NEXT_SPILL.fetch_add(1, Ordering::Relaxed);
+        let mut partitions = Vec::with_capacity(PARTITIONS);
+        for i in 0..PARTITIONS {
+            let path = std::env::temp_dir().join(format!(
+                "sqlite-rust-spill-{}-{}-{}",
+                std::process::id(),
+                spill,
+                i
+            ));
+            let file = File::create(&path)
+                .map_err(|e| anyhow!("cannot create spill file {}: {}", path.display(), e))?;
+            partitions.push(Partition {
+                path,
+                writer: Some(BufWriter::new(file)),
+            });
+        }
+        Ok(Self { partitions })
+    }
+
+    /// Sets aside the `row_index`-th row, whose group key encodes to `key`
+    pub fn write(&mut self, key: &[u8], row_index: usize) -> Result<()> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        let partition = &mut self.partitions[hasher.finish() as usize % PARTITIONS];
+        match &mut partition.writer {
+            Some(writer) => Ok(writer.write_all(&(row_index as u64).to_le_bytes())?),
+            None => Err(anyhow!("spill partition already read")),
+        }
+    }
+
+    /// Reads back the row indexes of each partition in turn, in the order they
+    /// were written, removing each file once read
+    pub fn into_partitions(self) -> impl Iterator<Item = Result<Vec<usize>>> {
+        self.partitions.into_iter().map(|mut partition| {
+            if let Some(writer) = partition.writer.take() {
+                writer.into_inner().map_err(|e| e.into_error())?;
+            }
+            let bytes = fs::read(&partition.path)?;
+            Ok(bytes
+                .chunks_exact(8)
+                .map(|chunk| {
+                    let mut index = [0; 8];
+                    index.copy_from_slice(chunk);
+                    u64::from_le_bytes(index) as usize
+                })
+                .collect())
+        })
+    }
+}
+
+impl Drop for Partition {
+    fn drop(&mut self) {
+        self.writer = None;
+        // The file may never have been created, or already be gone
+        let _ = fs::remove_file(&self.path);
+    }
+}
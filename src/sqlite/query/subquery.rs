@@ -0,0 +1,83 @@
+//! Subqueries
+//!
+//! The evaluator has no access to the database, so before a query runs, the rows
+//! of every EXISTS subquery it contains (including subqueries nested inside
+//! those) are read into memory. Each subquery's FROM clause is read once, whether
+//! or not the subquery refers to the enclosing query. Its WHERE clause is left
+//! for the evaluator, which checks it per enclosing row and stops at the first
+//! row that passes.
+
+use crate::sqlite::core::schema::TableSchema;
+use crate::sqlite::parser::expression::Expression;
+use crate::sqlite::parser::statement::Statement;
+use crate::sqlite::query::aggregate::Aggregates;
+use crate::sqlite::query::eval::SubqueryRows;
+use crate::sqlite::query::limit::Bounds;
+use crate::sqlite::query::scan::Row;
+use crate::sqlite::storage::db::SQLiteDatabase;
+use anyhow::{anyhow, Result};
+
+impl SQLiteDatabase {
+    /// Reads the rows of every subquery in `stmt`
+    pub(crate) fn read_subqueries<'s>(
+        &mut self,
+        stmt: &'s Statement,
+    ) -> Result<Vec<SubqueryRows<'s>>> {
+        let mut subqueries = Vec::new();
+        find_subqueries(stmt, &mut subqueries);
+
+        let mut read = Vec::with_capacity(subqueries.len());
+        for statement in subqueries {
+            let (schema, rows, tables) = match &statement.from_table {
+                Some(_) => self.join_rows(statement)?,
+                None => {
+                    let schema = TableSchema {
+                        name: String::new(),
+                        columns: Vec::new(),
+                        sql: String::new(),
+                        without_rowid: false,
+                        primary_key: Vec::new(),
+                        unique_keys: Vec::new(),
+                    };
+                    let row = Row {
+                        rowid: 0,
+                        values: Vec::new(),
+                    };
+                    (schema, vec![row], Vec::new())
+                }
+            };
+            if statement.having.is_some() {
+                return Err(anyhow!("HAVING is not supported in EXISTS subqueries"));
+            }
+            // A grouped subquery has a row for each group, so it has rows
+            // exactly when its WHERE clause passes some row
+            let single_row = statement.group_by.is_empty()
+                && !Aggregates::collect(
+                    statement.selections.iter().map(|selection| &selection.expr),
+                )?
+                .is_empty();
+            let bounds = Bounds::of(statement, &mut self.rng)?;
+            read.push(SubqueryRows {
+                statement,
+                schema,
+                rows,
+                tables,
+                single_row,
+                bounds,
+            });
+        }
+        Ok(read)
+    }
+}
+
+/// Collects the subqueries of `stmt` and, recursively, of those subqueries
+fn find_subqueries<'s>(stmt: &'s Statement, found: &mut Vec<&'s Statement>) {
+    for expr in stmt.expressions() {
+        expr.walk(&mut |expr| {
+            if let Expression::Exists(subquery) = expr {
+                found.push(subquery);
+                find_subqueries(subquery, found);
+            }
+        });
+    }
+}
@@ -0,0 +1,53 @@
+//! Query Tracing
+//!
+//! Every query run through `SQLiteDatabase::query` or
+//! `SQLiteDatabase::execute_prepared` is wrapped in a `query` tracing span and
+//! ends with one structured event carrying the row count, pages read, duration
+//! and whether the result came from the cache, or, for a failed query, the
+//! error. Both are logged at info level, so a failure is not printed twice at
+//! the default verbosity.
+
+use crate::sqlite::query::execute::ExecuteResult;
+use crate::sqlite::storage::db::SQLiteDatabase;
+use anyhow::Result;
+use std::time::Instant;
+use tracing::{info, info_span};
+
+impl SQLiteDatabase {
+    /// Runs a query inside a `query` span, logs how it went and keeps its
+    /// counts for `last_query_stats`; `run` returns the result and whether it
+    /// was served from the cache
+    pub(crate) fn traced_query(
+        &mut self,
+        sql: &str,
+        run: impl FnOnce(&mut Self) -> Result<(ExecuteResult, bool)>,
+    ) -> Result<ExecuteResult> {
+        let span = info_span!("query", sql = %sql.trim());
+        let _guard = span.enter();
+        let started = Instant::now();
+        let before = self.metrics();
+
+        let outcome = run(self);
+        self.last_query_stats = self.metrics().since(&before);
+        match outcome {
+            Ok((result, cached)) => {
+                info!(
+                    rows = result.row_count(),
+                    pages_read = self.last_query_stats.pages_read,
+                    duration_us = started.elapsed().as_micros() as u64,
+                    cached,
+                    "query finished"
+                );
+                Ok(result)
+            }
+            Err(e) => {
+                info!(
+                    error = %e,
+                    duration_us = started.elapsed().as_micros() as u64,
+                    "query failed"
+                );
+                Err(e)
+            }
+        }
+    }
+}
@@ -0,0 +1,210 @@
+//! UPDATE Statements
+//!
+//! An UPDATE runs in two passes. The first finds the rows the WHERE clause
+//! accepts, using the same scans and lookups as a SELECT, and evaluates the
+//! new value of every assigned column against each row as it was. The second
+//! checks each changed row against the table's unique keys, as an INSERT
+//! does, then re-encodes it, replaces its record in the table B-tree and
+//! moves its entries in the indexes whose key it changes. Both
+//! passes finish before the transaction commits, so an error part way leaves
+//! the file untouched. A NULL in a NOT NULL column or a duplicate key fails
+//! the statement with a [`ConstraintViolation`].
+//!
+//! Every value is stored as [`ColumnDef::stored_value`] says, so new values
+//! take the affinity of their column, as they would on insert. If a column is
+//! assigned more than once, the last assignment wins.
+//!
+//! [`ColumnDef::stored_value`]: crate::sqlite::core::schema::ColumnDef::stored_value
+//! [`ConstraintViolation`]: crate::sqlite::core::error::ConstraintViolation
+//!
+//! Not supported yet: changing the rowid or an INTEGER PRIMARY KEY column,
+//! WITHOUT ROWID tables, tables with partial or expression indexes and EXISTS
+//! subqueries.
+
+use crate::sqlite::core::error::ConstraintViolation;
+use crate::sqlite::core::record::RecordBuilder;
+use crate::sqlite::core::schema::TableSchema;
+use crate::sqlite::core::value::Value;
+use crate::sqlite::parser::expression::Expression;
+use crate::sqlite::parser::statement::Update;
+use crate::sqlite::query::aggregate::reject_aggregates;
+use crate::sqlite::query::eval::{evaluate, is_rowid_alias, RowContext};
+use crate::sqlite::query::index::IndexDef;
+use crate::sqlite::query::scan::Row;
+use crate::sqlite::storage::db::SQLiteDatabase;
+use anyhow::{anyhow, Result};
+use tracing::debug;
+
+impl SQLiteDatabase {
+    /// Runs an UPDATE statement, returning the number of rows it changed
+    pub fn update(&mut self, update: &Update) -> Result<usize> {
+        self.write_transaction(|db| db.apply_update(update))
+    }
+
+    /// Updates the matching rows without committing
+    fn apply_update(&mut self, update: &Update) -> Result<usize> {
+        let (schema, root_page, indexes) = self.writable_table(&update.table, "UPDATE")?;
+        let expressions = update.assignments.iter().map(|(_, expr)| expr);
+        for expr in expressions.chain(&update.where_clause) {
+            check_write_expression(expr, "UPDATE")?;
+        }
+
+        let assigned = assigned_columns(&schema, &update.assignments)?;
+        let (_, rows) = self.scan_table(&update.table, update.where_clause.as_ref(), None)?;
+        debug!("Updating {} rows of {}", rows.len(), schema.name);
+        let mut updated = Vec::with_capacity(rows.len());
+        for row in &rows {
+            updated.push(self.updated_row(&schema, row, &assigned)?);
+        }
+
+        for (old, new) in rows.iter().zip(&updated) {
+            if let Some(conflict) = self.unique_conflict(&schema, &indexes, new)? {
+                return Err(conflict.violation(&schema).into());
+            }
+            self.update_index_entries(&schema, &indexes, old, new)?;
+            let record = self.encode_row(&schema, new);
+            self.replace_record(root_page, new.rowid, &record)?;
+        }
+        Ok(updated.len())
+    }
+
+    /// Returns `row` with the assigned columns given the values of their
+    /// expressions, evaluated against the row as it was, failing if a NOT NULL
+    /// column would hold NULL
+    pub(crate) fn updated_row(
+        &mut self,
+        schema: &TableSchema,
+        row: &Row,
+        assigned: &[Option<&Expression>],
+    ) -> Result<Row> {
+        let context = RowContext::new(schema, row, self.header.encoding());
+        let mut values = Vec::with_capacity(row.values.len());
+        for ((column, value), expr) in schema.columns.iter().zip(&row.values).zip(assigned) {
+            values.push(match expr {
+                Some(expr) => column.stored_value(&evaluate(expr, &context, &mut self.rng)?),
+                None => value.clone(),
+            });
+        }
+        let row = Row {
+            rowid: row.rowid,
+            values,
+        };
+        check_not_null(schema, &row)?;
+        Ok(row)
+    }
+
+    /// Encodes the record that stores a row of the table, given its values as
+    /// they are read
+    pub(crate) fn encode_row(&self, schema: &TableSchema, row: &Row) -> Vec<u8> {
+        let mut builder = RecordBuilder::for_database(&self.header);
+        for (column, value) in schema.columns.iter().zip(&row.values) {
+            builder.push(&column.stored_value(value));
+        }
+        builder.build()
+    }
+
+    /// Finds a table that `statement` may change, returning its schema, its
+    /// root page and the indexes its writes must keep up to date
+    pub(crate) fn writable_table(
+        &mut self,
+        table: &str,
+        statement: &str,
+    ) -> Result<(TableSchema, u32, Vec<IndexDef>)> {
+        if self.virtual_table(table).is_some() {
+            return Err(anyhow!("table {} may not be modified", table));
+        }
+        let (schema, root_page) = self.find_table(table)?;
+        if schema.without_rowid {
+            return Err(anyhow!(
+                "{} of WITHOUT ROWID tables is not supported",
+                statement
+            ));
+        }
+        // Indexes that cannot be kept up to date are left out of the
+        // definitions, or have fewer columns than their key
+        let indexes = self.table_indexes(&schema)?;
+        for name in self.index_names(&schema.name)? {
+            if !indexes
+                .iter()
+                .any(|index| index.name == name && index.columns.len() == index.key_columns)
+            {
+                return Err(anyhow!(
+                    "{} of tables with partial or expression indexes is not supported (index {})",
+                    statement,
+                    name
+                ));
+            }
+        }
+        Ok((schema, root_page, indexes))
+    }
+
+    /// Returns the names of the indexes on a table
+    fn index_names(&mut self, table: &str) -> Result<Vec<String>> {
+        let mut names = Vec::new();
+        for entry in self.schema_entries()? {
+            // Columns: type, name, tbl_name, rootpage, sql
+            if let [Value::Text(kind), Value::Text(name), Value::Text(tbl_name), ..] =
+                entry.values.as_slice()
+            {
+                if kind == "index" && tbl_name.eq_ignore_ascii_case(table) {
+                    names.push(name.clone());
+                }
+            }
+        }
+        Ok(names)
+    }
+}
+
+/// Returns the expression assigned to each column of the table, in table
+/// order, with the last assignment to a column winning
+pub(crate) fn assigned_columns<'a>(
+    schema: &TableSchema,
+    assignments: &'a [(String, Expression)],
+) -> Result<Vec<Option<&'a Expression>>> {
+    let mut assigned = vec![None; schema.columns.len()];
+    for (name, expr) in assignments {
+        match schema
+            .columns
+            .iter()
+            .position(|column| column.name.eq_ignore_ascii_case(name))
+        {
+            Some(position) if schema.columns[position].rowid_alias => {
+                return Err(anyhow!("changing the rowid is not supported"))
+            }
+            Some(position) => assigned[position] = Some(expr),
+            None if is_rowid_alias(name) => {
+                return Err(anyhow!("changing the rowid is not supported"))
+            }
+            None => return Err(anyhow!("no such column: {}", name)),
+        }
+    }
+    Ok(assigned)
+}
+
+/// Checks that no NOT NULL column of a row about to be stored holds NULL
+pub(crate) fn check_not_null(schema: &TableSchema, row: &Row) -> Result<(), ConstraintViolation> {
+    match schema
+        .columns
+        .iter()
+        .zip(&row.values)
+        .find(|(column, value)| column.not_null && matches!(value, Value::Null))
+    {
+        Some((column, _)) => Err(ConstraintViolation::NotNull {
+            table: schema.name.clone(),
+            column: column.name.clone(),
+        }),
+        None => Ok(()),
+    }
+}
+
+/// Checks that an expression of `statement` can be evaluated one row at a
+/// time: aggregates and subqueries are refused
+pub(crate) fn check_write_expression(expr: &Expression, statement: &str) -> Result<()> {
+    reject_aggregates(expr)?;
+    let mut subquery = false;
+    expr.walk(&mut |expr| subquery |= matches!(expr, Expression::Exists(_)));
+    if subquery {
+        return Err(anyhow!("subqueries are not supported in {}", statement));
+    }
+    Ok(())
+}
@@ -0,0 +1,160 @@
+//! Async Database Handle
+//!
+//! Built with the `tokio` feature, [`AsyncSQLiteDatabase`] gives async code a
+//! connection it can await without blocking the runtime it runs on. The
+//! connection lives on a thread of its own, where it is opened and where every
+//! statement runs, one at a time in the order they were sent; each call
+//! returns a future that the thread completes through a tokio channel. The
+//! handle is `Send` and `Sync`, so its futures can be spawned onto a
+//! multi-threaded runtime.
+//!
+//! Opening looks the file up with `tokio::fs` first, so a path that names no
+//! database file fails without starting a thread.
+//!
+//! The connection never leaves its thread, so only SQL and results cross
+//! between threads. Handles are cheap to clone, and the connection closes
+//! once the last one is dropped.
+//!
+//! ## Rows
+//!
+//! [`AsyncSQLiteDatabase::rows`] returns a [`RowStream`], a
+//! [`Stream`](futures_core::Stream) that yields a query's rows one at a time
+//! as the connection's thread sends them. Queries still run to completion
+//! before their first row is sent, so a query's rows are all in memory at
+//! once, as they are for [`SQLiteDatabase::query`].
+
+use crate::sqlite::core::value::Value;
+use crate::sqlite::query::columns::ColumnInfo;
+use crate::sqlite::query::execute::{ExecuteResult, ResultRows};
+use crate::sqlite::storage::db::{OpenConfig, SQLiteDatabase, MEMORY_PATH};
+use anyhow::{anyhow, Result};
+use futures_core::Stream;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::thread;
+use tokio::sync::{mpsc, oneshot};
+
+/// Work sent to the connection's thread
+type Job = Box<dyn FnOnce(&mut SQLiteDatabase) + Send>;
+
+/// A database connection running on a thread of its own, driven by futures
+#[derive(Clone)]
+pub struct AsyncSQLiteDatabase {
+    /// Queue of work for the connection's thread
+    jobs: mpsc::UnboundedSender<Job>,
+}
+
+impl AsyncSQLiteDatabase {
+    /// Opens a SQLite database file at the given path, or a new in-memory
+    /// database for [`MEMORY_PATH`]
+    pub async fn open(path: PathBuf) -> Result<Self> {
+        Self::open_with(path, OpenConfig::default()).await
+    }
+
+    /// Opens the database at the given path as `config` asks, on a new thread
+    pub async fn open_with(path: PathBuf, config: OpenConfig) -> Result<Self> {
+        if path.as_os_str() != MEMORY_PATH && !config.create {
+            let metadata = tokio::fs::metadata(&path)
+                .await
+                .map_err(|e| anyhow!("unable to open database file {}: {}", path.display(), e))?;
+            if !metadata.is_file() {
+                return Err(anyhow!("{} is not a database file", path.display()));
+            }
+        }
+
+        let (jobs, mut queue) = mpsc::unbounded_channel::<Job>();
+        let (opened, reply) = oneshot::channel();
+        thread::Builder::new()
+            .name("sqlite-connection".to_string())
+            .spawn(move || {
+                let mut db = match SQLiteDatabase::open_with(&path, config) {
+                    Ok(db) => db,
+                    Err(e) => {
+                        let _ = opened.send(Err(e));
+                        return;
+                    }
+                };
+                let _ = opened.send(Ok(()));
+                while let Some(job) = queue.blocking_recv() {
+                    job(&mut db);
+                }
+            })?;
+        reply.await.map_err(|_| stopped())??;
+        Ok(Self { jobs })
+    }
+
+    /// Parses and executes SQL, as [`SQLiteDatabase::query`] does
+    pub async fn query(&self, sql: &str) -> Result<ExecuteResult> {
+        let sql = sql.to_string();
+        self.run(move |db| db.query(&sql)).await
+    }
+
+    /// Parses and executes SQL, returning a stream of its rows
+    pub async fn rows(&self, sql: &str) -> Result<RowStream> {
+        let sql = sql.to_string();
+        let (sender, rows) = mpsc::unbounded_channel();
+        let columns = self
+            .run(move |db| {
+                let result = db.query(&sql)?;
+                match result.rows {
+                    ResultRows::Count(count) => {
+                        let _ = sender.send(vec![Value::Integer(count.into())]);
+                    }
+                    // Stops early if the call is abandoned
+                    ResultRows::Values(rows) => {
+                        for row in rows {
+                            if sender.send(row).is_err() {
+                                break;
+                            }
+                        }
+                    }
+                }
+                Ok(result.columns)
+            })
+            .await?;
+        Ok(RowStream { columns, rows })
+    }
+
+    /// Runs `work` on the connection's thread, after any work sent before it
+    pub async fn run<T: Send + 'static>(
+        &self,
+        work: impl FnOnce(&mut SQLiteDatabase) -> Result<T> + Send + 'static,
+    ) -> Result<T> {
+        let (sender, reply) = oneshot::channel();
+        self.jobs
+            .send(Box::new(move |db| {
+                let _ = sender.send(work(db));
+            }))
+            .map_err(|_| stopped())?;
+        // The reply is dropped unsent if the work panicked
+        reply.await.map_err(|_| stopped())?
+    }
+}
+
+/// The error of a call the connection's thread will never answer
+fn stopped() -> anyhow::Error {
+    anyhow!("the database connection has stopped")
+}
+
+/// The rows of a query, yielded one at a time
+#[derive(Debug)]
+pub struct RowStream {
+    columns: Vec<ColumnInfo>,
+    rows: mpsc::UnboundedReceiver<Vec<Value>>,
+}
+
+impl RowStream {
+    /// Returns the name and declared type of each result column
+    pub fn columns(&self) -> &[ColumnInfo] {
+        &self.columns
+    }
+}
+
+impl Stream for RowStream {
+    type Item = Vec<Value>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Vec<Value>>> {
+        self.rows.poll_recv(cx)
+    }
+}
@@ -0,0 +1,180 @@
+//! B-tree Statistics
+//!
+//! Walks the B-tree of every table and index, along with the sqlite_schema
+//! B-tree rooted at page 1, and reports its shape: how deep it is, how many
+//! interior and leaf pages it has, how many cells those pages hold on average
+//! and how full they are. Shallow trees of nearly full pages are cheap to
+//! search; low fill factors point at space left behind by deletes.
+//!
+//! ## Fill Factor
+//!
+//! The fill factor of a page is the share of its usable bytes that are not
+//! free: everything except the unallocated gap, the freeblocks and the
+//! fragmented bytes counted by [`PageFreeSpace`]. Page headers and cell pointers
+//! count as used. On page 1 the 100-byte database header is left out.
+//!
+//! Every leaf of a B-tree is at the same depth; a tree whose leaves are not, or
+//! that reaches a page twice, is reported as corrupt.
+
+use crate::sqlite::core::error::{corrupt, CorruptPage};
+use crate::sqlite::core::freespace::PageFreeSpace;
+use crate::sqlite::core::value::Value;
+use crate::sqlite::storage::db::SQLiteDatabase;
+use anyhow::Result;
+use std::collections::HashSet;
+use std::fmt::Display;
+
+/// Shape of one B-tree
+#[derive(Debug, Clone, PartialEq)]
+pub struct BTreeStats {
+    /// Name of the table or index, as recorded in sqlite_schema
+    pub name: String,
+    /// Root page of the B-tree
+    pub root_page: u32,
+    /// True for index B-trees (also used by WITHOUT ROWID tables)
+    pub index: bool,
+    /// Number of levels, 1 for a tree that is a single leaf
+    pub depth: usize,
+    pub interior_pages: u32,
+    pub leaf_pages: u32,
+    /// Cells on interior pages
+    pub interior_cells: u64,
+    /// Cells on leaf pages: the rows of a table, or the entries of an index
+    pub leaf_cells: u64,
+    /// Usable bytes of all the tree's pages
+    pub usable_bytes: u64,
+    /// Free bytes on all the tree's pages
+    pub free_bytes: u64,
+}
+
+impl BTreeStats {
+    /// Returns the number of pages in the tree
+    pub fn pages(&self) -> u32 {
+        self.interior_pages + self.leaf_pages
+    }
+
+    /// Returns the average number of cells per page
+    pub fn average_cells(&self) -> f64 {
+        (self.interior_cells + self.leaf_cells) as f64 / self.pages() as f64
+    }
+
+    /// Returns the share of usable bytes in use, from 0 to 1
+    pub fn fill_factor(&self) -> f64 {
+        if self.usable_bytes == 0 {
+            return 0.0;
+        }
+        (self.usable_bytes - self.free_bytes) as f64 / self.usable_bytes as f64
+    }
+}
+
+impl Display for BTreeStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} {} root={} depth={} interior={} leaf={} cells/page={:.1} fill={:.1}%",
+            if self.index { "index" } else { "table" },
+            self.name,
+            self.root_page,
+            self.depth,
+            self.interior_pages,
+            self.leaf_pages,
+            self.average_cells(),
+            self.fill_factor() * 100.0
+        )
+    }
+}
+
+impl SQLiteDatabase {
+    /// Collects the statistics of the sqlite_schema B-tree and of the B-tree of
+    /// every table and index, in schema order
+    pub fn btree_stats(&mut self) -> Result<Vec<BTreeStats>> {
+        let mut trees = vec![("sqlite_schema".to_string(), 1)];
+        for entry in self.schema_entries()? {
+            // Columns: type, name, tbl_name, rootpage, sql; views, triggers and
+            // virtual tables have no B-tree
+            if let [_, Value::Text(name), _, Value::Integer(root_page), ..] =
+                entry.values.as_slice()
+            {
+                if let Ok(root_page @ 1..) = u32::try_from(*root_page) {
+                    trees.push((name.clone(), root_page));
+                }
+            }
+        }
+
+        trees
+            .into_iter()
+            .map(|(name, root_page)| self.walk_btree(name, root_page))
+            .collect()
+    }
+
+    /// Visits every page of the B-tree rooted at `root_page`
+    fn walk_btree(&mut self, name: String, root_page: u32) -> Result<BTreeStats> {
+        let usable_size = self.usable_size()?;
+        let mut stats = BTreeStats {
+            name,
+            root_page,
+            index: false,
+            depth: 0,
+            interior_pages: 0,
+            leaf_pages: 0,
+            interior_cells: 0,
+            leaf_cells: 0,
+            usable_bytes: 0,
+            free_bytes: 0,
+        };
+        let mut visited = HashSet::new();
+        let mut stack = vec![(root_page, 1)];
+
+        while let Some((page_num, depth)) = stack.pop() {
+            if !visited.insert(page_num) {
+                return Err(corrupt(0, format!("B-tree of {} loops", stats.name)))
+                    .on_page(page_num);
+            }
+            let page = self.pager.read_btree_page(page_num)?;
+            if page_num == root_page {
+                stats.index = page.is_index();
+            } else if page.is_index() != stats.index {
+                return Err(corrupt(
+                    page.header_offset(),
+                    format!(
+                        "page type {} does not belong in the B-tree of {}",
+                        page.page_type(),
+                        stats.name
+                    ),
+                ))
+                .on_page(page_num);
+            }
+
+            let free_space =
+                PageFreeSpace::analyze(page.data(), page.header_offset()).on_page(page_num)?;
+            stats.usable_bytes += (usable_size - page.header_offset()) as u64;
+            stats.free_bytes += free_space.total() as u64;
+
+            let cells = page.num_cells() as u64;
+            if page.is_interior() {
+                stats.interior_pages += 1;
+                stats.interior_cells += cells;
+                for child in page.get_child_pages()? {
+                    stack.push((child, depth + 1));
+                }
+            } else {
+                stats.leaf_pages += 1;
+                stats.leaf_cells += cells;
+                if stats.depth == 0 {
+                    stats.depth = depth;
+                } else if stats.depth != depth {
+                    return Err(corrupt(
+                        0,
+                        format!(
+                            "leaves of the B-tree of {} are at depths {} and {}",
+                            stats.name, stats.depth, depth
+                        ),
+                    ))
+                    .on_page(page_num);
+                }
+            }
+        }
+
+        Ok(stats)
+    }
+}
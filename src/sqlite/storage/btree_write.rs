@@ -0,0 +1,972 @@
+//! B-tree Writes
+//!
+//! Adds, changes and removes the rows of table B-trees and the entries of
+//! index B-trees. Pages are edited with a [`PageWriter`] and written back
+//! through the pager, which holds them until the transaction commits.
+//!
+//! ## Replacing a Record
+//!
+//! A row whose record changes keeps its rowid, and so its place in the tree:
+//! its leaf cell is rebuilt around the new record and takes the place of the
+//! old cell on the same page. A cell that grows takes space from the page's
+//! free space, defragmenting the page if it has to; if it no longer fits, it
+//! is inserted again as below. The overflow pages of the old record, if any,
+//! are freed.
+//!
+//! ## Inserting a Row
+//!
+//! A new row's cell goes onto the leaf its rowid belongs on. If the leaf is
+//! full, its cells and the new one are spread over as many pages as it takes,
+//! usually two: the last group stays on the old page, new pages to its left
+//! take the rest, and a divider cell for each new page goes into the parent,
+//! which may split in turn. A root that splits moves all of its cells onto new
+//! pages and becomes their parent, so the tree grows from the top and every
+//! leaf stays at the same depth.
+//!
+//! A row appended past the end of the right-most leaf starts a new leaf by
+//! itself, as SQLite's quick balance does, so tables filled in rowid order end
+//! up with full pages. New pages come from the freelist before the file grows.
+//!
+//! ## Overflow Pages
+//!
+//! A record too large to fit on its page keeps only a prefix in its cell, as
+//! much as [`PayloadLayout`] allows, followed by the number of the first of a
+//! chain of overflow pages holding the rest. Each overflow page starts with
+//! the number of the next, 0 on the last, and is filled with payload after
+//! it. The chain is written before its cell goes into the tree, and the cell
+//! keeps it as it moves between pages; index entries moving up to or down from
+//! an interior page keep theirs too, since index leaves and interior pages
+//! split payloads alike.
+//!
+//! ## Deleting a Row
+//!
+//! A deleted row's cell is dropped from its leaf, and its overflow pages, if
+//! any, go on the freelist. A page left more than two thirds empty is then
+//! balanced with up to two of its siblings, as SQLite does: their cells are
+//! spread over as few pages as hold them, pages no longer needed are freed and
+//! the parent's divider cells are replaced. The parent, having lost dividers,
+//! may be balanced in turn. A root left with no cells, only a right-most
+//! child, takes over the content of that child, and the tree gets one level
+//! shallower.
+//!
+//! Deleting every row frees every page of the tree except the root, which is
+//! emptied in place.
+//!
+//! ## Index Entries
+//!
+//! Index B-trees split and rebalance as table B-trees do, except that every
+//! page, leaves included, is divided from the next by an entry: the parent's
+//! divider cell holds the whole entry between the two pages rather than a
+//! copy of a rowid. A leaf that splits sends one of its entries up, and
+//! leaves being rebalanced take their dividers down among their entries.
+//!
+//! An entry deleted from an interior page is replaced by the entry just
+//! before it, taken from the right-most leaf of its left subtree.
+//!
+//! ## Schema Changes
+//!
+//! Any write to sqlite_schema, the table rooted at page 1, is a schema change:
+//! the transaction then bumps the schema cookie as it commits, telling other
+//! connections to reload the schema.
+
+use crate::sqlite::core::btree::{cell_size, BTreePage, PayloadLayout, MAX_DEPTH};
+use crate::sqlite::core::checked;
+use crate::sqlite::core::collation::{compare_index_keys, KeyColumn};
+use crate::sqlite::core::error::{corrupt, CorruptPage};
+use crate::sqlite::core::page_writer::PageWriter;
+use crate::sqlite::core::record::RecordBuilder;
+use crate::sqlite::core::value::Value;
+use crate::sqlite::core::varint::{decode_varint, encode_varint};
+use crate::sqlite::storage::db::SQLiteDatabase;
+use anyhow::{anyhow, Result};
+
+/// Random rowids tried for a new row once the largest rowid is taken, as in
+/// SQLite
+const MAX_ROWID_PROBES: usize = 100;
+
+impl SQLiteDatabase {
+    /// Inserts a row into the table B-tree rooted at `root_page`, which must
+    /// not already have a row with its rowid
+    pub(crate) fn insert_record(
+        &mut self,
+        root_page: u32,
+        rowid: i64,
+        record: &[u8],
+    ) -> Result<()> {
+        self.note_write(root_page);
+        let mut cursor = self.cursor(root_page)?;
+        if cursor.seek_rowid(rowid)? {
+            return Err(anyhow!("rowid {} is already in use", rowid));
+        }
+        let path = cursor.rowid_slot(rowid)?;
+        drop(cursor);
+        let cell = self.table_leaf_cell(rowid, record)?;
+        self.insert_cells(&path, vec![cell])?;
+        Ok(())
+    }
+
+    /// Replaces the record of the row with the given rowid in the table B-tree
+    /// rooted at `root_page`
+    pub(crate) fn replace_record(
+        &mut self,
+        root_page: u32,
+        rowid: i64,
+        record: &[u8],
+    ) -> Result<()> {
+        self.note_write(root_page);
+        let usable_size = self.usable_size()?;
+
+        let mut cursor = self.cursor(root_page)?;
+        if !cursor.seek_rowid(rowid)? {
+            return Err(anyhow!("no row with rowid {}", rowid));
+        }
+        let path = cursor.path();
+        drop(cursor);
+        let Some(&(page_num, index)) = path.last() else {
+            return Err(anyhow!("no row with rowid {}", rowid));
+        };
+
+        let mut page = self.pager.read_page(page_num)?;
+        let old_cell = PageWriter::new(
+            &mut page,
+            BTreePage::header_offset_for(page_num),
+            usable_size,
+        )
+        .on_page(page_num)?
+        .cell(index)
+        .on_page(page_num)?
+        .to_vec();
+        let old_overflow = self.overflow_pages(&old_cell, 13).on_page(page_num)?;
+        // Overflow pages come and go before the page is read for writing,
+        // since doing so may change page 1, which may be this page
+        for overflow_page in old_overflow {
+            self.free_page(overflow_page)?;
+        }
+        let cell = self.table_leaf_cell(rowid, record)?;
+
+        let mut page = self.pager.read_page(page_num)?;
+        let mut writer = PageWriter::new(
+            &mut page,
+            BTreePage::header_offset_for(page_num),
+            usable_size,
+        )
+        .on_page(page_num)?;
+        if writer.replace_cell(index, &cell).on_page(page_num)? {
+            return self.pager.write_page(page_num, page);
+        }
+        // The new cell does not fit beside the others, so the page is split
+        writer.drop_cell(index).on_page(page_num)?;
+        self.pager.write_page(page_num, page)?;
+        self.insert_cells(&path, vec![cell])?;
+        Ok(())
+    }
+
+    /// Deletes the row with the given rowid from the table B-tree rooted at
+    /// `root_page`
+    pub(crate) fn delete_record(&mut self, root_page: u32, rowid: i64) -> Result<()> {
+        self.note_write(root_page);
+        let mut cursor = self.cursor(root_page)?;
+        if !cursor.seek_rowid(rowid)? {
+            return Err(anyhow!("no row with rowid {}", rowid));
+        }
+        let path = cursor.path();
+        drop(cursor);
+        self.delete_leaf_cell(path, true)
+    }
+
+    /// Deletes the leaf cell at the end of `path`, freeing its overflow pages
+    /// if `free_overflow` is set, and rebalances the leaf if it is left
+    /// underfull
+    fn delete_leaf_cell(&mut self, mut path: Vec<(u32, usize)>, free_overflow: bool) -> Result<()> {
+        let Some((page_num, index)) = path.pop() else {
+            return Err(anyhow!("no cell to delete"));
+        };
+        let usable_size = self.usable_size()?;
+        let mut page = self.pager.read_page(page_num)?;
+        let mut writer = PageWriter::new(
+            &mut page,
+            BTreePage::header_offset_for(page_num),
+            usable_size,
+        )
+        .on_page(page_num)?;
+        let overflow = match free_overflow {
+            true => self
+                .overflow_pages(writer.cell(index)?, writer.page_type())
+                .on_page(page_num)?,
+            false => Vec::new(),
+        };
+        writer.drop_cell(index).on_page(page_num)?;
+        let underfull = is_underfull(writer.free_space()?, usable_size);
+        self.pager.write_page(page_num, page)?;
+
+        for overflow_page in overflow {
+            self.free_page(overflow_page)?;
+        }
+        if underfull && !path.is_empty() {
+            self.balance(&path)?;
+        }
+        Ok(())
+    }
+
+    /// Inserts an entry, the key columns followed by the rowid, into the index
+    /// B-tree rooted at `root_page`, where `key` orders its key columns
+    pub(crate) fn insert_index_entry(
+        &mut self,
+        root_page: u32,
+        entry: &[Value],
+        key: &[KeyColumn],
+    ) -> Result<()> {
+        let mut builder = RecordBuilder::for_database(&self.header);
+        for value in entry {
+            builder.push(value);
+        }
+        let record = builder.build();
+
+        let compare = |other: &[Value]| compare_index_keys(other, entry, key);
+        let mut cursor = self.cursor(root_page)?;
+        if cursor.seek_key(&compare)? {
+            return Err(anyhow!("index entry is already present"));
+        }
+        let path = cursor.key_slot(&compare)?;
+        drop(cursor);
+        let mut cell = Vec::new();
+        encode_varint(record.len() as u64, &mut cell);
+        self.push_payload(&mut cell, &record, 10)?;
+        self.insert_cells(&path, vec![cell])?;
+        Ok(())
+    }
+
+    /// Deletes an entry, the key columns followed by the rowid, from the index
+    /// B-tree rooted at `root_page`, where `key` orders its key columns
+    ///
+    /// An entry on an interior page gives its place to the entry before it,
+    /// the last one on the right-most leaf of its left subtree, as SQLite
+    /// does; that entry leaves its leaf first, which is rebalanced if it is
+    /// left underfull.
+    pub(crate) fn delete_index_entry(
+        &mut self,
+        root_page: u32,
+        entry: &[Value],
+        key: &[KeyColumn],
+    ) -> Result<()> {
+        let compare = |other: &[Value]| compare_index_keys(other, entry, key);
+        let mut cursor = self.cursor(root_page)?;
+        if !cursor.seek_key(&compare)? {
+            return Err(corrupt(0, "index entry to delete is missing")).on_page(root_page);
+        }
+        let path = cursor.path();
+        if !cursor.prev()? || cursor.path().len() <= path.len() {
+            // The entry is on a leaf
+            drop(cursor);
+            return self.delete_leaf_cell(path, true);
+        }
+        let leaf_path = cursor.path();
+        drop(cursor);
+
+        let usable_size = self.usable_size()?;
+        let Some(&(leaf_num, leaf_index)) = leaf_path.last() else {
+            return Err(anyhow!("no leaf below an interior entry"));
+        };
+        let mut leaf = self.pager.read_page(leaf_num)?;
+        let moved = PageWriter::new(
+            &mut leaf,
+            BTreePage::header_offset_for(leaf_num),
+            usable_size,
+        )
+        .on_page(leaf_num)?
+        .cell(leaf_index)
+        .on_page(leaf_num)?
+        .to_vec();
+        // Its overflow pages, if any, move with it
+        self.delete_leaf_cell(leaf_path, false)?;
+
+        // Rebalancing may have moved the entry, even onto a leaf
+        let mut cursor = self.cursor(root_page)?;
+        if !cursor.seek_key(&compare)? {
+            return Err(corrupt(0, "index entry to delete is missing")).on_page(root_page);
+        }
+        let path = cursor.path();
+        drop(cursor);
+        let Some(&(page_num, index)) = path.last() else {
+            return Err(anyhow!("no page holds the index entry"));
+        };
+        let mut page = self.pager.read_page(page_num)?;
+        let mut writer = PageWriter::new(
+            &mut page,
+            BTreePage::header_offset_for(page_num),
+            usable_size,
+        )
+        .on_page(page_num)?;
+        let page_type = writer.page_type();
+        let overflow = self
+            .overflow_pages(writer.cell(index)?, page_type)
+            .on_page(page_num)?;
+        let cell = match page_type {
+            2 => divider_cell(writer.child(index).on_page(page_num)?, &moved),
+            _ => moved,
+        };
+        if writer.replace_cell(index, &cell).on_page(page_num)? {
+            self.pager.write_page(page_num, page)?;
+        } else {
+            writer.drop_cell(index).on_page(page_num)?;
+            self.pager.write_page(page_num, page)?;
+            self.insert_cells(&path, vec![cell])?;
+        }
+        for overflow_page in overflow {
+            self.free_page(overflow_page)?;
+        }
+        Ok(())
+    }
+
+    /// Deletes every row of the table or index B-tree rooted at `root_page`,
+    /// returning how many there were
+    pub(crate) fn clear_table(&mut self, root_page: u32) -> Result<u64> {
+        self.note_write(root_page);
+        let index = self.pager.read_btree_page(root_page)?.is_index();
+        let rows = self.clear_page(root_page, root_page, 0)?;
+        let usable_size = self.usable_size()?;
+        let mut page = self.pager.read_page(root_page)?;
+        PageWriter::init(
+            &mut page,
+            BTreePage::header_offset_for(root_page),
+            usable_size,
+            if index { 10 } else { 13 },
+        );
+        self.pager.write_page(root_page, page)?;
+        Ok(rows)
+    }
+
+    /// Creates an empty B-tree, a table B-tree or an index B-tree, returning
+    /// its root page
+    pub(crate) fn create_btree(&mut self, index: bool) -> Result<u32> {
+        let root_page = self.allocate_page()?;
+        let usable_size = self.usable_size()?;
+        let mut page = self.pager.read_page(root_page)?;
+        page.fill(0);
+        PageWriter::init(&mut page, 0, usable_size, if index { 10 } else { 13 });
+        self.pager.write_page(root_page, page)?;
+        Ok(root_page)
+    }
+
+    /// Returns the rowid for a new row of the table B-tree rooted at
+    /// `root_page`: one more than the largest in the table, or 1 if the table
+    /// is empty
+    ///
+    /// Once the largest possible rowid has been used, as SQLite does, random
+    /// positive rowids are tried until one is free, giving up after
+    /// [`MAX_ROWID_PROBES`] tries.
+    pub(crate) fn next_rowid(&mut self, root_page: u32) -> Result<i64> {
+        let mut cursor = self.cursor(root_page)?;
+        if !cursor.last()? {
+            return Ok(1);
+        }
+        if let Some(rowid) = cursor.rowid()?.checked_add(1) {
+            return Ok(rowid);
+        }
+        for _ in 0..MAX_ROWID_PROBES {
+            let rowid = (self.rng.next_u64() >> 1).max(1) as i64;
+            if !self.cursor(root_page)?.seek_rowid(rowid)? {
+                return Ok(rowid);
+            }
+        }
+        Err(anyhow!("database or disk is full"))
+    }
+
+    /// Notes a write to the table rooted at `root_page`, which is a schema
+    /// change, dropping the schema cache, if the table is sqlite_schema
+    fn note_write(&mut self, root_page: u32) {
+        if root_page == 1 {
+            self.schema_changed = true;
+            self.clear_schema_cache();
+        }
+    }
+
+    /// Frees the overflow pages of every cell below `page_num` and every page
+    /// below it, then `page_num` itself unless it is the root, returning the
+    /// number of rows on the leaves, or of entries for an index B-tree
+    fn clear_page(&mut self, root_page: u32, page_num: u32, depth: usize) -> Result<u64> {
+        if depth > MAX_DEPTH {
+            return Err(corrupt(0, "B-tree is too deep")).on_page(page_num);
+        }
+        let page = self.pager.read_btree_page(page_num)?;
+
+        let mut rows = 0;
+        if page.is_interior() {
+            for child in page.get_child_pages()? {
+                rows += self.clear_page(root_page, child, depth + 1)?;
+            }
+        }
+        // Table B-trees keep their rows on the leaves, while index B-trees
+        // keep entries on every page
+        if page.is_index() || !page.is_interior() {
+            let usable_size = self.usable_size()?;
+            let page_type = page.page_type();
+            for i in 0..page.num_cells() as usize {
+                let pos = page.cell_pointer(i)?;
+                let size = cell_size(page.data(), page_type, pos, usable_size).on_page(page_num)?;
+                let cell = checked::slice(page.data(), pos, size).on_page(page_num)?;
+                for overflow_page in self.overflow_pages(cell, page_type).on_page(page_num)? {
+                    self.free_page(overflow_page)?;
+                }
+            }
+            rows += page.num_cells() as u64;
+        }
+        if page_num != root_page {
+            self.free_page(page_num)?;
+        }
+        Ok(rows)
+    }
+
+    /// Rebalances an underfull page with its siblings
+    ///
+    /// `path` leads from the root to the page's parent, whose position is the
+    /// page's. The page and up to two neighbours, three pages in all, have
+    /// their cells spread afresh over as few pages as hold them; for interior
+    /// pages the parent's divider cells between them come down to join the
+    /// cells, and new dividers go up. Pages no longer needed are freed, and a
+    /// parent left underfull is balanced in turn.
+    fn balance(&mut self, path: &[(u32, usize)]) -> Result<()> {
+        let Some((&(parent_num, position), ancestors)) = path.split_last() else {
+            return Ok(());
+        };
+        let usable_size = self.usable_size()?;
+        let parent_offset = BTreePage::header_offset_for(parent_num);
+        let mut parent = self.pager.read_page(parent_num)?;
+        let writer =
+            PageWriter::new(&mut parent, parent_offset, usable_size).on_page(parent_num)?;
+        let num_cells = writer.num_cells();
+        let mut parent_cells = (0..num_cells)
+            .map(|i| Ok(writer.cell(i)?.to_vec()))
+            .collect::<Result<Vec<_>>>()
+            .on_page(parent_num)?;
+        let mut parent_right = writer.child(num_cells).on_page(parent_num)?;
+        let parent_type = writer.page_type();
+
+        // The page with a neighbour on each side where it has them, as SQLite
+        // chooses them
+        let first = match position {
+            _ if num_cells < 2 || position == 0 => 0,
+            _ if position == num_cells => num_cells - 2,
+            _ => position - 1,
+        };
+        let last = (first + 2).min(num_cells);
+        let siblings = (first..=last)
+            .map(|i| writer.child(i))
+            .collect::<Result<Vec<_>>>()
+            .on_page(parent_num)?;
+
+        let mut page_type = 0;
+        let mut cells = Vec::new();
+        let mut right_child = None;
+        for (i, &sibling_num) in siblings.iter().enumerate() {
+            let mut page = self.pager.read_page(sibling_num)?;
+            let writer = PageWriter::new(
+                &mut page,
+                BTreePage::header_offset_for(sibling_num),
+                usable_size,
+            )
+            .on_page(sibling_num)?;
+            if i == 0 {
+                page_type = writer.page_type();
+            } else if writer.page_type() != page_type {
+                return Err(corrupt(
+                    0,
+                    format!(
+                        "page type {} differs from its siblings'",
+                        writer.page_type()
+                    ),
+                ))
+                .on_page(sibling_num);
+            }
+            for j in 0..writer.num_cells() {
+                cells.push(writer.cell(j).on_page(sibling_num)?.to_vec());
+            }
+            if is_interior(page_type) {
+                let child = writer.child(writer.num_cells()).on_page(sibling_num)?;
+                if i + 1 < siblings.len() {
+                    // The divider comes down, pointing at this page's
+                    // right-most child
+                    let mut cell = parent_cells[first + i].clone();
+                    cell[..4].copy_from_slice(&child.to_be_bytes());
+                    cells.push(cell);
+                } else {
+                    right_child = Some(child);
+                }
+            } else if page_type == 10 && i + 1 < siblings.len() {
+                // An index divider is an entry, which comes down as a leaf cell
+                cells.push(parent_cells[first + i][4..].to_vec());
+            }
+        }
+
+        let split = spread_cells(page_type, cells, right_child, usable_size)?;
+        let mut pages = Vec::with_capacity(split.pages.len());
+        for i in 0..split.pages.len() {
+            pages.push(match siblings.get(i) {
+                Some(&page_num) => page_num,
+                None => self.allocate_page()?,
+            });
+        }
+        for (&page_num, (cells, right_child)) in pages.iter().zip(&split.pages) {
+            self.write_cells(page_num, page_type, cells, *right_child)?;
+        }
+        for &page_num in siblings.iter().skip(pages.len()) {
+            self.free_page(page_num)?;
+        }
+
+        // The parent loses the old dividers and points at the last new page
+        // where it pointed at the last sibling; the new dividers go in front
+        let last_page = pages[pages.len() - 1];
+        parent_cells.drain(first..last);
+        match parent_cells.get_mut(first) {
+            Some(cell) => cell[..4].copy_from_slice(&last_page.to_be_bytes()),
+            None => parent_right = last_page,
+        }
+        self.write_cells(parent_num, parent_type, &parent_cells, Some(parent_right))?;
+        let dividers = pages
+            .iter()
+            .zip(&split.keys)
+            .map(|(&page_num, key)| divider_cell(page_num, key))
+            .collect::<Vec<_>>();
+        let mut parent_path = ancestors.to_vec();
+        parent_path.push((parent_num, first));
+        if self.insert_cells(&parent_path, dividers)? {
+            // A parent that split is full enough
+            return Ok(());
+        }
+
+        let mut parent = self.pager.read_page(parent_num)?;
+        let writer =
+            PageWriter::new(&mut parent, parent_offset, usable_size).on_page(parent_num)?;
+        if ancestors.is_empty() {
+            if writer.num_cells() == 0 {
+                let only_child = writer.child(0).on_page(parent_num)?;
+                self.take_over_child(parent_num, only_child)?;
+            }
+        } else if is_underfull(writer.free_space()?, usable_size) {
+            self.balance(ancestors)?;
+        }
+        Ok(())
+    }
+
+    /// Moves the content of the only child of a root with no cells into the
+    /// root, so the tree gets one level shallower
+    ///
+    /// On page 1 the content may not fit, after the database header; the root
+    /// is then left as it is, as SQLite leaves it.
+    fn take_over_child(&mut self, root_page: u32, child_num: u32) -> Result<()> {
+        let usable_size = self.usable_size()?;
+        let mut child = self.pager.read_page(child_num)?;
+        let writer = PageWriter::new(&mut child, 0, usable_size).on_page(child_num)?;
+        let page_type = writer.page_type();
+        let num_cells = writer.num_cells();
+        let cells = (0..num_cells)
+            .map(|i| Ok(writer.cell(i)?.to_vec()))
+            .collect::<Result<Vec<_>>>()
+            .on_page(child_num)?;
+        let right_child = match is_interior(page_type) {
+            true => Some(writer.child(num_cells).on_page(child_num)?),
+            false => None,
+        };
+        let header_size = if right_child.is_some() { 12 } else { 8 };
+        let used: usize = cells.iter().map(cell_cost).sum();
+        if used + header_size > usable_size - BTreePage::header_offset_for(root_page) {
+            return Ok(());
+        }
+        self.write_cells(root_page, page_type, &cells, right_child)?;
+        self.free_page(child_num)
+    }
+
+    /// Inserts `cells`, in order, at the position the last step of `path`
+    /// names, splitting the page if they do not all fit, and returns true if
+    /// it split
+    ///
+    /// `path` leads from the root of a table or index B-tree, as
+    /// [`BTreeCursor::path`](crate::sqlite::storage::cursor::BTreeCursor::path)
+    /// does, and may end past the page's last cell.
+    fn insert_cells(&mut self, path: &[(u32, usize)], cells: Vec<Vec<u8>>) -> Result<bool> {
+        let Some((&(page_num, index), ancestors)) = path.split_last() else {
+            return Err(anyhow!("no page to insert cells into"));
+        };
+        let usable_size = self.usable_size()?;
+        let header_offset = BTreePage::header_offset_for(page_num);
+        let mut page = self.pager.read_page(page_num)?;
+
+        // Insert into a copy, so the page is left as it was if it overflows
+        let mut copy = page.clone();
+        let mut writer =
+            PageWriter::new(&mut copy, header_offset, usable_size).on_page(page_num)?;
+        let mut fits = true;
+        for (i, cell) in cells.iter().enumerate() {
+            if !writer.insert_cell(index + i, cell).on_page(page_num)? {
+                fits = false;
+                break;
+            }
+        }
+        if fits {
+            self.pager.write_page(page_num, copy)?;
+            return Ok(false);
+        }
+
+        let writer = PageWriter::new(&mut page, header_offset, usable_size).on_page(page_num)?;
+        let page_type = writer.page_type();
+        let num_cells = writer.num_cells();
+        let mut all = (0..num_cells)
+            .map(|i| Ok(writer.cell(i)?.to_vec()))
+            .collect::<Result<Vec<_>>>()
+            .on_page(page_num)?;
+        let right_child = match page_type {
+            10 | 13 => None,
+            2 | 5 => Some(writer.child(num_cells).on_page(page_num)?),
+            _ => {
+                return Err(corrupt(
+                    header_offset,
+                    format!("invalid B-tree page type {}", page_type),
+                ))
+                .on_page(page_num)
+            }
+        };
+        let appended = page_type == 13 && index == num_cells && cells.len() == 1;
+        all.splice(index..index, cells);
+
+        let Some(&(parent_num, position)) = ancestors.last() else {
+            let split = if appended {
+                // Rows added in rowid order leave the old cells together
+                let cell = all.pop().unwrap_or_default();
+                leaf_split(vec![all, vec![cell]])?
+            } else {
+                spread_cells(page_type, all, right_child, usable_size)?
+            };
+            self.split_root(page_num, page_type, split)?;
+            return Ok(true);
+        };
+
+        let parent = self.pager.read_btree_page(parent_num)?;
+        if appended && position == parent.num_cells() as usize {
+            // A row past the end of the right-most leaf starts a new leaf,
+            // leaving this one full
+            let new_page = self.allocate_page()?;
+            let cell = all.pop().unwrap_or_default();
+            self.write_cells(new_page, page_type, &[cell], None)?;
+            let key = leaf_rowid(all.last().map_or(&[][..], Vec::as_slice)).on_page(page_num)?;
+            let mut parent = parent.data().to_vec();
+            PageWriter::new(
+                &mut parent,
+                BTreePage::header_offset_for(parent_num),
+                usable_size,
+            )
+            .on_page(parent_num)?
+            .set_child(position, new_page)
+            .on_page(parent_num)?;
+            self.pager.write_page(parent_num, parent)?;
+            self.insert_cells(ancestors, vec![divider_cell(page_num, &rowid_key(key))])?;
+            return Ok(true);
+        }
+
+        // The last group of cells stays on this page, and new pages to its
+        // left take the others
+        let split = spread_cells(page_type, all, right_child, usable_size)?;
+        let mut dividers = Vec::with_capacity(split.keys.len());
+        let last = split.pages.len() - 1;
+        for (i, (cells, right_child)) in split.pages.iter().enumerate() {
+            let target = if i == last {
+                page_num
+            } else {
+                self.allocate_page()?
+            };
+            self.write_cells(target, page_type, cells, *right_child)?;
+            if let Some(key) = split.keys.get(i) {
+                dividers.push(divider_cell(target, key));
+            }
+        }
+        self.insert_cells(ancestors, dividers)?;
+        Ok(true)
+    }
+
+    /// Moves the cells of an overflowing root onto new pages, leaving the root
+    /// an interior page over them, so the tree gets one level deeper
+    fn split_root(&mut self, root_page: u32, page_type: u8, split: Split) -> Result<()> {
+        let mut dividers = Vec::with_capacity(split.keys.len());
+        let mut right_child = 0;
+        for (i, (cells, child)) in split.pages.iter().enumerate() {
+            let page_num = self.allocate_page()?;
+            self.write_cells(page_num, page_type, cells, *child)?;
+            match split.keys.get(i) {
+                Some(key) => dividers.push(divider_cell(page_num, key)),
+                None => right_child = page_num,
+            }
+        }
+        let interior_type = if is_index(page_type) { 2 } else { 5 };
+        self.write_cells(root_page, interior_type, &dividers, Some(right_child))
+    }
+
+    /// Makes a page a B-tree page of `page_type` holding `cells` and, on an
+    /// interior page, the right-most child `right_child`
+    fn write_cells(
+        &mut self,
+        page_num: u32,
+        page_type: u8,
+        cells: &[Vec<u8>],
+        right_child: Option<u32>,
+    ) -> Result<()> {
+        let usable_size = self.usable_size()?;
+        let mut page = self.pager.read_page(page_num)?;
+        let mut writer = PageWriter::init(
+            &mut page,
+            BTreePage::header_offset_for(page_num),
+            usable_size,
+            page_type,
+        );
+        for (i, cell) in cells.iter().enumerate() {
+            if !writer.insert_cell(i, cell).on_page(page_num)? {
+                return Err(anyhow!(
+                    "{} cells do not fit on page {}",
+                    cells.len(),
+                    page_num
+                ));
+            }
+        }
+        if let Some(child) = right_child {
+            writer.set_child(cells.len(), child).on_page(page_num)?;
+        }
+        self.pager.write_page(page_num, page)
+    }
+
+    /// Builds the leaf cell of a table row, writing any overflow pages its
+    /// record needs
+    fn table_leaf_cell(&mut self, rowid: i64, record: &[u8]) -> Result<Vec<u8>> {
+        let mut cell = Vec::new();
+        encode_varint(record.len() as u64, &mut cell);
+        encode_varint(rowid as u64, &mut cell);
+        self.push_payload(&mut cell, record, 13)?;
+        Ok(cell)
+    }
+
+    /// Appends `payload` to `cell`, a cell for a page of `page_type` holding
+    /// only its header so far, spilling what does not fit locally onto new
+    /// overflow pages
+    fn push_payload(&mut self, cell: &mut Vec<u8>, payload: &[u8], page_type: u8) -> Result<()> {
+        let layout = PayloadLayout::new(self.usable_size()?, page_type);
+        let local = layout.local_size(payload.len());
+        cell.extend_from_slice(&payload[..local]);
+        if local < payload.len() {
+            let first = self.write_overflow(&payload[local..], layout.overflow_capacity())?;
+            cell.extend_from_slice(&first.to_be_bytes());
+        }
+        Ok(())
+    }
+
+    /// Writes `data` to a chain of newly allocated overflow pages holding
+    /// `capacity` bytes each, returning the first page
+    fn write_overflow(&mut self, data: &[u8], capacity: usize) -> Result<u32> {
+        let pages = data
+            .chunks(capacity)
+            .map(|_| self.allocate_page())
+            .collect::<Result<Vec<_>>>()?;
+        let page_size = self.pager.page_size() as usize;
+        for (i, chunk) in data.chunks(capacity).enumerate() {
+            let next = pages.get(i + 1).copied().unwrap_or(0);
+            let mut page = vec![0; page_size];
+            page[..4].copy_from_slice(&next.to_be_bytes());
+            page[4..4 + chunk.len()].copy_from_slice(chunk);
+            self.pager.write_page(pages[i], page)?;
+        }
+        Ok(pages[0])
+    }
+
+    /// Returns the overflow pages of a cell of a page of `page_type`, in chain
+    /// order
+    fn overflow_pages(&mut self, cell: &[u8], page_type: u8) -> Result<Vec<u32>> {
+        if page_type == 5 {
+            return Ok(Vec::new());
+        }
+        let usable_size = self.usable_size()?;
+        let layout = PayloadLayout::new(usable_size, page_type);
+        // Interior index cells start with their left child
+        let start = if page_type == 2 { 4 } else { 0 };
+        let (payload_size, size_len) = decode_varint(cell, start)?;
+        let mut pos = start + size_len;
+        if page_type == 13 {
+            pos += decode_varint(cell, pos)?.1;
+        }
+        let local = layout.local_size(payload_size as usize);
+        let overflow = payload_size as usize - local;
+        if overflow == 0 {
+            return Ok(Vec::new());
+        }
+
+        let count = (overflow + layout.overflow_capacity() - 1) / layout.overflow_capacity();
+        let page_count = self.pager.page_count()?;
+        let mut pages = Vec::with_capacity(count);
+        let mut next = checked::be_u32(cell, pos + local)?;
+        while pages.len() < count {
+            if next == 0 || next > page_count || pages.contains(&next) {
+                return Err(corrupt(
+                    pos + local,
+                    format!("overflow chain has a bad page {}", next),
+                ));
+            }
+            pages.push(next);
+            next = checked::be_u32(&self.pager.read_page(next)?, 0).on_page(next)?;
+        }
+        Ok(pages)
+    }
+}
+
+/// The cells of an overflowing page spread over several pages
+struct Split {
+    /// The cells of each page, in key order, and the right-most child of
+    /// each interior page
+    pages: Vec<(Vec<Vec<u8>>, Option<u32>)>,
+    /// The key dividing each page from the next, as it follows the child
+    /// pointer in the parent's divider cell: a rowid varint in a table
+    /// B-tree, and a whole entry, size and payload, in an index B-tree
+    keys: Vec<Vec<u8>>,
+}
+
+/// Spreads the cells of B-tree pages over as few pages as hold them
+///
+/// Pages are filled in order, and cells are then moved rightwards until each
+/// page holds no more than the one before it. Except on table leaves, the
+/// cell between two pages goes up to the parent as their divider, and on
+/// interior pages its child becomes the right-most child of the page on its
+/// left; `right_child` is the right-most child of the last page.
+fn spread_cells(
+    page_type: u8,
+    cells: Vec<Vec<u8>>,
+    right_child: Option<u32>,
+    usable_size: usize,
+) -> Result<Split> {
+    let interior = is_interior(page_type);
+    // Cells that divide pages leave them for the parent
+    let divided = page_type != 13;
+    let capacity = usable_size - if interior { 12 } else { 8 };
+    let mut pages: Vec<Vec<Vec<u8>>> = vec![Vec::new()];
+    let mut used = vec![0];
+    let mut dividers: Vec<Vec<u8>> = Vec::new();
+    for cell in cells {
+        let last = pages.len() - 1;
+        if pages[last].is_empty() || used[last] + cell_cost(&cell) <= capacity {
+            used[last] += cell_cost(&cell);
+            pages[last].push(cell);
+        } else if divided {
+            dividers.push(cell);
+            pages.push(Vec::new());
+            used.push(0);
+        } else {
+            used.push(cell_cost(&cell));
+            pages.push(vec![cell]);
+        }
+    }
+
+    for i in (1..pages.len()).rev() {
+        while pages[i - 1].len() > 1 {
+            let leaving = pages[i - 1].last().map_or(0, cell_cost);
+            // Where cells divide pages the divider comes down and the cell
+            // replaces it
+            let arriving = if divided {
+                cell_cost(&dividers[i - 1])
+            } else {
+                leaving
+            };
+            if used[i] + arriving > used[i - 1] - leaving {
+                break;
+            }
+            let Some(mut cell) = pages[i - 1].pop() else {
+                break;
+            };
+            if divided {
+                std::mem::swap(&mut cell, &mut dividers[i - 1]);
+            }
+            pages[i].insert(0, cell);
+            used[i - 1] -= leaving;
+            used[i] += arriving;
+        }
+    }
+
+    if !divided {
+        return leaf_split(pages);
+    }
+    if pages.iter().any(Vec::is_empty) {
+        return Err(anyhow!("cells could not be spread over pages"));
+    }
+    let mut split = Split {
+        pages: Vec::with_capacity(pages.len()),
+        keys: Vec::with_capacity(dividers.len()),
+    };
+    for (i, cells) in pages.into_iter().enumerate() {
+        let child = match dividers.get(i) {
+            Some(divider) if interior => {
+                split
+                    .keys
+                    .push(checked::slice(divider, 4, divider.len() - 4)?.to_vec());
+                Some(checked::be_u32(divider, 0)?)
+            }
+            Some(divider) => {
+                split.keys.push(divider.clone());
+                None
+            }
+            None => right_child,
+        };
+        split.pages.push((cells, child));
+    }
+    Ok(split)
+}
+
+/// Divides table leaf pages by the rowid of the last cell on each page but the
+/// last
+fn leaf_split(pages: Vec<Vec<Vec<u8>>>) -> Result<Split> {
+    let keys = pages[..pages.len().saturating_sub(1)]
+        .iter()
+        .map(|cells| {
+            Ok(rowid_key(leaf_rowid(
+                cells.last().map_or(&[][..], Vec::as_slice),
+            )?))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Ok(Split {
+        pages: pages.into_iter().map(|cells| (cells, None)).collect(),
+        keys,
+    })
+}
+
+/// Returns the bytes a cell takes up on its page, with its cell pointer
+fn cell_cost(cell: &Vec<u8>) -> usize {
+    cell.len().max(4) + 2
+}
+
+/// Returns true if more than two thirds of a page is free, which is when
+/// SQLite rebalances it
+fn is_underfull(free_space: usize, usable_size: usize) -> bool {
+    free_space > usable_size * 2 / 3
+}
+
+/// Returns the rowid of a table leaf cell
+fn leaf_rowid(cell: &[u8]) -> Result<i64> {
+    let (_, len) = decode_varint(cell, 0)?;
+    Ok(decode_varint(cell, len)?.0 as i64)
+}
+
+/// Encodes a rowid as the key of a table B-tree's divider cell
+fn rowid_key(rowid: i64) -> Vec<u8> {
+    let mut key = Vec::with_capacity(9);
+    encode_varint(rowid as u64, &mut key);
+    key
+}
+
+/// Builds the interior cell pointing at `child`, whose keys come before
+/// `key`, or are `key` and below in a table B-tree
+fn divider_cell(child: u32, key: &[u8]) -> Vec<u8> {
+    let mut cell = child.to_be_bytes().to_vec();
+    cell.extend_from_slice(key);
+    cell
+}
+
+/// Returns true for the page types of interior pages
+fn is_interior(page_type: u8) -> bool {
+    matches!(page_type, 2 | 5)
+}
+
+/// Returns true for the page types of index B-tree pages
+fn is_index(page_type: u8) -> bool {
+    matches!(page_type, 2 | 10)
+}
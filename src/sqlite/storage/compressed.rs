@@ -0,0 +1,221 @@
+//! Compressed Page Container
+//!
+//! Stores a database as individually zstd-compressed pages in a sidecar container
+//! file (`<database>.zpages`). Opening the container yields a `DatabaseFile` that
+//! presents ordinary uncompressed pages to the pager, so archived databases can be
+//! queried transparently.
+//!
+//! ## Container Format
+//!
+//! All integers are big-endian.
+//!
+//! - Bytes 0-7: Magic string "SQLZPAGE"
+//! - Bytes 8-11: Format version (1)
+//! - Bytes 12-15: Page size in bytes
+//! - Bytes 16-19: Number of pages
+//! - Bytes 20-31: Reserved (zero)
+//! - Page index: one 12-byte entry per page (8-byte frame offset, 4-byte frame length)
+//! - Page frames: one zstd frame per page
+
+use crate::sqlite::core::header::DatabaseHeader;
+use crate::sqlite::storage::pager::Pager;
+use crate::sqlite::storage::vfs::DatabaseFile;
+use anyhow::{anyhow, Result};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tracing::debug;
+
+/// Magic string at the start of every container
+const MAGIC: &[u8; 8] = b"SQLZPAGE";
+/// Current container format version
+const VERSION: u32 = 1;
+/// Size of the container header in bytes
+const HEADER_SIZE: usize = 32;
+/// Size of one page index entry in bytes
+const INDEX_ENTRY_SIZE: usize = 12;
+
+/// Location of one compressed page inside the container
+#[derive(Debug, Clone, Copy)]
+struct IndexEntry {
+    offset: u64,
+    length: u32,
+}
+
+/// Summary of a finished compression run
+#[derive(Debug)]
+pub struct CompressionSummary {
+    /// Number of pages written
+    pub page_count: u32,
+    /// Size of the source database in bytes
+    pub original_size: u64,
+    /// Size of the container in bytes
+    pub compressed_size: u64,
+}
+
+/// A database image read out of a compressed page container
+pub struct CompressedFile {
+    /// The container file
+    file: File,
+    /// Uncompressed page size
+    page_size: u32,
+    /// Frame location of every page
+    index: Vec<IndexEntry>,
+    /// Most recently decompressed page, keyed by zero-based page index
+    cached: Mutex<Option<(usize, Arc<[u8]>)>>,
+}
+
+impl CompressedFile {
+    /// Returns the default sidecar path for a database file
+    pub fn sidecar_path(database: &Path) -> PathBuf {
+        let mut path = database.as_os_str().to_owned();
+        path.push(".zpages");
+        PathBuf::from(path)
+    }
+
+    /// Returns true if the file starts with the container magic string
+    pub fn is_container(file: &File) -> Result<bool> {
+        let mut magic = [0u8; 8];
+        if file.len()? < MAGIC.len() as u64 {
+            return Ok(false);
+        }
+        file.read_exact_at(0, &mut magic)?;
+        Ok(&magic == MAGIC)
+    }
+
+    /// Opens a container and loads its page index
+    pub fn open(file: File) -> Result<Self> {
+        let mut header = [0u8; HEADER_SIZE];
+        file.read_exact_at(0, &mut header)?;
+        if &header[0..8] != MAGIC {
+            return Err(anyhow!("Not a compressed page container"));
+        }
+
+        let version = u32::from_be_bytes([header[8], header[9], header[10], header[11]]);
+        if version != VERSION {
+            return Err(anyhow!("Unsupported container version: {}", version));
+        }
+        let page_size = u32::from_be_bytes([header[12], header[13], header[14], header[15]]);
+        let page_count = u32::from_be_bytes([header[16], header[17], header[18], header[19]]);
+        debug!(
+            "Opening compressed container: {} pages of {} bytes",
+            page_count, page_size
+        );
+
+        let mut raw_index = vec![0u8; page_count as usize * INDEX_ENTRY_SIZE];
+        file.read_exact_at(HEADER_SIZE as u64, &mut raw_index)?;
+        let index = raw_index
+            .chunks_exact(INDEX_ENTRY_SIZE)
+            .map(|entry| IndexEntry {
+                offset: u64::from_be_bytes(entry[0..8].try_into().unwrap()),
+                length: u32::from_be_bytes(entry[8..12].try_into().unwrap()),
+            })
+            .collect();
+
+        Ok(Self {
+            file,
+            page_size,
+            index,
+            cached: Mutex::new(None),
+        })
+    }
+
+    /// Compresses the database at `source` into a container at `dest`
+    pub fn create(source: &Path, dest: &Path, level: i32) -> Result<CompressionSummary> {
+        let input = File::open(source)?;
+        let header = DatabaseHeader::parse(&Pager::read_raw_header(&input)?)?;
+        let page_size = header.page_size as u64;
+        let original_size = input.len()?;
+        if original_size % page_size != 0 {
+            return Err(anyhow!(
+                "Database size {} is not a multiple of the page size {}",
+                original_size,
+                page_size
+            ));
+        }
+        let page_count = (original_size / page_size) as u32;
+
+        let mut output = BufWriter::new(File::create(dest)?);
+        output.write_all(MAGIC)?;
+        output.write_all(&VERSION.to_be_bytes())?;
+        output.write_all(&(page_size as u32).to_be_bytes())?;
+        output.write_all(&page_count.to_be_bytes())?;
+        output.write_all(&[0u8; HEADER_SIZE - 20])?;
+
+        // Compress every page up front so the index can precede the frames
+        let mut page = vec![0u8; page_size as usize];
+        let mut frames = Vec::with_capacity(page_count as usize);
+        for page_index in 0..page_count as u64 {
+            input.read_exact_at(page_index * page_size, &mut page)?;
+            frames.push(zstd::bulk::compress(&page, level)?);
+        }
+
+        let mut offset = (HEADER_SIZE + frames.len() * INDEX_ENTRY_SIZE) as u64;
+        for frame in &frames {
+            output.write_all(&offset.to_be_bytes())?;
+            output.write_all(&(frame.len() as u32).to_be_bytes())?;
+            offset += frame.len() as u64;
+        }
+        for frame in &frames {
+            output.write_all(frame)?;
+        }
+        output.flush()?;
+
+        Ok(CompressionSummary {
+            page_count,
+            original_size,
+            compressed_size: offset,
+        })
+    }
+
+    /// Returns the uncompressed page with the given zero-based index
+    fn page(&self, page_index: usize) -> Result<Arc<[u8]>> {
+        let mut cached = self
+            .cached
+            .lock()
+            .map_err(|_| anyhow!("a thread panicked while decompressing a page"))?;
+        if !matches!(&*cached, Some((cached, _)) if *cached == page_index) {
+            let entry = *self
+                .index
+                .get(page_index)
+                .ok_or_else(|| anyhow!("Page index {} out of range", page_index))?;
+            let mut frame = vec![0u8; entry.length as usize];
+            self.file.read_exact_at(entry.offset, &mut frame)?;
+            let page = zstd::bulk::decompress(&frame, self.page_size as usize)?;
+            if page.len() != self.page_size as usize {
+                return Err(anyhow!(
+                    "Decompressed page {} has {} bytes, expected {}",
+                    page_index + 1,
+                    page.len(),
+                    self.page_size
+                ));
+            }
+            *cached = Some((page_index, page.into()));
+        }
+
+        Ok(cached.as_ref().unwrap().1.clone())
+    }
+}
+
+impl DatabaseFile for CompressedFile {
+    fn read_exact_at(&self, offset: u64, buf: &mut [u8]) -> Result<()> {
+        let page_size = self.page_size as u64;
+        let mut written = 0;
+
+        while written < buf.len() {
+            let position = offset + written as u64;
+            let page_offset = (position % page_size) as usize;
+            let page = self.page((position / page_size) as usize)?;
+            let count = (page.len() - page_offset).min(buf.len() - written);
+            buf[written..written + count].copy_from_slice(&page[page_offset..page_offset + count]);
+            written += count;
+        }
+
+        Ok(())
+    }
+
+    fn len(&self) -> Result<u64> {
+        Ok(self.index.len() as u64 * self.page_size as u64)
+    }
+}
@@ -0,0 +1,509 @@
+//! B-tree Cursors
+//!
+//! A cursor walks the entries of one table or index B-tree in key order. It
+//! keeps the path from the root to its current entry as a stack of pages, each
+//! with the position of the cell or child the path goes through, so moving to
+//! the next or previous entry reads only the pages it steps onto.
+//!
+//! A cursor borrows the connection's pager while it moves. Cursors that must
+//! stay open together, like those of the tables of a join, are parked between
+//! moves and resumed on the entry they were on.
+//!
+//! Table B-trees keep their rows on the leaves, and interior cells hold only
+//! the rowid keys that separate the subtrees. Index B-trees keep entries on
+//! interior pages too: each interior cell's entry comes after every entry in
+//! its left child and before every entry in the next child.
+//!
+//! # Example
+//! ```
+//! let mut cursor = db.cursor(root_page)?;
+//! cursor.seek_rowid(100)?;
+//! while cursor.is_valid() && cursor.rowid()? < 200 {
+//!     let values = cursor.current()?;
+//!     cursor.next()?;
+//! }
+//! ```
+
+use crate::sqlite::core::btree::{search_cells, BTreePage, Cell, Payload, MAX_DEPTH};
+use crate::sqlite::core::error::{corrupt, CorruptPage};
+use crate::sqlite::core::header::DatabaseHeader;
+use crate::sqlite::core::record::decode_record;
+use crate::sqlite::core::value::Value;
+use crate::sqlite::core::varint::decode_varint;
+use crate::sqlite::storage::db::SQLiteDatabase;
+use crate::sqlite::storage::pager::Pager;
+use anyhow::{anyhow, Result};
+use std::cmp::Ordering;
+
+/// A page on the cursor's path
+struct Frame {
+    page_num: u32,
+    page: BTreePage,
+    interior: bool,
+    num_cells: usize,
+    /// The cell the cursor is on, for the last frame; the child the path
+    /// descends into, for the others, where `num_cells` is the right-most child
+    index: usize,
+}
+
+impl Frame {
+    /// Returns the offset of the `i`-th cell
+    fn cell_offset(&self, i: usize) -> Result<usize> {
+        self.page.cell_pointer(i)
+    }
+
+    /// Returns the page number of the `i`-th child of an interior page
+    fn child(&self, i: usize) -> Result<u32> {
+        self.page.child(i)
+    }
+}
+
+/// What a seek looks for
+enum Target<'k> {
+    Rowid(i64),
+    /// Compares an index entry with the key sought
+    Key(&'k dyn Fn(&[Value]) -> Ordering),
+}
+
+/// A position in a table or index B-tree
+pub struct BTreeCursor<'a> {
+    pager: &'a mut Pager,
+    header: &'a DatabaseHeader,
+    usable_size: usize,
+    root_page: u32,
+    /// True for index B-trees (also used by WITHOUT ROWID tables)
+    index: bool,
+    /// Path from the root to the current entry; empty when the cursor is not
+    /// on an entry
+    stack: Vec<Frame>,
+}
+
+/// The position of a cursor that has let go of the pager, so that several
+/// cursors can stay open on one connection and be moved in turn
+pub struct ParkedCursor {
+    usable_size: usize,
+    root_page: u32,
+    index: bool,
+    stack: Vec<Frame>,
+}
+
+impl<'a> BTreeCursor<'a> {
+    /// Creates a cursor over the B-tree rooted at `root_page`, positioned on no
+    /// entry
+    pub fn new(
+        pager: &'a mut Pager,
+        header: &'a DatabaseHeader,
+        usable_size: usize,
+        root_page: u32,
+    ) -> Result<Self> {
+        let mut cursor = Self {
+            pager,
+            header,
+            usable_size,
+            root_page,
+            index: false,
+            stack: Vec::new(),
+        };
+        let root = cursor.read_frame(root_page, None)?;
+        cursor.index = root.page.is_index();
+        Ok(cursor)
+    }
+
+    /// Lets go of the pager, keeping the cursor's position and the pages on
+    /// its path for [`resume`](Self::resume)
+    pub fn park(self) -> ParkedCursor {
+        ParkedCursor {
+            usable_size: self.usable_size,
+            root_page: self.root_page,
+            index: self.index,
+            stack: self.stack,
+        }
+    }
+
+    /// Reopens a parked cursor on the entry it was on
+    ///
+    /// The pages on its path are those read before it was parked, so the tree
+    /// must not have been written since.
+    pub fn resume(pager: &'a mut Pager, header: &'a DatabaseHeader, parked: ParkedCursor) -> Self {
+        Self {
+            pager,
+            header,
+            usable_size: parked.usable_size,
+            root_page: parked.root_page,
+            index: parked.index,
+            stack: parked.stack,
+        }
+    }
+
+    /// Returns true if the cursor is on an entry
+    pub fn is_valid(&self) -> bool {
+        !self.stack.is_empty()
+    }
+
+    /// Returns true if the cursor walks an index B-tree
+    pub fn is_index(&self) -> bool {
+        self.index
+    }
+
+    /// Returns the page the current entry is on
+    pub fn page_num(&self) -> Result<u32> {
+        Ok(self.top()?.page_num)
+    }
+
+    /// Returns the position of the current entry among the cells of its page
+    pub fn cell_index(&self) -> Result<usize> {
+        Ok(self.top()?.index)
+    }
+
+    /// Returns the path to the current entry, from the root down: each page
+    /// with the child the path descends into, and last the current entry's
+    /// page and cell
+    pub fn path(&self) -> Vec<(u32, usize)> {
+        self.stack
+            .iter()
+            .map(|frame| (frame.page_num, frame.index))
+            .collect()
+    }
+
+    /// Returns the path to where a row with the given rowid belongs, as
+    /// [`path`](Self::path) does, ending with the leaf and the position its
+    /// cell has or would be inserted at
+    ///
+    /// The cursor is left on no entry.
+    pub fn rowid_slot(&mut self, rowid: i64) -> Result<Vec<(u32, usize)>> {
+        if self.index {
+            return Err(anyhow!("cannot seek a rowid in an index B-tree"));
+        }
+        self.descend_to(&Target::Rowid(rowid))?;
+        let path = self.path();
+        self.stack.clear();
+        Ok(path)
+    }
+
+    /// Returns the path to where an entry belongs in an index B-tree, as
+    /// [`rowid_slot`](Self::rowid_slot) does, given `compare` as for
+    /// [`seek_key`](Self::seek_key)
+    ///
+    /// The entry goes on a leaf, before the first entry there that `compare`
+    /// does not return Less for. The cursor is left on no entry.
+    pub fn key_slot(
+        &mut self,
+        compare: &dyn Fn(&[Value]) -> Ordering,
+    ) -> Result<Vec<(u32, usize)>> {
+        if !self.index {
+            return Err(anyhow!("cannot seek a key in a table B-tree"));
+        }
+        self.descend_to(&Target::Key(compare))?;
+        let path = self.path();
+        self.stack.clear();
+        Ok(path)
+    }
+
+    /// Moves to the first entry, returning false if the tree is empty
+    pub fn first(&mut self) -> Result<bool> {
+        self.stack.clear();
+        self.descend_first(self.root_page)
+    }
+
+    /// Moves to the last entry, returning false if the tree is empty
+    pub fn last(&mut self) -> Result<bool> {
+        self.stack.clear();
+        self.descend_last(self.root_page)
+    }
+
+    /// Moves to the next entry, returning false once past the last one
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self) -> Result<bool> {
+        let Some(top) = self.stack.last_mut() else {
+            return Ok(false);
+        };
+        if top.interior {
+            // The entries after an interior cell start in the next child
+            top.index += 1;
+            let child = top.child(top.index)?;
+            return self.descend_first(child);
+        }
+        top.index += 1;
+        if top.index < top.num_cells {
+            return Ok(true);
+        }
+        let leaf = top.page_num;
+        self.stack.pop();
+        while let Some(parent) = self.stack.last_mut() {
+            if parent.index < parent.num_cells {
+                if self.index {
+                    // The cell after a left child holds the next entry
+                    return Ok(true);
+                }
+                parent.index += 1;
+                let child = parent.child(parent.index)?;
+                return self.next_leaf(leaf, child);
+            }
+            self.stack.pop();
+        }
+        Ok(false)
+    }
+
+    /// Moves to the previous entry, returning false once before the first one
+    pub fn prev(&mut self) -> Result<bool> {
+        let Some(top) = self.stack.last_mut() else {
+            return Ok(false);
+        };
+        if top.interior {
+            // The entries before an interior cell end in its left child
+            let child = top.child(top.index)?;
+            return self.descend_last(child);
+        }
+        if top.index > 0 {
+            top.index -= 1;
+            return Ok(true);
+        }
+        self.stack.pop();
+        while let Some(parent) = self.stack.last_mut() {
+            if parent.index > 0 {
+                parent.index -= 1;
+                if self.index {
+                    // The cell before a child holds the previous entry
+                    return Ok(true);
+                }
+                let child = parent.child(parent.index)?;
+                return self.descend_last(child);
+            }
+            self.stack.pop();
+        }
+        Ok(false)
+    }
+
+    /// Moves to the first row of a table B-tree whose rowid is not below
+    /// `rowid`, returning true if its rowid is `rowid`
+    ///
+    /// The cursor is left on no entry if every rowid is below `rowid`.
+    pub fn seek_rowid(&mut self, rowid: i64) -> Result<bool> {
+        if self.index {
+            return Err(anyhow!("cannot seek a rowid in an index B-tree"));
+        }
+        self.seek(&Target::Rowid(rowid))?;
+        Ok(self.is_valid() && self.rowid()? == rowid)
+    }
+
+    /// Moves to the first entry of an index B-tree for which `compare` does
+    /// not return Less, returning true if it returns Equal for that entry
+    ///
+    /// `compare` orders an entry against the key sought, so the entries it
+    /// returns Less for must all come first. The cursor is left on no entry if
+    /// it returns Less for every entry.
+    pub fn seek_key(&mut self, compare: &dyn Fn(&[Value]) -> Ordering) -> Result<bool> {
+        if !self.index {
+            return Err(anyhow!("cannot seek a key in a table B-tree"));
+        }
+        self.seek(&Target::Key(compare))?;
+        Ok(self.is_valid() && compare(&self.current()?) == Ordering::Equal)
+    }
+
+    /// Returns the rowid of the current row of a table B-tree
+    pub fn rowid(&self) -> Result<i64> {
+        let top = self.top()?;
+        if self.index {
+            return Err(anyhow!("index entries have no rowid key"));
+        }
+        let pos = top.cell_offset(top.index)?;
+        let data = top.page.data();
+        let (_, len) = decode_varint(data, pos).on_page(top.page_num)?;
+        Ok(decode_varint(data, pos + len).on_page(top.page_num)?.0 as i64)
+    }
+
+    /// Parses the current cell, with its whole payload
+    pub fn cell(&mut self) -> Result<Cell> {
+        let top = self
+            .stack
+            .last()
+            .ok_or_else(|| anyhow!("cursor is not on an entry"))?;
+        read_cell(self.pager, self.usable_size, top, top.index)
+    }
+
+    /// Returns the record payload of the current entry, as a range of its page
+    /// unless it spills onto overflow pages
+    pub fn payload(&mut self) -> Result<Payload> {
+        self.cell()?
+            .into_payload()
+            .ok_or_else(|| anyhow!("cell has no payload"))
+    }
+
+    /// Decodes the record of the current entry: a table row's stored columns,
+    /// or an index entry's key columns followed by the rowid
+    pub fn current(&mut self) -> Result<Vec<Value>> {
+        let payload = self.payload()?;
+        let page_num = self.top()?.page_num;
+        decode_record(&payload, self.header).on_page(page_num)
+    }
+
+    /// Returns the frame of the current entry
+    fn top(&self) -> Result<&Frame> {
+        self.stack
+            .last()
+            .ok_or_else(|| anyhow!("cursor is not on an entry"))
+    }
+
+    /// Reads a page of the tree, checking that it is of the tree's kind
+    fn read_frame(&mut self, page_num: u32, index: Option<bool>) -> Result<Frame> {
+        if self.stack.len() > MAX_DEPTH {
+            return Err(corrupt(0, "B-tree is too deep")).on_page(page_num);
+        }
+        let page = self.pager.read_btree_page(page_num)?;
+        let header_offset = page.header_offset();
+        if index.map_or(false, |index| index != page.is_index()) {
+            return Err(corrupt(
+                header_offset,
+                format!(
+                    "page type {} does not belong in this B-tree",
+                    page.page_type()
+                ),
+            ))
+            .on_page(page_num);
+        }
+        let interior = page.is_interior();
+        let num_cells = page.num_cells() as usize;
+        // Only a root may have no cells, and an interior root only on page 1,
+        // whose only child may not fit after the database header
+        let may_be_empty = page_num == self.root_page && (!interior || page_num == 1);
+        if num_cells == 0 && !may_be_empty {
+            return Err(corrupt(header_offset + 3, "B-tree page has no cells")).on_page(page_num);
+        }
+        Ok(Frame {
+            page_num,
+            page,
+            interior,
+            num_cells,
+            index: 0,
+        })
+    }
+
+    /// Moves on from the leaf `leaf` to the first entry of the subtree at
+    /// `child`, telling the pager whether the two leaves are next to each
+    /// other in the file, so that it reads ahead while they are
+    fn next_leaf(&mut self, leaf: u32, child: u32) -> Result<bool> {
+        let found = self.descend_first(child)?;
+        let next = self.stack.last().map(|frame| frame.page_num);
+        self.pager.set_sequential(next == Some(leaf + 1));
+        Ok(found)
+    }
+
+    /// Descends from `page_num` to the first entry of its subtree
+    fn descend_first(&mut self, mut page_num: u32) -> Result<bool> {
+        loop {
+            let frame = self.read_frame(page_num, Some(self.index))?;
+            if !frame.interior {
+                let empty = frame.num_cells == 0;
+                self.stack.push(frame);
+                if empty {
+                    self.stack.clear();
+                }
+                return Ok(!empty);
+            }
+            page_num = frame.child(0)?;
+            self.stack.push(frame);
+        }
+    }
+
+    /// Descends from `page_num` to the last entry of its subtree
+    fn descend_last(&mut self, mut page_num: u32) -> Result<bool> {
+        loop {
+            let mut frame = self.read_frame(page_num, Some(self.index))?;
+            if !frame.interior {
+                if frame.num_cells == 0 {
+                    self.stack.clear();
+                    return Ok(false);
+                }
+                frame.index = frame.num_cells - 1;
+                self.stack.push(frame);
+                return Ok(true);
+            }
+            frame.index = frame.num_cells;
+            page_num = frame.child(frame.num_cells)?;
+            self.stack.push(frame);
+        }
+    }
+
+    /// Moves to the first entry that does not come before `target`
+    fn seek(&mut self, target: &Target) -> Result<()> {
+        self.descend_to(target)?;
+        let past_end = self
+            .stack
+            .last()
+            .map_or(false, |top| top.index == top.num_cells);
+        if past_end {
+            // Step back onto the last cell so that moving on climbs to
+            // whatever follows this leaf
+            match self.stack.last_mut() {
+                Some(top) if top.num_cells > 0 => top.index -= 1,
+                _ => {
+                    self.stack.clear();
+                    return Ok(());
+                }
+            }
+            self.next()?;
+        }
+        Ok(())
+    }
+
+    /// Descends to the leaf `target` belongs on, leaving the last frame on the
+    /// first cell that does not come before it, or past the last cell
+    fn descend_to(&mut self, target: &Target) -> Result<()> {
+        self.pager.count_seek();
+        self.stack.clear();
+        let mut page_num = self.root_page;
+        loop {
+            let mut frame = self.read_frame(page_num, Some(self.index))?;
+            let low = search_cells(frame.num_cells, |i| self.cell_before(&frame, i, target))?;
+            frame.index = low;
+            let interior = frame.interior;
+            if interior {
+                page_num = frame.child(low)?;
+            }
+            self.stack.push(frame);
+            if !interior {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Returns true if the `i`-th cell of `frame` comes before `target`
+    fn cell_before(&mut self, frame: &Frame, i: usize, target: &Target) -> Result<bool> {
+        let mut pos = frame.cell_offset(i)?;
+        if frame.interior {
+            pos += 4;
+        }
+        match target {
+            Target::Rowid(rowid) => {
+                if !frame.interior {
+                    pos += decode_varint(frame.page.data(), pos)
+                        .on_page(frame.page_num)?
+                        .1;
+                }
+                let (key, _) = decode_varint(frame.page.data(), pos).on_page(frame.page_num)?;
+                Ok((key as i64) < *rowid)
+            }
+            Target::Key(compare) => {
+                let cell = read_cell(self.pager, self.usable_size, frame, i)?;
+                let payload = cell.payload().unwrap_or_default();
+                let entry = decode_record(payload, self.header).on_page(frame.page_num)?;
+                Ok(compare(&entry) == Ordering::Less)
+            }
+        }
+    }
+}
+
+/// Parses the `i`-th cell of a page on a cursor's path
+fn read_cell(pager: &mut Pager, usable_size: usize, frame: &Frame, i: usize) -> Result<Cell> {
+    frame.page.cell(i as u16, usable_size, &mut |page_num| {
+        pager.read_page(page_num)
+    })
+}
+
+impl SQLiteDatabase {
+    /// Opens a cursor over the table or index B-tree rooted at `root_page`
+    pub fn cursor(&mut self, root_page: u32) -> Result<BTreeCursor<'_>> {
+        let usable_size = self.usable_size()?;
+        BTreeCursor::new(&mut self.pager, &self.header, usable_size, root_page)
+    }
+}
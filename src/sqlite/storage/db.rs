@@ -8,34 +8,72 @@
 //!
 //! - Database header (100 bytes)
 //! - First page of the sqlite_master table
+use crate::sqlite::core::error::CorruptPage;
+use crate::sqlite::core::freespace::PageFreeSpace;
 use crate::sqlite::core::header::DatabaseHeader;
+use crate::sqlite::core::page_writer::PageWriter;
+use crate::sqlite::query::cache::ResultCache;
+use crate::sqlite::query::functions::Rng;
+use crate::sqlite::query::group::DEFAULT_GROUP_MEMORY_LIMIT;
+use crate::sqlite::query::schema_cache::SchemaCache;
+#[cfg(feature = "sqlcipher")]
+use crate::sqlite::storage::cipher::{CipherConfig, CipherKey, SqlCipherCodec};
+#[cfg(feature = "zstd")]
+use crate::sqlite::storage::compressed::CompressedFile;
+use crate::sqlite::storage::journal::Journal;
+use crate::sqlite::storage::metrics::Metrics;
+use crate::sqlite::storage::mmap::MmapFile;
+use crate::sqlite::storage::page_cache::CacheConfig;
+use crate::sqlite::storage::pager::{PageVerification, Pager};
 use crate::sqlite::storage::table::TableReader;
-use anyhow::Result;
-use std::fs::File;
-use std::io::prelude::*;
+use crate::sqlite::storage::vfs::{DatabaseFile, MemoryFile, ReaderFile};
+use crate::sqlite::storage::vtab::VirtualTable;
+use crate::sqlite::storage::wal::Wal;
+use anyhow::{anyhow, Result};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek};
 use std::path::PathBuf;
-use tracing::info;
+use tracing::debug;
 
 /// Represents a SQLite database file
 pub struct SQLiteDatabase {
-    /// The underlying database file handle
-    pub file: File,
+    /// Page reader over the underlying database file
+    pub pager: Pager,
     /// Parsed database header
     pub header: DatabaseHeader,
+    /// Virtual tables registered on this connection, keyed by lowercase name
+    virtual_tables: HashMap<String, Box<dyn VirtualTable>>,
+    /// Optional cache of query results
+    pub(crate) result_cache: Option<ResultCache>,
+    /// The schema as read from sqlite_schema, none until first needed
+    pub(crate) schema_cache: Option<SchemaCache>,
+    /// Bytes GROUP BY may keep in memory before spilling to temporary files
+    pub(crate) group_memory_limit: usize,
+    /// Threads a full scan may be split between
+    pub(crate) scan_threads: usize,
+    /// Connection-level counters; I/O counters live in the pager
+    pub(crate) metrics: Metrics,
+    /// Counts for the last query, kept by `traced_query`
+    pub(crate) last_query_stats: Metrics,
+    /// Generator behind random() and randomblob()
+    pub(crate) rng: Rng,
+    /// Set when the open write transaction changes sqlite_schema
+    pub(crate) schema_changed: bool,
 }
 
 /// Contains metadata about a SQLite database
 #[derive(Debug)]
 pub struct SQLiteDatabaseInfo {
     /// Size of each page in bytes
-    page_size: u16,
+    page_size: u32,
     /// Number of tables in the database
     num_tables: u32,
 }
 
 impl SQLiteDatabaseInfo {
     /// Returns the page size in bytes
-    pub fn page_size(&self) -> u16 {
+    pub fn page_size(&self) -> u32 {
         self.page_size
     }
 
@@ -45,22 +83,273 @@ impl SQLiteDatabaseInfo {
     }
 }
 
+/// Path naming a database held in memory rather than in a file, as in SQLite
+pub const MEMORY_PATH: &str = ":memory:";
+
+/// Page size of new databases, as in SQLite
+pub const DEFAULT_PAGE_SIZE: u32 = 4096;
+
+/// How [`SQLiteDatabase::open_with`] opens a database
+#[derive(Debug, Clone, Copy, Default)]
+pub struct OpenConfig {
+    /// Creates the database if the file does not exist, as
+    /// [`SQLiteDatabase::open_create`] does
+    pub create: bool,
+    /// Reads pages through a memory map of the file rather than with read
+    /// calls, falling back to read calls where the file cannot be mapped
+    pub mmap: bool,
+    /// How closely pages are checked as they are read, as
+    /// [`SQLiteDatabase::set_page_verification`] sets it
+    pub verify: PageVerification,
+    /// How many pages are cached and which a full cache drops, as
+    /// [`SQLiteDatabase::set_cache_config`] sets it
+    pub cache: CacheConfig,
+}
+
 impl SQLiteDatabase {
-    /// Opens a SQLite database file at the given path
+    /// Opens a SQLite database file at the given path, or a new in-memory
+    /// database for [`MEMORY_PATH`]
     pub fn open(path: &PathBuf) -> Result<Self> {
-        let mut file = File::open(path)?;
-        let mut header_bytes = vec![0; DatabaseHeader::HEADER_SIZE];
-        file.read_exact(&mut header_bytes)?;
+        Self::open_with(path, OpenConfig::default())
+    }
+
+    /// Opens the database at the given path, creating it if it does not exist
+    ///
+    /// A new or empty file becomes an empty database of one page: the database
+    /// header followed by an empty sqlite_schema table.
+    pub fn open_create(path: &PathBuf) -> Result<Self> {
+        let config = OpenConfig {
+            create: true,
+            ..OpenConfig::default()
+        };
+        Self::open_with(path, config)
+    }
+
+    /// Opens the database at the given path as `config` asks, or a new
+    /// in-memory database for [`MEMORY_PATH`]
+    pub fn open_with(path: &PathBuf, config: OpenConfig) -> Result<Self> {
+        if path.as_os_str() == MEMORY_PATH {
+            return Self::open_memory();
+        }
+        if config.create {
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .open(path)?;
+            if file.len()? == 0 {
+                debug!("Creating database {}", path.display());
+                file.write_all_at(0, &new_database_page(DEFAULT_PAGE_SIZE))?;
+                file.sync()?;
+            }
+        }
+        let (file, writable) = Self::open_file(path, config.mmap)?;
+        let mut db = Self::with_file(file, writable, Some(path))?;
+        db.set_page_verification(config.verify);
+        db.set_cache_config(config.cache);
+        Ok(db)
+    }
+
+    /// Sets how closely pages are checked as they are read: with verification
+    /// on, a page that does not hold together is reported as corrupt, with
+    /// its page number and what is wrong with it, before a query uses it
+    pub fn set_page_verification(&mut self, verification: PageVerification) {
+        self.pager
+            .set_verification(verification, self.header.usable_size());
+    }
+
+    /// Returns how many pages are cached and which a full cache drops
+    pub fn cache_config(&self) -> CacheConfig {
+        self.pager.cache().config()
+    }
+
+    /// Sets how many pages are cached and which a full cache drops, for this
+    /// connection and every connection cloned from the same database
+    ///
+    /// Pages beyond the new bounds are dropped at once. Pinning the schema
+    /// takes effect as the schema is next read, so it is read again.
+    pub fn set_cache_config(&mut self, config: CacheConfig) {
+        let pin_schema = self.cache_config().pin_schema;
+        self.pager.cache().set_config(config);
+        if config.pin_schema && !pin_schema {
+            self.clear_schema_cache();
+        }
+    }
+
+    /// Bounds the page cache as `PRAGMA cache_size` does: a positive size is
+    /// a number of pages and a negative one a number of kibibytes
+    pub fn set_cache_size(&mut self, size: i64) {
+        let mut config = self.cache_config();
+        if size >= 0 {
+            config.max_pages = usize::try_from(size).unwrap_or(usize::MAX);
+            config.max_bytes = None;
+        } else {
+            let bytes = size.unsigned_abs().saturating_mul(1024);
+            config.max_pages = usize::MAX;
+            config.max_bytes = Some(usize::try_from(bytes).unwrap_or(usize::MAX));
+        }
+        self.set_cache_config(config);
+    }
+
+    /// Opens a new, empty database held in memory, which is gone once dropped
+    pub fn open_memory() -> Result<Self> {
+        let file = MemoryFile::new(new_database_page(DEFAULT_PAGE_SIZE));
+        Self::with_file(Box::new(file), true, None)
+    }
+
+    /// Opens a copy of the database image in `bytes`, held in memory, where
+    /// writes change only the copy; no bytes at all make a new, empty database
+    ///
+    /// This suits images that are already in memory, such as one downloaded
+    /// from object storage. A WAL-mode image is refused, as its WAL is
+    /// elsewhere.
+    pub fn open_from_bytes(bytes: &[u8]) -> Result<Self> {
+        let image = match bytes.is_empty() {
+            true => new_database_page(DEFAULT_PAGE_SIZE),
+            false => bytes.to_vec(),
+        };
+        Self::with_file(Box::new(MemoryFile::new(image)), true, None)
+    }
+
+    /// Opens the database image that `reader` reads, read-only, reading pages
+    /// from it as they are needed
+    pub fn open_reader<R: Read + Seek + Send + 'static>(reader: R) -> Result<Self> {
+        Self::with_file(Box::new(ReaderFile::new(reader)), false, None)
+    }
+
+    /// Sets up a database over an opened file; `path` is where a database on
+    /// disk looks for its WAL
+    fn with_file(
+        file: Box<dyn DatabaseFile>,
+        writable: bool,
+        path: Option<&PathBuf>,
+    ) -> Result<Self> {
+        // Before anything is read, as a crash may have left the file with
+        // only part of a commit written
+        let journal = path.map(|path| Journal::new(path));
+        if let Some(journal) = &journal {
+            journal.recover(file.as_ref(), writable)?;
+        }
+        let header_bytes = Pager::read_raw_header(file.as_ref())?;
+
+        let mut header = DatabaseHeader::parse(&header_bytes)?;
+        let mut pager = Pager::new(file, header.page_size);
+        if !writable {
+            pager.set_read_only();
+        }
+
+        // Read/write versions of 2 mark a database in WAL mode, whose writes
+        // go to the WAL even if it does not exist yet
+        if header.read_version == 2 {
+            let path = path.ok_or_else(|| anyhow!("WAL mode needs a database file"))?;
+            pager.set_wal(Wal::open(path, header.page_size)?);
+            // Page 1 may have a newer copy of the header in the WAL
+            header = DatabaseHeader::parse(&pager.read_page(1)?)?;
+        } else if let Some(journal) = journal {
+            pager.set_journal(journal);
+        }
+        if header.is_auto_vacuum() {
+            pager.set_auto_vacuum(header.usable_size());
+        }
+
+        Ok(Self::with_pager(pager, header))
+    }
+
+    /// Makes a connection over `pager` with nothing cached and every setting
+    /// at its default
+    fn with_pager(pager: Pager, header: DatabaseHeader) -> Self {
+        Self {
+            pager,
+            header,
+            virtual_tables: HashMap::new(),
+            result_cache: None,
+            schema_cache: None,
+            group_memory_limit: DEFAULT_GROUP_MEMORY_LIMIT,
+            scan_threads: 1,
+            metrics: Metrics::default(),
+            last_query_stats: Metrics::default(),
+            rng: Rng::from_entropy(),
+            schema_changed: false,
+        }
+    }
+
+    /// Opens another connection to the same database, sharing this one's file
+    /// handle and page cache, to run queries on another thread
+    ///
+    /// The new connection reads pages this one has already read from the
+    /// shared cache, but keeps its own WAL snapshot, transactions, schema
+    /// cache and counters. It starts with no virtual tables and no result
+    /// cache. Commits made through either connection are seen by the other
+    /// from its next statement, as with connections in separate processes.
+    pub fn try_clone(&self) -> Result<Self> {
+        let mut db = Self::with_pager(self.pager.try_clone()?, self.header.clone());
+        db.group_memory_limit = self.group_memory_limit;
+        db.scan_threads = self.scan_threads;
+        Ok(db)
+    }
+
+    /// Opens a SQLCipher-encrypted database file with the given key
+    ///
+    /// Page 1 is decrypted before the database header can be parsed, so the page
+    /// size comes from `config` rather than from the file.
+    #[cfg(feature = "sqlcipher")]
+    pub fn open_encrypted(path: &PathBuf, key: &CipherKey, config: CipherConfig) -> Result<Self> {
+        let (file, writable) = Self::open_file(path, false)?;
+        let salt = Pager::read_raw_header(file.as_ref())?;
+        let codec = SqlCipherCodec::new(key, &salt, config)?;
+
+        let mut pager = Pager::with_codec(file, config.page_size, Some(Box::new(codec)));
+        if !writable {
+            pager.set_read_only();
+        }
+        let header = DatabaseHeader::parse(&pager.read_page(1)?)
+            .map_err(|e| anyhow!("Failed to decrypt database (wrong key?): {}", e))?;
+        if header.page_size != config.page_size {
+            return Err(anyhow!(
+                "Decrypted page size {} does not match cipher page size {}",
+                header.page_size,
+                config.page_size
+            ));
+        }
+        if header.is_auto_vacuum() {
+            pager.set_auto_vacuum(header.usable_size());
+        }
 
-        let header = DatabaseHeader::parse(&header_bytes)?;
+        Ok(Self::with_pager(pager, header))
+    }
+
+    /// Opens the file backing a database, unwrapping compressed page containers
+    /// and mapping it into memory if `mmap` is set, and tells whether it could
+    /// be opened for writing
+    ///
+    /// A file that cannot be written, such as one without write permission, is
+    /// opened read-only.
+    fn open_file(path: &PathBuf, mmap: bool) -> Result<(Box<dyn DatabaseFile>, bool)> {
+        let (file, writable) = match OpenOptions::new().read(true).write(true).open(path) {
+            Ok(file) => (file, true),
+            Err(_) => (File::open(path)?, false),
+        };
+
+        #[cfg(feature = "zstd")]
+        if CompressedFile::is_container(&file)? {
+            debug!("Opening {} as a compressed page container", path.display());
+            return Ok((Box::new(CompressedFile::open(file)?), false));
+        }
+
+        if mmap {
+            match MmapFile::new(file.try_clone()?) {
+                Ok(mapped) => return Ok((Box::new(mapped), writable)),
+                Err(e) => debug!("Reading {} without mmap: {}", path.display(), e),
+            }
+        }
 
-        Ok(Self { file, header })
+        Ok((Box::new(file), writable))
     }
 
     /// Returns basic database information
     pub fn get_info(&mut self) -> Result<SQLiteDatabaseInfo> {
         let num_tables = self.list_tables()?.len() as u32;
-        info!("Found {} tables", num_tables);
+        debug!("Found {} tables", num_tables);
 
         Ok(SQLiteDatabaseInfo {
             page_size: self.header.page_size,
@@ -70,8 +359,132 @@ impl SQLiteDatabase {
 
     /// Lists all user tables in the database
     pub fn list_tables(&mut self) -> Result<Vec<String>> {
-        let page_size = self.header.page_size as usize;
-        let mut reader = TableReader::new(&mut self.file, page_size);
-        reader.list_user_tables()
+        TableReader::new(self.cursor(1)?).list_user_tables()
     }
+
+    /// Registers a virtual table so it can be queried by name
+    pub fn register_virtual_table(
+        &mut self,
+        name: &str,
+        table: Box<dyn VirtualTable>,
+    ) -> Result<()> {
+        let key = name.to_lowercase();
+        if self.virtual_tables.contains_key(&key) {
+            return Err(anyhow!("Virtual table already registered: {}", name));
+        }
+        self.virtual_tables.insert(key, table);
+        Ok(())
+    }
+
+    /// Returns the virtual table registered under the given name, if any
+    pub fn virtual_table(&self, name: &str) -> Option<&dyn VirtualTable> {
+        self.virtual_tables
+            .get(&name.to_lowercase())
+            .map(|table| table.as_ref())
+    }
+
+    /// Runs `write` as one transaction: the pages it writes are committed if it
+    /// succeeds and dropped if it fails
+    ///
+    /// A transaction that changes sqlite_schema also bumps the schema cookie.
+    pub(crate) fn write_transaction<T>(
+        &mut self,
+        write: impl FnOnce(&mut Self) -> Result<T>,
+    ) -> Result<T> {
+        self.schema_changed = false;
+        let result = write(self).and_then(|value| {
+            if self.schema_changed {
+                self.bump_schema_cookie()?;
+            }
+            self.pager.commit()?;
+            Ok(value)
+        });
+        if result.is_err() {
+            self.pager.rollback();
+            if self.schema_changed {
+                self.clear_schema_cache();
+            }
+        }
+        self.schema_changed = false;
+        // The commit bumped the change counter on page 1
+        self.header = DatabaseHeader::parse(&self.pager.read_page(1)?)?;
+        result
+    }
+
+    /// Increments the schema cookie in the header on page 1
+    fn bump_schema_cookie(&mut self) -> Result<()> {
+        let mut page_1 = self.pager.read_page(1)?;
+        let cookie = u32::from_be_bytes([page_1[40], page_1[41], page_1[42], page_1[43]]);
+        page_1[40..44].copy_from_slice(&cookie.wrapping_add(1).to_be_bytes());
+        self.pager.write_page(1, page_1)
+    }
+
+    /// Rereads the database header if other connections have changed the
+    /// database since it was last read, seeing their newest commits in WAL
+    /// mode, and returns true if it did
+    ///
+    /// The header is parsed at open and kept, and every statement starts here.
+    /// Every commit bumps the file change counter, so only the counter is read
+    /// unless it has moved; in WAL mode, where SQLite may leave the counter
+    /// alone, new WAL frames count as a change too. A change drops the page
+    /// cache, and the schema cache is dropped when the schema cookie moves.
+    ///
+    /// Cached results are keyed by the change counter, so they are dropped
+    /// along with the schema cache, and whenever the WAL has changed, since
+    /// the counter may not have.
+    pub fn refresh_header(&mut self) -> Result<bool> {
+        let wal_changed = self.pager.refresh()?;
+        if !wal_changed && self.pager.change_counter()? == self.header.file_change_counter {
+            return Ok(false);
+        }
+        self.pager.clear_cache();
+        if wal_changed {
+            if let Some(cache) = self.result_cache.as_mut() {
+                cache.clear();
+            }
+        }
+        let header = DatabaseHeader::parse(&self.pager.read_page(1)?)?;
+        if header.schema_cookie != self.header.schema_cookie {
+            debug!(
+                "Schema cookie changed from {} to {}",
+                self.header.schema_cookie, header.schema_cookie
+            );
+            if let Some(cache) = self.result_cache.as_mut() {
+                cache.clear();
+            }
+            self.clear_schema_cache();
+        }
+        self.header = header;
+        Ok(true)
+    }
+
+    /// Returns the number of usable bytes per page (page size minus reserved space)
+    pub(crate) fn usable_size(&self) -> Result<usize> {
+        let usable_size = self.header.usable_size();
+        // SQLite requires at least 480 usable bytes, which the payload math relies on
+        if usable_size < 480 {
+            return Err(anyhow!("Unsupported usable page size: {}", usable_size));
+        }
+        Ok(usable_size)
+    }
+
+    /// Analyzes the free space of the B-tree page with the given number
+    pub fn page_free_space(&mut self, page_num: u32) -> Result<PageFreeSpace> {
+        let page = self.pager.read_btree_page(page_num)?;
+        PageFreeSpace::analyze(page.data(), page.header_offset()).on_page(page_num)
+    }
+}
+
+/// Builds page 1 of a new database: the database header followed by an empty
+/// sqlite_schema leaf
+fn new_database_page(page_size: u32) -> Vec<u8> {
+    let mut page = vec![0; page_size as usize];
+    page[..DatabaseHeader::HEADER_SIZE].copy_from_slice(&DatabaseHeader::new_database(page_size));
+    PageWriter::init(
+        &mut page,
+        DatabaseHeader::HEADER_SIZE,
+        page_size as usize,
+        13,
+    );
+    page
 }
@@ -0,0 +1,260 @@
+//! Freelist
+//!
+//! Pages that are no longer in use are kept on the freelist until they are
+//! reused or the file is vacuumed. The freelist is a linked list of trunk
+//! pages, the first of which is named in bytes 32-35 of the database header;
+//! bytes 36-39 hold the total number of pages on the freelist, trunks
+//! included.
+//!
+//! ## Freelist Trunk Page Format
+//!
+//! - Bytes 0-3: Page number of the next trunk page (0 ends the list)
+//! - Bytes 4-7: Number of leaf page numbers that follow
+//! - Bytes 8-: Leaf page numbers, 4 bytes each
+//!
+//! Leaf pages hold no information; their content is undefined.
+//!
+//! ## Freeing Pages
+//!
+//! A freed page is added as a leaf of the first trunk while it has room, and
+//! otherwise becomes the new first trunk. Like SQLite, a trunk is filled only
+//! to `usable_size / 4 - 8` leaves, since versions before 3.6.0 rejected
+//! fuller trunks as corrupt.
+//!
+//! ## Allocating Pages
+//!
+//! A new page is taken from the first trunk: its first leaf, whose slot the
+//! last leaf moves into, or the trunk itself once it lists no leaves. With the
+//! freelist empty, the file grows by a page, skipping the lock-byte page.
+
+use crate::sqlite::core::checked;
+use crate::sqlite::core::error::{corrupt, CorruptPage};
+use crate::sqlite::core::header::DatabaseHeader;
+use crate::sqlite::storage::db::SQLiteDatabase;
+use crate::sqlite::storage::pager::{lock_byte_page, Pager};
+use anyhow::{anyhow, Result};
+
+/// One trunk page of the freelist and the leaf pages it lists
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FreelistTrunk {
+    /// Page number of the trunk page
+    pub page: u32,
+    /// Free pages listed on the trunk, in the order they are stored
+    pub leaves: Vec<u32>,
+}
+
+/// The freelist of a database
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Freelist {
+    /// Trunk pages in list order
+    pub trunks: Vec<FreelistTrunk>,
+    /// Size of each page in bytes
+    page_size: u32,
+}
+
+impl Freelist {
+    /// Walks the freelist starting at `first_trunk`
+    ///
+    /// The walk fails if a trunk lists more leaves than fit on a page, if a
+    /// page number is outside the database, or if the list has more trunks
+    /// than the database has pages, which means it loops.
+    pub fn read(pager: &mut Pager, first_trunk: u32, usable_size: usize) -> Result<Self> {
+        let page_count = pager.page_count()?;
+        let max_leaves = (usable_size / 4 - 2) as u32;
+        let mut trunks = Vec::new();
+        let mut trunk = first_trunk;
+
+        while trunk != 0 {
+            if trunk > page_count {
+                return Err(corrupt(
+                    0,
+                    format!(
+                        "freelist trunk page {} is past the end of a {}-page database",
+                        trunk, page_count
+                    ),
+                ));
+            }
+            if trunks.len() as u32 >= page_count {
+                return Err(corrupt(0, "freelist trunk list loops")).on_page(trunk);
+            }
+            let page = pager.read_page(trunk)?;
+            let next = checked::be_u32(&page, 0).on_page(trunk)?;
+            let count = checked::be_u32(&page, 4).on_page(trunk)?;
+            if count > max_leaves {
+                return Err(corrupt(4, format!("freelist trunk lists {} pages", count)))
+                    .on_page(trunk);
+            }
+            let mut leaves = Vec::with_capacity(count as usize);
+            for i in 0..count as usize {
+                let leaf = checked::be_u32(&page, 8 + i * 4).on_page(trunk)?;
+                if leaf == 0 || leaf > page_count {
+                    return Err(corrupt(
+                        8 + i * 4,
+                        format!("freelist leaf page {} is out of range", leaf),
+                    ))
+                    .on_page(trunk);
+                }
+                leaves.push(leaf);
+            }
+            trunks.push(FreelistTrunk {
+                page: trunk,
+                leaves,
+            });
+            trunk = next;
+        }
+
+        Ok(Self {
+            trunks,
+            page_size: pager.page_size(),
+        })
+    }
+
+    /// Returns the trunk page numbers, in list order
+    pub fn trunk_pages(&self) -> impl Iterator<Item = u32> + '_ {
+        self.trunks.iter().map(|trunk| trunk.page)
+    }
+
+    /// Returns the leaf page numbers, trunk by trunk
+    pub fn leaf_pages(&self) -> impl Iterator<Item = u32> + '_ {
+        self.trunks
+            .iter()
+            .flat_map(|trunk| trunk.leaves.iter().copied())
+    }
+
+    /// Returns every free page number, trunks and leaves, in ascending order
+    pub fn free_pages(&self) -> Vec<u32> {
+        let mut pages: Vec<u32> = self.trunk_pages().chain(self.leaf_pages()).collect();
+        pages.sort_unstable();
+        pages
+    }
+
+    /// Returns the number of pages on the freelist, trunks included
+    pub fn page_count(&self) -> u32 {
+        self.trunks
+            .iter()
+            .map(|trunk| 1 + trunk.leaves.len() as u32)
+            .sum()
+    }
+
+    /// Returns the bytes taken up by free pages
+    pub fn free_bytes(&self) -> u64 {
+        self.page_count() as u64 * self.page_size as u64
+    }
+}
+
+impl SQLiteDatabase {
+    /// Reads the freelist, checking its length against the database header
+    pub fn freelist(&mut self) -> Result<Freelist> {
+        let usable_size = self.usable_size()?;
+        let freelist = Freelist::read(
+            &mut self.pager,
+            self.header.first_freelist_trunk,
+            usable_size,
+        )?;
+        if freelist.page_count() != self.header.total_freelist_pages {
+            return Err(corrupt(
+                36,
+                format!(
+                    "header counts {} freelist pages but the freelist has {}",
+                    self.header.total_freelist_pages,
+                    freelist.page_count()
+                ),
+            ))
+            .on_page(1);
+        }
+        Ok(freelist)
+    }
+
+    /// Puts a page that is no longer used on the freelist
+    ///
+    /// The header fields on page 1 are changed through the pager, so they
+    /// reach `self.header` when the transaction ends.
+    pub(crate) fn free_page(&mut self, page_num: u32) -> Result<()> {
+        if self.header.is_auto_vacuum() {
+            return Err(anyhow!(
+                "freeing pages of auto-vacuum databases is not supported"
+            ));
+        }
+        let usable_size = self.usable_size()?;
+        let mut page_1 = self.pager.read_page(1)?;
+        let header = DatabaseHeader::parse(&page_1)?;
+        let first_trunk = header.first_freelist_trunk;
+
+        let mut added = false;
+        if first_trunk != 0 {
+            let mut trunk = self.pager.read_page(first_trunk)?;
+            let count = checked::be_u32(&trunk, 4).on_page(first_trunk)? as usize;
+            if count < usable_size / 4 - 8 {
+                let offset = 8 + count * 4;
+                trunk[offset..offset + 4].copy_from_slice(&page_num.to_be_bytes());
+                trunk[4..8].copy_from_slice(&(count as u32 + 1).to_be_bytes());
+                self.pager.write_page(first_trunk, trunk)?;
+                added = true;
+            }
+        }
+        if !added {
+            // The rest of the page is left as it was, as SQLite does
+            let mut trunk = self.pager.read_page(page_num)?;
+            trunk[0..4].copy_from_slice(&first_trunk.to_be_bytes());
+            trunk[4..8].fill(0);
+            self.pager.write_page(page_num, trunk)?;
+            page_1[32..36].copy_from_slice(&page_num.to_be_bytes());
+        }
+        let total = header.total_freelist_pages + 1;
+        page_1[36..40].copy_from_slice(&total.to_be_bytes());
+        self.pager.write_page(1, page_1)
+    }
+
+    /// Takes a page for a B-tree to use, from the freelist if it has one
+    ///
+    /// The page's content is undefined; a page added to the end of the file
+    /// starts out zeroed.
+    pub(crate) fn allocate_page(&mut self) -> Result<u32> {
+        if self.header.is_auto_vacuum() {
+            return Err(anyhow!(
+                "allocating pages in auto-vacuum databases is not supported"
+            ));
+        }
+        let page_count = self.pager.page_count()?;
+        let mut page_1 = self.pager.read_page(1)?;
+        let header = DatabaseHeader::parse(&page_1)?;
+        let first_trunk = header.first_freelist_trunk;
+
+        if first_trunk == 0 {
+            let mut page_num = page_count + 1;
+            if page_num == lock_byte_page(self.pager.page_size()) {
+                page_num += 1;
+            }
+            self.pager
+                .write_page(page_num, vec![0; self.pager.page_size() as usize])?;
+            return Ok(page_num);
+        }
+
+        let mut trunk = self.pager.read_page(first_trunk)?;
+        let count = checked::be_u32(&trunk, 4).on_page(first_trunk)? as usize;
+        let page_num = if count == 0 {
+            // The trunk itself is taken, and the next trunk comes first
+            page_1[32..36].copy_from_slice(&trunk[0..4]);
+            first_trunk
+        } else {
+            let leaf = checked::be_u32(&trunk, 8).on_page(first_trunk)?;
+            if leaf < 2 || leaf > page_count {
+                return Err(corrupt(
+                    8,
+                    format!("freelist leaf page {} is out of range", leaf),
+                ))
+                .on_page(first_trunk);
+            }
+            let last = 8 + (count - 1) * 4;
+            checked::slice(&trunk, last, 4).on_page(first_trunk)?;
+            trunk.copy_within(last..last + 4, 8);
+            trunk[4..8].copy_from_slice(&(count as u32 - 1).to_be_bytes());
+            self.pager.write_page(first_trunk, trunk)?;
+            leaf
+        };
+        let total = header.total_freelist_pages.saturating_sub(1);
+        page_1[36..40].copy_from_slice(&total.to_be_bytes());
+        self.pager.write_page(1, page_1)?;
+        Ok(page_num)
+    }
+}
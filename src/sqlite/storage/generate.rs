@@ -0,0 +1,484 @@
+//! Synthetic Database Generator
+//!
+//! Writes a new database file filled with pseudo-random rows, for producing
+//! reproducible fixtures without the sqlite3 tool. The same options and seed
+//! always produce a byte-identical file.
+//!
+//! Each table is bulk-loaded bottom-up: rows are packed into leaf pages in rowid
+//! order, payloads too large for a page spill onto overflow chains, and interior
+//! levels are added until a single root page remains. The schema table is built
+//! the same way with page 1 as its root. Pages are laid out by the same
+//! [`PageWriter`] the write path uses.
+//!
+//! ## Options
+//!
+//! Options are given as `key=value` words, for example
+//! `.gendb tables=2 rows=5000 columns=integer,text,blob text-size=6000 depth=3`.
+//!
+//! - `tables`: number of tables, named t1, t2, ... (default 1)
+//! - `rows`: rows per table (default 100)
+//! - `columns`: comma-separated column types from integer, real, text and blob
+//!   (default integer,text); columns are named c1, c2, ...
+//! - `text-size`: length of every text and blob value; values larger than about a
+//!   page are stored with overflow pages (default 16)
+//! - `depth`: limits cells per page so table B-trees are about this many levels
+//!   deep; pages that fill up first add levels (default: pack pages full)
+//! - `page-size`: power of two from 512 to 65536 (default 4096)
+//! - `seed`: random seed (default 0)
+
+use crate::sqlite::core::btree::{BTreePage, PayloadLayout};
+use crate::sqlite::core::header::DatabaseHeader;
+use crate::sqlite::core::page_writer::PageWriter;
+use crate::sqlite::core::record::encode_record;
+use crate::sqlite::core::value::Value;
+use crate::sqlite::core::varint::encode_varint;
+use crate::sqlite::query::functions::Rng;
+use crate::sqlite::storage::pager::lock_byte_page;
+use anyhow::{anyhow, Result};
+use std::fmt::Display;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::Path;
+use std::str::FromStr;
+
+/// Type of a generated column
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColumnKind {
+    Integer,
+    Real,
+    Text,
+    Blob,
+}
+
+impl ColumnKind {
+    fn name(self) -> &'static str {
+        match self {
+            ColumnKind::Integer => "integer",
+            ColumnKind::Real => "real",
+            ColumnKind::Text => "text",
+            ColumnKind::Blob => "blob",
+        }
+    }
+
+    /// Produces one random value of this kind
+    fn generate(self, rng: &mut Rng, text_size: usize) -> Value {
+        match self {
+            // Vary the magnitude so every integer serial type appears
+            ColumnKind::Integer => Value::Integer(rng.next_u64() as i64 >> (rng.next_u64() % 64)),
+            ColumnKind::Real => Value::Real((rng.next_u64() >> 11) as f64 / (1u64 << 40) as f64),
+            ColumnKind::Text => Value::Text(
+                (0..text_size)
+                    .map(|_| (b'a' + (rng.next_u64() % 26) as u8) as char)
+                    .collect(),
+            ),
+            ColumnKind::Blob => {
+                let mut bytes = vec![0; text_size];
+                rng.fill_bytes(&mut bytes);
+                Value::Blob(bytes)
+            }
+        }
+    }
+}
+
+/// What to generate
+#[derive(Debug, Clone, PartialEq)]
+pub struct GenerateOptions {
+    pub tables: usize,
+    pub rows: usize,
+    pub columns: Vec<ColumnKind>,
+    pub text_size: usize,
+    pub depth: Option<u32>,
+    pub page_size: usize,
+    pub seed: u64,
+}
+
+impl Default for GenerateOptions {
+    fn default() -> Self {
+        Self {
+            tables: 1,
+            rows: 100,
+            columns: vec![ColumnKind::Integer, ColumnKind::Text],
+            text_size: 16,
+            depth: None,
+            page_size: 4096,
+            seed: 0,
+        }
+    }
+}
+
+impl FromStr for GenerateOptions {
+    type Err = String;
+
+    /// Parses whitespace-separated `key=value` words
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        fn number<T: FromStr>(key: &str, value: &str) -> Result<T, String> {
+            value
+                .parse()
+                .map_err(|_| format!("Invalid value for {}: {}", key, value))
+        }
+
+        let mut options = GenerateOptions::default();
+        for word in s.split_whitespace() {
+            let (key, value) = word
+                .split_once('=')
+                .ok_or_else(|| format!("Expected key=value, got: {}", word))?;
+            match key {
+                "tables" => options.tables = number(key, value)?,
+                "rows" => options.rows = number(key, value)?,
+                "text-size" => options.text_size = number(key, value)?,
+                "depth" => options.depth = Some(number(key, value)?),
+                "page-size" => options.page_size = number(key, value)?,
+                "seed" => options.seed = number(key, value)?,
+                "columns" => {
+                    options.columns = value
+                        .split(',')
+                        .map(|kind| match kind.to_lowercase().as_str() {
+                            "integer" => Ok(ColumnKind::Integer),
+                            "real" => Ok(ColumnKind::Real),
+                            "text" => Ok(ColumnKind::Text),
+                            "blob" => Ok(ColumnKind::Blob),
+                            _ => Err(format!("Unknown column type: {}", kind)),
+                        })
+                        .collect::<Result<_, _>>()?
+                }
+                _ => return Err(format!("Unknown option: {}", key)),
+            }
+        }
+
+        if !options.page_size.is_power_of_two() || !(512..=65536).contains(&options.page_size) {
+            return Err(format!("Invalid page size: {}", options.page_size));
+        }
+        if options.columns.is_empty() {
+            return Err("At least one column is required".to_string());
+        }
+        if options.depth == Some(0) {
+            return Err("Depth must be at least 1".to_string());
+        }
+        Ok(options)
+    }
+}
+
+impl Display for GenerateOptions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let columns: Vec<_> = self.columns.iter().map(|kind| kind.name()).collect();
+        write!(
+            f,
+            "tables={} rows={} columns={} text-size={}",
+            self.tables,
+            self.rows,
+            columns.join(","),
+            self.text_size
+        )?;
+        if let Some(depth) = self.depth {
+            write!(f, " depth={}", depth)?;
+        }
+        write!(f, " page-size={} seed={}", self.page_size, self.seed)
+    }
+}
+
+/// Summary of a generated database
+#[derive(Debug)]
+pub struct GenerateSummary {
+    /// Number of pages in the file
+    pub page_count: u32,
+    /// Rows written across all tables
+    pub rows: usize,
+}
+
+/// Writes a new database at `path`, which must not exist yet
+pub fn generate(path: &Path, options: &GenerateOptions) -> Result<GenerateSummary> {
+    let mut builder = FileBuilder::new(options.page_size);
+    let mut rng = Rng::with_seed(options.seed);
+    let fanout = options
+        .depth
+        .map(|depth| fanout_for_depth(options.rows, depth));
+
+    let column_defs: Vec<String> = options
+        .columns
+        .iter()
+        .enumerate()
+        .map(|(i, kind)| format!("c{} {}", i + 1, kind.name().to_uppercase()))
+        .collect();
+
+    let mut schema_rows = Vec::new();
+    for table in 1..=options.tables {
+        let rows = (1..=options.rows as i64).map(|rowid| {
+            let values: Vec<Value> = options
+                .columns
+                .iter()
+                .map(|kind| kind.generate(&mut rng, options.text_size))
+                .collect();
+            (rowid, encode_record(&values))
+        });
+        let root = builder.build_table(rows, fanout, None)?;
+
+        let name = format!("t{}", table);
+        let sql = format!("CREATE TABLE {}({})", name, column_defs.join(", "));
+        let record = encode_record(&[
+            Value::Text("table".to_string()),
+            Value::Text(name.clone()),
+            Value::Text(name),
+            Value::Integer(root as i64),
+            Value::Text(sql),
+        ]);
+        schema_rows.push((table as i64, record));
+    }
+    builder.build_table(schema_rows.into_iter(), None, Some(1))?;
+
+    let page_count = builder.pages.len() as u32;
+    builder.write_header();
+
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(path)
+        .map_err(|e| anyhow!("Cannot create {}: {}", path.display(), e))?;
+    for page in &builder.pages {
+        file.write_all(page)?;
+    }
+
+    Ok(GenerateSummary {
+        page_count,
+        rows: options.tables * options.rows,
+    })
+}
+
+/// Smallest number of cells per page that fits `rows` rows in `depth` levels
+fn fanout_for_depth(rows: usize, depth: u32) -> usize {
+    let mut fanout = 2usize;
+    while fanout.saturating_pow(depth) < rows {
+        fanout += 1;
+    }
+    fanout
+}
+
+/// One page being assembled: its cells, the largest rowid beneath it, and for
+/// interior pages the right-most child
+struct Node {
+    cells: Vec<Vec<u8>>,
+    max_rowid: i64,
+    right_child: Option<u32>,
+}
+
+impl Node {
+    fn leaf() -> Self {
+        Self {
+            cells: Vec::new(),
+            max_rowid: 0,
+            right_child: None,
+        }
+    }
+
+    /// Bytes the node's cells and cell pointers occupy
+    fn size(&self) -> usize {
+        self.cells.iter().map(|cell| cell.len() + 2).sum()
+    }
+
+    /// Bytes of the node's B-tree page header
+    fn header_size(&self) -> usize {
+        match self.right_child {
+            Some(_) => 12,
+            None => 8,
+        }
+    }
+}
+
+/// Pages of the file being generated, held in memory until written
+struct FileBuilder {
+    page_size: usize,
+    /// Page N is `pages[N - 1]`; page 1 exists from the start
+    pages: Vec<Vec<u8>>,
+}
+
+impl FileBuilder {
+    fn new(page_size: usize) -> Self {
+        Self {
+            page_size,
+            pages: vec![vec![0; page_size]],
+        }
+    }
+
+    /// Adds a page, skipping the lock-byte page of files over 1GiB
+    fn allocate(&mut self) -> u32 {
+        if self.pages.len() as u32 + 1 == lock_byte_page(self.page_size as u32) {
+            self.pages.push(vec![0; self.page_size]);
+        }
+        self.pages.push(vec![0; self.page_size]);
+        self.pages.len() as u32
+    }
+
+    /// Bytes available for cells on a page whose B-tree header is `header_size`
+    fn capacity(&self, page_num: u32, header_size: usize) -> usize {
+        self.page_size - BTreePage::header_offset_for(page_num) - header_size
+    }
+
+    /// Bulk-loads a table B-tree from rows in rowid order, returning its root page
+    ///
+    /// `fanout` caps the cells per leaf page and children per interior page.
+    /// The root is written to `root` if given, otherwise to a new page.
+    fn build_table(
+        &mut self,
+        rows: impl Iterator<Item = (i64, Vec<u8>)>,
+        fanout: Option<usize>,
+        root: Option<u32>,
+    ) -> Result<u32> {
+        let fanout = fanout.unwrap_or(usize::MAX);
+        let leaf_capacity = self.capacity(0, 8);
+
+        let mut leaves = Vec::new();
+        let mut node = Node::leaf();
+        for (rowid, record) in rows {
+            let cell = self.leaf_cell(rowid, &record);
+            if !node.cells.is_empty()
+                && (node.cells.len() >= fanout || node.size() + cell.len() + 2 > leaf_capacity)
+            {
+                leaves.push(std::mem::replace(&mut node, Node::leaf()));
+            }
+            node.cells.push(cell);
+            node.max_rowid = rowid;
+        }
+        leaves.push(node);
+
+        let mut level = leaves;
+        loop {
+            if level.len() == 1 {
+                let node = level.pop().expect("one node");
+                if node.size() <= self.capacity(root.unwrap_or(0), node.header_size()) {
+                    let page = root.unwrap_or_else(|| self.allocate());
+                    self.write_page(page, &node)?;
+                    return Ok(page);
+                }
+                // Too big for page 1 once the file header is accounted for: move
+                // it down and give the root no cells, only a right child, as
+                // SQLite itself does when the root overflows
+                let child = self.allocate();
+                self.write_page(child, &node)?;
+                level.push(Node {
+                    cells: Vec::new(),
+                    max_rowid: node.max_rowid,
+                    right_child: Some(child),
+                });
+                continue;
+            }
+
+            let mut children = Vec::new();
+            for node in level {
+                let page = self.allocate();
+                self.write_page(page, &node)?;
+                children.push((page, node.max_rowid));
+            }
+            level = self.interior_level(children, fanout);
+        }
+    }
+
+    /// Groups child pages under interior nodes
+    fn interior_level(&self, children: Vec<(u32, i64)>, fanout: usize) -> Vec<Node> {
+        // Leave room for one extra cell so a lone trailing child can be merged
+        // into the previous node
+        let capacity = self.capacity(0, 12) - interior_cell(0, i64::MAX).len() - 2;
+        let mut nodes: Vec<Node> = Vec::new();
+        let mut group: Vec<(u32, i64)> = Vec::new();
+        let mut size = 0;
+
+        for child in children {
+            if let Some(&(page, max_rowid)) = group.last() {
+                let cell_size = interior_cell(page, max_rowid).len() + 2;
+                if group.len() >= fanout || size + cell_size > capacity {
+                    nodes.push(interior_node(std::mem::take(&mut group)));
+                    size = 0;
+                } else {
+                    size += cell_size;
+                }
+            }
+            group.push(child);
+        }
+
+        match nodes.last_mut() {
+            // An interior page needs at least one cell besides its right child
+            Some(last) if group.len() == 1 => {
+                let right = last.right_child.expect("interior node");
+                last.cells.push(interior_cell(right, last.max_rowid));
+                last.right_child = Some(group[0].0);
+                last.max_rowid = group[0].1;
+            }
+            _ => nodes.push(interior_node(group)),
+        }
+        nodes
+    }
+
+    /// Builds a leaf cell, writing any overflow pages it needs
+    fn leaf_cell(&mut self, rowid: i64, record: &[u8]) -> Vec<u8> {
+        let local = PayloadLayout::new(self.page_size, 13).local_size(record.len());
+        let mut cell = Vec::with_capacity(local + 22);
+        encode_varint(record.len() as u64, &mut cell);
+        encode_varint(rowid as u64, &mut cell);
+        cell.extend_from_slice(&record[..local]);
+        if local < record.len() {
+            let first = self.write_overflow(&record[local..]);
+            cell.extend_from_slice(&first.to_be_bytes());
+        }
+        cell
+    }
+
+    /// Writes `data` to a chain of overflow pages, returning the first page
+    fn write_overflow(&mut self, data: &[u8]) -> u32 {
+        let chunks: Vec<&[u8]> = data.chunks(self.page_size - 4).collect();
+        let pages: Vec<u32> = chunks.iter().map(|_| self.allocate()).collect();
+        for (i, chunk) in chunks.iter().enumerate() {
+            let next = pages.get(i + 1).copied().unwrap_or(0);
+            let bytes = &mut self.pages[pages[i] as usize - 1];
+            bytes[..4].copy_from_slice(&next.to_be_bytes());
+            bytes[4..4 + chunk.len()].copy_from_slice(chunk);
+        }
+        pages[0]
+    }
+
+    /// Lays out a node as a table B-tree page with a [`PageWriter`], which
+    /// packs the cells against the end of the page in pointer order
+    fn write_page(&mut self, page_num: u32, node: &Node) -> Result<()> {
+        let page_size = self.page_size;
+        let page = &mut self.pages[page_num as usize - 1];
+        let page_type = if node.right_child.is_some() { 5 } else { 13 };
+        let header_offset = BTreePage::header_offset_for(page_num);
+        let mut writer = PageWriter::init(page, header_offset, page_size, page_type);
+        for (i, cell) in node.cells.iter().enumerate() {
+            if !writer.insert_cell(i, cell)? {
+                return Err(anyhow!("cell {} does not fit on page {}", i, page_num));
+            }
+        }
+        if let Some(right) = node.right_child {
+            writer.set_child(node.cells.len(), right)?;
+        }
+        Ok(())
+    }
+
+    /// Fills in the 100-byte file header on page 1
+    fn write_header(&mut self) {
+        let page_count = self.pages.len() as u32;
+        let header = &mut self.pages[0];
+        header[..DatabaseHeader::HEADER_SIZE]
+            .copy_from_slice(&DatabaseHeader::new_database(self.page_size as u32));
+        header[28..32].copy_from_slice(&page_count.to_be_bytes());
+        header[40..44].copy_from_slice(&1u32.to_be_bytes()); // schema cookie
+    }
+}
+
+/// Builds an interior cell: the left child's page and the largest rowid in it
+fn interior_cell(left_child: u32, max_rowid: i64) -> Vec<u8> {
+    let mut cell = left_child.to_be_bytes().to_vec();
+    encode_varint(max_rowid as u64, &mut cell);
+    cell
+}
+
+/// Builds an interior node over `children`, the last becoming the right child
+fn interior_node(mut children: Vec<(u32, i64)>) -> Node {
+    let (right, max_rowid) = children.pop().expect("at least one child");
+    Node {
+        cells: children
+            .into_iter()
+            .map(|(page, max_rowid)| interior_cell(page, max_rowid))
+            .collect(),
+        max_rowid,
+        right_child: Some(right),
+    }
+}
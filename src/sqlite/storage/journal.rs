@@ -0,0 +1,268 @@
+//! Rollback Journal
+//!
+//! Outside WAL mode a commit writes pages over their old copies in the
+//! database file. So that a crash part way through cannot leave a mix of old
+//! and new pages, the pages about to be overwritten are first saved, as they
+//! were, in a rollback journal next to the database, the `-journal` file, in
+//! SQLite's format. The commit is done once the journal is deleted.
+//!
+//! A journal left behind by a commit that never finished is hot: the next
+//! connection to open the database copies its pages back into the file and
+//! cuts the file back to its old size before reading anything, as SQLite
+//! does. A journal is only hot while no connection holds RESERVED, which a
+//! writer holds for as long as its journal exists.
+//!
+//! ## Journal Header Format (big-endian, padded to one sector)
+//!
+//! - Bytes 0-7: Magic number (d9 d5 05 f9 20 a1 63 d7)
+//! - Bytes 8-11: Number of page records
+//! - Bytes 12-15: Nonce for the record checksums
+//! - Bytes 16-19: Size of the database in pages before the commit
+//! - Bytes 20-23: Sector size
+//! - Bytes 24-27: Page size
+//!
+//! ## Page Record Format
+//!
+//! Each record holds a page number, the page as it was, and a checksum: the
+//! nonce plus every 200th byte of the page, counting down from 200 bytes
+//! before its end. Play-back stops at the first record that fails its
+//! checksum, which was not completely written.
+//!
+//! SQLite starts a new segment of the journal, with a header of its own at
+//! the next sector boundary, each time a commit has to write pages out before
+//! it is done; this crate writes one segment per commit.
+
+use crate::sqlite::query::functions::Rng;
+use crate::sqlite::storage::vfs::{DatabaseFile, FileLock};
+use anyhow::{anyhow, Result};
+use std::fs::{self, OpenOptions};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use tracing::debug;
+
+/// Bytes every journal starts with
+const MAGIC: [u8; 8] = [0xd9, 0xd5, 0x05, 0xf9, 0x20, 0xa1, 0x63, 0xd7];
+
+/// Size of the header written, padded to the sector size SQLite assumes
+const SECTOR_SIZE: u32 = 512;
+
+/// Record count marking a journal whose records run to its end
+const COUNT_TO_END: u32 = 0xffff_ffff;
+
+/// Reads a big-endian u32 at `offset`
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes([
+        bytes[offset],
+        bytes[offset + 1],
+        bytes[offset + 2],
+        bytes[offset + 3],
+    ])
+}
+
+/// Checksum of a page record, as SQLite computes it
+fn record_checksum(nonce: u32, page: &[u8]) -> u32 {
+    (1..)
+        .map(|step| page.len() as isize - 200 * step)
+        .take_while(|&i| i > 0)
+        .fold(nonce, |sum, i| sum.wrapping_add(page[i as usize] as u32))
+}
+
+/// The parts of a journal header play-back needs
+#[derive(Debug, Clone, Copy)]
+struct JournalHeader {
+    records: u32,
+    nonce: u32,
+    db_pages: u32,
+    sector_size: u32,
+    page_size: u32,
+}
+
+impl JournalHeader {
+    /// Parses a header, returning none if `bytes` do not start with a whole
+    /// one, as for a journal whose header was never written
+    fn parse(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < 28 || bytes[..8] != MAGIC {
+            return None;
+        }
+        let header = Self {
+            records: read_u32(bytes, 8),
+            nonce: read_u32(bytes, 12),
+            db_pages: read_u32(bytes, 16),
+            sector_size: read_u32(bytes, 20),
+            page_size: read_u32(bytes, 24),
+        };
+        let valid_size = |size: u32| size.is_power_of_two() && (512..=65536).contains(&size);
+        (valid_size(header.sector_size) && valid_size(header.page_size)).then_some(header)
+    }
+}
+
+/// The rollback journal of a database file
+#[derive(Debug, Clone)]
+pub struct Journal {
+    path: PathBuf,
+}
+
+impl Journal {
+    /// Returns the journal belonging to `database`, which need not exist
+    pub fn new(database: &Path) -> Self {
+        let mut path = database.as_os_str().to_owned();
+        path.push("-journal");
+        Self {
+            path: PathBuf::from(path),
+        }
+    }
+
+    /// Returns the path of the journal file
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Saves the pages of `file` about to be overwritten in a new journal and
+    /// flushes it to stable storage
+    ///
+    /// Pages past the end of the file have nothing to save; play-back drops
+    /// them by cutting the file back to its size. The records are flushed
+    /// before the header that makes them count, so that a crash in between
+    /// leaves a journal that is not hot.
+    pub fn write(
+        &self,
+        file: &dyn DatabaseFile,
+        page_size: u32,
+        pages: impl Iterator<Item = u32>,
+    ) -> Result<()> {
+        let db_pages = (file.len()? / page_size as u64) as u32;
+        let nonce = Rng::from_entropy().next_u64() as u32;
+        let mut records = Vec::new();
+        let mut page = vec![0; page_size as usize];
+        let mut count = 0u32;
+        for page_num in pages.filter(|&page_num| page_num <= db_pages) {
+            file.read_exact_at((page_num - 1) as u64 * page_size as u64, &mut page)?;
+            records.extend_from_slice(&page_num.to_be_bytes());
+            records.extend_from_slice(&page);
+            records.extend_from_slice(&record_checksum(nonce, &page).to_be_bytes());
+            count += 1;
+        }
+
+        let mut header = vec![0; SECTOR_SIZE as usize];
+        header[..8].copy_from_slice(&MAGIC);
+        header[8..12].copy_from_slice(&count.to_be_bytes());
+        header[12..16].copy_from_slice(&nonce.to_be_bytes());
+        header[16..20].copy_from_slice(&db_pages.to_be_bytes());
+        header[20..24].copy_from_slice(&SECTOR_SIZE.to_be_bytes());
+        header[24..28].copy_from_slice(&page_size.to_be_bytes());
+
+        let journal = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&self.path)
+            .map_err(|e| anyhow!("cannot create {}: {}", self.path.display(), e))?;
+        journal.write_all_at(SECTOR_SIZE as u64, &records)?;
+        DatabaseFile::sync(&journal)?;
+        journal.write_all_at(0, &header)?;
+        DatabaseFile::sync(&journal)?;
+        debug!("Saved {} pages in {}", count, self.path.display());
+        Ok(())
+    }
+
+    /// Deletes the journal, which commits the pages written since it was
+    /// saved
+    pub fn delete(&self) -> Result<()> {
+        match fs::remove_file(&self.path) {
+            Err(e) if e.kind() != ErrorKind::NotFound => {
+                Err(anyhow!("cannot delete {}: {}", self.path.display(), e))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Copies the pages saved in the journal back into `file`, cuts the file
+    /// back to its size before the commit, and deletes the journal
+    ///
+    /// A journal without a whole header has nothing to play back and is only
+    /// deleted.
+    pub fn play_back(&self, file: &dyn DatabaseFile) -> Result<()> {
+        let bytes = match fs::read(&self.path) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(anyhow!("cannot read {}: {}", self.path.display(), e)),
+        };
+        // A commit that spills pages before it is done syncs the journal and
+        // starts a new segment, with a header of its own, at the next sector
+        let mut db_size = None;
+        let mut restored = 0;
+        let mut offset = 0;
+        'segments: while let Some(header) = bytes.get(offset..).and_then(JournalHeader::parse) {
+            let page_size = header.page_size as usize;
+            let sector_size = header.sector_size as usize;
+            let start = offset + sector_size;
+            let available = bytes.len().saturating_sub(start) / (page_size + 8);
+            let records = match header.records {
+                COUNT_TO_END => available,
+                records => records as usize,
+            };
+            db_size.get_or_insert(header.db_pages as u64 * page_size as u64);
+            for record in bytes[start.min(bytes.len())..]
+                .chunks_exact(page_size + 8)
+                .take(records)
+            {
+                let page_num = read_u32(record, 0);
+                let page = &record[4..4 + page_size];
+                if page_num == 0
+                    || read_u32(record, 4 + page_size) != record_checksum(header.nonce, page)
+                {
+                    break 'segments;
+                }
+                file.write_all_at((page_num - 1) as u64 * page_size as u64, page)?;
+                restored += 1;
+            }
+            let end = start + records * (page_size + 8);
+            offset = (end + sector_size - 1) / sector_size * sector_size;
+        }
+        if let Some(db_size) = db_size {
+            file.sync()?;
+            file.set_len(db_size)?;
+            file.sync()?;
+            debug!("Restored {} pages from {}", restored, self.path.display());
+        }
+        self.delete()
+    }
+
+    /// Plays back the journal if a crash left it hot, before the database is
+    /// read
+    ///
+    /// A journal whose writer still holds RESERVED is in use and left alone.
+    /// A hot journal cannot be played back through a connection that may not
+    /// write, so opening one fails.
+    pub fn recover(&self, file: &dyn DatabaseFile, writable: bool) -> Result<()> {
+        if !self.path.exists() || !file.lock(FileLock::Shared)? {
+            return Ok(());
+        }
+        let recovered = self.recover_locked(file, writable);
+        let unlocked = file.unlock();
+        recovered?;
+        unlocked
+    }
+
+    /// Plays back a hot journal while holding SHARED
+    fn recover_locked(&self, file: &dyn DatabaseFile, writable: bool) -> Result<()> {
+        if !file.lock(FileLock::Reserved)? {
+            return Ok(());
+        }
+        if !writable {
+            // A journal whose header was never written is not hot
+            let bytes = fs::read(&self.path).unwrap_or_default();
+            if JournalHeader::parse(&bytes).is_none() {
+                return Ok(());
+            }
+            return Err(anyhow!(
+                "{} holds an unfinished commit, which a read-only connection cannot roll back",
+                self.path.display()
+            ));
+        }
+        if !file.lock(FileLock::Exclusive)? {
+            return Err(anyhow!("database is locked"));
+        }
+        self.play_back(file)
+    }
+}
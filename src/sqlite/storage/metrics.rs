@@ -0,0 +1,107 @@
+//! Connection Metrics
+//!
+//! Cumulative counters describing the work a connection has done, for embedding
+//! applications to export to their monitoring system. I/O counters are kept by the
+//! pager; the rest are kept on the connection and merged into one snapshot by
+//! `SQLiteDatabase::metrics`.
+//!
+//! The same counters are also kept for each query on its own: the difference
+//! between the snapshots taken before and after it runs is available from
+//! `SQLiteDatabase::last_query_stats`, or returned with the result by
+//! `SQLiteDatabase::query_with_stats`.
+
+use crate::sqlite::storage::db::SQLiteDatabase;
+use std::fmt;
+
+/// Snapshot of a connection's counters
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Metrics {
+    /// Pages read from the database file or WAL
+    pub pages_read: u64,
+    /// Page reads served from the page cache instead of the file
+    pub page_cache_hits: u64,
+    /// Bytes read from the database file or WAL
+    pub bytes_read: u64,
+    /// Page reads that did not continue from the previously read page
+    pub non_sequential_reads: u64,
+    /// B-tree seeks, each a search from a root page down to a key
+    pub seeks: u64,
+    /// Queries answered from the result cache
+    pub cache_hits: u64,
+    /// Cacheable queries the result cache had no result for
+    pub cache_misses: u64,
+    /// B-tree cells read from table and index leaves
+    pub cells_visited: u64,
+    /// Table records decoded into result rows
+    pub rows_decoded: u64,
+    /// Record payload bytes decoded, including any on overflow pages
+    pub bytes_decoded: u64,
+}
+
+impl Metrics {
+    /// Returns the counts accumulated since the `earlier` snapshot was taken
+    pub fn since(&self, earlier: &Metrics) -> Metrics {
+        Metrics {
+            pages_read: self.pages_read.saturating_sub(earlier.pages_read),
+            page_cache_hits: self.page_cache_hits.saturating_sub(earlier.page_cache_hits),
+            bytes_read: self.bytes_read.saturating_sub(earlier.bytes_read),
+            non_sequential_reads: self
+                .non_sequential_reads
+                .saturating_sub(earlier.non_sequential_reads),
+            seeks: self.seeks.saturating_sub(earlier.seeks),
+            cache_hits: self.cache_hits.saturating_sub(earlier.cache_hits),
+            cache_misses: self.cache_misses.saturating_sub(earlier.cache_misses),
+            cells_visited: self.cells_visited.saturating_sub(earlier.cells_visited),
+            rows_decoded: self.rows_decoded.saturating_sub(earlier.rows_decoded),
+            bytes_decoded: self.bytes_decoded.saturating_sub(earlier.bytes_decoded),
+        }
+    }
+}
+
+impl fmt::Display for Metrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "pages read: {}, page cache hits: {}, bytes read: {}, \
+             non-sequential reads: {}, seeks: {}, result cache hits: {}, \
+             result cache misses: {}, cells visited: {}, rows decoded: {}, \
+             bytes decoded: {}",
+            self.pages_read,
+            self.page_cache_hits,
+            self.bytes_read,
+            self.non_sequential_reads,
+            self.seeks,
+            self.cache_hits,
+            self.cache_misses,
+            self.cells_visited,
+            self.rows_decoded,
+            self.bytes_decoded
+        )
+    }
+}
+
+impl SQLiteDatabase {
+    /// Returns a snapshot of this connection's counters
+    pub fn metrics(&self) -> Metrics {
+        Metrics {
+            pages_read: self.pager.pages_read(),
+            page_cache_hits: self.pager.page_cache_hits(),
+            bytes_read: self.pager.bytes_read(),
+            non_sequential_reads: self.pager.non_sequential_reads(),
+            seeks: self.pager.seeks(),
+            ..self.metrics
+        }
+    }
+
+    /// Resets every counter to zero
+    pub fn reset_metrics(&mut self) {
+        self.pager.reset_counters();
+        self.metrics = Metrics::default();
+    }
+
+    /// Returns the counts for the last query run through `query` or a
+    /// prepared statement, whether or not it succeeded
+    pub fn last_query_stats(&self) -> Metrics {
+        self.last_query_stats
+    }
+}
@@ -0,0 +1,139 @@
+//! Memory-Mapped Database Files
+//!
+//! [`MmapFile`] maps a database file into memory with `memmap2` and hands
+//! pages to the pager as ranges of the mapping, so reading a page copies
+//! nothing and costs no system call once the operating system has it cached.
+//! The pages go into the page cache and B-tree pages as they are, still
+//! pointing into the mapping. It plugs in below the pager like any other
+//! [`DatabaseFile`], so nothing above the pager knows whether pages came from
+//! a mapping or from read calls. Pages the pager decodes, such as encrypted
+//! ones, are still copied out of the mapping first.
+//!
+//! The mapping is read-only and shared, so it sees every write to the file,
+//! including those made through it: writes go to the file with ordinary write
+//! calls. A page held while the file is written reads the new bytes, which is
+//! why commits drop the page cache. A file that grows is mapped again, whole,
+//! the next time a read reaches past the end of the mapping, and one cut short
+//! by rolling back a commit is mapped again on the next read. An empty file is
+//! not mapped until it has bytes to map. Pages keep the mapping they came
+//! from alive until the last of them is dropped, so one file can be read from
+//! several threads while another maps it again.
+//!
+//! As with SQLite's mmap mode, a file truncated by another process while it is
+//! mapped makes reads past its new end fault rather than fail.
+
+use crate::sqlite::core::shared_page::SharedPage;
+use crate::sqlite::storage::vfs::{DatabaseFile, FileLock};
+use anyhow::{anyhow, Result};
+use memmap2::{Mmap, MmapOptions};
+use std::fs::File;
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+
+/// Maps the first `len` bytes of `file`, which must be at least 1
+fn map(file: &File, len: usize) -> Result<Arc<Mmap>> {
+    // SAFETY: the mapping is only read. As in SQLite, bytes changed under it,
+    // by this process or another, are read as they now are, and a file
+    // truncated under it faults rather than fails.
+    let mapping = unsafe { MmapOptions::new().len(len).map(file) }
+        .map_err(|e| anyhow!("cannot map {} bytes of the database file: {}", len, e))?;
+    Ok(Arc::new(mapping))
+}
+
+/// A database file read through a memory mapping
+pub struct MmapFile {
+    file: File,
+    /// The mapping, none while the file is empty
+    mapping: Mutex<Option<Arc<Mmap>>>,
+}
+
+impl MmapFile {
+    /// Maps `file`, failing if the operating system will not map it
+    pub fn new(file: File) -> Result<Self> {
+        let len = usize::try_from(file.metadata()?.len())?;
+        let mapping = match len {
+            0 => None,
+            len => Some(map(&file, len)?),
+        };
+        Ok(Self {
+            file,
+            mapping: Mutex::new(mapping),
+        })
+    }
+
+    /// Returns a mapping that covers at least the first `end` bytes, mapping
+    /// the file again if it has grown past the end of the current one
+    fn mapping(&self, end: usize) -> Result<Option<Arc<Mmap>>> {
+        let mut mapping = self
+            .mapping
+            .lock()
+            .map_err(|_| anyhow!("a thread panicked while mapping the database"))?;
+        if end > mapping.as_ref().map_or(0, |mapping| mapping.len()) {
+            let len = usize::try_from(self.file.metadata()?.len())?;
+            if len > mapping.as_ref().map_or(0, |mapping| mapping.len()) {
+                *mapping = Some(map(&self.file, len)?);
+            }
+        }
+        Ok(mapping.clone())
+    }
+
+    /// Returns a mapping holding the `len` bytes at `offset`, and their range
+    /// within it
+    fn mapped(&self, offset: u64, len: usize) -> Result<(Arc<Mmap>, Range<usize>)> {
+        let start = usize::try_from(offset)?;
+        let end = start
+            .checked_add(len)
+            .ok_or_else(|| anyhow!("read past the end of the address space"))?;
+        match self.mapping(end)? {
+            Some(mapping) if end <= mapping.len() => Ok((mapping, start..end)),
+            _ => Err(anyhow!(
+                "Partial read: {} bytes at offset {} past the end of the file",
+                len,
+                offset
+            )),
+        }
+    }
+}
+
+impl DatabaseFile for MmapFile {
+    fn read_exact_at(&self, offset: u64, buf: &mut [u8]) -> Result<()> {
+        let (mapping, range) = self.mapped(offset, buf.len())?;
+        buf.copy_from_slice(&mapping[range]);
+        Ok(())
+    }
+
+    fn read_shared_at(&self, offset: u64, len: usize) -> Result<Option<SharedPage>> {
+        let (mapping, range) = self.mapped(offset, len)?;
+        Ok(SharedPage::slice(mapping, range))
+    }
+
+    fn len(&self) -> Result<u64> {
+        Ok(self.file.metadata()?.len())
+    }
+
+    fn write_all_at(&self, offset: u64, buf: &[u8]) -> Result<()> {
+        self.file.write_all_at(offset, buf)
+    }
+
+    fn sync(&self) -> Result<()> {
+        self.file.sync()
+    }
+
+    fn set_len(&self, len: u64) -> Result<()> {
+        let mut mapping = self
+            .mapping
+            .lock()
+            .map_err(|_| anyhow!("a thread panicked while mapping the database"))?;
+        // Mapped again on the next read, so that no read reaches past the end
+        *mapping = None;
+        DatabaseFile::set_len(&self.file, len)
+    }
+
+    fn lock(&self, level: FileLock) -> Result<bool> {
+        self.file.lock(level)
+    }
+
+    fn unlock(&self) -> Result<()> {
+        self.file.unlock()
+    }
+}
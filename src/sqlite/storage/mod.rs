@@ -1,2 +1,25 @@
+#[cfg(feature = "tokio")]
+pub mod async_db;
+pub mod btree_stats;
+pub mod btree_write;
+#[cfg(feature = "sqlcipher")]
+pub mod cipher;
+#[cfg(feature = "zstd")]
+pub mod compressed;
+pub mod cursor;
 pub mod db;
+pub mod freelist;
+pub mod generate;
+pub mod journal;
+pub mod metrics;
+pub mod mmap;
+pub mod page_cache;
+pub mod pagemap;
+pub mod pager;
+pub mod ptrmap;
+pub mod space_report;
 pub mod table;
+pub mod vfs;
+pub mod vtab;
+pub mod wal;
+pub mod wal_index;
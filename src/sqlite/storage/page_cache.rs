@@ -0,0 +1,340 @@
+//! Page Cache
+//!
+//! Pages read from the database file are kept in a [`PageCache`], so reading
+//! one again costs no read call, and readers that only look at a page share
+//! the cached copy rather than copying it. Only pages as they are in the file
+//! are cached, after the codec has decoded them: pages written but not yet
+//! committed stay with the pager that wrote them, and pages read from the WAL
+//! depend on the snapshot of the connection reading them.
+//!
+//! The cache locks itself, so the connections cloned from one database share
+//! a single cache, and with it every page any of them has read, whichever
+//! thread they run on.
+//!
+//! ## Invalidation
+//!
+//! The whole cache is dropped whenever the file may have changed: after a
+//! commit writes to it, and when a connection sees that another process has
+//! changed the database, or cannot tell that it has not, as in WAL mode while
+//! the WAL is empty. Each drop starts a new generation, and a page read
+//! from the file is only cached if no drop happened while it was being read,
+//! so a read racing a commit on another thread cannot put back the page the
+//! commit replaced.
+//!
+//! ## Eviction
+//!
+//! A [`CacheConfig`] bounds the cache by pages, and optionally by bytes, and
+//! picks what a full cache drops:
+//!
+//! - [`Eviction::Lru`] drops the eighth of its pages that were used least
+//!   recently, which keeps the cost of eviction low when a scan reads many
+//!   pages in a row.
+//! - [`Eviction::Clock`] sweeps a hand over the pages, dropping the first one
+//!   not used since the hand last passed it. A page read only once, as by a
+//!   scan, goes before one read again, so a scan of a large table does not
+//!   push out the pages other queries keep coming back to.
+//!
+//! Pinned pages are never dropped, though they count against the bounds: a
+//! cache whose pinned pages fill it caches nothing else. A page stays pinned
+//! across drops of the whole cache, and is pinned again as soon as it is
+//! read back.
+
+use crate::sqlite::core::shared_page::SharedPage;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Mutex, MutexGuard};
+
+/// Pages a cache holds by default
+pub const DEFAULT_CACHE_PAGES: usize = 2000;
+
+/// Pages of the database file shared by every connection to it
+#[derive(Debug, Default)]
+pub struct PageCache {
+    state: Mutex<CacheState>,
+}
+
+/// How much a [`PageCache`] holds and which pages a full one drops
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CacheConfig {
+    /// Most pages the cache holds; 0 turns caching off
+    pub max_pages: usize,
+    /// Most bytes of page data the cache holds, if bounded other than by
+    /// `max_pages`
+    pub max_bytes: Option<usize>,
+    /// Which pages a full cache drops
+    pub eviction: Eviction,
+    /// Pins page 1 and the other pages of sqlite_schema as they are read, so
+    /// that scans of large tables do not push them out of the cache
+    pub pin_schema: bool,
+}
+
+/// Which pages a full [`PageCache`] drops
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Eviction {
+    /// The pages used least recently
+    #[default]
+    Lru,
+    /// The first page the clock hand finds not used since it last passed
+    Clock,
+}
+
+#[derive(Debug, Default)]
+struct CacheState {
+    config: CacheConfig,
+    /// Cached pages by page number
+    pages: HashMap<u32, Entry>,
+    /// Counts uses, ordering them for LRU eviction
+    tick: u64,
+    /// Cached page numbers in the order the clock hand visits them, kept
+    /// only for clock eviction
+    clock: Vec<u32>,
+    /// Position of the clock hand in `clock`
+    hand: usize,
+    /// Pages never evicted
+    pinned: HashSet<u32>,
+    /// Counts drops of the whole cache
+    generation: u64,
+}
+
+#[derive(Debug)]
+struct Entry {
+    page: SharedPage,
+    /// Tick of the page's last use
+    last_used: u64,
+    /// Set when the page is used, cleared as the clock hand passes it
+    referenced: bool,
+}
+
+impl PageCache {
+    /// Creates an empty cache holding up to `capacity` pages
+    pub fn new(capacity: usize) -> Self {
+        Self::with_config(CacheConfig {
+            max_pages: capacity,
+            ..CacheConfig::default()
+        })
+    }
+
+    /// Creates an empty cache configured by `config`
+    pub fn with_config(config: CacheConfig) -> Self {
+        let cache = Self::default();
+        cache.state().config = config;
+        cache
+    }
+
+    /// Returns how much the cache holds and which pages it drops
+    pub fn config(&self) -> CacheConfig {
+        self.state().config
+    }
+
+    /// Reconfigures the cache, dropping pages until it is within its new
+    /// bounds
+    ///
+    /// Turning `pin_schema` off unpins every page, and turning caching off
+    /// drops pinned pages too.
+    pub fn set_config(&self, config: CacheConfig) {
+        let mut state = self.state();
+        if !config.pin_schema {
+            state.pinned.clear();
+        }
+        let eviction = state.config.eviction;
+        state.config = config;
+        if config.eviction != eviction {
+            state.hand = 0;
+            state.clock = match config.eviction {
+                Eviction::Lru => Vec::new(),
+                Eviction::Clock => state.pages.keys().copied().collect(),
+            };
+        }
+
+        let page_size = state
+            .pages
+            .values()
+            .next()
+            .map_or(1, |entry| entry.page.len());
+        let limit = config.limit(page_size);
+        if limit == 0 {
+            state.pages.clear();
+            state.clock.clear();
+            state.hand = 0;
+        }
+        state.shrink_to(limit);
+    }
+
+    /// Returns the number of pages cached
+    pub fn len(&self) -> usize {
+        self.state().pages.len()
+    }
+
+    /// Returns true if no pages are cached
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the cached copy of a page, if there is one
+    pub fn get(&self, page_num: u32) -> Option<SharedPage> {
+        let mut state = self.state();
+        state.tick += 1;
+        let tick = state.tick;
+        let entry = state.pages.get_mut(&page_num)?;
+        entry.last_used = tick;
+        entry.referenced = true;
+        Some(entry.page.clone())
+    }
+
+    /// Returns the current generation, to pass to `insert` for a page about
+    /// to be read from the file
+    pub fn generation(&self) -> u64 {
+        self.state().generation
+    }
+
+    /// Caches a page read from the file, unless the cache has been dropped
+    /// since `generation` was taken
+    pub fn insert(&self, generation: u64, page_num: u32, page: SharedPage) {
+        let mut state = self.state();
+        let limit = state.config.limit(page.len());
+        if limit == 0 || state.generation != generation {
+            return;
+        }
+        state.tick += 1;
+        let tick = state.tick;
+        if let Some(entry) = state.pages.get_mut(&page_num) {
+            entry.page = page;
+            entry.last_used = tick;
+            return;
+        }
+
+        if state.pages.len() >= limit {
+            let target = match state.config.eviction {
+                Eviction::Lru => limit - (limit / 8).max(1),
+                Eviction::Clock => limit - 1,
+            };
+            state.shrink_to(target);
+            if state.pages.len() >= limit && !state.pinned.contains(&page_num) {
+                return;
+            }
+        }
+        if state.config.eviction == Eviction::Clock {
+            // Just behind the hand, so it is the last page the hand reaches
+            let hand = state.hand.min(state.clock.len());
+            state.clock.insert(hand, page_num);
+            state.hand = hand + 1;
+        }
+        let entry = Entry {
+            page,
+            last_used: tick,
+            referenced: false,
+        };
+        state.pages.insert(page_num, entry);
+    }
+
+    /// Keeps a page in the cache once it is cached, however full the cache
+    pub fn pin(&self, page_num: u32) {
+        self.state().pinned.insert(page_num);
+    }
+
+    /// Lets every pinned page be evicted again
+    pub fn unpin_all(&self) {
+        self.state().pinned.clear();
+    }
+
+    /// Drops every cached page and starts a new generation
+    pub fn clear(&self) {
+        let mut state = self.state();
+        state.pages.clear();
+        state.clock.clear();
+        state.hand = 0;
+        state.generation += 1;
+    }
+
+    /// Locks the cache; every change to it is complete once made, so a
+    /// thread that panicked holding the lock left nothing half done
+    fn state(&self) -> MutexGuard<'_, CacheState> {
+        self.state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl CacheConfig {
+    /// Returns the most pages of `page_size` bytes the cache holds
+    fn limit(&self, page_size: usize) -> usize {
+        let by_bytes = self
+            .max_bytes
+            .map_or(usize::MAX, |bytes| bytes / page_size.max(1));
+        self.max_pages.min(by_bytes)
+    }
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            max_pages: DEFAULT_CACHE_PAGES,
+            max_bytes: None,
+            eviction: Eviction::Lru,
+            pin_schema: false,
+        }
+    }
+}
+
+impl CacheState {
+    /// Drops unpinned pages as the eviction policy picks them until no more
+    /// than `target` pages are cached, or only pinned pages are left
+    fn shrink_to(&mut self, target: usize) {
+        if self.pages.len() <= target {
+            return;
+        }
+        match self.config.eviction {
+            Eviction::Lru => self.evict_lru(self.pages.len() - target),
+            Eviction::Clock => {
+                while self.pages.len() > target {
+                    let Some(victim) = self.clock_victim() else {
+                        break;
+                    };
+                    let page_num = self.clock.remove(victim);
+                    self.pages.remove(&page_num);
+                    self.hand = victim;
+                }
+            }
+        }
+    }
+
+    /// Drops the `count` least recently used unpinned pages
+    fn evict_lru(&mut self, count: usize) {
+        let pinned = &self.pinned;
+        let mut ticks: Vec<u64> = self
+            .pages
+            .iter()
+            .filter(|(page_num, _)| !pinned.contains(page_num))
+            .map(|(_, entry)| entry.last_used)
+            .collect();
+        let count = count.min(ticks.len());
+        if count == 0 {
+            return;
+        }
+        // Ticks are unique, so exactly `count` pages are at or below the cutoff
+        let (_, &mut cutoff, _) = ticks.select_nth_unstable(count - 1);
+        self.pages
+            .retain(|page_num, entry| entry.last_used > cutoff || pinned.contains(page_num));
+    }
+
+    /// Moves the clock hand to the first unpinned page not used since the
+    /// hand last passed it, clearing the mark of each used page it passes,
+    /// and returns its position, or None if every page is pinned
+    fn clock_victim(&mut self) -> Option<usize> {
+        // Two turns clear every mark, so the second finds a page if any can go
+        for _ in 0..2 * self.clock.len() {
+            if self.hand >= self.clock.len() {
+                self.hand = 0;
+            }
+            let page_num = self.clock[self.hand];
+            if !self.pinned.contains(&page_num) {
+                let entry = self.pages.get_mut(&page_num)?;
+                if !entry.referenced {
+                    return Some(self.hand);
+                }
+                entry.referenced = false;
+            }
+            self.hand += 1;
+        }
+        None
+    }
+}
@@ -0,0 +1,372 @@
+//! Whole-File Page Map
+//!
+//! Classifies every page of the database by walking all on-disk structures: the
+//! B-trees listed in sqlite_schema together with their overflow chains, the
+//! freelist, the pointer-map pages of auto-vacuum databases and the lock-byte page.
+//! Pages that none of these structures reach are reported as unreferenced.
+//!
+//! ## Cell Layouts
+//!
+//! - Table leaf (13): payload size varint, rowid varint, payload, overflow page
+//! - Table interior (5): 4-byte left child page, rowid varint
+//! - Index leaf (10): payload size varint, payload, overflow page
+//! - Index interior (2): 4-byte left child page, payload size varint, payload, overflow page
+//!
+//! The 4-byte overflow page number is only present when the payload does not fit
+//! on the page. Each overflow page starts with the 4-byte number of the next one.
+
+use crate::sqlite::core::btree::PayloadLayout;
+use crate::sqlite::core::checked;
+use crate::sqlite::core::error::{corrupt, CorruptPage};
+use crate::sqlite::core::header::DatabaseHeader;
+use crate::sqlite::core::record::{decode_integer, record_fields};
+use crate::sqlite::core::varint::decode_varint;
+use crate::sqlite::storage::db::SQLiteDatabase;
+use crate::sqlite::storage::freelist::Freelist;
+use crate::sqlite::storage::pager::{lock_byte_page, Pager};
+use anyhow::Result;
+use std::fmt::Display;
+use tracing::debug;
+
+/// Position of a page within its B-tree
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BTreeRole {
+    Root,
+    Interior,
+    Leaf,
+}
+
+/// What a page is used for
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PageKind {
+    /// A page of the B-tree of a table or index
+    BTree {
+        role: BTreeRole,
+        /// Name of the table or index, as recorded in sqlite_schema
+        object: String,
+        /// True for index B-trees (also used by WITHOUT ROWID tables)
+        index: bool,
+    },
+    /// Overflow page holding part of a large payload of the given object
+    Overflow { object: String },
+    /// Freelist trunk page
+    FreelistTrunk,
+    /// Free page listed on a freelist trunk
+    FreelistLeaf,
+    /// Pointer-map page of an auto-vacuum database
+    PointerMap,
+    /// Page containing the lock bytes at offset 1GiB
+    LockByte,
+    /// Page not reachable from any structure
+    Unreferenced,
+}
+
+impl Display for PageKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PageKind::BTree {
+                role,
+                object,
+                index,
+            } => {
+                let tree = if *index { "index" } else { "table" };
+                let role = match role {
+                    BTreeRole::Root => "root",
+                    BTreeRole::Interior => "interior",
+                    BTreeRole::Leaf => "leaf",
+                };
+                write!(f, "{} {} {}", tree, role, object)
+            }
+            PageKind::Overflow { object } => write!(f, "overflow {}", object),
+            PageKind::FreelistTrunk => write!(f, "freelist trunk"),
+            PageKind::FreelistLeaf => write!(f, "freelist leaf"),
+            PageKind::PointerMap => write!(f, "ptrmap"),
+            PageKind::LockByte => write!(f, "lock-byte"),
+            PageKind::Unreferenced => write!(f, "unreferenced"),
+        }
+    }
+}
+
+/// Classification of every page in a database
+#[derive(Debug, Clone)]
+pub struct PageMap {
+    /// Kind of each page, indexed by page number - 1
+    pages: Vec<PageKind>,
+}
+
+impl PageMap {
+    /// Returns the number of pages in the database
+    pub fn page_count(&self) -> u32 {
+        self.pages.len() as u32
+    }
+
+    /// Returns the kind of the given page
+    pub fn kind(&self, page_num: u32) -> Option<&PageKind> {
+        self.pages.get((page_num as usize).checked_sub(1)?)
+    }
+
+    /// Iterates over `(page number, kind)` for every page
+    pub fn iter(&self) -> impl Iterator<Item = (u32, &PageKind)> {
+        self.pages
+            .iter()
+            .enumerate()
+            .map(|(i, kind)| (i as u32 + 1, kind))
+    }
+}
+
+/// Walks the file's structures, claiming each page exactly once
+struct PageMapBuilder<'a> {
+    pager: &'a mut Pager,
+    header: &'a DatabaseHeader,
+    /// Usable bytes per page (page size minus reserved space)
+    usable_size: usize,
+    pages: Vec<Option<PageKind>>,
+}
+
+/// An entry of sqlite_schema that owns a B-tree
+struct SchemaObject {
+    name: String,
+    root_page: u32,
+}
+
+impl<'a> PageMapBuilder<'a> {
+    /// Records the use of a page, rejecting pages claimed twice or out of range
+    fn claim(&mut self, page_num: u32, kind: PageKind) -> Result<()> {
+        let page_count = self.pages.len();
+        let slot = (page_num as usize)
+            .checked_sub(1)
+            .and_then(|i| self.pages.get_mut(i))
+            .ok_or_else(|| {
+                corrupt(
+                    0,
+                    format!(
+                        "reference to page {} of a {}-page database",
+                        page_num, page_count
+                    ),
+                )
+            })?;
+        if let Some(existing) = slot {
+            return Err(corrupt(
+                0,
+                format!("page used as both {} and {}", existing, kind),
+            ))
+            .on_page(page_num);
+        }
+        *slot = Some(kind);
+        Ok(())
+    }
+
+    /// Marks the lock-byte page, if the file is large enough to have one
+    fn claim_lock_byte_page(&mut self) -> Result<()> {
+        let lock_page = lock_byte_page(self.pager.page_size());
+        if lock_page as usize > self.pages.len() {
+            return Ok(());
+        }
+        self.claim(lock_page, PageKind::LockByte)
+    }
+
+    /// Marks the pointer-map pages of an auto-vacuum database
+    fn claim_ptrmap_pages(&mut self) -> Result<()> {
+        for page_num in 2..=self.pages.len() as u32 {
+            if self.pager.is_ptrmap_page(page_num) {
+                self.claim(page_num, PageKind::PointerMap)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Marks the freelist trunk pages and the free pages they list
+    fn claim_freelist(&mut self) -> Result<()> {
+        let freelist = Freelist::read(
+            self.pager,
+            self.header.first_freelist_trunk,
+            self.usable_size,
+        )?;
+        for trunk in freelist.trunks {
+            self.claim(trunk.page, PageKind::FreelistTrunk)?;
+            for leaf in trunk.leaves {
+                self.claim(leaf, PageKind::FreelistLeaf)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Marks the overflow chain of a payload, appending its bytes to `payload`
+    fn claim_overflow(
+        &mut self,
+        first: u32,
+        mut remaining: usize,
+        object: &str,
+        mut payload: Option<&mut Vec<u8>>,
+    ) -> Result<()> {
+        let mut page_num = first;
+        while remaining > 0 {
+            if page_num == 0 {
+                return Err(corrupt(
+                    0,
+                    format!(
+                        "overflow chain of {} ends {} bytes early",
+                        object, remaining
+                    ),
+                ));
+            }
+            self.claim(
+                page_num,
+                PageKind::Overflow {
+                    object: object.to_string(),
+                },
+            )?;
+            let page = self.pager.read_page(page_num)?;
+            let take = remaining.min(self.usable_size - 4);
+            if let Some(payload) = payload.as_mut() {
+                payload.extend_from_slice(checked::slice(&page, 4, take).on_page(page_num)?);
+            }
+            remaining -= take;
+            page_num = checked::be_u32(&page, 0).on_page(page_num)?;
+        }
+        Ok(())
+    }
+
+    /// Marks every page of a B-tree, returning the leaf payloads if `collect` is set
+    fn claim_btree(&mut self, root: u32, object: &str, collect: bool) -> Result<Vec<Vec<u8>>> {
+        let mut payloads = Vec::new();
+        let mut stack = vec![root];
+        let mut root_is_index = None;
+
+        while let Some(page_num) = stack.pop() {
+            let btree_page = self.pager.read_btree_page(page_num)?;
+            let (index, leaf) = (btree_page.is_index(), !btree_page.is_interior());
+            if *root_is_index.get_or_insert(index) != index {
+                return Err(corrupt(
+                    btree_page.header_offset(),
+                    format!(
+                        "page type {} does not belong in the B-tree of {}",
+                        btree_page.page_type(),
+                        object
+                    ),
+                ))
+                .on_page(page_num);
+            }
+
+            let role = if page_num == root {
+                BTreeRole::Root
+            } else if leaf {
+                BTreeRole::Leaf
+            } else {
+                BTreeRole::Interior
+            };
+            self.claim(
+                page_num,
+                PageKind::BTree {
+                    role,
+                    object: object.to_string(),
+                    index,
+                },
+            )?;
+
+            if !leaf {
+                stack.push(btree_page.right_child()?);
+            }
+
+            let page = btree_page.data();
+            let layout = PayloadLayout::new(self.usable_size, btree_page.page_type());
+            for i in 0..btree_page.num_cells() as usize {
+                let mut pos = btree_page.cell_pointer(i)?;
+
+                // Interior cells start with the left child pointer
+                if !leaf {
+                    stack.push(checked::be_u32(page, pos).on_page(page_num)?);
+                    pos += 4;
+                }
+                // Table interior cells hold only a rowid key
+                if !leaf && !index {
+                    continue;
+                }
+
+                let (payload_size, len) = decode_varint(page, pos).on_page(page_num)?;
+                pos += len;
+                if !index {
+                    // Skip the rowid
+                    pos += decode_varint(page, pos).on_page(page_num)?.1;
+                }
+
+                let payload_size = payload_size as usize;
+                let local = layout.local_size(payload_size);
+                let mut payload = checked::slice(page, pos, local).on_page(page_num)?.to_vec();
+                if local < payload_size {
+                    let first_overflow = checked::be_u32(page, pos + local).on_page(page_num)?;
+                    self.claim_overflow(
+                        first_overflow,
+                        payload_size - local,
+                        object,
+                        collect.then_some(&mut payload),
+                    )?;
+                }
+                if collect && leaf {
+                    payloads.push(payload);
+                }
+            }
+        }
+
+        Ok(payloads)
+    }
+
+    /// Decodes the schema entries that own a B-tree
+    fn schema_objects(&self, rows: &[Vec<u8>]) -> Result<Vec<SchemaObject>> {
+        let mut objects = Vec::new();
+        for row in rows {
+            let fields = record_fields(row)?;
+            let (Some(name), Some(root)) = (fields.get(1), fields.get(3)) else {
+                return Err(corrupt(0, "sqlite_schema row has too few columns")).on_page(1);
+            };
+            let root_page = match root.0 {
+                1..=6 => decode_integer(root.1),
+                _ => 0,
+            };
+            // Views, triggers and virtual tables have no B-tree
+            if root_page <= 0 {
+                continue;
+            }
+            objects.push(SchemaObject {
+                name: self.header.decode_text(name.1),
+                root_page: root_page as u32,
+            });
+        }
+        Ok(objects)
+    }
+}
+
+impl SQLiteDatabase {
+    /// Classifies every page of the database file
+    pub fn page_map(&mut self) -> Result<PageMap> {
+        let page_count = self.pager.page_count()?;
+        let usable_size = self.usable_size()?;
+        let mut builder = PageMapBuilder {
+            pager: &mut self.pager,
+            header: &self.header,
+            usable_size,
+            pages: vec![None; page_count as usize],
+        };
+
+        builder.claim_lock_byte_page()?;
+        builder.claim_ptrmap_pages()?;
+        builder.claim_freelist()?;
+
+        let rows = builder.claim_btree(1, "sqlite_schema", true)?;
+        for object in builder.schema_objects(&rows)? {
+            debug!(
+                "Mapping B-tree of {} at page {}",
+                object.name, object.root_page
+            );
+            builder.claim_btree(object.root_page, &object.name, false)?;
+        }
+
+        Ok(PageMap {
+            pages: builder
+                .pages
+                .into_iter()
+                .map(|kind| kind.unwrap_or(PageKind::Unreferenced))
+                .collect(),
+        })
+    }
+}
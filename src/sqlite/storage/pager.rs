@@ -0,0 +1,887 @@
+//! Page Access Layer
+//!
+//! Every page read goes through the pager, which locates the page in the database
+//! file, validates the read and hands the raw bytes to an optional codec before
+//! anything above this layer parses them.
+//!
+//! ## Page Numbering
+//!
+//! Pages are numbered from 1. Page N starts at byte offset `(N - 1) * page_size`.
+//! Page 1 additionally begins with the 100-byte database header.
+//!
+//! ## Lock-Byte Page
+//!
+//! SQLite locks a database through the bytes at offset 1GiB, so in a file
+//! larger than that the page holding those bytes is never used for data. It
+//! belongs to no B-tree or freelist, and the pager refuses to read it.
+//!
+//! ## WAL Mode
+//!
+//! When a WAL is attached, a page's newest committed frame in the WAL takes
+//! precedence over its copy in the database file.
+//!
+//! ## Writing
+//!
+//! Pages written through the pager are held in memory as dirty pages, which
+//! later reads see in place of the file, until [`Pager::commit`] writes them
+//! all out or [`Pager::rollback`] drops them. Every commit also bumps the file
+//! change counter on page 1, so readers that cache anything keyed by it know
+//! the file has changed.
+//!
+//! Without a WAL the pages are written over their copies in the database
+//! file. With a [`Journal`] set, as for every database opened from a path, the
+//! commit first takes SQLite's RESERVED lock and saves the pages it replaces
+//! in the rollback journal, then takes EXCLUSIVE. Neither lock is waited for:
+//! while a SQLite connection is reading the file, or another connection is
+//! writing it, the commit fails with "database is locked" and nothing is
+//! written. A
+//! crash part way through leaves the journal hot for the next connection to
+//! play back. The commit is done once the journal is deleted.
+//!
+//! When a WAL is attached the dirty pages are appended to it as one
+//! transaction instead, leaving the database file alone, which also makes the
+//! commit atomic. Encrypted databases are read-only.
+//!
+//! ## Read-Ahead
+//!
+//! A scan whose leaves sit one after another in the file tells the pager so
+//! with [`Pager::set_sequential`]. While it does, a read from the file that
+//! continues where the previous one ended also reads the next few pages in the
+//! same call, and the reads that follow are served from them. A scan is then
+//! not bound by the latency of one read call per page. Pages read ahead are
+//! dropped once the database changes, by a commit here or, as seen by
+//! [`Pager::refresh`], by another connection.
+//!
+//! ## Caching
+//!
+//! Pages read from the file are kept in a [`PageCache`] and served from it
+//! until the file changes, by a commit here or, as seen by
+//! [`SQLiteDatabase::refresh_header`](crate::sqlite::storage::db::SQLiteDatabase::refresh_header),
+//! by another process. Pages from the WAL and dirty pages are not cached.
+//! The cache's [`CacheConfig`](crate::sqlite::storage::page_cache::CacheConfig)
+//! bounds it and picks the pages it drops when full; pages read while
+//! [`Pager::set_pinning`] is set are never dropped.
+//!
+//! ## Verification
+//!
+//! Readers check what they read of a page as they read it, which leaves a
+//! malformed page to fail wherever it is first misread. With
+//! [`Pager::set_verification`] the pager checks more up front: every B-tree
+//! page read through [`Pager::read_btree_page`] has its whole layout checked
+//! before it is parsed further, and with checksums on, every page read from
+//! the file is checksummed the first time and checked against that checksum
+//! each time it is read from the file again, until the file changes. A page
+//! that fails is reported as corrupt, with its page number and what was wrong.
+//!
+//! ## Sharing
+//!
+//! The file is read with positional reads through a shared reference, and the
+//! page cache locks itself, so both can be shared: [`Pager::try_clone`] makes
+//! a pager for another connection over the same file and cache, with a WAL
+//! snapshot, dirty pages and counters of its own. A single pager keeps
+//! counters and read-ahead state, so its own reads need exclusive access.
+//! [`SharedPager`] lends a pager to several threads at once by taking turns
+//! behind a mutex; only the reads themselves are serialized.
+
+use crate::sqlite::core::btree::BTreePage;
+use crate::sqlite::core::error::{corrupt, CorruptPage};
+use crate::sqlite::core::header::DatabaseHeader;
+use crate::sqlite::core::shared_page::SharedPage;
+use crate::sqlite::storage::journal::Journal;
+use crate::sqlite::storage::page_cache::PageCache;
+use crate::sqlite::storage::ptrmap::is_ptrmap_page;
+use crate::sqlite::storage::vfs::{DatabaseFile, FileLock};
+use crate::sqlite::storage::wal::{wal_checksum, Wal};
+use anyhow::{anyhow, Result};
+use std::collections::{BTreeMap, HashMap};
+use std::ops::Deref;
+use std::sync::{Arc, Mutex};
+use tracing::debug;
+
+/// Byte offset covered by the lock-byte page
+const LOCK_BYTE_OFFSET: u64 = 1_073_741_824;
+
+/// Pages read past a sequential read by default while a scan reads in order
+pub const DEFAULT_READ_AHEAD: usize = 16;
+
+/// Returns the page containing the byte at offset 1GiB, which SQLite uses for
+/// locking, for a database with the given page size
+pub fn lock_byte_page(page_size: u32) -> u32 {
+    (LOCK_BYTE_OFFSET / page_size as u64) as u32 + 1
+}
+
+/// Transforms pages between their on-disk and in-memory representation
+///
+/// Used to plug in decryption (or other page-level encodings) below the B-tree
+/// layer. Codecs are shared by the pagers cloned from one another, which may
+/// run on different threads.
+pub trait PageCodec: Send + Sync {
+    /// Decodes a page in place right after it has been read from disk
+    fn decode(&self, page_num: u32, page: &mut [u8]) -> Result<()>;
+}
+
+/// Reads pages from a database file
+pub struct Pager {
+    /// The underlying database file, shared with cloned pagers
+    file: Arc<dyn DatabaseFile>,
+    /// Size of each page in bytes
+    page_size: u32,
+    /// Optional codec applied to every page after reading
+    codec: Option<Arc<dyn PageCodec>>,
+    /// Pages read from the file, shared with cloned pagers
+    cache: Arc<PageCache>,
+    /// Write-ahead log consulted before the database file
+    wal: Option<Wal>,
+    /// Rollback journal kept by commits that write the database file in place
+    journal: Option<Journal>,
+    /// Number of pages read since the counters were last reset
+    pages_read: u64,
+    /// Number of page reads served from the page cache
+    page_cache_hits: u64,
+    /// Number of bytes read from the database file or WAL
+    bytes_read: u64,
+    /// Number of reads that did not continue from the previously read page
+    non_sequential_reads: u64,
+    /// Number of searches down a B-tree from its root page for a key
+    seeks: u64,
+    /// Last page read from the database file, used to detect sequential reads
+    last_page: Option<u32>,
+    /// Usable page size of an auto-vacuum database, whose pointer-map pages
+    /// are only read through `read_ptrmap_page`
+    ptrmap_usable_size: Option<usize>,
+    /// True if the file could only be opened for reading
+    read_only: bool,
+    /// Pages written since the last commit, by page number
+    dirty: BTreeMap<u32, Vec<u8>>,
+    /// Pages read past a sequential read while reads are sequential; 0 turns
+    /// read-ahead off
+    read_ahead: usize,
+    /// Set while a scan is reading pages in file order
+    sequential: bool,
+    /// The first of the pages last read ahead, their raw bytes, and the
+    /// generation of the page cache they were read in
+    ahead: Option<(u32, Vec<u8>, u64)>,
+    /// How closely pages are checked as they are read
+    verification: PageVerification,
+    /// Usable size of a page, which B-tree pages are checked against
+    usable_size: usize,
+    /// Checksums of pages as first read from the file, while they are taken
+    checksums: PageChecksums,
+    /// Set while every page read is to be pinned in the page cache
+    pinning: bool,
+}
+
+/// How closely the pager checks the pages it reads
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PageVerification {
+    /// Pages are checked only as far as they are read
+    #[default]
+    Off,
+    /// The whole layout of every B-tree page is checked before it is used
+    Structure,
+    /// As `Structure`, and every page read from the file must match the
+    /// checksum taken when it was first read
+    Checksums,
+}
+
+/// Checksums of the pages read from the file since the page cache last
+/// started a generation
+#[derive(Debug, Default)]
+struct PageChecksums {
+    /// Generation of the page cache the checksums were taken in
+    generation: u64,
+    /// Checksum of each page read, by page number
+    pages: HashMap<u32, (u32, u32)>,
+}
+
+impl Pager {
+    /// Creates a pager over a plain database file
+    pub fn new(file: Box<dyn DatabaseFile>, page_size: u32) -> Self {
+        Self::with_codec(file, page_size, None)
+    }
+
+    /// Creates a pager that decodes every page with the given codec, if any
+    pub fn with_codec(
+        file: Box<dyn DatabaseFile>,
+        page_size: u32,
+        codec: Option<Box<dyn PageCodec>>,
+    ) -> Self {
+        Self {
+            file: Arc::from(file),
+            page_size,
+            codec: codec.map(Arc::from),
+            cache: Arc::new(PageCache::default()),
+            wal: None,
+            journal: None,
+            pages_read: 0,
+            page_cache_hits: 0,
+            bytes_read: 0,
+            non_sequential_reads: 0,
+            seeks: 0,
+            last_page: None,
+            ptrmap_usable_size: None,
+            read_only: false,
+            dirty: BTreeMap::new(),
+            read_ahead: DEFAULT_READ_AHEAD,
+            sequential: false,
+            ahead: None,
+            verification: PageVerification::Off,
+            usable_size: page_size as usize,
+            checksums: PageChecksums::default(),
+            pinning: false,
+        }
+    }
+
+    /// Creates a pager for another connection over the same file, codec and
+    /// page cache
+    ///
+    /// The new pager opens the WAL again, if there is one, for a snapshot of
+    /// its own, and starts with no dirty pages and its counters at zero.
+    pub fn try_clone(&self) -> Result<Self> {
+        Ok(Self {
+            file: self.file.clone(),
+            page_size: self.page_size,
+            codec: self.codec.clone(),
+            cache: self.cache.clone(),
+            wal: self.wal.as_ref().map(Wal::reopen).transpose()?,
+            journal: self.journal.clone(),
+            pages_read: 0,
+            page_cache_hits: 0,
+            bytes_read: 0,
+            non_sequential_reads: 0,
+            seeks: 0,
+            last_page: None,
+            ptrmap_usable_size: self.ptrmap_usable_size,
+            read_only: self.read_only,
+            dirty: BTreeMap::new(),
+            read_ahead: self.read_ahead,
+            sequential: false,
+            ahead: None,
+            verification: self.verification,
+            usable_size: self.usable_size,
+            checksums: PageChecksums::default(),
+            pinning: false,
+        })
+    }
+
+    /// Returns the page cache, which cloned pagers share
+    pub fn cache(&self) -> &PageCache {
+        &self.cache
+    }
+
+    /// Drops every page in the page cache, for when the file may have been
+    /// changed by another process
+    pub fn clear_cache(&self) {
+        self.cache.clear();
+    }
+
+    /// Attaches a write-ahead log whose committed frames override file pages
+    pub fn set_wal(&mut self, wal: Wal) {
+        self.wal = Some(wal);
+    }
+
+    /// Saves the pages each commit overwrites in the database file in
+    /// `journal` first, under SQLite's locks
+    pub fn set_journal(&mut self, journal: Journal) {
+        self.journal = Some(journal);
+    }
+
+    /// Marks the database as keeping pointer-map pages, which `read_page`
+    /// then refuses to read
+    pub fn set_auto_vacuum(&mut self, usable_size: usize) {
+        self.ptrmap_usable_size = Some(usable_size);
+    }
+
+    /// Marks the file as opened for reading only, so that writes are refused
+    pub fn set_read_only(&mut self) {
+        self.read_only = true;
+    }
+
+    /// Returns true if the given page is a pointer-map page
+    pub fn is_ptrmap_page(&self, page_num: u32) -> bool {
+        self.ptrmap_usable_size.map_or(false, |usable_size| {
+            is_ptrmap_page(page_num, usable_size, self.page_size)
+        })
+    }
+
+    /// Returns true if the given page is the lock-byte page
+    pub fn is_lock_byte_page(&self, page_num: u32) -> bool {
+        page_num == lock_byte_page(self.page_size)
+    }
+
+    /// Sets how closely pages are checked as they are read, with B-tree pages
+    /// checked against the given usable page size
+    pub fn set_verification(&mut self, verification: PageVerification, usable_size: usize) {
+        self.verification = verification;
+        self.usable_size = usable_size;
+        self.checksums = PageChecksums::default();
+    }
+
+    /// Returns how closely pages are checked as they are read
+    pub fn verification(&self) -> PageVerification {
+        self.verification
+    }
+
+    /// Pins every page read in the page cache while `pinning` is set, for
+    /// pages every statement needs, such as those of sqlite_schema
+    pub fn set_pinning(&mut self, pinning: bool) {
+        self.pinning = pinning;
+    }
+
+    /// Sets how many pages a sequential read reads ahead while a scan reads in
+    /// order; 0 turns read-ahead off
+    pub fn set_read_ahead(&mut self, pages: usize) {
+        self.read_ahead = pages;
+        self.ahead = None;
+    }
+
+    /// Tells the pager whether the scan under way is reading pages in file
+    /// order, which lets sequential reads read ahead
+    pub fn set_sequential(&mut self, sequential: bool) {
+        self.sequential = sequential;
+    }
+
+    /// Catches up with transactions other connections have committed to the
+    /// WAL since it was read, returning true if there were any, or if there
+    /// may have been
+    ///
+    /// Pages read ahead from the file are dropped, as other connections may
+    /// have changed them. Without a WAL there is nothing more to do, as the
+    /// change counter tells of changes to the file. A WAL without committed
+    /// frames tells nothing: another process may have committed to it and
+    /// checkpointed it into the file since, without moving the counter, so
+    /// the database may always have changed. The snapshot is kept while this
+    /// pager has uncommitted writes.
+    pub fn refresh(&mut self) -> Result<bool> {
+        self.ahead = None;
+        if let Some(wal) = &mut self.wal {
+            if !self.dirty.is_empty() {
+                return Ok(false);
+            }
+            if !wal.is_current()? {
+                debug!("WAL changed since it was read, reopening it");
+                wal.reload()?;
+                self.last_page = None;
+                return Ok(true);
+            }
+            if wal.db_size() == 0 {
+                self.last_page = None;
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Returns the file change counter in the header on page 1
+    ///
+    /// Only the counter's four bytes are read, unless page 1 has to come
+    /// whole from the dirty pages, the WAL or the codec.
+    pub fn change_counter(&mut self) -> Result<u32> {
+        let in_wal = match &self.wal {
+            Some(wal) => wal.find_frame(1)?.is_some(),
+            None => false,
+        };
+        if in_wal || self.codec.is_some() || self.dirty.contains_key(&1) {
+            // Not from the page cache, which may hold the page from before
+            // another process changed it
+            let page_1 = self.read_from(1, false)?;
+            return Ok(u32::from_be_bytes([
+                page_1[24], page_1[25], page_1[26], page_1[27],
+            ]));
+        }
+        let mut counter = [0; 4];
+        self.file.read_exact_at(24, &mut counter)?;
+        self.bytes_read += counter.len() as u64;
+        Ok(u32::from_be_bytes(counter))
+    }
+
+    /// Returns the attached write-ahead log, if any
+    pub fn wal(&self) -> Option<&Wal> {
+        self.wal.as_ref()
+    }
+
+    /// Returns the page size in bytes
+    pub fn page_size(&self) -> u32 {
+        self.page_size
+    }
+
+    /// Returns the number of pages in the database
+    ///
+    /// The newest commit in the WAL, if any, determines the size; otherwise it is
+    /// derived from the file length. Uncommitted pages past the end count too.
+    pub fn page_count(&mut self) -> Result<u32> {
+        let last_dirty = self.dirty.keys().next_back().copied().unwrap_or(0);
+        if let Some(wal) = &self.wal {
+            if wal.db_size() > 0 {
+                return Ok(wal.db_size().max(last_dirty));
+            }
+        }
+        let file_pages = (self.file.len()? / self.page_size as u64) as u32;
+        Ok(file_pages.max(last_dirty))
+    }
+
+    /// Returns the number of pages read so far
+    pub fn pages_read(&self) -> u64 {
+        self.pages_read
+    }
+
+    /// Returns the number of page reads served from the page cache
+    pub fn page_cache_hits(&self) -> u64 {
+        self.page_cache_hits
+    }
+
+    /// Returns the number of bytes read from the database file or WAL
+    pub fn bytes_read(&self) -> u64 {
+        self.bytes_read
+    }
+
+    /// Returns the number of non-sequential page reads
+    pub fn non_sequential_reads(&self) -> u64 {
+        self.non_sequential_reads
+    }
+
+    /// Returns the number of B-tree seeks
+    pub fn seeks(&self) -> u64 {
+        self.seeks
+    }
+
+    /// Counts a search down a B-tree from its root page for a key
+    pub fn count_seek(&mut self) {
+        self.seeks += 1;
+    }
+
+    /// Resets the I/O counters to zero
+    pub fn reset_counters(&mut self) {
+        self.pages_read = 0;
+        self.page_cache_hits = 0;
+        self.bytes_read = 0;
+        self.non_sequential_reads = 0;
+        self.seeks = 0;
+        self.last_page = None;
+    }
+
+    /// Reads the raw database header without going through the codec
+    pub fn read_raw_header(file: &dyn DatabaseFile) -> Result<Vec<u8>> {
+        let mut header_bytes = vec![0; DatabaseHeader::HEADER_SIZE];
+        file.read_exact_at(0, &mut header_bytes)?;
+        Ok(header_bytes)
+    }
+
+    /// Reads the page with the given number
+    ///
+    /// Pointer-map pages and the lock-byte page are refused, since a B-tree or
+    /// freelist that points at one is corrupt and would otherwise misread it.
+    pub fn read_page(&mut self, page_num: u32) -> Result<Vec<u8>> {
+        self.check_readable(page_num)?;
+        self.read(page_num)
+    }
+
+    /// Reads a page as `read_page` does, for readers that only look at it
+    ///
+    /// A page in the page cache is shared rather than copied, and one read
+    /// from the file is cached as it is returned, so readers can keep slices
+    /// of it without copying them out.
+    pub fn read_shared_page(&mut self, page_num: u32) -> Result<SharedPage> {
+        self.check_readable(page_num)?;
+        Ok(self.read_from(page_num, true)?.into_shared())
+    }
+
+    /// Reads and parses a B-tree page, checking its whole layout first while
+    /// verification is on
+    pub fn read_btree_page(&mut self, page_num: u32) -> Result<BTreePage> {
+        let page = BTreePage::parse(page_num, self.read_shared_page(page_num)?)?;
+        if self.verification != PageVerification::Off {
+            page.verify(self.usable_size)?;
+        }
+        Ok(page)
+    }
+
+    /// Refuses pointer-map pages and the lock-byte page
+    fn check_readable(&self, page_num: u32) -> Result<()> {
+        if self.is_ptrmap_page(page_num) {
+            return Err(corrupt(
+                0,
+                "pointer-map page referenced as a B-tree or freelist page",
+            ))
+            .on_page(page_num);
+        }
+        if self.is_lock_byte_page(page_num) {
+            return Err(corrupt(
+                0,
+                "lock-byte page referenced as a B-tree or freelist page",
+            ))
+            .on_page(page_num);
+        }
+        Ok(())
+    }
+
+    /// Reads a pointer-map page of an auto-vacuum database
+    pub fn read_ptrmap_page(&mut self, page_num: u32) -> Result<Vec<u8>> {
+        if !self.is_ptrmap_page(page_num) {
+            return Err(anyhow!("page {} is not a pointer-map page", page_num));
+        }
+        self.read(page_num)
+    }
+
+    /// Replaces the contents of a page until the next commit or rollback
+    ///
+    /// Pointer-map pages and the lock-byte page are refused, as in `read_page`.
+    pub fn write_page(&mut self, page_num: u32, page: Vec<u8>) -> Result<()> {
+        if self.read_only {
+            return Err(anyhow!("attempt to write a readonly database"));
+        }
+        if self.codec.is_some() {
+            return Err(anyhow!("writing encrypted databases is not supported"));
+        }
+        if page_num == 0 || self.is_ptrmap_page(page_num) || self.is_lock_byte_page(page_num) {
+            return Err(anyhow!("page {} cannot be written", page_num));
+        }
+        if page.len() != self.page_size as usize {
+            return Err(anyhow!(
+                "page {} is {} bytes, expected {}",
+                page_num,
+                page.len(),
+                self.page_size
+            ));
+        }
+        self.dirty.insert(page_num, page);
+        Ok(())
+    }
+
+    /// Returns true if pages have been written since the last commit
+    pub fn has_changes(&self) -> bool {
+        !self.dirty.is_empty()
+    }
+
+    /// Writes every dirty page to the file in page order, through the
+    /// rollback journal if one is set, or appends them to the WAL if one is
+    /// attached, bumping the file change counter on page 1
+    ///
+    /// The counter is also stored as the version-valid-for number, alongside
+    /// the new size of the database in pages, so that the size in the header
+    /// stays trustworthy.
+    pub fn commit(&mut self) -> Result<()> {
+        if self.dirty.is_empty() {
+            return Ok(());
+        }
+        let page_count = self.page_count()?;
+        let mut page_1 = self.read(1)?;
+        let counter =
+            u32::from_be_bytes([page_1[24], page_1[25], page_1[26], page_1[27]]).wrapping_add(1);
+        page_1[24..28].copy_from_slice(&counter.to_be_bytes());
+        page_1[28..32].copy_from_slice(&page_count.to_be_bytes());
+        page_1[92..96].copy_from_slice(&counter.to_be_bytes());
+        self.dirty.insert(1, page_1);
+
+        if let Some(wal) = &mut self.wal {
+            wal.commit(&self.dirty, page_count)?;
+        } else if let Some(journal) = &self.journal {
+            self.write_journaled(journal)?;
+        } else {
+            self.write_dirty()?;
+        }
+        self.dirty.clear();
+        // The file changed under the sequential-read tracking
+        self.last_page = None;
+        self.ahead = None;
+        Ok(())
+    }
+
+    /// Writes the dirty pages into the database file after saving the pages
+    /// they replace in `journal`, holding RESERVED and then EXCLUSIVE
+    ///
+    /// If writing fails part way, the journal is played back at once.
+    fn write_journaled(&self, journal: &Journal) -> Result<()> {
+        let locked = self.file.lock(FileLock::Shared)? && self.file.lock(FileLock::Reserved)?;
+        let written = if locked {
+            self.write_locked(journal)
+        } else {
+            Err(anyhow!("database is locked"))
+        };
+        let unlocked = self.file.unlock();
+        written?;
+        unlocked
+    }
+
+    /// Does the work of `write_journaled` once RESERVED is held
+    fn write_locked(&self, journal: &Journal) -> Result<()> {
+        journal.write(
+            self.file.as_ref(),
+            self.page_size,
+            self.dirty.keys().copied(),
+        )?;
+        if !self.file.lock(FileLock::Exclusive)? {
+            journal.delete()?;
+            return Err(anyhow!("database is locked"));
+        }
+        if let Err(error) = self.write_dirty() {
+            journal.play_back(self.file.as_ref())?;
+            self.cache.clear();
+            return Err(error);
+        }
+        journal.delete()
+    }
+
+    /// Writes every dirty page over its copy in the database file
+    fn write_dirty(&self) -> Result<()> {
+        // Before the pages are written too, so that every read overlapping
+        // the writes sees the cache change under it
+        self.cache.clear();
+        for (&page_num, page) in &self.dirty {
+            let offset = (page_num - 1) as u64 * self.page_size as u64;
+            debug!("Writing page {} at offset {}", page_num, offset);
+            self.file.write_all_at(offset, page)?;
+        }
+        self.file.sync()?;
+        // Only once the pages are written, so that no read started before
+        // can cache what they replaced
+        self.cache.clear();
+        Ok(())
+    }
+
+    /// Drops every page written since the last commit
+    pub fn rollback(&mut self) {
+        self.dirty.clear();
+    }
+
+    /// Reads a page from the dirty pages, the WAL, the page cache or the
+    /// database file
+    fn read(&mut self, page_num: u32) -> Result<Vec<u8>> {
+        Ok(self.read_from(page_num, true)?.into_vec())
+    }
+
+    /// Reads a page as `read` does, skipping the page cache unless `cached`
+    /// is set
+    fn read_from(&mut self, page_num: u32, cached: bool) -> Result<Fetched> {
+        if page_num == 0 {
+            return Err(anyhow!("Invalid page number: 0"));
+        }
+        if self.pinning {
+            self.cache.pin(page_num);
+        }
+        if let Some(page) = self.dirty.get(&page_num) {
+            return Ok(Fetched::Owned(page.clone()));
+        }
+
+        if let Some(wal) = &mut self.wal {
+            if let Some(frame) = wal.find_frame(page_num)? {
+                debug!("Reading page {} from WAL frame {}", page_num, frame);
+                let mut page = vec![0; self.page_size as usize];
+                wal.read_frame(frame, &mut page)?;
+                self.pages_read += 1;
+                self.bytes_read += page.len() as u64;
+                // Frames are scattered through the WAL, so no frame read is
+                // sequential
+                self.non_sequential_reads += 1;
+                self.last_page = None;
+                return Ok(Fetched::Owned(self.decode(page_num, page)?));
+            }
+        }
+
+        if cached {
+            if let Some(page) = self.cache.get(page_num) {
+                self.page_cache_hits += 1;
+                self.last_page = Some(page_num);
+                return Ok(Fetched::Shared(page));
+            }
+        }
+        let generation = self.cache.generation();
+
+        let page_size = self.page_size as usize;
+        // Pages read ahead before a commit by another connection are stale
+        let ahead = self
+            .ahead
+            .as_ref()
+            .filter(|&&(_, _, read_in)| read_in == generation);
+        if let Some((first, pages, _)) = ahead {
+            let start = page_num.checked_sub(*first).map(|i| i as usize * page_size);
+            if let Some(bytes) = start.and_then(|start| pages.get(start..start + page_size)) {
+                let page = bytes.to_vec();
+                self.pages_read += 1;
+                self.last_page = Some(page_num);
+                return self.decode_and_cache(page_num, page, generation);
+            }
+        }
+
+        // Calculate page offset
+        let offset = ((page_num - 1) as u64) * (self.page_size as u64);
+        debug!("Seeking to offset: {} for page {}", offset, page_num);
+
+        // Verify file length
+        let file_len = self.file.len()?;
+        if offset >= file_len {
+            return Err(anyhow!(
+                "Page offset {} exceeds file length {}",
+                offset,
+                file_len
+            ));
+        }
+
+        let sequential = self.last_page.map_or(false, |last| last + 1 == page_num);
+
+        // A page the file can share needs no copy, and no pages read ahead
+        let shared = match &self.codec {
+            None => self.file.read_shared_at(offset, page_size)?,
+            Some(_) => None,
+        };
+        if let Some(page) = shared {
+            self.pages_read += 1;
+            self.bytes_read += page.len() as u64;
+            if !sequential {
+                self.non_sequential_reads += 1;
+            }
+            self.last_page = Some(page_num);
+            self.check_checksum(page_num, &page, generation)?;
+            self.cache.insert(generation, page_num, page.clone());
+            return Ok(Fetched::Shared(page));
+        }
+
+        // Read the page, and the pages after it if a scan is reading in order
+        let mut page = vec![0; page_size];
+        let available = ((file_len - offset) / self.page_size as u64).max(1);
+        let count = match self.sequential && sequential {
+            true => available.min(1 + self.read_ahead as u64) as usize,
+            false => 1,
+        };
+        if count > 1 {
+            debug!("Reading {} pages ahead of page {}", count - 1, page_num);
+            let mut pages = vec![0; count * page_size];
+            self.file.read_exact_at(offset, &mut pages)?;
+            page.copy_from_slice(&pages[..page_size]);
+            self.bytes_read += pages.len() as u64;
+            self.ahead = Some((page_num, pages, generation));
+        } else {
+            self.file.read_exact_at(offset, &mut page)?;
+            self.bytes_read += page.len() as u64;
+        }
+        self.pages_read += 1;
+        if !sequential {
+            self.non_sequential_reads += 1;
+        }
+        self.last_page = Some(page_num);
+
+        self.decode_and_cache(page_num, page, generation)
+    }
+
+    /// Decodes a page freshly read from the file and caches it, unless the
+    /// cache has been dropped since `generation`
+    fn decode_and_cache(
+        &mut self,
+        page_num: u32,
+        page: Vec<u8>,
+        generation: u64,
+    ) -> Result<Fetched> {
+        self.check_checksum(page_num, &page, generation)?;
+        let page: SharedPage = self.decode(page_num, page)?.into();
+        self.cache.insert(generation, page_num, page.clone());
+        Ok(Fetched::Shared(page))
+    }
+
+    /// Checks a page freshly read from the file against the checksum taken
+    /// when it was first read, or takes one, while checksums are on
+    ///
+    /// Checksums hold for one generation of the page cache. A commit starts a
+    /// new one both before and after writing, so a read that overlapped the
+    /// writes, and may have seen a page half written, is not checked.
+    fn check_checksum(&mut self, page_num: u32, page: &[u8], generation: u64) -> Result<()> {
+        if self.verification != PageVerification::Checksums || self.cache.generation() != generation
+        {
+            return Ok(());
+        }
+        let checksums = &mut self.checksums;
+        if checksums.generation != generation {
+            checksums.pages.clear();
+            checksums.generation = generation;
+        }
+        let checksum = wal_checksum(false, page, (0, 0));
+        let first = *checksums.pages.entry(page_num).or_insert(checksum);
+        if first != checksum {
+            return Err(corrupt(0, "page changed since it was first read")).on_page(page_num);
+        }
+        Ok(())
+    }
+
+    /// Runs the codec, if any, over a freshly read page
+    fn decode(&self, page_num: u32, mut page: Vec<u8>) -> Result<Vec<u8>> {
+        if let Some(codec) = &self.codec {
+            codec.decode(page_num, &mut page)?;
+        }
+        Ok(page)
+    }
+}
+
+/// A page as read: shared with the page cache, or a copy of its own
+enum Fetched {
+    Shared(SharedPage),
+    Owned(Vec<u8>),
+}
+
+impl Fetched {
+    fn into_vec(self) -> Vec<u8> {
+        match self {
+            Fetched::Shared(page) => page.to_vec(),
+            Fetched::Owned(page) => page,
+        }
+    }
+
+    fn into_shared(self) -> SharedPage {
+        match self {
+            Fetched::Shared(page) => page,
+            Fetched::Owned(page) => page.into(),
+        }
+    }
+}
+
+impl Deref for Fetched {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            Fetched::Shared(page) => page,
+            Fetched::Owned(page) => page,
+        }
+    }
+}
+
+/// A pager that several threads can read pages through at once
+pub struct SharedPager<'a> {
+    pager: Mutex<&'a mut Pager>,
+}
+
+impl<'a> SharedPager<'a> {
+    /// Lends `pager` out until the shared pager is dropped
+    pub fn new(pager: &'a mut Pager) -> Self {
+        Self {
+            pager: Mutex::new(pager),
+        }
+    }
+
+    /// Reads a page, waiting for any read in progress on another thread
+    pub fn read_page(&self, page_num: u32) -> Result<Vec<u8>> {
+        let mut pager = self
+            .pager
+            .lock()
+            .map_err(|_| anyhow!("a thread panicked while reading a page"))?;
+        pager.read_page(page_num)
+    }
+
+    /// Reads a B-tree page as [`Pager::read_btree_page`] does, waiting for any
+    /// read in progress on another thread
+    pub fn read_btree_page(&self, page_num: u32) -> Result<BTreePage> {
+        let mut pager = self
+            .pager
+            .lock()
+            .map_err(|_| anyhow!("a thread panicked while reading a page"))?;
+        pager.read_btree_page(page_num)
+    }
+
+    /// Reads a page as [`Pager::read_shared_page`] does, waiting for any read
+    /// in progress on another thread
+    pub fn read_shared_page(&self, page_num: u32) -> Result<SharedPage> {
+        let mut pager = self
+            .pager
+            .lock()
+            .map_err(|_| anyhow!("a thread panicked while reading a page"))?;
+        pager.read_shared_page(page_num)
+    }
+}
@@ -0,0 +1,105 @@
+//! Pointer-Map Pages
+//!
+//! Databases with auto-vacuum or incremental vacuum enabled, marked by a
+//! non-zero largest root page in the database header, keep pointer-map pages
+//! interleaved with the rest of the file. They record the parent of every
+//! other page so that pages can be moved when the file is shrunk. Pointer-map
+//! pages belong to no B-tree, so the pager refuses to hand them out as
+//! ordinary pages and they are read only through [`SQLiteDatabase::ptrmap_entry`].
+//!
+//! ## Layout
+//!
+//! The first pointer-map page is page 2. Each one holds `usable_size / 5`
+//! entries describing the pages that directly follow it, and the next
+//! pointer-map page comes right after the last page it describes. A
+//! pointer-map page that would land on the lock-byte page moves to the page
+//! after it.
+//!
+//! Each entry is 5 bytes:
+//!
+//! - Byte 0: Page type (see [`PtrmapType`])
+//! - Bytes 1-4: Parent page number (big-endian), 0 for root and free pages
+
+use crate::sqlite::core::checked;
+use crate::sqlite::core::error::{corrupt, CorruptPage};
+use crate::sqlite::storage::db::SQLiteDatabase;
+use crate::sqlite::storage::pager::lock_byte_page;
+use anyhow::{anyhow, Result};
+
+/// Size of one pointer-map entry in bytes
+const ENTRY_SIZE: usize = 5;
+
+/// What a page described by a pointer-map entry is used for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PtrmapType {
+    /// Root page of a B-tree; the parent is 0
+    RootPage,
+    /// Page on the freelist; the parent is 0
+    FreePage,
+    /// First page of an overflow chain; the parent is the B-tree page holding
+    /// the cell
+    Overflow1,
+    /// Later page of an overflow chain; the parent is the previous overflow page
+    Overflow2,
+    /// Non-root B-tree page; the parent is its parent B-tree page
+    BTree,
+}
+
+/// The pointer-map entry of one page
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PtrmapEntry {
+    pub page_type: PtrmapType,
+    pub parent: u32,
+}
+
+/// Returns the pointer-map page holding the entry for `page_num`, or None for
+/// page 1, which has no entry
+///
+/// For a pointer-map page this is the page itself.
+pub fn ptrmap_page_for(page_num: u32, usable_size: usize, page_size: u32) -> Option<u32> {
+    if page_num < 2 {
+        return None;
+    }
+    let pages_per_map = (usable_size / ENTRY_SIZE) as u32 + 1;
+    let mut ptrmap_page = (page_num - 2) / pages_per_map * pages_per_map + 2;
+    if ptrmap_page == lock_byte_page(page_size) {
+        ptrmap_page += 1;
+    }
+    Some(ptrmap_page)
+}
+
+/// Returns true if `page_num` is a pointer-map page
+pub fn is_ptrmap_page(page_num: u32, usable_size: usize, page_size: u32) -> bool {
+    ptrmap_page_for(page_num, usable_size, page_size) == Some(page_num)
+}
+
+impl SQLiteDatabase {
+    /// Reads the pointer-map entry of a page, or returns None if the database
+    /// has no pointer map
+    pub fn ptrmap_entry(&mut self, page_num: u32) -> Result<Option<PtrmapEntry>> {
+        if !self.header.is_auto_vacuum() {
+            return Ok(None);
+        }
+        let usable_size = self.usable_size()?;
+        let page_size = self.pager.page_size();
+        let ptrmap_page = ptrmap_page_for(page_num, usable_size, page_size)
+            .filter(|&ptrmap_page| ptrmap_page != page_num)
+            .ok_or_else(|| anyhow!("page {} has no pointer-map entry", page_num))?;
+
+        let page = self.pager.read_ptrmap_page(ptrmap_page)?;
+        let offset = ENTRY_SIZE * (page_num - ptrmap_page - 1) as usize;
+        let page_type = match checked::byte(&page, offset).on_page(ptrmap_page)? {
+            1 => PtrmapType::RootPage,
+            2 => PtrmapType::FreePage,
+            3 => PtrmapType::Overflow1,
+            4 => PtrmapType::Overflow2,
+            5 => PtrmapType::BTree,
+            t => {
+                return Err(corrupt(offset, format!("invalid pointer-map type: {}", t)))
+                    .on_page(ptrmap_page)
+            }
+        };
+        let parent = checked::be_u32(&page, offset + 1).on_page(ptrmap_page)?;
+        Ok(Some(PtrmapEntry { page_type, parent }))
+    }
+}
@@ -0,0 +1,105 @@
+//! Free-Space Report
+//!
+//! Adds up the free space inside the B-tree pages of every table and index, as
+//! broken down by [`PageFreeSpace`]: the unallocated gap between the cell
+//! pointers and the cell content, the freeblocks released by deleted cells and
+//! the fragmented bytes too small to be freeblocks. Pages on the freelist are
+//! not part of any object and are covered by [`Freelist`] instead.
+//!
+//! Pages are assigned to objects with the [`PageMap`], so the report also
+//! covers sqlite_schema and the automatic indexes.
+//!
+//! [`Freelist`]: crate::sqlite::storage::freelist::Freelist
+//! [`PageMap`]: crate::sqlite::storage::pagemap::PageMap
+
+use crate::sqlite::core::freespace::PageFreeSpace;
+use crate::sqlite::storage::db::SQLiteDatabase;
+use crate::sqlite::storage::pagemap::PageKind;
+use anyhow::Result;
+use std::collections::HashMap;
+use std::fmt::Display;
+
+/// Free space in the B-tree pages of one table or index
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ObjectFreeSpace {
+    /// Name of the table or index, as recorded in sqlite_schema
+    pub name: String,
+    /// Free space of each page of the B-tree, in page order
+    pub pages: Vec<(u32, PageFreeSpace)>,
+}
+
+impl ObjectFreeSpace {
+    /// Bytes in the unallocated gaps of all pages
+    pub fn unallocated(&self) -> usize {
+        self.pages.iter().map(|(_, page)| page.unallocated).sum()
+    }
+
+    /// Bytes in the freeblocks of all pages
+    pub fn freeblock_bytes(&self) -> usize {
+        self.pages
+            .iter()
+            .map(|(_, page)| page.freeblock_bytes())
+            .sum()
+    }
+
+    /// Number of freeblocks on all pages
+    pub fn freeblocks(&self) -> usize {
+        self.pages
+            .iter()
+            .map(|(_, page)| page.freeblocks.len())
+            .sum()
+    }
+
+    /// Fragmented bytes on all pages
+    pub fn fragmented_bytes(&self) -> usize {
+        self.pages
+            .iter()
+            .map(|(_, page)| page.fragmented_bytes as usize)
+            .sum()
+    }
+
+    /// Total free bytes on all pages
+    pub fn total(&self) -> usize {
+        self.pages.iter().map(|(_, page)| page.total()).sum()
+    }
+}
+
+impl Display for ObjectFreeSpace {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}: {} pages, {} free bytes ({} unallocated, {} in {} freeblocks, {} fragmented)",
+            self.name,
+            self.pages.len(),
+            self.total(),
+            self.unallocated(),
+            self.freeblock_bytes(),
+            self.freeblocks(),
+            self.fragmented_bytes()
+        )
+    }
+}
+
+impl SQLiteDatabase {
+    /// Reports the free space in the B-tree pages of every object, in the order
+    /// of their first page
+    pub fn free_space_report(&mut self) -> Result<Vec<ObjectFreeSpace>> {
+        let mut objects: Vec<ObjectFreeSpace> = Vec::new();
+        let mut positions: HashMap<String, usize> = HashMap::new();
+        for (page_num, kind) in self.page_map()?.iter() {
+            let PageKind::BTree { object, .. } = kind else {
+                continue;
+            };
+            let space = self.page_free_space(page_num)?;
+            let position = *positions.entry(object.clone()).or_insert_with(|| {
+                objects.push(ObjectFreeSpace {
+                    name: object.clone(),
+                    pages: Vec::new(),
+                });
+                objects.len() - 1
+            });
+            objects[position].pages.push((page_num, space));
+        }
+        Ok(objects)
+    }
+}
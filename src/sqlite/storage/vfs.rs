@@ -0,0 +1,361 @@
+//! Virtual File System Layer
+//!
+//! The pager reads pages through the `DatabaseFile` trait rather than from a
+//! `std::fs::File` directly, so alternative backings (compressed containers,
+//! in-memory buffers, ...) can present ordinary database bytes to the layers above.
+//!
+//! Writing is optional: a backing that cannot be written keeps the default
+//! `write_all_at`, which refuses, and the database stays read-only.
+//!
+//! Files other processes can open also take SQLite's locks while a commit
+//! writes to them: SHARED, then RESERVED while the rollback journal is
+//! written, then EXCLUSIVE while pages are written in place. They are POSIX
+//! locks on the bytes SQLite uses, at offset 1GiB, so they exclude the
+//! `sqlite3` shell and other SQLite connections as well as this crate's.
+//!
+//! [`MemoryFile`] keeps the whole image in memory, for `:memory:` databases and
+//! databases opened from bytes. [`ReaderFile`] reads the image from any
+//! seekable reader, read-only.
+
+use crate::sqlite::core::shared_page::SharedPage;
+use anyhow::{anyhow, Result};
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::sync::{Mutex, RwLock};
+
+/// Offset of the byte locked while a connection waits for EXCLUSIVE
+const PENDING_BYTE: u64 = 0x4000_0000;
+
+/// Offset of the byte locked by the one connection holding RESERVED
+const RESERVED_BYTE: u64 = PENDING_BYTE + 1;
+
+/// Offset of the bytes readers lock shared, and EXCLUSIVE locks whole
+const SHARED_FIRST: u64 = PENDING_BYTE + 2;
+
+/// Number of bytes from `SHARED_FIRST` locked for SHARED and EXCLUSIVE
+const SHARED_SIZE: u64 = 510;
+
+/// Levels of SQLite's lock on a database file, each taken while holding the
+/// one before it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileLock {
+    /// Held while reading; any number of connections may hold it
+    Shared,
+    /// Held by the one connection about to write, while others still read
+    Reserved,
+    /// Held while writing to the file, with no other connection reading
+    Exclusive,
+}
+
+/// Random-access byte source holding a database image
+///
+/// Every access names its offset and goes through `&self`, so there is no
+/// shared file position to fight over. Files must be `Send` and `Sync` so that
+/// one file can serve every connection cloned from a database, and the
+/// workers of a parallel scan, on several threads at once.
+pub trait DatabaseFile: Send + Sync {
+    /// Fills `buf` with the bytes starting at `offset`
+    fn read_exact_at(&self, offset: u64, buf: &mut [u8]) -> Result<()>;
+
+    /// Returns the `len` bytes starting at `offset` without copying them, if
+    /// the file holds them in memory it can share
+    ///
+    /// Files that cannot share their bytes keep the default, which returns
+    /// none, and are read with `read_exact_at` instead.
+    fn read_shared_at(&self, offset: u64, len: usize) -> Result<Option<SharedPage>> {
+        let _ = (offset, len);
+        Ok(None)
+    }
+
+    /// Returns the logical size of the database image in bytes
+    fn len(&self) -> Result<u64>;
+
+    /// Returns true if the database image is empty
+    fn is_empty(&self) -> Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    /// Writes `buf` at `offset`, growing the image if it ends past the end
+    fn write_all_at(&self, offset: u64, buf: &[u8]) -> Result<()> {
+        let _ = (offset, buf);
+        Err(anyhow!("attempt to write a readonly database"))
+    }
+
+    /// Flushes everything written so far to stable storage
+    fn sync(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Cuts the image down to its first `len` bytes
+    fn set_len(&self, len: u64) -> Result<()> {
+        let _ = len;
+        Err(anyhow!("attempt to write a readonly database"))
+    }
+
+    /// Takes the lock of the given level, one above the level held, without
+    /// waiting, returning false if another connection holds a conflicting
+    /// lock
+    ///
+    /// Files no other connection can reach keep the default, which always
+    /// succeeds.
+    fn lock(&self, level: FileLock) -> Result<bool> {
+        let _ = level;
+        Ok(true)
+    }
+
+    /// Drops every lock taken with `lock`
+    fn unlock(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// A POSIX lock on a range of bytes of a file
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum ByteLock {
+    Shared,
+    Exclusive,
+    Unlocked,
+}
+
+/// Locks `len` bytes of `file` from `offset` without waiting, returning false
+/// if another connection holds a conflicting lock
+///
+/// On Linux the lock is taken on the open file description instead of for
+/// the whole process, as closing any other descriptor for the file would drop
+/// a process-wide POSIX lock; the two kinds of lock still conflict with each
+/// other. Locks are released when the file is closed.
+#[cfg(unix)]
+pub(crate) fn lock_bytes(file: &File, offset: u64, len: u64, lock: ByteLock) -> Result<bool> {
+    use std::os::unix::io::AsRawFd;
+
+    #[cfg(target_os = "linux")]
+    const SET_LOCK: libc::c_int = libc::F_OFD_SETLK;
+    #[cfg(not(target_os = "linux"))]
+    const SET_LOCK: libc::c_int = libc::F_SETLK;
+
+    // SAFETY: flock is plain data, for which all zeroes is a valid value
+    let mut request: libc::flock = unsafe { std::mem::zeroed() };
+    request.l_type = match lock {
+        ByteLock::Shared => libc::F_RDLCK,
+        ByteLock::Exclusive => libc::F_WRLCK,
+        ByteLock::Unlocked => libc::F_UNLCK,
+    } as _;
+    request.l_whence = libc::SEEK_SET as _;
+    request.l_start = offset as _;
+    request.l_len = len as _;
+    // SAFETY: the descriptor is open for as long as `file` is borrowed, and
+    // `request` outlives the call
+    if unsafe { libc::fcntl(file.as_raw_fd(), SET_LOCK, &request) } == 0 {
+        return Ok(true);
+    }
+    let error = std::io::Error::last_os_error();
+    match error.raw_os_error() {
+        Some(libc::EACCES) | Some(libc::EAGAIN) => Ok(false),
+        _ => Err(error.into()),
+    }
+}
+
+/// Takes no lock where POSIX locks are not available, always succeeding
+#[cfg(not(unix))]
+pub(crate) fn lock_bytes(_file: &File, _offset: u64, _len: u64, _lock: ByteLock) -> Result<bool> {
+    Ok(true)
+}
+
+/// Reads into `buf` from `offset` without moving the file position
+#[cfg(unix)]
+fn read_at(file: &File, buf: &mut [u8], offset: u64) -> std::io::Result<usize> {
+    std::os::unix::fs::FileExt::read_at(file, buf, offset)
+}
+
+/// Writes `buf` at `offset` without relying on the file position
+#[cfg(unix)]
+fn write_at(file: &File, buf: &[u8], offset: u64) -> std::io::Result<usize> {
+    std::os::unix::fs::FileExt::write_at(file, buf, offset)
+}
+
+/// Reads into `buf` from `offset`; the file position moves, but no read
+/// depends on it
+#[cfg(windows)]
+fn read_at(file: &File, buf: &mut [u8], offset: u64) -> std::io::Result<usize> {
+    std::os::windows::fs::FileExt::seek_read(file, buf, offset)
+}
+
+/// Writes `buf` at `offset`; the file position moves, but no write depends
+/// on it
+#[cfg(windows)]
+fn write_at(file: &File, buf: &[u8], offset: u64) -> std::io::Result<usize> {
+    std::os::windows::fs::FileExt::seek_write(file, buf, offset)
+}
+
+impl DatabaseFile for File {
+    fn read_exact_at(&self, offset: u64, buf: &mut [u8]) -> Result<()> {
+        let mut filled = 0;
+        while filled < buf.len() {
+            match read_at(self, &mut buf[filled..], offset + filled as u64) {
+                Ok(0) => {
+                    return Err(anyhow!(
+                        "Partial read: got {} bytes, expected {}",
+                        filled,
+                        buf.len()
+                    ))
+                }
+                Ok(n) => filled += n,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(())
+    }
+
+    fn len(&self) -> Result<u64> {
+        Ok(self.metadata()?.len())
+    }
+
+    fn write_all_at(&self, offset: u64, buf: &[u8]) -> Result<()> {
+        let mut written = 0;
+        while written < buf.len() {
+            match write_at(self, &buf[written..], offset + written as u64) {
+                Ok(0) => return Err(anyhow!("failed to write the whole buffer")),
+                Ok(n) => written += n,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(())
+    }
+
+    fn sync(&self) -> Result<()> {
+        Ok(self.sync_data()?)
+    }
+
+    fn set_len(&self, len: u64) -> Result<()> {
+        Ok(File::set_len(self, len)?)
+    }
+
+    fn lock(&self, level: FileLock) -> Result<bool> {
+        match level {
+            // PENDING is held for a moment so that no new reader starts while
+            // a writer waits for the readers before it to finish
+            FileLock::Shared => {
+                if !lock_bytes(self, PENDING_BYTE, 1, ByteLock::Shared)? {
+                    return Ok(false);
+                }
+                let locked = lock_bytes(self, SHARED_FIRST, SHARED_SIZE, ByteLock::Shared)?;
+                lock_bytes(self, PENDING_BYTE, 1, ByteLock::Unlocked)?;
+                Ok(locked)
+            }
+            FileLock::Reserved => lock_bytes(self, RESERVED_BYTE, 1, ByteLock::Exclusive),
+            FileLock::Exclusive => Ok(lock_bytes(self, PENDING_BYTE, 1, ByteLock::Exclusive)?
+                && lock_bytes(self, SHARED_FIRST, SHARED_SIZE, ByteLock::Exclusive)?),
+        }
+    }
+
+    fn unlock(&self) -> Result<()> {
+        lock_bytes(self, PENDING_BYTE, 2 + SHARED_SIZE, ByteLock::Unlocked)?;
+        Ok(())
+    }
+}
+
+/// A database image held in memory, which grows as pages are written past its
+/// end and is gone once dropped
+#[derive(Debug, Default)]
+pub struct MemoryFile {
+    data: RwLock<Vec<u8>>,
+}
+
+impl MemoryFile {
+    /// Wraps the bytes of a database image
+    pub fn new(data: Vec<u8>) -> Self {
+        Self {
+            data: RwLock::new(data),
+        }
+    }
+}
+
+/// A database image read from a seekable reader, such as a cursor over bytes
+/// or a file opened elsewhere; it cannot be written
+///
+/// The reader has a single position, so reads take turns.
+pub struct ReaderFile<R> {
+    reader: Mutex<R>,
+}
+
+impl<R: Read + Seek + Send> ReaderFile<R> {
+    /// Wraps a reader positioned anywhere in the image
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader: Mutex::new(reader),
+        }
+    }
+}
+
+impl<R: Read + Seek + Send> DatabaseFile for ReaderFile<R> {
+    fn read_exact_at(&self, offset: u64, buf: &mut [u8]) -> Result<()> {
+        let mut reader = self
+            .reader
+            .lock()
+            .map_err(|_| anyhow!("a thread panicked while reading the database"))?;
+        reader.seek(SeekFrom::Start(offset))?;
+        reader.read_exact(buf).map_err(|e| {
+            anyhow!(
+                "Partial read: {} bytes at offset {}: {}",
+                buf.len(),
+                offset,
+                e
+            )
+        })
+    }
+
+    fn len(&self) -> Result<u64> {
+        let mut reader = self
+            .reader
+            .lock()
+            .map_err(|_| anyhow!("a thread panicked while reading the database"))?;
+        Ok(reader.seek(SeekFrom::End(0))?)
+    }
+}
+
+impl DatabaseFile for MemoryFile {
+    fn read_exact_at(&self, offset: u64, buf: &mut [u8]) -> Result<()> {
+        let data = self
+            .data
+            .read()
+            .map_err(|_| anyhow!("a thread panicked while writing the database"))?;
+        let start = usize::try_from(offset)?;
+        let bytes = start
+            .checked_add(buf.len())
+            .and_then(|end| data.get(start..end))
+            .ok_or_else(|| {
+                anyhow!(
+                    "Partial read: {} bytes at offset {} of {}",
+                    buf.len(),
+                    offset,
+                    data.len()
+                )
+            })?;
+        buf.copy_from_slice(bytes);
+        Ok(())
+    }
+
+    fn len(&self) -> Result<u64> {
+        let data = self
+            .data
+            .read()
+            .map_err(|_| anyhow!("a thread panicked while writing the database"))?;
+        Ok(data.len() as u64)
+    }
+
+    fn write_all_at(&self, offset: u64, buf: &[u8]) -> Result<()> {
+        let mut data = self
+            .data
+            .write()
+            .map_err(|_| anyhow!("a thread panicked while writing the database"))?;
+        let start = usize::try_from(offset)?;
+        let end = start + buf.len();
+        if data.len() < end {
+            data.resize(end, 0);
+        }
+        data[start..end].copy_from_slice(buf);
+        Ok(())
+    }
+}
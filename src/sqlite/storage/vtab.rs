@@ -0,0 +1,117 @@
+//! Virtual Table Module API
+//!
+//! Virtual tables let Rust code expose arbitrary data sources as tables that can be
+//! queried through the SQL layer, following the shape of SQLite's virtual table
+//! interface.
+//!
+//! ## Query Lifecycle
+//!
+//! For every statement that reads a virtual table:
+//!
+//! 1. `best_index` is called with the constraints the query could push down: the
+//!    comparisons of a column with a literal ANDed together in its WHERE clause.
+//!    The table marks which constraints it will handle and picks an `idx_num`
+//!    plan.
+//! 2. `open` creates a cursor.
+//! 3. `filter` starts the scan with the chosen `idx_num` and the values of the
+//!    constraints the table asked for, in `argv_index` order.
+//! 4. `eof`/`column`/`rowid`/`next` iterate the rows. The rest of the WHERE
+//!    clause, and the constraints the table did not omit, are checked against
+//!    each row before it is projected, sorted, grouped or limited like the rows
+//!    of any other table.
+
+use crate::sqlite::core::value::Value;
+use anyhow::Result;
+
+/// Comparison operator of a constraint offered to `best_index`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConstraintOp {
+    Eq,
+    Gt,
+    Le,
+    Lt,
+    Ge,
+}
+
+/// A `column op value` constraint from the query
+#[derive(Debug, Clone)]
+pub struct IndexConstraint {
+    /// Column ordinal the constraint applies to
+    pub column: usize,
+    /// Comparison operator
+    pub op: ConstraintOp,
+    /// Whether the constraint can be used for this plan
+    pub usable: bool,
+}
+
+/// How the table wants a constraint delivered to `filter`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ConstraintUsage {
+    /// 1-based position of the constraint value in the `filter` arguments, or 0
+    /// if the value is not needed
+    pub argv_index: usize,
+    /// Set if the table fully enforces the constraint so the SQL layer can skip
+    /// re-checking it
+    pub omit: bool,
+}
+
+/// Input and output of `VirtualTable::best_index`
+#[derive(Debug, Clone, Default)]
+pub struct IndexInfo {
+    /// Constraints the SQL layer can push down (input)
+    pub constraints: Vec<IndexConstraint>,
+    /// One entry per constraint, filled in by the table (output)
+    pub constraint_usage: Vec<ConstraintUsage>,
+    /// Plan number passed back to `filter` (output)
+    pub idx_num: i32,
+    /// Estimated cost of the plan (output)
+    pub estimated_cost: f64,
+}
+
+impl IndexInfo {
+    /// Creates an index info for the given constraints
+    pub fn new(constraints: Vec<IndexConstraint>) -> Self {
+        let constraint_usage = vec![ConstraintUsage::default(); constraints.len()];
+        Self {
+            constraints,
+            constraint_usage,
+            idx_num: 0,
+            estimated_cost: f64::MAX,
+        }
+    }
+}
+
+/// A table whose rows come from Rust code instead of the database file
+///
+/// Tables must be `Send`, as the connection they are registered on may move
+/// to another thread.
+pub trait VirtualTable: Send {
+    /// Returns the column names of the table
+    fn columns(&self) -> Vec<String>;
+
+    /// Chooses a query plan for the given constraints
+    fn best_index(&self, _info: &mut IndexInfo) -> Result<()> {
+        Ok(())
+    }
+
+    /// Opens a new cursor over the table
+    fn open(&self) -> Result<Box<dyn VirtualTableCursor + '_>>;
+}
+
+/// A cursor iterating the rows of a virtual table
+pub trait VirtualTableCursor {
+    /// Starts a scan using the plan chosen by `best_index`
+    fn filter(&mut self, idx_num: i32, args: &[Value]) -> Result<()>;
+
+    /// Advances to the next row
+    fn next(&mut self) -> Result<()>;
+
+    /// Returns true once the cursor has moved past the last row
+    fn eof(&self) -> bool;
+
+    /// Returns the value of the given column for the current row
+    fn column(&self, index: usize) -> Result<Value>;
+
+    /// Returns the rowid of the current row
+    fn rowid(&self) -> Result<i64>;
+}
@@ -0,0 +1,657 @@
+//! Write-Ahead Log
+//!
+//! Databases in WAL mode keep recently committed pages in a separate `-wal` file
+//! until they are checkpointed back into the database file. A reader must prefer
+//! the newest committed copy of a page from the WAL over the database file.
+//! While it does, it keeps a reader slot of the wal-index locked, as described
+//! for [`Shm`], so that no checkpoint copies frames newer than its snapshot
+//! into the database file, and no writer starts the WAL over.
+//!
+//! ## WAL Header Format (32 bytes, big-endian)
+//!
+//! - Bytes 0-3: Magic number (0x377f0682 or 0x377f0683)
+//! - Bytes 4-7: File format version (3007000)
+//! - Bytes 8-11: Database page size
+//! - Bytes 12-15: Checkpoint sequence number
+//! - Bytes 16-19: Salt-1
+//! - Bytes 20-23: Salt-2
+//! - Bytes 24-27: Checksum-1
+//! - Bytes 28-31: Checksum-2
+//!
+//! ## Frame Header Format (24 bytes, big-endian)
+//!
+//! - Bytes 0-3: Page number
+//! - Bytes 4-7: Database size in pages after commit (non-zero only for commit frames)
+//! - Bytes 8-11: Salt-1 copied from the WAL header
+//! - Bytes 12-15: Salt-2 copied from the WAL header
+//! - Bytes 16-19: Checksum-1
+//! - Bytes 20-23: Checksum-2
+//!
+//! Each frame header is followed by one page of data. A frame is valid if its salts
+//! match the header and its cumulative checksum matches; only frames up to the last
+//! valid commit frame are visible to readers.
+//!
+//! ## Writing
+//!
+//! A transaction is appended after the last committed frame, one frame per
+//! page with the last marked as the commit frame, and the WAL is synced before
+//! the wal-index publishes the new frames to other connections. Frames past
+//! the last commit are left over from a transaction that never finished and
+//! are overwritten. A WAL without committed frames is started over with a new
+//! header, whose salts keep frames of the earlier generation from passing for
+//! new ones. Checkpointing is left to SQLite.
+//!
+//! A commit holds SQLite's WAL write lock on the `-shm` file from before it
+//! checks the WAL until the wal-index publishes its frames, so no other writer
+//! or checkpoint can append to the WAL or start it over meanwhile. Once it has
+//! the lock, a commit is still refused if the WAL or the wal-index changed
+//! since this connection read them, as its pages would be based on a stale
+//! snapshot. A lock held by another connection is not waited for: the commit
+//! fails with "database is locked", as SQLite's do without a busy timeout.
+
+use crate::sqlite::query::functions::Rng;
+use crate::sqlite::storage::vfs::DatabaseFile;
+use crate::sqlite::storage::wal_index::{Shm, WalIndex};
+use anyhow::{anyhow, Result};
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::path::{Path, PathBuf};
+use tracing::debug;
+
+/// Magic number of a WAL whose checksums use little-endian words; the low bit
+/// is set for big-endian ones
+const WAL_MAGIC: u32 = 0x377f0682;
+/// WAL file format version
+const WAL_VERSION: u32 = 3007000;
+
+/// Computes the WAL checksum of `data` continuing from `seed`
+///
+/// `data` is interpreted as pairs of 32-bit words in the given byte order; its
+/// length must be a multiple of 8.
+pub fn wal_checksum(big_endian: bool, data: &[u8], seed: (u32, u32)) -> (u32, u32) {
+    let (mut s1, mut s2) = seed;
+    for words in data.chunks_exact(8) {
+        let (x0, x1) = if big_endian {
+            (
+                u32::from_be_bytes([words[0], words[1], words[2], words[3]]),
+                u32::from_be_bytes([words[4], words[5], words[6], words[7]]),
+            )
+        } else {
+            (
+                u32::from_le_bytes([words[0], words[1], words[2], words[3]]),
+                u32::from_le_bytes([words[4], words[5], words[6], words[7]]),
+            )
+        };
+        s1 = s1.wrapping_add(x0).wrapping_add(s2);
+        s2 = s2.wrapping_add(x1).wrapping_add(s1);
+    }
+    (s1, s2)
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes([
+        bytes[offset],
+        bytes[offset + 1],
+        bytes[offset + 2],
+        bytes[offset + 3],
+    ])
+}
+
+/// The header at the start of a WAL file
+#[derive(Debug, Clone)]
+pub struct WalHeader {
+    /// Magic number; the low bit selects big-endian checksums
+    pub magic: u32,
+    /// File format version
+    pub format_version: u32,
+    /// Database page size
+    pub page_size: u32,
+    /// Checkpoint sequence number
+    pub checkpoint_seq: u32,
+    /// Salt values copied into every valid frame
+    pub salt: (u32, u32),
+    /// Checksum of the first 24 bytes of the header
+    pub checksum: (u32, u32),
+}
+
+impl WalHeader {
+    /// Size of the WAL header in bytes
+    pub const SIZE: usize = 32;
+
+    /// Parses a WAL header from raw bytes
+    pub fn parse(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() < Self::SIZE {
+            return Err(anyhow!("WAL header too short"));
+        }
+
+        let header = Self {
+            magic: read_u32(bytes, 0),
+            format_version: read_u32(bytes, 4),
+            page_size: read_u32(bytes, 8),
+            checkpoint_seq: read_u32(bytes, 12),
+            salt: (read_u32(bytes, 16), read_u32(bytes, 20)),
+            checksum: (read_u32(bytes, 24), read_u32(bytes, 28)),
+        };
+
+        if header.magic & !1 != WAL_MAGIC {
+            return Err(anyhow!("Invalid WAL magic number: {:#x}", header.magic));
+        }
+        if wal_checksum(header.big_endian_checksum(), &bytes[..24], (0, 0)) != header.checksum {
+            return Err(anyhow!("WAL header checksum mismatch"));
+        }
+
+        Ok(header)
+    }
+
+    /// Creates the header of a new WAL, with checksums in the native byte order
+    pub fn new(page_size: u32, checkpoint_seq: u32, salt: (u32, u32)) -> Self {
+        let mut header = Self {
+            magic: WAL_MAGIC | cfg!(target_endian = "big") as u32,
+            format_version: WAL_VERSION,
+            page_size,
+            checkpoint_seq,
+            salt,
+            checksum: (0, 0),
+        };
+        header.checksum =
+            wal_checksum(header.big_endian_checksum(), &header.encode()[..24], (0, 0));
+        header
+    }
+
+    /// Encodes the header in its on-disk layout
+    pub fn encode(&self) -> [u8; Self::SIZE] {
+        let mut bytes = [0u8; Self::SIZE];
+        let fields = [
+            self.magic,
+            self.format_version,
+            self.page_size,
+            self.checkpoint_seq,
+            self.salt.0,
+            self.salt.1,
+            self.checksum.0,
+            self.checksum.1,
+        ];
+        for (chunk, field) in bytes.chunks_exact_mut(4).zip(fields) {
+            chunk.copy_from_slice(&field.to_be_bytes());
+        }
+        bytes
+    }
+
+    /// Returns true if frame checksums are computed on big-endian words
+    pub fn big_endian_checksum(&self) -> bool {
+        self.magic & 1 == 1
+    }
+}
+
+/// The header preceding each page image in the WAL
+#[derive(Debug, Clone)]
+pub struct FrameHeader {
+    /// Page number the frame holds
+    pub page_num: u32,
+    /// Database size in pages for commit frames, otherwise zero
+    pub db_size: u32,
+    /// Salt values; must match the WAL header
+    pub salt: (u32, u32),
+    /// Cumulative checksum up to and including this frame
+    pub checksum: (u32, u32),
+}
+
+impl FrameHeader {
+    /// Size of a frame header in bytes
+    pub const SIZE: usize = 24;
+
+    /// Parses a frame header from raw bytes
+    pub fn parse(bytes: &[u8]) -> Self {
+        Self {
+            page_num: read_u32(bytes, 0),
+            db_size: read_u32(bytes, 4),
+            salt: (read_u32(bytes, 8), read_u32(bytes, 12)),
+            checksum: (read_u32(bytes, 16), read_u32(bytes, 20)),
+        }
+    }
+
+    /// Returns true if the frame ends a transaction
+    pub fn is_commit(&self) -> bool {
+        self.db_size != 0
+    }
+
+    /// Encodes the frame header in its on-disk layout
+    pub fn encode(&self) -> [u8; Self::SIZE] {
+        let mut bytes = [0u8; Self::SIZE];
+        let fields = [
+            self.page_num,
+            self.db_size,
+            self.salt.0,
+            self.salt.1,
+            self.checksum.0,
+            self.checksum.1,
+        ];
+        for (chunk, field) in bytes.chunks_exact_mut(4).zip(fields) {
+            chunk.copy_from_slice(&field.to_be_bytes());
+        }
+        bytes
+    }
+}
+
+/// A WAL file as of the last committed transaction
+pub struct Wal {
+    /// Path of the database the WAL belongs to
+    database: PathBuf,
+    /// Path of the WAL file
+    path: PathBuf,
+    /// Path of the wal-index
+    shm_path: PathBuf,
+    /// The wal-index file with this connection's locks on it, unless it
+    /// could not be opened for writing, or locks are not available
+    shm: Option<Shm>,
+    /// The WAL file, until the first write creates it
+    file: Option<File>,
+    /// Length of the WAL file when it was last read or written
+    file_len: u64,
+    /// The WAL header, unless the file is too short to hold one
+    header: Option<WalHeader>,
+    /// Database page size
+    page_size: u32,
+    /// Last frame visible to readers (1-based, 0 if none)
+    max_frame: u32,
+    /// Database size in pages as of `max_frame`
+    db_size: u32,
+    /// Cumulative checksum as of `max_frame`
+    checksum: (u32, u32),
+    /// Maps page numbers to frames: the `-shm` wal-index if it is consistent,
+    /// otherwise one rebuilt by scanning every frame of the WAL
+    index: WalIndex,
+}
+
+impl Wal {
+    /// Returns the WAL path for a database file
+    pub fn wal_path(database: &Path) -> PathBuf {
+        let mut path = database.as_os_str().to_owned();
+        path.push("-wal");
+        PathBuf::from(path)
+    }
+
+    /// Returns the wal-index path for a database file
+    pub fn shm_path(database: &Path) -> PathBuf {
+        let mut path = database.as_os_str().to_owned();
+        path.push("-shm");
+        PathBuf::from(path)
+    }
+
+    /// Opens the WAL belonging to `database`
+    ///
+    /// A missing WAL, or one too short to hold a header, has no frames; the
+    /// first commit creates it.
+    pub fn open(database: &Path, page_size: u32) -> Result<Self> {
+        let shm = Shm::open(&Self::shm_path(database), false)?;
+        Self::open_with_shm(database, page_size, shm)
+    }
+
+    /// Opens the WAL, locking a reader slot in the `-shm` file for the
+    /// snapshot if it is open
+    fn open_with_shm(database: &Path, page_size: u32, mut shm: Option<Shm>) -> Result<Self> {
+        // Locked before the WAL is read, so that it is not started over
+        // in between
+        let shared_index = match &mut shm {
+            Some(shm) => shm.begin_read()?,
+            None => WalIndex::open(&Self::shm_path(database))?,
+        };
+        let path = Self::wal_path(database);
+        let file = match OpenOptions::new().read(true).write(true).open(&path) {
+            Ok(file) => Some(file),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(_) => Some(File::open(&path)?),
+        };
+        let mut wal = Self {
+            database: database.to_path_buf(),
+            path,
+            shm_path: Self::shm_path(database),
+            shm,
+            file,
+            file_len: 0,
+            header: None,
+            page_size,
+            max_frame: 0,
+            db_size: 0,
+            checksum: (0, 0),
+            index: WalIndex::new(&WalHeader::new(page_size, 0, (0, 0))),
+        };
+        let Some(file) = &mut wal.file else {
+            return Ok(wal);
+        };
+        wal.file_len = file.len()?;
+        if wal.file_len < WalHeader::SIZE as u64 {
+            return Ok(wal);
+        }
+
+        let mut header_bytes = [0u8; WalHeader::SIZE];
+        file.read_exact_at(0, &mut header_bytes)?;
+        let header = WalHeader::parse(&header_bytes)?;
+        if header.page_size != page_size {
+            return Err(anyhow!(
+                "WAL page size {} does not match database page size {}",
+                header.page_size,
+                page_size
+            ));
+        }
+
+        match shared_index {
+            Some(index) if index.matches(&header) => {
+                debug!(
+                    "Using wal-index: max frame {}, backfilled {}",
+                    index.header.max_frame, index.checkpoint.backfill
+                );
+                wal.max_frame = index.header.max_frame;
+                wal.db_size = index.header.db_size;
+                wal.checksum = index.header.frame_checksum;
+                wal.index = index;
+                wal.header = Some(header);
+            }
+            _ => {
+                debug!("No usable wal-index, scanning WAL frames");
+                if let Some(shm) = &mut wal.shm {
+                    shm.end_read()?;
+                }
+                wal.scan_frames(header)?;
+            }
+        }
+
+        Ok(wal)
+    }
+
+    /// Opens the WAL again, for another connection with a snapshot of its own
+    pub fn reopen(&self) -> Result<Self> {
+        Self::open(&self.database, self.page_size)
+    }
+
+    /// Validates every frame and indexes the frames up to the last commit
+    fn scan_frames(&mut self, header: WalHeader) -> Result<()> {
+        let page_size = self.page_size as usize;
+        let frame_size = (FrameHeader::SIZE + page_size) as u64;
+        let frame_count = (self.file_len - WalHeader::SIZE as u64) / frame_size;
+        let big_endian = header.big_endian_checksum();
+
+        let mut index = WalIndex::new(&header);
+        let mut checksum = header.checksum;
+        self.checksum = header.checksum;
+        let mut frame = vec![0u8; FrameHeader::SIZE + page_size];
+        let file = self
+            .file
+            .as_mut()
+            .ok_or_else(|| anyhow!("WAL file is not open"))?;
+
+        for frame_num in 1..=frame_count as u32 {
+            file.read_exact_at(Self::frame_offset(self.page_size, frame_num), &mut frame)?;
+            let frame_header = FrameHeader::parse(&frame);
+            if frame_header.salt != header.salt || frame_header.page_num == 0 {
+                break;
+            }
+
+            checksum = wal_checksum(big_endian, &frame[..8], checksum);
+            checksum = wal_checksum(big_endian, &frame[FrameHeader::SIZE..], checksum);
+            if checksum != frame_header.checksum {
+                break;
+            }
+
+            index.append(frame_num, frame_header.page_num);
+            if frame_header.is_commit() {
+                self.max_frame = frame_num;
+                self.db_size = frame_header.db_size;
+                self.checksum = checksum;
+            }
+        }
+
+        debug!("Scanned WAL: {} committed frames", self.max_frame);
+        index.set_committed(self.max_frame, self.db_size, self.checksum);
+        self.index = index;
+        self.header = Some(header);
+        Ok(())
+    }
+
+    /// Byte offset of the frame header of a 1-based frame number
+    fn frame_offset(page_size: u32, frame_num: u32) -> u64 {
+        WalHeader::SIZE as u64
+            + (frame_num as u64 - 1) * (FrameHeader::SIZE as u64 + page_size as u64)
+    }
+
+    /// Returns the WAL header, unless the WAL has none yet
+    pub fn header(&self) -> Option<&WalHeader> {
+        self.header.as_ref()
+    }
+
+    /// Returns the database size in pages as seen through the WAL
+    pub fn db_size(&self) -> u32 {
+        self.db_size
+    }
+
+    /// Returns the newest visible frame holding the given page
+    pub fn find_frame(&self, page_num: u32) -> Result<Option<u32>> {
+        if self.max_frame == 0 {
+            return Ok(None);
+        }
+        self.index.find_frame(page_num)
+    }
+
+    /// Reads the page image stored in the given frame
+    pub fn read_frame(&mut self, frame_num: u32, page: &mut [u8]) -> Result<()> {
+        let offset = Self::frame_offset(self.page_size, frame_num) + FrameHeader::SIZE as u64;
+        let file = self
+            .file
+            .as_mut()
+            .ok_or_else(|| anyhow!("WAL file is not open"))?;
+        file.read_exact_at(offset, page)
+    }
+
+    /// Appends a transaction writing `pages`, after which the database is
+    /// `db_size` pages long, and publishes it in the wal-index
+    ///
+    /// SQLite's WAL write lock is held until the wal-index is published.
+    /// Where it cannot be taken, writing is refused while a `-shm` file shows
+    /// that other connections may have the database open.
+    pub fn commit(&mut self, pages: &BTreeMap<u32, Vec<u8>>, db_size: u32) -> Result<()> {
+        if self.shm.is_none() {
+            self.shm = Shm::open(&self.shm_path, true)?;
+        }
+        let shm = self.shm.take();
+        let result = match &shm {
+            Some(shm) => shm.lock_write().and_then(|()| {
+                let appended = self.append(pages, db_size, Some(shm));
+                let unlocked = shm.unlock_write();
+                appended.and(unlocked)
+            }),
+            None if self.shm_path.exists() => Err(anyhow!(
+                "database is locked: the wal-index cannot be locked on this platform"
+            )),
+            None => self.append(pages, db_size, None),
+        };
+        self.shm = shm;
+        result
+    }
+
+    /// Appends the frames of a transaction and publishes them in the
+    /// wal-index, holding the write lock on `shm` if there is one
+    fn append(
+        &mut self,
+        pages: &BTreeMap<u32, Vec<u8>>,
+        db_size: u32,
+        shm: Option<&Shm>,
+    ) -> Result<()> {
+        self.check_unchanged(shm)?;
+        if self.max_frame == 0 {
+            self.restart()?;
+        } else {
+            self.index.discard_after(self.max_frame);
+        }
+        let header = self
+            .header
+            .clone()
+            .ok_or_else(|| anyhow!("WAL has no header"))?;
+        let big_endian = header.big_endian_checksum();
+
+        let mut frames =
+            Vec::with_capacity(pages.len() * (FrameHeader::SIZE + self.page_size as usize));
+        let mut checksum = self.checksum;
+        for (i, (&page_num, page)) in pages.iter().enumerate() {
+            let mut frame_header = FrameHeader {
+                page_num,
+                db_size: if i + 1 == pages.len() { db_size } else { 0 },
+                salt: header.salt,
+                checksum: (0, 0),
+            };
+            checksum = wal_checksum(big_endian, &frame_header.encode()[..8], checksum);
+            checksum = wal_checksum(big_endian, page, checksum);
+            frame_header.checksum = checksum;
+            frames.extend_from_slice(&frame_header.encode());
+            frames.extend_from_slice(page);
+        }
+
+        let first_frame = self.max_frame + 1;
+        let offset = Self::frame_offset(self.page_size, first_frame);
+        debug!(
+            "Appending {} frames to the WAL at frame {}",
+            pages.len(),
+            first_frame
+        );
+        let file = self
+            .file
+            .as_mut()
+            .ok_or_else(|| anyhow!("WAL file is not open"))?;
+        file.write_all_at(offset, &frames)?;
+        file.sync()?;
+        self.file_len = file.len()?;
+
+        for (frame, &page_num) in (first_frame..).zip(pages.keys()) {
+            self.index.append(frame, page_num);
+        }
+        self.max_frame += pages.len() as u32;
+        self.db_size = db_size;
+        self.checksum = checksum;
+        self.index.set_committed(self.max_frame, db_size, checksum);
+        match shm {
+            Some(shm) => self.index.write(shm.file(), first_frame),
+            None => Ok(()),
+        }
+    }
+
+    /// Returns true if no other connection has written to the WAL since it was
+    /// read
+    pub fn is_current(&self) -> Result<bool> {
+        let file_len = match std::fs::metadata(&self.path) {
+            Ok(metadata) => metadata.len(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => 0,
+            Err(e) => return Err(e.into()),
+        };
+        if file_len != self.file_len {
+            return Ok(false);
+        }
+        if !self.index.is_shared() {
+            return Ok(true);
+        }
+        let index = match &self.shm {
+            Some(shm) => WalIndex::read(shm.file())?,
+            None => WalIndex::open(&self.shm_path)?,
+        };
+        Ok(index.map(|index| index.header).as_ref() == Some(&self.index.header))
+    }
+
+    /// Reads the WAL again, as of its newest commit
+    ///
+    /// The `-shm` file stays open, as closing it would drop the locks of
+    /// every connection in the process where they are process-wide.
+    pub fn reload(&mut self) -> Result<()> {
+        let shm = match self.shm.take() {
+            Some(shm) => Some(shm),
+            None => Shm::open(&self.shm_path, false)?,
+        };
+        *self = Self::open_with_shm(&self.database, self.page_size, shm)?;
+        Ok(())
+    }
+
+    /// Refuses to write if another connection has written to the WAL since it
+    /// was read, as its frames would be overwritten
+    ///
+    /// The WAL must be as long as it was and have the same salts, so it was
+    /// neither appended to nor started over. Frames past the last commit can
+    /// be overwritten without changing its length, so a wal-index describing
+    /// this WAL must also show the same last commit; one left over from an
+    /// earlier WAL says nothing about this one.
+    fn check_unchanged(&self, shm: Option<&Shm>) -> Result<()> {
+        let (file_len, header) = self.read_current_header()?;
+        let mut unchanged = file_len == self.file_len
+            && header.map(|header| header.salt) == self.header.as_ref().map(|header| header.salt);
+        if let (Some(shm), Some(header)) = (shm, &self.header) {
+            if let Some(index) = WalIndex::read(shm.file())? {
+                if index.matches(header) {
+                    unchanged &= index.header.describes_same_frames(&self.index.header);
+                }
+            }
+        }
+        if !unchanged {
+            return Err(anyhow!(
+                "database is locked: another connection has written to the WAL"
+            ));
+        }
+        Ok(())
+    }
+
+    /// Returns the length of the WAL file as it is now, and its header
+    /// unless it is too short to hold one
+    fn read_current_header(&self) -> Result<(u64, Option<WalHeader>)> {
+        let file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok((0, None)),
+            Err(e) => return Err(e.into()),
+        };
+        let file_len = file.len()?;
+        if file_len < WalHeader::SIZE as u64 {
+            return Ok((file_len, None));
+        }
+        let mut header_bytes = [0u8; WalHeader::SIZE];
+        file.read_exact_at(0, &mut header_bytes)?;
+        Ok((file_len, Some(WalHeader::parse(&header_bytes)?)))
+    }
+
+    /// Starts the WAL over with a new header, creating the file if needed
+    ///
+    /// As in SQLite, the checkpoint sequence number and the first salt are
+    /// incremented and the second salt is random.
+    fn restart(&mut self) -> Result<()> {
+        let mut rng = Rng::from_entropy();
+        let (checkpoint_seq, salt_1) = match &self.header {
+            Some(header) => (
+                header.checkpoint_seq.wrapping_add(1),
+                header.salt.0.wrapping_add(1),
+            ),
+            None => (0, rng.next_u64() as u32),
+        };
+        let header = WalHeader::new(
+            self.page_size,
+            checkpoint_seq,
+            (salt_1, rng.next_u64() as u32),
+        );
+        debug!(
+            "Starting WAL with salts {:#x} {:#x}",
+            header.salt.0, header.salt.1
+        );
+
+        if self.file.is_none() {
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .open(&self.path)?;
+            self.file = Some(file);
+        }
+        let file = self
+            .file
+            .as_mut()
+            .ok_or_else(|| anyhow!("WAL file is not open"))?;
+        file.write_all_at(0, &header.encode())?;
+        self.file_len = file.len()?;
+
+        self.checksum = header.checksum;
+        self.index = WalIndex::new(&header);
+        self.header = Some(header);
+        Ok(())
+    }
+}
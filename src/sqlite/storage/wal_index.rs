@@ -0,0 +1,640 @@
+//! WAL-Index (`-shm`) Parsing
+//!
+//! The wal-index is a shared-memory file maintained by the connections using a WAL
+//! database. It records how far the WAL is committed and checkpointed and holds hash
+//! tables mapping page numbers to frames, so a page lookup does not have to scan the
+//! WAL. Integers are in the host's native byte order, except for the salts which
+//! are copied verbatim from the WAL header.
+//!
+//! ## Layout
+//!
+//! The file is divided into 32KB blocks. Each block holds a page-number array of
+//! 4096 `u32` entries (one per frame) followed by a hash table of 8192 `u16` slots.
+//! The first block starts with the 136-byte header, which shortens its page-number
+//! array to 4062 entries.
+//!
+//! ## Header (136 bytes)
+//!
+//! - Bytes 0-47: Index header (`WalIndexHeader`)
+//! - Bytes 48-95: Second copy of the index header, used to detect torn writes
+//! - Bytes 96-135: Checkpoint information (`CheckpointInfo`)
+//!
+//! ## Appending Frames
+//!
+//! A writer records each new frame in the page-number array and the hash table
+//! of its block, clearing a block when its first frame is added, then rewrites
+//! the header with the new last frame. The second copy of the header is written
+//! first, so a reader that catches the write half done sees copies that differ
+//! and retries. An index that could not be read is rebuilt from the WAL
+//! instead, the way SQLite's recovery does, and written out in full.
+
+use crate::sqlite::storage::vfs::{lock_bytes, ByteLock, DatabaseFile};
+use crate::sqlite::storage::wal::{wal_checksum, WalHeader};
+use anyhow::{anyhow, Result};
+use std::fs::File;
+use std::path::Path;
+use tracing::debug;
+
+/// Size of the combined wal-index header in bytes
+const HEADER_SIZE: usize = 136;
+/// Size of one copy of the index header in bytes
+const INDEX_HEADER_SIZE: usize = 48;
+/// Size of one wal-index block in bytes
+const BLOCK_SIZE: usize = 32768;
+/// Number of page-number entries per block
+const PAGES_PER_BLOCK: u32 = 4096;
+/// Number of page-number entries in the first block
+const PAGES_IN_FIRST_BLOCK: u32 = PAGES_PER_BLOCK - (HEADER_SIZE / 4) as u32;
+/// Number of hash slots per block
+const HASH_SLOTS: usize = 8192;
+/// Expected value of `WalIndexHeader::version`
+const INDEX_VERSION: u32 = 3007000;
+/// Read mark of a reader slot nobody is using
+const READ_MARK_NOT_USED: u32 = 0xffffffff;
+/// Byte range of the lock bytes within the checkpoint information, which
+/// SQLite locks but never writes
+const LOCK_BYTES: std::ops::Range<usize> = 120..128;
+/// Lock byte held exclusively by the one connection allowed to append to the
+/// WAL
+const WRITE_LOCK: usize = LOCK_BYTES.start;
+/// Lock byte of the first reader slot; slot `i` is locked at `READ_LOCK + i`
+const READ_LOCK: usize = LOCK_BYTES.start + 3;
+/// Number of reader slots, each with a read mark
+const READERS: usize = 5;
+/// Lock byte every connection holds shared while it uses the wal-index, and
+/// the first one to open the database holds exclusively while it resets it
+const DMS_LOCK: usize = LOCK_BYTES.end;
+/// Offset of the first read mark in the wal-index
+const READ_MARKS: usize = 2 * INDEX_HEADER_SIZE + 4;
+/// Attempts at locking a reader slot before giving up
+const READ_ATTEMPTS: usize = 100;
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_ne_bytes([
+        bytes[offset],
+        bytes[offset + 1],
+        bytes[offset + 2],
+        bytes[offset + 3],
+    ])
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_ne_bytes([bytes[offset], bytes[offset + 1]])
+}
+
+fn write_u32(bytes: &mut [u8], offset: usize, value: u32) {
+    bytes[offset..offset + 4].copy_from_slice(&value.to_ne_bytes());
+}
+
+fn write_u16(bytes: &mut [u8], offset: usize, value: u16) {
+    bytes[offset..offset + 2].copy_from_slice(&value.to_ne_bytes());
+}
+
+/// The index header shared by all connections
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WalIndexHeader {
+    /// Wal-index format version
+    pub version: u32,
+    /// Counter incremented on every transaction
+    pub change: u32,
+    /// Non-zero once the header has been initialized
+    pub is_init: u8,
+    /// Non-zero if WAL checksums use big-endian words
+    pub big_endian_checksum: u8,
+    /// Database page size
+    pub page_size: u32,
+    /// Index of the last valid committed frame
+    pub max_frame: u32,
+    /// Database size in pages
+    pub db_size: u32,
+    /// Checksum of the last frame in the WAL
+    pub frame_checksum: (u32, u32),
+    /// Salt values copied from the WAL header
+    pub salt: (u32, u32),
+    /// Checksum over the first 40 bytes of this header
+    pub checksum: (u32, u32),
+}
+
+impl WalIndexHeader {
+    /// Returns true if both headers make the same frames of the same WAL
+    /// visible, however many transactions each has counted
+    pub fn describes_same_frames(&self, other: &Self) -> bool {
+        self.salt == other.salt
+            && self.max_frame == other.max_frame
+            && self.frame_checksum == other.frame_checksum
+    }
+
+    /// Parses one copy of the index header
+    fn parse(bytes: &[u8]) -> Self {
+        let page_size = read_u16(bytes, 14) as u32;
+        Self {
+            version: read_u32(bytes, 0),
+            change: read_u32(bytes, 8),
+            is_init: bytes[12],
+            big_endian_checksum: bytes[13],
+            // A stored page size of 1 means 65536 bytes
+            page_size: if page_size == 1 { 65536 } else { page_size },
+            max_frame: read_u32(bytes, 16),
+            db_size: read_u32(bytes, 20),
+            frame_checksum: (read_u32(bytes, 24), read_u32(bytes, 28)),
+            // Salts are copied byte-for-byte from the big-endian WAL header
+            salt: (
+                u32::from_be_bytes([bytes[32], bytes[33], bytes[34], bytes[35]]),
+                u32::from_be_bytes([bytes[36], bytes[37], bytes[38], bytes[39]]),
+            ),
+            checksum: (read_u32(bytes, 40), read_u32(bytes, 44)),
+        }
+    }
+
+    /// Writes one copy of the index header, recomputing its checksum
+    fn write(&mut self, bytes: &mut [u8]) {
+        bytes[..INDEX_HEADER_SIZE].fill(0);
+        write_u32(bytes, 0, self.version);
+        write_u32(bytes, 8, self.change);
+        bytes[12] = self.is_init;
+        bytes[13] = self.big_endian_checksum;
+        write_u16(
+            bytes,
+            14,
+            if self.page_size == 65536 {
+                1
+            } else {
+                self.page_size as u16
+            },
+        );
+        write_u32(bytes, 16, self.max_frame);
+        write_u32(bytes, 20, self.db_size);
+        write_u32(bytes, 24, self.frame_checksum.0);
+        write_u32(bytes, 28, self.frame_checksum.1);
+        bytes[32..36].copy_from_slice(&self.salt.0.to_be_bytes());
+        bytes[36..40].copy_from_slice(&self.salt.1.to_be_bytes());
+        let native_big_endian = cfg!(target_endian = "big");
+        self.checksum = wal_checksum(native_big_endian, &bytes[..40], (0, 0));
+        write_u32(bytes, 40, self.checksum.0);
+        write_u32(bytes, 44, self.checksum.1);
+    }
+}
+
+/// Checkpoint progress recorded in the wal-index
+#[derive(Debug, Clone)]
+pub struct CheckpointInfo {
+    /// Number of WAL frames already copied back into the database
+    pub backfill: u32,
+    /// Read marks of active readers
+    pub read_marks: [u32; 5],
+    /// Number of frames a checkpoint has attempted to backfill
+    pub backfill_attempted: u32,
+}
+
+impl CheckpointInfo {
+    fn parse(bytes: &[u8]) -> Self {
+        let mut read_marks = [0u32; 5];
+        for (i, mark) in read_marks.iter_mut().enumerate() {
+            *mark = read_u32(bytes, 4 + i * 4);
+        }
+        Self {
+            backfill: read_u32(bytes, 0),
+            read_marks,
+            backfill_attempted: read_u32(bytes, 32),
+        }
+    }
+
+    fn write(&self, bytes: &mut [u8]) {
+        write_u32(bytes, 0, self.backfill);
+        for (i, mark) in self.read_marks.iter().enumerate() {
+            write_u32(bytes, 4 + i * 4, *mark);
+        }
+        write_u32(bytes, 32, self.backfill_attempted);
+    }
+}
+
+/// Snapshot of a wal-index file
+pub struct WalIndex {
+    /// Raw wal-index contents
+    data: Vec<u8>,
+    /// The index header
+    pub header: WalIndexHeader,
+    /// Checkpoint progress
+    pub checkpoint: CheckpointInfo,
+    /// True if the index was built here rather than read from a `-shm` file,
+    /// so that it has to be written out in full
+    rebuilt: bool,
+}
+
+impl WalIndex {
+    /// Reads the wal-index at `path`
+    ///
+    /// Returns None if the file is missing or its header is not in a consistent
+    /// state (e.g. a writer is in the middle of updating it), in which case the
+    /// caller should fall back to scanning the WAL.
+    pub fn open(path: &Path) -> Result<Option<Self>> {
+        let data = match std::fs::read(path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Self::parse(data))
+    }
+
+    /// Reads the wal-index from an open `-shm` file, returning None if its
+    /// header is not in a consistent state
+    pub fn read(file: &dyn DatabaseFile) -> Result<Option<Self>> {
+        let mut data = vec![0; file.len()? as usize];
+        file.read_exact_at(0, &mut data)?;
+        Ok(Self::parse(data))
+    }
+
+    /// Parses a wal-index snapshot, returning None if its header is inconsistent
+    pub fn parse(data: Vec<u8>) -> Option<Self> {
+        if data.len() < HEADER_SIZE {
+            return None;
+        }
+
+        let first = &data[..INDEX_HEADER_SIZE];
+        let second = &data[INDEX_HEADER_SIZE..2 * INDEX_HEADER_SIZE];
+        if first != second {
+            debug!("Wal-index header copies differ");
+            return None;
+        }
+
+        let header = WalIndexHeader::parse(first);
+        if header.is_init == 0 || header.version != INDEX_VERSION {
+            return None;
+        }
+        // The header checksum always uses native byte order
+        let native_big_endian = cfg!(target_endian = "big");
+        if wal_checksum(native_big_endian, &first[..40], (0, 0)) != header.checksum {
+            debug!("Wal-index header checksum mismatch");
+            return None;
+        }
+
+        let checkpoint = CheckpointInfo::parse(&data[2 * INDEX_HEADER_SIZE..HEADER_SIZE]);
+        Some(Self {
+            data,
+            header,
+            checkpoint,
+            rebuilt: false,
+        })
+    }
+
+    /// Creates an empty index for the WAL with the given header
+    pub fn new(wal_header: &WalHeader) -> Self {
+        let mut index = Self {
+            data: vec![0; BLOCK_SIZE],
+            header: WalIndexHeader {
+                version: INDEX_VERSION,
+                change: 0,
+                is_init: 1,
+                big_endian_checksum: wal_header.big_endian_checksum() as u8,
+                page_size: wal_header.page_size,
+                max_frame: 0,
+                db_size: 0,
+                frame_checksum: wal_header.checksum,
+                salt: wal_header.salt,
+                checksum: (0, 0),
+            },
+            checkpoint: CheckpointInfo {
+                backfill: 0,
+                read_marks: [
+                    0,
+                    READ_MARK_NOT_USED,
+                    READ_MARK_NOT_USED,
+                    READ_MARK_NOT_USED,
+                    READ_MARK_NOT_USED,
+                ],
+                backfill_attempted: 0,
+            },
+            rebuilt: true,
+        };
+        index.set_committed(0, 0, wal_header.checksum);
+        index
+    }
+
+    /// Returns true if the index was read from a `-shm` file, or has since been
+    /// written to one
+    pub fn is_shared(&self) -> bool {
+        !self.rebuilt
+    }
+
+    /// Returns true if this index describes the given WAL file
+    pub fn matches(&self, wal_header: &WalHeader) -> bool {
+        self.header.salt == wal_header.salt && self.header.page_size == wal_header.page_size
+    }
+
+    /// Hash slot for a page number
+    fn hash(page_num: u32) -> usize {
+        (page_num as usize).wrapping_mul(383) & (HASH_SLOTS - 1)
+    }
+
+    /// Block holding the entry for a 1-based frame number
+    fn block_for_frame(frame: u32) -> usize {
+        ((frame + PAGES_PER_BLOCK - PAGES_IN_FIRST_BLOCK - 1) / PAGES_PER_BLOCK) as usize
+    }
+
+    /// Returns where a block's page-number array starts, where its hash table
+    /// starts and the frame number before its first frame
+    fn block_layout(block: usize) -> (usize, usize, u32) {
+        let block_start = block * BLOCK_SIZE;
+        let hash_start = block_start + PAGES_PER_BLOCK as usize * 4;
+        if block == 0 {
+            (HEADER_SIZE, hash_start, 0)
+        } else {
+            let first_frame = PAGES_IN_FIRST_BLOCK + (block as u32 - 1) * PAGES_PER_BLOCK;
+            (block_start, hash_start, first_frame)
+        }
+    }
+
+    /// Records that `frame` holds `page_num`
+    ///
+    /// Frames must be appended in order. The first frame of a block clears
+    /// whatever an earlier generation of the WAL left in it.
+    pub fn append(&mut self, frame: u32, page_num: u32) {
+        let block = Self::block_for_frame(frame);
+        let (pages_start, hash_start, first_frame) = Self::block_layout(block);
+        let block_end = (block + 1) * BLOCK_SIZE;
+        if self.data.len() < block_end {
+            self.data.resize(block_end, 0);
+        }
+        let entry = frame - first_frame;
+        if entry == 1 {
+            self.data[pages_start..block_end].fill(0);
+        }
+
+        write_u32(
+            &mut self.data,
+            pages_start + (entry as usize - 1) * 4,
+            page_num,
+        );
+        let mut slot = Self::hash(page_num);
+        while read_u16(&self.data, hash_start + slot * 2) != 0 {
+            slot = (slot + 1) & (HASH_SLOTS - 1);
+        }
+        write_u16(&mut self.data, hash_start + slot * 2, entry as u16);
+    }
+
+    /// Forgets the frames after `max_frame`, which belong to a transaction
+    /// that never committed, so that new frames can take their place
+    pub fn discard_after(&mut self, max_frame: u32) {
+        let block = Self::block_for_frame(max_frame);
+        let (pages_start, hash_start, first_frame) = Self::block_layout(block);
+        if hash_start + HASH_SLOTS * 2 > self.data.len() {
+            return;
+        }
+        let last_entry = max_frame - first_frame;
+        for slot in 0..HASH_SLOTS {
+            if read_u16(&self.data, hash_start + slot * 2) as u32 > last_entry {
+                write_u16(&mut self.data, hash_start + slot * 2, 0);
+            }
+        }
+        self.data[pages_start + last_entry as usize * 4..hash_start].fill(0);
+    }
+
+    /// Makes the frames up to `max_frame` visible, with the database `db_size`
+    /// pages long and `frame_checksum` the checksum of the last frame
+    pub fn set_committed(&mut self, max_frame: u32, db_size: u32, frame_checksum: (u32, u32)) {
+        self.header.change = self.header.change.wrapping_add(1);
+        self.header.max_frame = max_frame;
+        self.header.db_size = db_size;
+        self.header.frame_checksum = frame_checksum;
+        let (first, rest) = self.data.split_at_mut(INDEX_HEADER_SIZE);
+        self.header.write(first);
+        rest[..INDEX_HEADER_SIZE].copy_from_slice(first);
+        if self.rebuilt {
+            // As recovery leaves it: nothing backfilled, with one reader slot
+            // pointing at the last frame
+            self.checkpoint.backfill_attempted = max_frame;
+            self.checkpoint.read_marks[1] = if max_frame == 0 {
+                READ_MARK_NOT_USED
+            } else {
+                max_frame
+            };
+        }
+    }
+
+    /// Writes the index to a `-shm` file, covering the frames from
+    /// `first_frame` on, or all of it if it was rebuilt
+    pub fn write(&mut self, file: &dyn DatabaseFile, first_frame: u32) -> Result<()> {
+        let first_block = if self.rebuilt {
+            0
+        } else {
+            Self::block_for_frame(first_frame)
+        };
+        let (pages_start, _, _) = Self::block_layout(first_block);
+        let last_block = Self::block_for_frame(self.header.max_frame);
+        let end = ((last_block + 1) * BLOCK_SIZE).min(self.data.len());
+        if pages_start < end {
+            file.write_all_at(pages_start as u64, &self.data[pages_start..end])?;
+        }
+
+        if self.rebuilt {
+            let start = 2 * INDEX_HEADER_SIZE;
+            let mut checkpoint = self.data[start..HEADER_SIZE].to_vec();
+            self.checkpoint.write(&mut checkpoint);
+            file.write_all_at(start as u64, &checkpoint[..LOCK_BYTES.start - start])?;
+            file.write_all_at(LOCK_BYTES.end as u64, &checkpoint[LOCK_BYTES.end - start..])?;
+        }
+        // The second copy goes first, so that readers never take a half
+        // written header for a consistent one
+        file.write_all_at(
+            INDEX_HEADER_SIZE as u64,
+            &self.data[INDEX_HEADER_SIZE..2 * INDEX_HEADER_SIZE],
+        )?;
+        file.write_all_at(0, &self.data[..INDEX_HEADER_SIZE])?;
+        file.sync()?;
+        self.rebuilt = false;
+        Ok(())
+    }
+
+    /// Returns the newest frame at or below `max_frame` that holds `page_num`
+    pub fn find_frame(&self, page_num: u32) -> Result<Option<u32>> {
+        let max_frame = self.header.max_frame;
+        if max_frame == 0 {
+            return Ok(None);
+        }
+
+        // Search blocks from newest to oldest; the first hit is the newest frame
+        for block in (0..=Self::block_for_frame(max_frame)).rev() {
+            let (pages_start, hash_start, first_frame) = Self::block_layout(block);
+            if hash_start + HASH_SLOTS * 2 > self.data.len() {
+                return Err(anyhow!("Wal-index truncated at block {}", block));
+            }
+
+            let mut found = None;
+            let mut slot = Self::hash(page_num);
+            for _ in 0..HASH_SLOTS {
+                let entry = read_u16(&self.data, hash_start + slot * 2) as u32;
+                if entry == 0 {
+                    break;
+                }
+                let frame = first_frame + entry;
+                let stored_page = read_u32(&self.data, pages_start + (entry as usize - 1) * 4);
+                if frame <= max_frame && stored_page == page_num {
+                    found = Some(frame);
+                }
+                slot = (slot + 1) & (HASH_SLOTS - 1);
+            }
+
+            if found.is_some() {
+                return Ok(found);
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+/// The `-shm` file, held open with the locks this connection has on it
+///
+/// Locks are taken the way SQLite takes them, as POSIX byte-range locks, and
+/// never waited for. On Linux they are taken on the open file description
+/// instead, as closing any other descriptor for the file would drop a
+/// process-wide POSIX lock; the two kinds of lock still conflict with each
+/// other. Elsewhere, closing one connection's `-shm` file drops the locks of
+/// every connection in the process. No locks are taken where POSIX locks are
+/// not available.
+///
+/// ## Reader Slots
+///
+/// A checkpoint copies frames into the database file only up to the lowest
+/// read mark of the reader slots locked by readers, and the WAL is only
+/// started over while no reader slot is locked. A snapshot locks a slot whose
+/// mark is at or below its last commit, setting one to it if no slot has it,
+/// so that neither can change the pages it reads. The slot stays locked for
+/// as long as the snapshot is kept, which holds checkpoints back until the
+/// connection moves on to a newer one.
+pub struct Shm {
+    file: File,
+    /// Reader slot locked for the current snapshot
+    read_slot: Option<usize>,
+}
+
+impl Shm {
+    /// Opens the `-shm` file at `path`, creating it if `create` is set, and
+    /// takes the shared lock every connection using it holds
+    ///
+    /// Returns None where locks are not available, or if the file does not
+    /// exist, or cannot be written to, when `create` is not set.
+    pub fn open(path: &Path, create: bool) -> Result<Option<Self>> {
+        if cfg!(not(unix)) {
+            return Ok(None);
+        }
+        let file = match std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(create)
+            .open(path)
+        {
+            Ok(file) => file,
+            Err(e) if !create => {
+                debug!("Not locking the wal-index: {}", e);
+                return Ok(None);
+            }
+            Err(e) => return Err(e.into()),
+        };
+        if !lock_byte(&file, DMS_LOCK, ByteLock::Shared)? {
+            return Err(anyhow!(
+                "database is locked: another connection is resetting the wal-index"
+            ));
+        }
+        Ok(Some(Self {
+            file,
+            read_slot: None,
+        }))
+    }
+
+    /// Returns the `-shm` file
+    pub fn file(&self) -> &File {
+        &self.file
+    }
+
+    /// Reads the wal-index as of its last commit and locks a reader slot for
+    /// it, releasing the slot of the previous snapshot
+    ///
+    /// Returns None, with no slot locked, if the wal-index is not in a
+    /// consistent state. Fails with "database is locked" if no slot could be
+    /// locked, as when checkpoints keep moving the marks.
+    pub fn begin_read(&mut self) -> Result<Option<WalIndex>> {
+        self.end_read()?;
+        for _ in 0..READ_ATTEMPTS {
+            let Some(index) = WalIndex::read(&self.file)? else {
+                return Ok(None);
+            };
+            let max_frame = index.header.max_frame;
+            let marks = index.checkpoint.read_marks;
+            let mut slot = (1..READERS)
+                .filter(|&i| marks[i] != READ_MARK_NOT_USED && marks[i] <= max_frame)
+                .max_by_key(|&i| marks[i]);
+            let mut mark = slot.map(|i| marks[i]);
+            if mark != Some(max_frame) {
+                // Set the mark of a slot no reader is using to the last commit
+                for i in 1..READERS {
+                    if lock_byte(&self.file, READ_LOCK + i, ByteLock::Exclusive)? {
+                        let offset = (READ_MARKS + i * 4) as u64;
+                        let written = self.file.write_all_at(offset, &max_frame.to_ne_bytes());
+                        lock_byte(&self.file, READ_LOCK + i, ByteLock::Unlocked)?;
+                        written?;
+                        (slot, mark) = (Some(i), Some(max_frame));
+                        break;
+                    }
+                }
+            }
+            let (Some(slot), Some(mark)) = (slot, mark) else {
+                continue;
+            };
+            if !lock_byte(&self.file, READ_LOCK + slot, ByteLock::Shared)? {
+                continue;
+            }
+
+            // A checkpoint or a commit may have come between reading the
+            // wal-index and locking the slot
+            match WalIndex::read(&self.file)? {
+                Some(current)
+                    if current.checkpoint.read_marks[slot] == mark
+                        && current.header == index.header =>
+                {
+                    debug!("Locked reader slot {} at frame {}", slot, mark);
+                    self.read_slot = Some(slot);
+                    return Ok(Some(current));
+                }
+                _ => lock_byte(&self.file, READ_LOCK + slot, ByteLock::Unlocked)?,
+            };
+        }
+        Err(anyhow!(
+            "database is locked: no reader slot could be locked"
+        ))
+    }
+
+    /// Releases the reader slot of the current snapshot
+    pub fn end_read(&mut self) -> Result<()> {
+        if let Some(slot) = self.read_slot.take() {
+            lock_byte(&self.file, READ_LOCK + slot, ByteLock::Unlocked)?;
+        }
+        Ok(())
+    }
+
+    /// Takes SQLite's WAL write lock, which only the connection appending to
+    /// the WAL or starting it over holds
+    pub fn lock_write(&self) -> Result<()> {
+        if !lock_byte(&self.file, WRITE_LOCK, ByteLock::Exclusive)? {
+            return Err(anyhow!(
+                "database is locked: another connection is writing to the WAL"
+            ));
+        }
+        debug!("Took the WAL write lock");
+        Ok(())
+    }
+
+    /// Releases the WAL write lock
+    pub fn unlock_write(&self) -> Result<()> {
+        lock_byte(&self.file, WRITE_LOCK, ByteLock::Unlocked)?;
+        Ok(())
+    }
+}
+
+/// Locks one byte of `file` without waiting, returning false if another
+/// connection holds a conflicting lock
+///
+/// Locks are released when the file is closed.
+fn lock_byte(file: &File, offset: usize, lock: ByteLock) -> Result<bool> {
+    lock_bytes(file, offset as u64, 1, lock)
+}
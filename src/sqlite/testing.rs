@@ -0,0 +1,45 @@
+//! Test Helpers
+//!
+//! Unit tests that check this crate against the real sqlite3 share a
+//! temporary database file and a way to run the sqlite3 shell on it. The
+//! shell may not be installed, so the checks needing it are skipped then.
+
+use std::fs;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use std::process::Command;
+
+/// A database file in the temporary directory, deleted when dropped
+pub struct TempFile(pub PathBuf);
+
+impl TempFile {
+    /// Names a file for the test called `test`, removing any left over
+    pub fn new(test: &str) -> Self {
+        let name = format!("sqlite-rust-{}-{}.db", std::process::id(), test);
+        let path = std::env::temp_dir().join(name);
+        let _ = fs::remove_file(&path);
+        Self(path)
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+/// Runs `sql` with the sqlite3 shell on the file, returning what it printed,
+/// or None if sqlite3 is not installed
+pub fn sqlite3(file: &TempFile, sql: &str) -> Option<String> {
+    let output = match Command::new("sqlite3").arg(&file.0).arg(sql).output() {
+        Ok(output) => output,
+        Err(error) if error.kind() == ErrorKind::NotFound => {
+            eprintln!("sqlite3 not found, skipping the checks that need it");
+            return None;
+        }
+        Err(error) => panic!("cannot run sqlite3: {}", error),
+    };
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "sqlite3 failed: {}", stderr);
+    Some(String::from_utf8_lossy(&output.stdout).into_owned())
+}